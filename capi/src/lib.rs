@@ -0,0 +1,7 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+//! Static and shared C library built from `gpu_video::capi`, kept in its own package so the Rust crate is only built as an rlib.
+//! The header is `include/gpu_video.h`, link with `-lgpu_video_capi`.
+
+pub use gpu_video::capi::*;
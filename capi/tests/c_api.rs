@@ -0,0 +1,55 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+// Builds main.c against the shared library with the system C compiler (`CC`, `cc` by default) and runs it on a generated clip
+
+#![cfg(unix)]
+
+use gpu_video::*;
+use std::path::{ Path, PathBuf };
+use std::process::Command;
+
+fn write_clip(path: &Path) {
+    let mut params = EncoderParams::new(EncoderCodec::H264, 320, 240, PixelFormat::YUV420P, 25.0);
+    params.bitrate = Bitrate::Constant(1.0);
+    let mut encoder = Encoder::new(IoType::from(path.to_string_lossy().as_ref()), params).unwrap();
+    for i in 0..15u8 {
+        let mut frame = VideoFrame::solid_color(PixelFormat::RGBA, 320, 240, [i * 16, 128, 255 - i * 16, 255]).unwrap();
+        encoder.encode_frame(&mut frame).unwrap();
+    }
+    encoder.finish().unwrap();
+}
+
+#[test]
+fn c_program_decodes() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let out_dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    // The test runs from target/<profile>/deps, the library is uplifted to target/<profile>
+    let deps_dir = std::env::current_exe().unwrap().parent().unwrap().to_path_buf();
+    let lib_dir = deps_dir.parent().unwrap().to_path_buf();
+
+    let input = out_dir.join("capi_input.mp4");
+    write_clip(&input);
+
+    let exe = out_dir.join("capi_test");
+    let status = Command::new(std::env::var("CC").unwrap_or_else(|_| "cc".into()))
+        .arg(root.join("tests/main.c"))
+        .arg("-I").arg(root.join("../include"))
+        .arg("-L").arg(&lib_dir)
+        .arg("-L").arg(&deps_dir)
+        .arg("-lgpu_video_capi")
+        .arg("-o").arg(&exe)
+        .status().expect("C compiler not found");
+    assert!(status.success(), "compiling main.c failed");
+
+    let library_path = std::env::join_paths([lib_dir.clone(), deps_dir].into_iter().chain(std::env::var_os("LD_LIBRARY_PATH").map(|x| std::env::split_paths(&x).collect::<Vec<_>>()).unwrap_or_default())).unwrap();
+    let output = Command::new(&exe)
+        .arg(&input)
+        .env("LD_LIBRARY_PATH", &library_path)
+        .env("DYLD_LIBRARY_PATH", &library_path)
+        .output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{stdout}\n{}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains("frame "));
+    assert!(stdout.ends_with("OK\n"));
+}
@@ -0,0 +1,82 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+// Usage: capi_test <input>
+// Decodes the input once through the path API and once through the callback IO API.
+
+#include <stdio.h>
+#include "gpu_video.h"
+
+#define CHECK(x) do { int _r = (x); if (_r < 0) { fprintf(stderr, "%s failed (%d): %s\n", #x, _r, gv_last_error()); return 1; } } while (0)
+
+static int64_t file_read(void *user_data, uint8_t *buf, size_t size) {
+    size_t n = fread(buf, 1, size, (FILE *)user_data);
+    if (n == 0 && ferror((FILE *)user_data)) return -1;
+    return (int64_t)n;
+}
+static int64_t file_seek(void *user_data, int64_t offset, int whence) {
+    FILE *f = (FILE *)user_data;
+    if (fseek(f, (long)offset, whence) != 0) return -1;
+    return (int64_t)ftell(f);
+}
+
+static int decode(GvDecoder *decoder) {
+    int count = gv_decoder_stream_count(decoder);
+    CHECK(count);
+    for (int i = 0; i < count; i++) {
+        GvStreamInfo info;
+        CHECK(gv_decoder_stream_info(decoder, i, &info));
+        printf("stream %u: type %d, %ux%u, %u channels @ %u Hz\n", info.index, info.stream_type, info.width, info.height, info.channels, info.sample_rate);
+    }
+
+    int frames = 0;
+    GvFrame *frame = NULL;
+    while (frames < 10) {
+        int r = gv_decoder_next_frame(decoder, &frame);
+        CHECK(r);
+        if (r == GV_END_OF_STREAM) break;
+
+        GvFrameInfo info;
+        CHECK(gv_frame_info(frame, &info));
+        int planes = gv_frame_plane_count(frame);
+        CHECK(planes);
+        printf("frame %lld us: %ux%u %s, %d planes", (long long)info.timestamp_us, info.width, info.height, gv_frame_format_name(frame), planes);
+        for (int p = 0; p < planes; p++) {
+            GvPlane plane;
+            CHECK(gv_frame_plane(frame, p, &plane));
+            if (plane.data == NULL || plane.size < plane.stride) { fprintf(stderr, "invalid plane %d\n", p); return 1; }
+            printf(" [stride %zu, size %zu]", plane.stride, plane.size);
+        }
+        printf("\n");
+        gv_frame_release(frame);
+        frames++;
+    }
+    if (frames == 0) { fprintf(stderr, "no frames decoded\n"); return 1; }
+    return 0;
+}
+
+int main(int argc, char **argv) {
+    if (argc < 2) { fprintf(stderr, "Usage: %s <input>\n", argv[0]); return 2; }
+
+    GvDecoder *decoder = NULL;
+    CHECK(gv_decoder_open(argv[1], -1, &decoder));
+    int ret = decode(decoder);
+    gv_decoder_free(decoder);
+    if (ret) return ret;
+
+    FILE *f = fopen(argv[1], "rb");
+    if (!f) { perror("fopen"); return 1; }
+    GvIoCallbacks callbacks = { f, file_read, file_seek };
+    CHECK(gv_decoder_open_callbacks(callbacks, -1, &decoder));
+    ret = decode(decoder);
+    gv_decoder_free(decoder);
+    fclose(f);
+
+    // Invalid arguments must be reported, not crash
+    if (gv_decoder_open(NULL, -1, &decoder) != GV_INVALID_ARGUMENT) { fprintf(stderr, "NULL path accepted\n"); return 1; }
+    GvIoCallbacks no_read = { NULL, NULL, NULL };
+    if (gv_decoder_open_callbacks(no_read, -1, &decoder) != GV_INVALID_ARGUMENT) { fprintf(stderr, "NULL read callback accepted\n"); return 1; }
+
+    if (!ret) printf("OK\n");
+    return ret;
+}
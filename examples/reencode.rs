@@ -0,0 +1,44 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+// Decodes the video stream of a file and encodes it again, checking that every frame is written
+// cargo run --example reencode -- <input> <output.mp4> [h264|h265|prores]
+
+use gpu_video::*;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let (Some(input), Some(output)) = (args.get(1), args.get(2)) else { return eprintln!("Usage: reencode <input> <output.mp4> [h264|h265|prores]"); };
+    let codec = match args.get(3).map(String::as_str) {
+        Some("h265") => EncoderCodec::H265,
+        Some("prores") => EncoderCodec::ProRes,
+        _ => EncoderCodec::H264,
+    };
+
+    let mut decoder = Decoder::new(input, DecoderOptions { gpu_index: Some(0), ..Default::default() }).unwrap();
+    let info = decoder.get_video_info().unwrap();
+    for stream in decoder.streams() {
+        stream.decode = matches!(stream.stream_type, StreamType::Video);
+    }
+
+    let params = EncoderParams::new(codec, info.width, info.height, PixelFormat::YUV420P, info.fps as f32);
+    let mut encoder = Encoder::new(IoType::from(output.as_str()), params).unwrap();
+
+    let mut encoded = 0;
+    while let Some(frame) = decoder.next_frame() {
+        let Frame::Video(mut frame) = frame else { continue; };
+        encoder.encode_frame(&mut frame).unwrap();
+        encoded += 1;
+    }
+    encoder.finish().unwrap();
+
+    let mut check = Decoder::new(output, DecoderOptions::default()).unwrap();
+    let mut decoded = 0;
+    while let Some(frame) = check.next_frame() {
+        if matches!(frame, Frame::Video(_)) { decoded += 1; }
+    }
+    println!("{encoded} frames encoded, {decoded} decoded from {output}");
+    if decoded != encoded {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,28 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+// Records a Chrome trace of decoding the first N frames
+// cargo run --example trace --features tracing -- <input> [frames] [trace.json]
+
+use gpu_video::*;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let Some(input) = args.get(1) else { return eprintln!("Usage: trace <input> [frames] [trace.json]"); };
+    let max_frames: usize = args.get(2).and_then(|x| x.parse().ok()).unwrap_or(100);
+    let output = args.get(3).map(String::as_str).unwrap_or("trace.json");
+
+    let _guard = profiling::chrome_trace_guard(output);
+
+    let mut decoder = Decoder::new(input, DecoderOptions { gpu_index: Some(0), ..Default::default() }).unwrap();
+    let mut frames = 0;
+    while let Some(frame) = decoder.next_frame() {
+        if let Frame::Video(mut v) = frame {
+            // Include the hw transfer in the trace
+            let _ = v.get_cpu_buffers();
+            frames += 1;
+            if frames >= max_frames { break; }
+        }
+    }
+    println!("Decoded {frames} frames, trace written to {output}");
+}
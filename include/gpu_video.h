@@ -0,0 +1,133 @@
+#ifndef GPU_VIDEO_H
+#define GPU_VIDEO_H
+
+/* Generated with cbindgen, do not edit. Regenerate with `just capi-header` */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#define GV_OK 0
+
+#define GV_END_OF_STREAM 1
+
+#define GV_ERROR -1
+
+#define GV_INVALID_ARGUMENT -2
+
+#define GV_PANIC -3
+
+typedef struct GvDecoder GvDecoder;
+
+typedef struct GvFrame GvFrame;
+
+typedef struct GvPlane {
+  uint8_t *data;
+  size_t stride;
+  size_t size;
+} GvPlane;
+
+typedef struct GvStreamInfo {
+  uint32_t index;
+  /**
+   * 0 - video, 1 - audio, 2 - subtitle, 3 - other
+   */
+  int stream_type;
+  uint32_t width;
+  uint32_t height;
+  uint32_t channels;
+  uint32_t sample_rate;
+  int time_base_num;
+  int time_base_den;
+} GvStreamInfo;
+
+typedef struct GvFrameInfo {
+  uint32_t width;
+  uint32_t height;
+  /**
+   * -1 when unknown
+   */
+  int64_t timestamp_us;
+  bool is_hardware;
+  uint32_t bit_depth;
+  /**
+   * 0 - unspecified, 1 - BT.709, 2 - BT.470BG, 3 - SMPTE170M, 4 - BT.2020 NCL, 5 - BT.2020 CL
+   */
+  int color_space;
+  /**
+   * 0 - unspecified, 1 - limited, 2 - full
+   */
+  int color_range;
+} GvFrameInfo;
+
+/**
+ * User callbacks for `gv_decoder_open_callbacks`. `read` returns the number of bytes read, 0 at the end and negative on error.
+ * `seek` uses the `SEEK_SET`/`SEEK_CUR`/`SEEK_END` whence values and returns the new position or negative on error.
+ * `read` is required, `seek` can be NULL for non-seekable input like pipes.
+ */
+typedef struct GvIoCallbacks {
+  void *user_data;
+  int64_t (*read)(void *user_data, uint8_t *buf, size_t size);
+  int64_t (*seek)(void *user_data, int64_t offset, int whence);
+} GvIoCallbacks;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Message of the last error on this thread, never NULL
+ */
+const char *gv_last_error(void);
+
+/**
+ * Opens a file or URL. `gpu_index` < 0 disables hardware decoding.
+ */
+int gv_decoder_open(const char *path, int gpu_index, GvDecoder **out);
+
+/**
+ * Opens an input read through the user callbacks
+ */
+int gv_decoder_open_callbacks(GvIoCallbacks callbacks, int gpu_index, GvDecoder **out);
+
+void gv_decoder_free(GvDecoder *decoder);
+
+int gv_decoder_stream_count(GvDecoder *decoder);
+
+int gv_decoder_stream_info(GvDecoder *decoder, uint32_t index, GvStreamInfo *out);
+
+int gv_decoder_set_stream_enabled(GvDecoder *decoder, uint32_t index, bool enabled);
+
+int gv_decoder_seek(GvDecoder *decoder, int64_t timestamp_us);
+
+/**
+ * Decodes the next video frame. Returns `GV_END_OF_STREAM` when there are no more frames.
+ */
+int gv_decoder_next_frame(GvDecoder *decoder, GvFrame **out);
+
+void gv_frame_release(GvFrame *frame);
+
+int gv_frame_info(GvFrame *frame, GvFrameInfo *out);
+
+/**
+ * Name of the pixel format, valid until the frame is released
+ */
+const char *gv_frame_format_name(GvFrame *frame);
+
+/**
+ * Number of CPU planes. Downloads hardware frames to CPU memory on first call.
+ */
+int gv_frame_plane_count(GvFrame *frame);
+
+/**
+ * Pointer, stride and size of the plane, valid until the frame is released
+ */
+int gv_frame_plane(GvFrame *frame, uint32_t plane, GvPlane *out);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* GPU_VIDEO_H */
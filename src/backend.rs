@@ -0,0 +1,227 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+use parking_lot::Mutex;
+
+#[derive(Debug, Clone)]
+pub struct BackendAvailability {
+    pub backend: String,
+    pub available: bool,
+    pub version: Option<String>,
+    pub reason: Option<String>,
+    pub searched_paths: Vec<String>,
+}
+
+lazy_static::lazy_static! {
+    static ref AVAILABILITY: Mutex<Option<Vec<BackendAvailability>>> = Mutex::new(None);
+}
+
+/// Returns the availability of every backend compiled into this build.
+/// Results are cached, use `refresh_available_backends` after installing the runtime libraries.
+pub fn available_backends() -> Vec<BackendAvailability> {
+    let mut cache = AVAILABILITY.lock();
+    cache.get_or_insert_with(check_backends).clone()
+}
+
+pub fn refresh_available_backends() -> Vec<BackendAvailability> {
+    let ret = check_backends();
+    *AVAILABILITY.lock() = Some(ret.clone());
+    ret
+}
+
+pub(crate) fn ensure_available(backend: &str) -> Result<(), crate::VideoProcessingError> {
+    let backends = available_backends();
+    if backends.is_empty() {
+        // A build configuration problem, not something wrong with the input
+        return Err(crate::VideoProcessingError::NoBackendCompiled);
+    }
+    match backends.into_iter().find(|x| x.backend == backend) {
+        Some(x) if x.available => Ok(()),
+        Some(x) => Err(crate::VideoProcessingError::BackendUnavailable {
+            backend: x.backend,
+            reason: x.reason.unwrap_or_default(),
+            searched_paths: x.searched_paths,
+            versions: Box::new(crate::runtime_versions()),
+        }),
+        None => Err(crate::VideoProcessingError::BackendUnavailable {
+            backend: backend.to_string(),
+            reason: "Backend not compiled in".into(),
+            searched_paths: Vec::new(),
+            versions: Box::new(crate::runtime_versions()),
+        })
+    }
+}
+
+/// Libraries and handles the host application initialized itself, which the crate uses as they are instead of setting up its own.
+/// There are no BRAW and R3D backends in this build yet, their SDK handles will be added here together with them
+#[derive(Debug, Default)]
+pub struct InitConfig {
+    /// ffmpeg is initialized by the host, the crate doesn't do any global ffmpeg setup.
+    /// The linked libraries must still have the major versions this crate was built against
+    pub ffmpeg_external: bool,
+    /// Hardware devices of the host, used instead of creating own devices of the same type and name
+    pub hw_devices: Vec<ExternalHwDevice>,
+}
+
+#[derive(Debug)]
+pub struct ExternalHwDevice {
+    device_ref: *mut std::ffi::c_void,
+    device_name: Option<String>,
+}
+impl ExternalHwDevice {
+    /// `device_ref` is an `AVBufferRef*` of an `AVHWDeviceContext`. The crate takes its own reference, the host keeps ownership of its one.
+    /// `device_name` is the same as the `hwaccel_device` option, decoders and encoders asking for this device name get it
+    ///
+    /// # Safety
+    /// `device_ref` must be null or point to a valid `AVBufferRef` until `init_with` returns. If the buffer is an `AVHWDeviceContext`,
+    /// it must be initialized and usable from any thread. Null pointers and buffers which aren't device contexts are rejected by `init_with`
+    /// as far as they can be detected, dangling pointers can't be
+    pub unsafe fn new(device_ref: *mut std::ffi::c_void, device_name: Option<&str>) -> Self {
+        Self { device_ref, device_name: device_name.map(str::to_owned) }
+    }
+}
+
+#[derive(Debug, Default)]
+struct InitState {
+    initialized: bool,
+    ffmpeg_external: bool,
+}
+
+lazy_static::lazy_static! {
+    static ref INIT: Mutex<InitState> = Mutex::new(InitState::default());
+}
+
+/// Initializes the crate with libraries the host set up itself. Otherwise the crate initializes everything on first use,
+/// after which this returns `AlreadyInitialized`.
+///
+/// Call it once, from any thread, before the first decoder, encoder or probe is created. Initialization is serialized,
+/// but decoders created concurrently with this call may still use their own devices. The external devices must stay valid
+/// and usable from any thread while the crate holds its reference, ffmpeg device contexts are thread safe for that.
+/// Nothing is changed when the config is invalid:
+///
+/// ```
+/// use gpu_video::{ init_with, InitConfig, ExternalHwDevice, VideoProcessingError };
+/// let device = unsafe { ExternalHwDevice::new(std::ptr::null_mut(), None) };
+/// let config = InitConfig { hw_devices: vec![device], ..Default::default() };
+/// assert!(matches!(init_with(config), Err(VideoProcessingError::InvalidExternalHandle { what: "hw device", .. })));
+/// assert!(init_with(InitConfig { ffmpeg_external: true, ..Default::default() }).is_ok());
+/// assert!(matches!(init_with(InitConfig::default()), Err(VideoProcessingError::AlreadyInitialized)));
+/// ```
+pub fn init_with(config: InitConfig) -> Result<(), crate::VideoProcessingError> {
+    // The constructor of `ExternalHwDevice` requires valid or null pointers
+    for device in &config.hw_devices {
+        unsafe { crate::support::ffmpeg_hw::validate_external_device(device.device_ref as *mut ffmpeg_next::ffi::AVBufferRef)?; }
+    }
+    let mut state = INIT.lock();
+    if state.initialized {
+        return Err(crate::VideoProcessingError::AlreadyInitialized);
+    }
+    if config.ffmpeg_external {
+        check_linked_ffmpeg()?;
+    } else {
+        ffmpeg_next::init()?;
+    }
+    for device in &config.hw_devices {
+        unsafe { crate::support::ffmpeg_hw::register_external_device(device.device_ref as *mut ffmpeg_next::ffi::AVBufferRef, device.device_name.as_deref())?; }
+    }
+    *state = InitState { initialized: true, ffmpeg_external: config.ffmpeg_external };
+    Ok(())
+}
+
+/// Global ffmpeg setup, done once unless the host declared it external in `init_with`
+pub(crate) fn init_ffmpeg() -> Result<(), ffmpeg_next::Error> {
+    let mut state = INIT.lock();
+    if !state.initialized {
+        ffmpeg_next::init()?;
+        state.initialized = true;
+    }
+    Ok(())
+}
+
+/// A host linking its own ffmpeg must link the ABI this crate was built against
+fn check_linked_ffmpeg() -> Result<(), crate::VideoProcessingError> {
+    use ffmpeg_next::ffi::*;
+    let libraries = unsafe { [
+        ("avutil", avutil_version(), LIBAVUTIL_VERSION_MAJOR),
+        ("avcodec", avcodec_version(), LIBAVCODEC_VERSION_MAJOR),
+        ("avformat", avformat_version(), LIBAVFORMAT_VERSION_MAJOR),
+    ] };
+    for (name, linked, built) in libraries {
+        if linked >> 16 != built {
+            return Err(crate::VideoProcessingError::InvalidExternalHandle { what: "ffmpeg", reason: format!("{name} {} is linked, but gpu-video was built against major version {built}", crate::version::lib_version(linked)) });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "ffmpeg")]
+fn check_backends() -> Vec<BackendAvailability> {
+    vec![check_ffmpeg()]
+}
+#[cfg(not(feature = "ffmpeg"))]
+fn check_backends() -> Vec<BackendAvailability> {
+    Vec::new()
+}
+
+#[cfg(feature = "ffmpeg")]
+fn check_ffmpeg() -> BackendAvailability {
+    // FFmpeg is linked at build time, so there's nothing to search for at runtime
+    match init_ffmpeg() {
+        Ok(_) => {
+            let version = unsafe { ffmpeg_next::ffi::avformat_version() };
+            BackendAvailability {
+                backend: "ffmpeg".into(),
+                available: true,
+                version: Some(format!("{}.{}.{}", version >> 16, (version >> 8) & 0xff, version & 0xff)),
+                reason: None,
+                searched_paths: Vec::new(),
+            }
+        },
+        Err(e) => BackendAvailability {
+            backend: "ffmpeg".into(),
+            available: false,
+            version: None,
+            reason: Some(format!("Failed to initialize ffmpeg: {e:?}")),
+            searched_paths: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "ffmpeg")]
+    fn compiled_backends() {
+        assert!(available_backends().iter().any(|x| x.backend == "ffmpeg" && x.available));
+        assert!(ensure_available("ffmpeg").is_ok());
+        assert!(matches!(ensure_available("braw"), Err(crate::VideoProcessingError::BackendUnavailable { backend, .. }) if backend == "braw"));
+    }
+
+    #[test]
+    #[cfg(not(feature = "ffmpeg"))]
+    fn no_backend_compiled() {
+        assert!(available_backends().is_empty());
+        assert!(matches!(ensure_available("ffmpeg"), Err(crate::VideoProcessingError::NoBackendCompiled)));
+        assert!(matches!(crate::Decoder::new("input.mp4", Default::default()), Err(crate::VideoProcessingError::NoBackendCompiled)));
+    }
+
+    #[test]
+    fn invalid_external_devices() {
+        // Rejected before the initialization state is checked, so this doesn't depend on other tests initializing ffmpeg
+        let init = |device_ref: *mut ffmpeg_next::ffi::AVBufferRef| {
+            let device = unsafe { ExternalHwDevice::new(device_ref as *mut _, Some("gpu-video-invalid-device-test")) };
+            match init_with(InitConfig { hw_devices: vec![device], ..Default::default() }) {
+                Err(crate::VideoProcessingError::InvalidExternalHandle { what: "hw device", reason }) => reason,
+                x => panic!("{x:?}"),
+            }
+        };
+        assert_eq!(init(std::ptr::null_mut()), "null AVBufferRef");
+        unsafe {
+            let mut zeroed = ffmpeg_next::ffi::av_buffer_allocz(std::mem::size_of::<ffmpeg_next::ffi::AVHWDeviceContext>());
+            assert!(init(zeroed).contains("unknown device type"));
+            ffmpeg_next::ffi::av_buffer_unref(&mut zeroed);
+        }
+    }
+}
@@ -10,10 +10,34 @@ fn main() {
 
     let _ = simple_log::new(simple_log::LogConfig::default());
 
+    let args: Vec<String> = std::env::args().collect();
+    match (args.get(1).map(String::as_str), args.get(2)) {
+        (Some("qc"), Some(input)) => return qc(input),
+        (Some("qc"), None) => return eprintln!("Usage: gpu_video qc <input>"),
+        (Some("probe"), Some(input)) => return probe(input, args.iter().any(|x| x == "--first-frame"), args.iter().any(|x| x == "--report")),
+        (Some("probe"), None) => return eprintln!("Usage: gpu_video probe <input> [--first-frame] [--report]"),
+        (Some("tag"), Some(input)) => return tag(input, &args[3..]),
+        (Some("tag"), None) => return eprintln!("Usage: gpu_video tag <input> [--output <path>] [--set key=value]... [--remove key]... [--rotation <degrees>] [--strip-gps]"),
+        (Some("fingerprint"), Some(input)) => return fingerprint_cmd(input, args.get(3)),
+        (Some("fingerprint"), None) => return eprintln!("Usage: gpu_video fingerprint <input> [<other input>]"),
+        (Some("sync"), Some(input)) => return sync_cmd(input),
+        (Some("sync"), None) => return eprintln!("Usage: gpu_video sync <input>"),
+        (Some("version"), _) => {
+            if args.iter().any(|x| x == "--verbose") {
+                println!("{}", runtime_versions());
+            } else {
+                println!("gpu-video {}", env!("CARGO_PKG_VERSION"));
+            }
+            return;
+        },
+        _ => { }
+    }
+
     let mut decoder = Decoder::new("E:/__GH011230.MP4", DecoderOptions {
         gpu_index: Some(4),
         ranges_ms: Vec::new(),
-        custom_options: HashMap::new()
+        custom_options: HashMap::new(),
+        ..Default::default()
     }).unwrap();
 
     for stream in decoder.streams() {
@@ -43,3 +67,122 @@ fn main() {
     println!("Done in {:.3}s ", _time.elapsed().as_millis() as f64 / 1000.0);
     std::io::stdout().flush().unwrap();
 }
+
+fn qc(input: &str) {
+    let findings = std::sync::Arc::new(parking_lot::Mutex::new(Vec::new()));
+    let findings2 = findings.clone();
+
+    let mut decoder = match Decoder::new(input, DecoderOptions {
+        analysis: AnalysisFlags { black_frames: true, freeze_frames: true, ..Default::default() },
+        diagnostics: Some(DiagnosticsCallback(std::sync::Arc::new(move |event| findings2.lock().push(event.clone())))),
+        ..Default::default()
+    }) {
+        Ok(x) => x,
+        Err(e) => { eprintln!("Failed to open {input}: {e}"); return; }
+    };
+    for stream in decoder.streams() {
+        stream.decode = matches!(stream.stream_type, StreamType::Video);
+    }
+
+    let mut frames = 0;
+    while let Some(frame) = decoder.next_frame() {
+        if let Frame::Video(_) = frame { frames += 1; }
+    }
+
+    let findings = findings.lock();
+    for event in findings.iter() {
+        match event {
+            Diagnostic::BlackFrame  { timestamp_us, confidence } => println!("{:>12.3}s  black frame   (confidence {:.2})", *timestamp_us as f64 / 1000000.0, confidence),
+            Diagnostic::FrozenFrame { timestamp_us, confidence } => println!("{:>12.3}s  frozen frame  (confidence {:.2})", *timestamp_us as f64 / 1000000.0, confidence),
+            other => println!("{other:?}"),
+        }
+    }
+    println!("Analyzed {frames} frames, {} findings", findings.len());
+}
+
+fn tag(input: &str, args: &[String]) {
+    let mut edits = MetadataEdits::default();
+    let mut output = None;
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match (arg.as_str(), it.clone().next()) {
+            ("--strip-gps", _) => { edits.strip_gps = true; continue; },
+            ("--output", Some(v)) => output = Some(v.as_str()),
+            ("--remove", Some(v)) => edits.remove.push(v.clone()),
+            ("--set", Some(v)) => match v.split_once('=') {
+                Some((k, v)) => { edits.set.insert(k.to_string(), v.to_string()); },
+                None => return eprintln!("Invalid --set {v}, expected key=value"),
+            },
+            ("--rotation", Some(v)) => match v.parse() {
+                Ok(r) => edits.rotation = Some(r),
+                Err(_) => return eprintln!("Invalid rotation {v}"),
+            },
+            _ => return eprintln!("Unknown argument {arg}"),
+        }
+        it.next();
+    }
+    match edit_metadata(input, output, &edits) {
+        Ok(()) => println!("Updated {}", output.unwrap_or(input)),
+        Err(e) => eprintln!("Failed to edit {input}: {e}"),
+    }
+}
+
+fn fingerprint_cmd(input: &str, other: Option<&String>) {
+    let print = |input: &str| match fingerprint(input, FingerprintOptions::default()) {
+        Ok(x) => {
+            println!("{input}: {x:?}");
+            Some(x)
+        },
+        Err(e) => { eprintln!("Failed to fingerprint {input}: {e}"); None }
+    };
+    let Some(a) = print(input) else { return; };
+    if let Some(b) = other.and_then(|x| print(x)) {
+        println!("Similarity: {:.3}", a.similarity(&b));
+    }
+}
+
+fn sync_cmd(input: &str) {
+    let offset = match measure_av_offset(input, AvOffsetOptions::default()) {
+        Ok(x) => x,
+        Err(e) => { eprintln!("Failed to measure {input}: {e}"); return; }
+    };
+    let ms = |us: i64| us as f64 / 1000.0;
+    println!("Video stream {}: {:.3} ms - {:.3} ms", offset.video_stream, ms(offset.video_start_us), ms(offset.video_end_us));
+    println!("Audio stream {}: {:.3} ms - {:.3} ms", offset.audio_stream, ms(offset.audio_start_us), ms(offset.audio_end_us));
+    println!("Start offset: {:+.3} ms, end offset: {:+.3} ms, drift: {:+.3} ms/min", ms(offset.start_offset_us), ms(offset.end_offset_us), offset.drift_ms_per_minute);
+    match &offset.ltc {
+        Some(ltc) => {
+            println!("LTC on stream {} channel {}: {} frames, {} - {} (video starts at {})", ltc.stream_index, ltc.channel, ltc.frames, ltc.first_timecode, ltc.last_timecode, ltc.video_timecode);
+            println!("LTC start offset: {:+.3} ms, end offset: {:+.3} ms, drift: {:+.3} ms/min", ms(ltc.start_offset_us), ms(ltc.end_offset_us), ltc.drift_ms_per_minute);
+        },
+        None => println!("No LTC compared")
+    }
+}
+
+fn probe(input: &str, first_frame: bool, report: bool) {
+    let mut decoder = match Decoder::new(input, DecoderOptions::default()) {
+        Ok(x) => x,
+        Err(e) => { eprintln!("Failed to open {input}: {e}"); return; }
+    };
+    match decoder.get_video_info() {
+        Ok(info) => println!("{info:?}"),
+        Err(e) => println!("No video info: {e}")
+    }
+    for stream in decoder.stream_infos() {
+        println!("{stream:?}");
+    }
+    if first_frame || report {
+        for stream in decoder.streams() {
+            stream.decode = matches!(stream.stream_type, StreamType::Video);
+        }
+        while let Some(frame) = decoder.next_frame() {
+            if let Frame::Video(mut v) = frame {
+                if first_frame { println!("{}", v.describe()); }
+                break;
+            }
+        }
+    }
+    if report {
+        println!("{}", decoder.pipeline_report());
+    }
+}
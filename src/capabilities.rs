@@ -0,0 +1,71 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+use crate::types::VideoProcessingError;
+use ffmpeg_next::{ ffi, decoder, encoder };
+use std::ffi::CStr;
+
+#[derive(Debug, Clone)]
+pub struct DeviceCapabilities {
+    pub backend: String,
+    pub min_size: (i32, i32),
+    pub max_size: (i32, i32),
+    pub hw_formats: Vec<String>,
+    pub sw_formats: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CodecCapabilities {
+    pub codec: String,
+    pub is_encoder: bool,
+    pub profiles: Vec<String>,
+    pub pixel_formats: Vec<String>,
+    pub devices: Vec<DeviceCapabilities>,
+}
+
+impl CodecCapabilities {
+    /// Whether any of the devices can handle frames of this size
+    pub fn supports_size(&self, width: u32, height: u32) -> bool {
+        self.devices.iter().any(|d| {
+            (d.max_size.0 <= 0 || width as i32 <= d.max_size.0) && (d.max_size.1 <= 0 || height as i32 <= d.max_size.1) &&
+            width as i32 >= d.min_size.0 && height as i32 >= d.min_size.1
+        })
+    }
+}
+
+/// Reports the profiles, pixel formats and hardware device limits of the encoder or decoder with the given name (e.g. `hevc_nvenc`, `h264`).
+/// `device` is the same as the `hwaccel_device` option.
+pub fn codec_capabilities(name: &str, is_encoder: bool, device: Option<&str>) -> Result<CodecCapabilities, VideoProcessingError> {
+    crate::backend::init_ffmpeg()?;
+
+    let mut codec = if is_encoder {
+        encoder::find_by_name(name).ok_or(VideoProcessingError::EncoderNotFound)?
+    } else {
+        decoder::find_by_name(name).ok_or(VideoProcessingError::DecoderNotFound)?
+    };
+    let ptr = unsafe { codec.as_mut_ptr() };
+
+    let mut profiles = Vec::new();
+    unsafe {
+        let mut p = (*ptr).profiles;
+        while !p.is_null() && (*p).profile != ffi::FF_PROFILE_UNKNOWN {
+            if !(*p).name.is_null() {
+                profiles.push(CStr::from_ptr((*p).name).to_string_lossy().into());
+            }
+            p = p.offset(1);
+        }
+    }
+    let pixel_formats = unsafe {
+        if (*ptr).pix_fmts.is_null() { Vec::new() } else { crate::support::ffmpeg_hw::pix_formats_to_vec((*ptr).pix_fmts) }
+    };
+
+    let devices = crate::support::ffmpeg_hw::codec_hw_capabilities(ptr, device);
+
+    Ok(CodecCapabilities {
+        codec: name.to_string(),
+        is_encoder,
+        profiles,
+        pixel_formats: pixel_formats.iter().map(|x| format!("{x:?}")).collect(),
+        devices,
+    })
+}
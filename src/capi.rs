@@ -0,0 +1,315 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+//! C ABI for the decoder. The header is generated with `cbindgen` (see `just capi-header`).
+//!
+//! Ownership rules:
+//! - `GvDecoder` returned by `gv_decoder_open*` must be freed with `gv_decoder_free`.
+//! - `GvFrame` returned by `gv_decoder_next_frame` must be freed with `gv_frame_release`. Frames may outlive the decoder.
+//! - Plane pointers and strings returned for a frame are valid until the frame is released.
+//! - The string returned by `gv_last_error` is valid until the next call on the same thread.
+
+use crate::*;
+
+use std::cell::RefCell;
+use std::ffi::{ CStr, CString };
+use std::os::raw::{ c_char, c_int, c_void };
+use std::panic::{ catch_unwind, AssertUnwindSafe };
+use std::ptr;
+
+pub const GV_OK: c_int = 0;
+pub const GV_END_OF_STREAM: c_int = 1;
+pub const GV_ERROR: c_int = -1;
+pub const GV_INVALID_ARGUMENT: c_int = -2;
+pub const GV_PANIC: c_int = -3;
+
+pub struct GvDecoder {
+    decoder: Decoder,
+}
+
+pub struct GvFrame {
+    frame: VideoFrame,
+    planes: Option<Vec<GvPlane>>,
+    format_name: CString,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct GvPlane {
+    pub data: *mut u8,
+    pub stride: usize,
+    pub size: usize,
+}
+
+#[repr(C)]
+pub struct GvStreamInfo {
+    pub index: u32,
+    /// 0 - video, 1 - audio, 2 - subtitle, 3 - other
+    pub stream_type: c_int,
+    pub width: u32,
+    pub height: u32,
+    pub channels: u32,
+    pub sample_rate: u32,
+    pub time_base_num: c_int,
+    pub time_base_den: c_int,
+}
+
+#[repr(C)]
+pub struct GvFrameInfo {
+    pub width: u32,
+    pub height: u32,
+    /// -1 when unknown
+    pub timestamp_us: i64,
+    pub is_hardware: bool,
+    pub bit_depth: u32,
+    /// 0 - unspecified, 1 - BT.709, 2 - BT.470BG, 3 - SMPTE170M, 4 - BT.2020 NCL, 5 - BT.2020 CL
+    pub color_space: c_int,
+    /// 0 - unspecified, 1 - limited, 2 - full
+    pub color_range: c_int,
+}
+
+/// User callbacks for `gv_decoder_open_callbacks`. `read` returns the number of bytes read, 0 at the end and negative on error.
+/// `seek` uses the `SEEK_SET`/`SEEK_CUR`/`SEEK_END` whence values and returns the new position or negative on error.
+/// `read` is required, `seek` can be NULL for non-seekable input like pipes.
+#[repr(C)]
+pub struct GvIoCallbacks {
+    pub user_data: *mut c_void,
+    pub read: Option<extern "C" fn(user_data: *mut c_void, buf: *mut u8, size: usize) -> i64>,
+    pub seek: Option<extern "C" fn(user_data: *mut c_void, offset: i64, whence: c_int) -> i64>,
+}
+
+struct CallbackReader(GvIoCallbacks);
+// The caller is responsible for the callbacks being callable from the decoding thread
+unsafe impl Send for CallbackReader { }
+
+impl std::io::Read for CallbackReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let Some(read) = self.0.read else { return Err(std::io::ErrorKind::Unsupported.into()); };
+        let ret = read(self.0.user_data, buf.as_mut_ptr(), buf.len());
+        if ret < 0 { return Err(std::io::Error::other(format!("read callback returned {ret}"))); }
+        Ok(ret as usize)
+    }
+}
+impl std::io::Seek for CallbackReader {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        let (offset, whence) = match pos {
+            std::io::SeekFrom::Start(x)   => (x as i64, 0),
+            std::io::SeekFrom::Current(x) => (x, 1),
+            std::io::SeekFrom::End(x)     => (x, 2),
+        };
+        let Some(seek) = self.0.seek else { return Err(std::io::ErrorKind::Unsupported.into()); };
+        let ret = seek(self.0.user_data, offset, whence);
+        if ret < 0 { return Err(std::io::Error::other(format!("seek callback returned {ret}"))); }
+        Ok(ret as u64)
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn set_last_error(msg: impl std::fmt::Display) {
+    let msg = CString::new(msg.to_string().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = msg);
+}
+
+fn guard(f: impl FnOnce() -> c_int) -> c_int {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(x) => x,
+        Err(e) => {
+            let msg = e.downcast_ref::<&str>().map(|x| x.to_string()).or_else(|| e.downcast_ref::<String>().cloned()).unwrap_or_default();
+            set_last_error(format!("panic: {msg}"));
+            GV_PANIC
+        }
+    }
+}
+
+fn options(gpu_index: c_int) -> DecoderOptions {
+    DecoderOptions {
+        gpu_index: if gpu_index >= 0 { Some(gpu_index as usize) } else { None },
+        ..Default::default()
+    }
+}
+
+unsafe fn open(io: IoType, gpu_index: c_int, out: *mut *mut GvDecoder) -> c_int {
+    match Decoder::new_from_io(io, options(gpu_index)) {
+        Ok(decoder) => {
+            *out = Box::into_raw(Box::new(GvDecoder { decoder }));
+            GV_OK
+        },
+        Err(e) => { set_last_error(e); GV_ERROR }
+    }
+}
+
+/// Message of the last error on this thread, never NULL
+#[no_mangle]
+pub extern "C" fn gv_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ptr())
+}
+
+/// Opens a file or URL. `gpu_index` < 0 disables hardware decoding.
+#[no_mangle]
+pub unsafe extern "C" fn gv_decoder_open(path: *const c_char, gpu_index: c_int, out: *mut *mut GvDecoder) -> c_int {
+    guard(|| {
+        if path.is_null() || out.is_null() { set_last_error("NULL argument"); return GV_INVALID_ARGUMENT; }
+        let path = CStr::from_ptr(path).to_string_lossy();
+        open(IoType::from(path.as_ref()), gpu_index, out)
+    })
+}
+
+/// Opens an input read through the user callbacks
+#[no_mangle]
+pub unsafe extern "C" fn gv_decoder_open_callbacks(callbacks: GvIoCallbacks, gpu_index: c_int, out: *mut *mut GvDecoder) -> c_int {
+    guard(|| {
+        if out.is_null() || callbacks.read.is_none() { set_last_error("NULL argument"); return GV_INVALID_ARGUMENT; }
+        open(IoType::Reader(Box::new(CallbackReader(callbacks))), gpu_index, out)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gv_decoder_free(decoder: *mut GvDecoder) {
+    if !decoder.is_null() {
+        let _ = catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(decoder))));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gv_decoder_stream_count(decoder: *mut GvDecoder) -> c_int {
+    guard(|| {
+        match decoder.as_ref() {
+            Some(d) => d.decoder.stream_infos().len() as c_int,
+            None => { set_last_error("NULL argument"); GV_INVALID_ARGUMENT }
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gv_decoder_stream_info(decoder: *mut GvDecoder, index: u32, out: *mut GvStreamInfo) -> c_int {
+    guard(|| {
+        let d = match (decoder.as_mut(), out.is_null()) { (Some(d), false) => d, _ => { set_last_error("NULL argument"); return GV_INVALID_ARGUMENT; } };
+        let infos = d.decoder.stream_infos();
+        let info = match infos.get(index as usize) { Some(x) => x, None => { set_last_error("Stream index out of range"); return GV_INVALID_ARGUMENT; } };
+        let time_base = d.decoder.streams().get(index as usize).map(|x| x.time_base).unwrap_or((0, 1));
+        *out = GvStreamInfo {
+            index,
+            stream_type: match info.stream_type { StreamType::Video => 0, StreamType::Audio => 1, StreamType::Subtitle => 2, StreamType::Other => 3 },
+            width: info.width.unwrap_or_default(),
+            height: info.height.unwrap_or_default(),
+            channels: info.channels.unwrap_or_default(),
+            sample_rate: info.sample_rate.unwrap_or_default(),
+            time_base_num: time_base.0,
+            time_base_den: time_base.1,
+        };
+        GV_OK
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gv_decoder_set_stream_enabled(decoder: *mut GvDecoder, index: u32, enabled: bool) -> c_int {
+    guard(|| {
+        let d = match decoder.as_mut() { Some(d) => d, None => { set_last_error("NULL argument"); return GV_INVALID_ARGUMENT; } };
+        match d.decoder.streams().into_iter().nth(index as usize) {
+            Some(s) => { s.decode = enabled; GV_OK },
+            None => { set_last_error("Stream index out of range"); GV_INVALID_ARGUMENT }
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gv_decoder_seek(decoder: *mut GvDecoder, timestamp_us: i64) -> c_int {
+    guard(|| {
+        let d = match decoder.as_mut() { Some(d) => d, None => { set_last_error("NULL argument"); return GV_INVALID_ARGUMENT; } };
+        if d.decoder.seek(timestamp_us) { GV_OK } else { set_last_error("Seek failed"); GV_ERROR }
+    })
+}
+
+/// Decodes the next video frame. Returns `GV_END_OF_STREAM` when there are no more frames.
+#[no_mangle]
+pub unsafe extern "C" fn gv_decoder_next_frame(decoder: *mut GvDecoder, out: *mut *mut GvFrame) -> c_int {
+    guard(|| {
+        let d = match (decoder.as_mut(), out.is_null()) { (Some(d), false) => d, _ => { set_last_error("NULL argument"); return GV_INVALID_ARGUMENT; } };
+        while let Some(frame) = d.decoder.next_frame() {
+            if let Frame::Video(frame) = frame {
+                let format_name = CString::new(format!("{:?}", frame.format())).unwrap_or_default();
+                *out = Box::into_raw(Box::new(GvFrame { frame, planes: None, format_name }));
+                return GV_OK;
+            }
+        }
+        *out = ptr::null_mut();
+        GV_END_OF_STREAM
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gv_frame_release(frame: *mut GvFrame) {
+    if !frame.is_null() {
+        let _ = catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(frame))));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gv_frame_info(frame: *mut GvFrame, out: *mut GvFrameInfo) -> c_int {
+    guard(|| {
+        let f = match (frame.as_ref(), out.is_null()) { (Some(f), false) => f, _ => { set_last_error("NULL argument"); return GV_INVALID_ARGUMENT; } };
+        let v = &f.frame;
+        *out = GvFrameInfo {
+            width: v.width(),
+            height: v.height(),
+            timestamp_us: v.timestamp_us().unwrap_or(-1),
+            is_hardware: v.is_hardware(),
+            bit_depth: v.format().bit_depth(),
+            color_space: match v.color_space() {
+                None => 0,
+                Some(ColorSpace::BT709) => 1,
+                Some(ColorSpace::BT470BG) => 2,
+                Some(ColorSpace::SMPTE170M) => 3,
+                Some(ColorSpace::BT2020NCL) => 4,
+                Some(ColorSpace::BT2020CL) => 5,
+            },
+            color_range: match v.color_range() { None => 0, Some(ColorRange::Limited) => 1, Some(ColorRange::Full) => 2 },
+        };
+        GV_OK
+    })
+}
+
+/// Name of the pixel format, valid until the frame is released
+#[no_mangle]
+pub unsafe extern "C" fn gv_frame_format_name(frame: *mut GvFrame) -> *const c_char {
+    match frame.as_ref() {
+        Some(f) => f.format_name.as_ptr(),
+        None => ptr::null()
+    }
+}
+
+unsafe fn frame_planes<'a>(frame: *mut GvFrame) -> Result<&'a [GvPlane], c_int> {
+    let f = match frame.as_mut() { Some(f) => f, None => { set_last_error("NULL argument"); return Err(GV_INVALID_ARGUMENT); } };
+    if f.planes.is_none() {
+        // Downloads hardware frames to CPU memory on first access
+        let buffers = f.frame.get_cpu_buffers().map_err(|e| { set_last_error(e); GV_ERROR })?;
+        let planes: Vec<GvPlane> = buffers.into_iter().map(|b| GvPlane { data: b.as_mut_ptr(), stride: 0, size: b.len() }).collect();
+        let strides = f.frame.get_cpu_strides().map_err(|e| { set_last_error(e); GV_ERROR })?;
+        f.planes = Some(planes.into_iter().zip(strides).map(|(p, stride)| GvPlane { stride, ..p }).collect());
+    }
+    Ok(f.planes.as_deref().unwrap_or_default())
+}
+
+/// Number of CPU planes. Downloads hardware frames to CPU memory on first call.
+#[no_mangle]
+pub unsafe extern "C" fn gv_frame_plane_count(frame: *mut GvFrame) -> c_int {
+    guard(|| match frame_planes(frame) { Ok(p) => p.len() as c_int, Err(e) => e })
+}
+
+/// Pointer, stride and size of the plane, valid until the frame is released
+#[no_mangle]
+pub unsafe extern "C" fn gv_frame_plane(frame: *mut GvFrame, plane: u32, out: *mut GvPlane) -> c_int {
+    guard(|| {
+        if out.is_null() { set_last_error("NULL argument"); return GV_INVALID_ARGUMENT; }
+        match frame_planes(frame) {
+            Ok(p) => match p.get(plane as usize) {
+                Some(x) => { *out = *x; GV_OK },
+                None => { set_last_error("Plane index out of range"); GV_INVALID_ARGUMENT }
+            },
+            Err(e) => e
+        }
+    })
+}
@@ -0,0 +1,96 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+//! Input and output transforms between linear Rec.709 and ACES2065-1 (AP0).
+//! Matrices include the CAT02 chromatic adaptation between D65 and the ACES white point.
+
+pub type Matrix3 = [[f32; 3]; 3];
+
+/// IDT: linear Rec.709 to ACES AP0
+pub const REC709_TO_AP0: Matrix3 = [
+    [0.439_577_04, 0.383_914_87, 0.176_508_09],
+    [0.089_545_73, 0.814_713_06, 0.095_741_21],
+    [0.017_425_96, 0.108_775_77, 0.873_798_27],
+];
+
+/// ODT: ACES AP0 to linear Rec.709, without tonemapping. The exact inverse of `REC709_TO_AP0`, so round trips don't drift
+pub const AP0_TO_REC709: Matrix3 = invert(&REC709_TO_AP0);
+
+/// ACES AP1 (ACEScg) to AP0
+pub const AP1_TO_AP0: Matrix3 = [
+    [ 0.695_452_2, 0.140_678_7, 0.163_869_1],
+    [ 0.044_794_6, 0.859_671_1, 0.095_534_3],
+    [-0.005_525_9, 0.004_025_2, 1.001_500_7],
+];
+
+pub const fn invert(m: &Matrix3) -> Matrix3 {
+    let [[a, b, c], [d, e, f], [g, h, i]] = *m;
+    let det = a * (e * i - f * h) - b * (d * i - f * g) + c * (d * h - e * g);
+    [
+        [(e * i - f * h) / det, (c * h - b * i) / det, (b * f - c * e) / det],
+        [(f * g - d * i) / det, (a * i - c * g) / det, (c * d - a * f) / det],
+        [(d * h - e * g) / det, (b * g - a * h) / det, (a * e - b * d) / det],
+    ]
+}
+
+pub fn apply(m: &Matrix3, rgb: [f32; 3]) -> [f32; 3] {
+    [
+        m[0][0] * rgb[0] + m[0][1] * rgb[1] + m[0][2] * rgb[2],
+        m[1][0] * rgb[0] + m[1][1] * rgb[1] + m[1][2] * rgb[2],
+        m[2][0] * rgb[0] + m[2][1] * rgb[1] + m[2][2] * rgb[2],
+    ]
+}
+
+/// Transforms interleaved linear RGB (`channels` = 3 or 4, alpha is kept) in place
+pub fn apply_to_buffer(m: &Matrix3, data: &mut [f32], channels: usize) {
+    for px in data.chunks_exact_mut(channels.max(3)) {
+        let [r, g, b] = apply(m, [px[0], px[1], px[2]]);
+        px[0] = r;
+        px[1] = g;
+        px[2] = b;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: [f32; 3], b: [f32; 3], tolerance: f32) {
+        assert!(a.iter().zip(&b).all(|(a, b)| (a - b).abs() <= tolerance), "{a:?} != {b:?}");
+    }
+
+    #[test]
+    fn white_is_kept() {
+        assert_close(apply(&REC709_TO_AP0, [1.0; 3]), [1.0; 3], 1e-6);
+        assert_close(apply(&AP0_TO_REC709, [1.0; 3]), [1.0; 3], 1e-6);
+        assert_close(apply(&AP1_TO_AP0, [1.0; 3]), [1.0; 3], 1e-6);
+    }
+
+    #[test]
+    fn known_values() {
+        // Rec.709 primaries in AP0, as in the ACES OCIO config
+        assert_close(apply(&REC709_TO_AP0, [1.0, 0.0, 0.0]), [0.439_577, 0.089_546, 0.017_426], 1e-6);
+        assert_close(apply(&REC709_TO_AP0, [0.0, 1.0, 0.0]), [0.383_915, 0.814_713, 0.108_776], 1e-6);
+        assert_close(apply(&REC709_TO_AP0, [0.0, 0.0, 1.0]), [0.176_508, 0.095_741, 0.873_798], 1e-6);
+        // AP0 primaries are outside of Rec.709
+        assert_close(apply(&AP0_TO_REC709, [1.0, 0.0, 0.0]), [2.521_776, -0.275_288, -0.016_022], 1e-5);
+        assert_close(apply(&AP0_TO_REC709, [0.18, 0.18, 0.18]), [0.18; 3], 1e-6);
+    }
+
+    #[test]
+    fn round_trip() {
+        for rgb in [[0.18, 0.18, 0.18], [1.0, 0.0, 0.0], [0.02, 0.5, 0.9], [4.0, 12.0, 0.001]] {
+            let back = apply(&AP0_TO_REC709, apply(&REC709_TO_AP0, rgb));
+            let tolerance = rgb.iter().fold(0.0f32, |a, b| a.max(*b)) * 1e-6;
+            assert_close(back, rgb, tolerance.max(1e-7));
+        }
+        let mut buffer = vec![0.25, 0.5, 0.75, 1.0, 2.0, 0.0, 0.1, 0.5];
+        let original = buffer.clone();
+        apply_to_buffer(&REC709_TO_AP0, &mut buffer, 4);
+        apply_to_buffer(&AP0_TO_REC709, &mut buffer, 4);
+        assert_eq!((buffer[3], buffer[7]), (1.0, 0.5));
+        for (a, b) in buffer.iter().zip(&original) {
+            assert!((a - b).abs() < 1e-6, "{buffer:?} != {original:?}");
+        }
+    }
+}
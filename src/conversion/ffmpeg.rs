@@ -1,2 +1,274 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 // Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+use super::*;
+use ffmpeg_next::color;
+
+impl From<ColorSpace> for color::Space {
+    fn from(v: ColorSpace) -> Self {
+        match v {
+            ColorSpace::BT709     => color::Space::BT709,
+            ColorSpace::BT470BG   => color::Space::BT470BG,
+            ColorSpace::SMPTE170M => color::Space::SMPTE170M,
+            ColorSpace::BT2020NCL => color::Space::BT2020NCL,
+            ColorSpace::BT2020CL  => color::Space::BT2020CL,
+        }
+    }
+}
+impl From<ColorTrc> for color::TransferCharacteristic {
+    fn from(v: ColorTrc) -> Self {
+        match v {
+            ColorTrc::BT709      => color::TransferCharacteristic::BT709,
+            ColorTrc::SMPTE170M  => color::TransferCharacteristic::SMPTE170M,
+            ColorTrc::Linear     => color::TransferCharacteristic::Linear,
+            ColorTrc::SRGB       => color::TransferCharacteristic::IEC61966_2_1,
+            ColorTrc::Gamma22    => color::TransferCharacteristic::GAMMA22,
+            ColorTrc::SMPTE2084  => color::TransferCharacteristic::SMPTE2084,
+            ColorTrc::AribStdB67 => color::TransferCharacteristic::ARIB_STD_B67,
+        }
+    }
+}
+impl From<ColorPrimaries> for color::Primaries {
+    fn from(v: ColorPrimaries) -> Self {
+        match v {
+            ColorPrimaries::BT709     => color::Primaries::BT709,
+            ColorPrimaries::BT470BG   => color::Primaries::BT470BG,
+            ColorPrimaries::SMPTE170M => color::Primaries::SMPTE170M,
+            ColorPrimaries::BT2020    => color::Primaries::BT2020,
+            ColorPrimaries::DCIP3     => color::Primaries::SMPTE431,
+            ColorPrimaries::DisplayP3 => color::Primaries::SMPTE432,
+            // No ffmpeg equivalent
+            ColorPrimaries::ACESAP0 | ColorPrimaries::ACESAP1 => color::Primaries::Unspecified,
+        }
+    }
+}
+impl From<ColorRange> for color::Range {
+    fn from(v: ColorRange) -> Self {
+        match v {
+            ColorRange::Limited => color::Range::MPEG,
+            ColorRange::Full    => color::Range::JPEG,
+        }
+    }
+}
+
+/// Tags the codec context with the output color description
+pub fn set_color_tags(ctx: &mut ffmpeg_next::encoder::video::Video, output: &ColorDescription) {
+    if let Some(v) = output.space     { ctx.set_colorspace(v.into()); }
+    if let Some(v) = output.range     { ctx.set_color_range(v.into()); }
+    unsafe {
+        let ptr = ctx.as_mut_ptr();
+        if let Some(v) = output.trc       { (*ptr).color_trc       = color::TransferCharacteristic::from(v).into(); }
+        if let Some(v) = output.primaries { (*ptr).color_primaries = color::Primaries::from(v).into(); }
+    }
+}
+
+/// Builds a libavfilter graph description converting between the color descriptions.
+/// Returns `None` when no conversion is needed. SDR conversions use the colorspace filter, zscale is only used where PQ or HLG is involved,
+/// HDR to SDR conversion is tonemapped in linear light.
+pub fn color_conversion_filter(input: &ColorDescription, output: &ColorDescription) -> Option<String> {
+    if !input.needs_conversion_to(output) { return None; }
+
+    let target = ColorDescription {
+        space:     output.space.or(input.space),
+        trc:       output.trc.or(input.trc),
+        primaries: output.primaries.or(input.primaries),
+        range:     output.range.or(input.range),
+    };
+    let space     = target.space.map(zscale_matrix).unwrap_or("input");
+    // zscale doesn't know ACES primaries, these are converted with the matrices in `aces`
+    let primaries = target.primaries.and_then(zscale_primaries).unwrap_or("input");
+    let range     = target.range.map(zscale_range).unwrap_or("input");
+
+    // colorspace has no PQ or HLG
+    if let Some(trc) = target.trc.and_then(zscale_hdr_transfer) {
+        return Some(format!("zscale=t={trc}:m={space}:p={primaries}:r={range}"));
+    }
+    let sdr = colorspace_filter(&target);
+    if input.trc.is_some_and(|x| x.is_hdr()) {
+        // Tonemapped to BT.709, colorspace then applies the target transfer
+        Some(format!("zscale=t=linear:npl=100,format=gbrpf32le,zscale=p={primaries},tonemap=tonemap=hable:desat=0,zscale=t=709:m={space}:r={range},{sdr}"))
+    } else {
+        Some(sdr)
+    }
+}
+
+/// colorspace filter converting to `target`, values it doesn't know are left as in the input
+fn colorspace_filter(target: &ColorDescription) -> String {
+    let mut options = Vec::new();
+    if let Some(v) = target.space.and_then(colorspace_matrix)         { options.push(format!("space={v}")); }
+    if let Some(v) = target.trc.and_then(colorspace_transfer)         { options.push(format!("trc={v}")); }
+    if let Some(v) = target.primaries.and_then(colorspace_primaries)  { options.push(format!("primaries={v}")); }
+    if let Some(v) = target.range                                     { options.push(format!("range={}", if v == ColorRange::Full { "pc" } else { "tv" })); }
+    format!("colorspace={}", options.join(":"))
+}
+
+fn colorspace_matrix(v: ColorSpace) -> Option<&'static str> {
+    Some(match v {
+        ColorSpace::BT709     => "bt709",
+        ColorSpace::BT470BG   => "bt470bg",
+        ColorSpace::SMPTE170M => "smpte170m",
+        ColorSpace::BT2020NCL => "bt2020ncl",
+        ColorSpace::BT2020CL  => return None,
+    })
+}
+fn colorspace_transfer(v: ColorTrc) -> Option<&'static str> {
+    Some(match v {
+        ColorTrc::BT709     => "bt709",
+        ColorTrc::SMPTE170M => "smpte170m",
+        ColorTrc::Linear    => "linear",
+        ColorTrc::SRGB      => "iec61966-2-1",
+        ColorTrc::Gamma22   => "gamma22",
+        ColorTrc::SMPTE2084 | ColorTrc::AribStdB67 => return None,
+    })
+}
+fn colorspace_primaries(v: ColorPrimaries) -> Option<&'static str> {
+    Some(match v {
+        ColorPrimaries::BT709     => "bt709",
+        ColorPrimaries::BT470BG   => "bt470bg",
+        ColorPrimaries::SMPTE170M => "smpte170m",
+        ColorPrimaries::BT2020    => "bt2020",
+        ColorPrimaries::DCIP3     => "smpte431",
+        ColorPrimaries::DisplayP3 => "smpte432",
+        ColorPrimaries::ACESAP0 | ColorPrimaries::ACESAP1 => return None,
+    })
+}
+
+fn zscale_matrix(v: ColorSpace) -> &'static str {
+    match v {
+        ColorSpace::BT709     => "709",
+        ColorSpace::BT470BG   => "470bg",
+        ColorSpace::SMPTE170M => "170m",
+        ColorSpace::BT2020NCL => "2020_ncl",
+        ColorSpace::BT2020CL  => "2020_cl",
+    }
+}
+fn zscale_hdr_transfer(v: ColorTrc) -> Option<&'static str> {
+    match v {
+        ColorTrc::SMPTE2084  => Some("smpte2084"),
+        ColorTrc::AribStdB67 => Some("arib-std-b67"),
+        _ => None
+    }
+}
+fn zscale_primaries(v: ColorPrimaries) -> Option<&'static str> {
+    Some(match v {
+        ColorPrimaries::BT709     => "709",
+        ColorPrimaries::BT470BG   => "470bg",
+        ColorPrimaries::SMPTE170M => "170m",
+        ColorPrimaries::BT2020    => "2020",
+        ColorPrimaries::DCIP3     => "smpte431",
+        ColorPrimaries::DisplayP3 => "smpte432",
+        ColorPrimaries::ACESAP0 | ColorPrimaries::ACESAP1 => return None,
+    })
+}
+fn zscale_range(v: ColorRange) -> &'static str {
+    match v {
+        ColorRange::Limited => "limited",
+        ColorRange::Full    => "full",
+    }
+}
+
+/// Builds a libavfilter graph description applying the CDL in RGB and converting back to `format`. Returns `None` for the identity
+pub fn cdl_filter(cdl: &crate::Cdl, format: ffmpeg_next::format::Pixel) -> Option<String> {
+    if cdl.is_identity() { return None; }
+    let name = format.descriptor()?.name();
+    let channel = |i: usize| format!("'pow(clip(val/maxval*{}+{},0,1),{})*maxval'", cdl.slope[i], cdl.offset[i], cdl.power[i]);
+    let mut filter = format!("lutrgb=r={}:g={}:b={}", channel(0), channel(1), channel(2));
+    if cdl.saturation != 1.0 {
+        // The ASC CDL saturation uses the Rec. 709 luma weights
+        const LUMA: [f32; 3] = [0.2126, 0.7152, 0.0722];
+        let weight = |out: usize, input: usize| LUMA[input] * (1.0 - cdl.saturation) + if out == input { cdl.saturation } else { 0.0 };
+        let weights = ["r", "g", "b"].iter().enumerate()
+            .flat_map(|(o, out)| ["r", "g", "b"].iter().enumerate().map(move |(i, input)| format!("{out}{input}={}", weight(o, i))))
+            .collect::<Vec<_>>();
+        filter += &format!(",colorchannelmixer={}", weights.join(":"));
+    }
+    Some(format!("{filter},format={name}"))
+}
+
+/// Filter graph running a `color_conversion_filter` description, rebuilt when the input format or size changes
+pub struct ColorConversionGraph {
+    input: (ffmpeg_next::format::Pixel, u32, u32),
+    graph: ffmpeg_next::filter::Graph,
+}
+
+impl ColorConversionGraph {
+    fn new(input: (ffmpeg_next::format::Pixel, u32, u32), description: &str) -> Result<Self, VideoProcessingError> {
+        use ffmpeg_next::filter;
+        let mut graph = filter::Graph::new();
+        let args = format!("video_size={}x{}:pix_fmt={}:time_base=1/1000000:pixel_aspect=1/1", input.1, input.2, ffmpeg_next::ffi::AVPixelFormat::from(input.0) as i32);
+        graph.add(&filter::find("buffer").ok_or(ffmpeg_next::Error::FilterNotFound)?, "in", &args)?;
+        graph.add(&filter::find("buffersink").ok_or(ffmpeg_next::Error::FilterNotFound)?, "out", "")?;
+        graph.output("in", 0)?.input("out", 0)?.parse(description)?;
+        graph.validate()?;
+        Ok(Self { input, graph })
+    }
+
+    /// Converts `src` with the graph in `slot`, which is created for the first frame
+    pub fn run(slot: &mut Option<Self>, src: &ffmpeg_next::frame::Video, description: &str) -> Result<ffmpeg_next::frame::Video, VideoProcessingError> {
+        let input = (src.format(), src.width(), src.height());
+        if slot.as_ref().is_none_or(|x| x.input != input) {
+            *slot = Some(Self::new(input, description)?);
+        }
+        let graph = &mut slot.as_mut().ok_or(VideoProcessingError::EncoderConverterEmpty)?.graph;
+        graph.get("in").ok_or(ffmpeg_next::Error::FilterNotFound)?.source().add(src)?;
+        let mut dst = ffmpeg_next::frame::Video::empty();
+        graph.get("out").ok_or(ffmpeg_next::Error::FilterNotFound)?.sink().frame(&mut dst)?;
+        dst.set_pts(src.pts());
+        Ok(dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sdr(trc: ColorTrc) -> ColorDescription {
+        ColorDescription { space: Some(ColorSpace::BT709), trc: Some(trc), primaries: Some(ColorPrimaries::BT709), range: Some(ColorRange::Limited) }
+    }
+
+    #[test]
+    fn no_conversion() {
+        assert_eq!(color_conversion_filter(&sdr(ColorTrc::BT709), &sdr(ColorTrc::BT709)), None);
+        assert_eq!(color_conversion_filter(&sdr(ColorTrc::BT709), &ColorDescription::default()), None);
+    }
+
+    #[test]
+    fn sdr_uses_colorspace() {
+        let filter = color_conversion_filter(&sdr(ColorTrc::BT709), &ColorDescription { trc: Some(ColorTrc::Gamma22), ..Default::default() }).unwrap();
+        assert_eq!(filter, "colorspace=space=bt709:trc=gamma22:primaries=bt709:range=tv");
+    }
+
+    #[test]
+    fn hdr_to_sdr_is_tonemapped() {
+        let hdr = ColorDescription { space: Some(ColorSpace::BT2020NCL), trc: Some(ColorTrc::AribStdB67), primaries: Some(ColorPrimaries::BT2020), range: Some(ColorRange::Limited) };
+        let filter = color_conversion_filter(&hdr, &sdr(ColorTrc::Gamma22)).unwrap();
+        assert!(filter.starts_with("zscale=t=linear"));
+        assert!(filter.contains("tonemap="));
+        assert!(filter.ends_with(",colorspace=space=bt709:trc=gamma22:primaries=bt709:range=tv"));
+
+        let filter = color_conversion_filter(&sdr(ColorTrc::BT709), &hdr).unwrap();
+        assert_eq!(filter, "zscale=t=arib-std-b67:m=2020_ncl:p=2020:r=limited");
+    }
+
+    #[test]
+    fn cdl() {
+        use ffmpeg_next::format::Pixel;
+        assert_eq!(cdl_filter(&crate::Cdl::default(), Pixel::YUV420P), None);
+        let look = crate::Cdl { slope: [0.5, 1.0, 1.0], offset: [0.0, -0.1, 0.0], power: [1.0, 1.0, 2.0], saturation: 1.0 };
+        assert_eq!(cdl_filter(&look, Pixel::YUV420P).unwrap(),
+            "lutrgb=r='pow(clip(val/maxval*0.5+0,0,1),1)*maxval':g='pow(clip(val/maxval*1+-0.1,0,1),1)*maxval':b='pow(clip(val/maxval*1+0,0,1),2)*maxval',format=yuv420p");
+        let gray = crate::Cdl { saturation: 0.0, ..Default::default() };
+        assert_eq!(cdl_filter(&gray, Pixel::RGB24).unwrap(),
+            "lutrgb=r='pow(clip(val/maxval*1+0,0,1),1)*maxval':g='pow(clip(val/maxval*1+0,0,1),1)*maxval':b='pow(clip(val/maxval*1+0,0,1),1)*maxval',\
+             colorchannelmixer=rr=0.2126:rg=0.7152:rb=0.0722:gr=0.2126:gg=0.7152:gb=0.0722:br=0.2126:bg=0.7152:bb=0.0722,format=rgb24");
+
+        // Halving the slope darkens the frame and keeps its format
+        crate::backend::init_ffmpeg().unwrap();
+        let mut src = ffmpeg_next::frame::Video::new(Pixel::RGB24, 64, 48);
+        src.data_mut(0).fill(200);
+        let half = crate::Cdl { slope: [0.5; 3], ..Default::default() };
+        let dst = ColorConversionGraph::run(&mut None, &src, &cdl_filter(&half, Pixel::RGB24).unwrap()).unwrap();
+        assert_eq!(dst.format(), Pixel::RGB24);
+        assert!(dst.data(0)[..64 * 3].iter().all(|x| x.abs_diff(100) <= 1), "{:?}", &dst.data(0)[..6]);
+    }
+}
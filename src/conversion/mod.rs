@@ -1,6 +1,250 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 // Copyright © 2023 Adrian <adrian.eddy at gmail>
 
+mod ffmpeg; pub use ffmpeg::*;
+mod packed; pub use packed::*;
+mod thumbnail; pub use thumbnail::*;
+mod software; pub use software::*;
+mod retime; pub use retime::*;
+pub mod aces;
+
+use crate::types::*;
+use crate::frame::*;
+
+#[derive(Debug, Default)]
 pub struct Converter {
+    /// Used instead of the frame's color space, useful for untagged or mistagged content
+    pub matrix_override: Option<YuvMatrix>,
+}
+
+impl Converter {
+    pub fn matrix_for(&self, frame: &VideoFrame) -> YuvMatrix {
+        self.matrix_override.unwrap_or_else(|| YuvMatrix::from_color_space(frame.color_space(), frame.height()))
+    }
+
+    /// Converts a packed 4:2:2 frame (UYVY422 or YUYV422) to tightly packed RGBA
+    pub fn packed_to_rgba(&self, frame: &mut VideoFrame) -> Result<Vec<u8>, VideoProcessingError> {
+        profile_span!("conversion", kind = "packed_to_rgba", timestamp_us = frame.timestamp_us());
+        let format = frame.format();
+        let layout = match PackedLayout::from_format(&format) {
+            Some(x) => x,
+            None => return Err(VideoProcessingError::PixelFormatNotSupported { format, supported: vec![PixelFormat::UYVY422, PixelFormat::YUYV422], frame: Some(Box::new(frame.describe())) })
+        };
+        let matrix = self.matrix_for(frame);
+        let range = frame.color_range().unwrap_or(ColorRange::Limited);
+        let (width, height) = (frame.width() as usize, frame.height() as usize);
+
+        let buffers = frame.get_cpu_buffers()?;
+        let src = buffers.first().ok_or(VideoProcessingError::FrameEmpty)?;
+        let src_stride = src.len() / height.max(1);
+
+        let mut rgba = vec![0u8; width * height * 4];
+        packed_422_to_rgba(src, src_stride, layout, (width, height), matrix, range, &mut rgba);
+        Ok(rgba)
+    }
+
+    /// Unpacks a packed 4:2:2 frame (UYVY422 or YUYV422) to tightly packed Y, U and V planes in the YUV422P layout, the chroma planes are `width / 2` wide
+    pub fn packed_to_planar(&self, frame: &mut VideoFrame) -> Result<[Vec<u8>; 3], VideoProcessingError> {
+        profile_span!("conversion", kind = "packed_to_planar", timestamp_us = frame.timestamp_us());
+        let format = frame.format();
+        let layout = match PackedLayout::from_format(&format) {
+            Some(x) => x,
+            None => return Err(VideoProcessingError::PixelFormatNotSupported { format, supported: vec![PixelFormat::UYVY422, PixelFormat::YUYV422], frame: Some(Box::new(frame.describe())) })
+        };
+        let (width, height) = (frame.width() as usize, frame.height() as usize);
+
+        let buffers = frame.get_cpu_buffers()?;
+        let src = buffers.first().ok_or(VideoProcessingError::FrameEmpty)?;
+        let src_stride = src.len() / height.max(1);
+
+        let mut y = vec![0u8; width * height];
+        let mut u = vec![0u8; width / 2 * height];
+        let mut v = vec![0u8; width / 2 * height];
+        unpack_422_to_planar(src, src_stride, layout, (width, height), &mut y, &mut u, &mut v);
+        Ok([y, u, v])
+    }
+
+    /// Expands a GRAY8, GRAY16LE or GRAY16BE frame to tightly packed RGBA by replicating the luma, 16-bit samples are reduced to 8 bits
+    pub fn gray_to_rgba(&self, frame: &mut VideoFrame) -> Result<Vec<u8>, VideoProcessingError> {
+        profile_span!("conversion", kind = "gray_to_rgba", timestamp_us = frame.timestamp_us());
+        let format = frame.format();
+        // Bytes per sample and offset of the high byte
+        let (bytes, high) = match format {
+            PixelFormat::GRAY8 => (1, 0),
+            PixelFormat::GRAY16LE => (2, 1),
+            PixelFormat::GRAY16BE => (2, 0),
+            _ => return Err(VideoProcessingError::PixelFormatNotSupported { format, supported: vec![PixelFormat::GRAY8, PixelFormat::GRAY16LE, PixelFormat::GRAY16BE], frame: Some(Box::new(frame.describe())) })
+        };
+        let (width, height) = (frame.width() as usize, frame.height() as usize);
+
+        let buffers = frame.get_cpu_buffers()?;
+        let src = buffers.first().ok_or(VideoProcessingError::FrameEmpty)?;
+        let src_stride = src.len() / height.max(1);
+
+        let mut rgba = vec![0u8; width * height * 4];
+        for (y, row) in rgba.chunks_exact_mut(width * 4).enumerate() {
+            for (x, px) in row.chunks_exact_mut(4).enumerate() {
+                let v = src[y * src_stride + x * bytes + high];
+                px.copy_from_slice(&[v, v, v, 255]);
+            }
+        }
+        Ok(rgba)
+    }
+
+    /// Converts an 8-bit YUV420P, YUV422P, YUV444P, NV12 or NV21 frame to tightly packed RGBA without swscale
+    pub fn yuv_to_rgba(&self, frame: &mut VideoFrame) -> Result<Vec<u8>, VideoProcessingError> {
+        profile_span!("conversion", kind = "yuv_to_rgba", timestamp_us = frame.timestamp_us());
+        let format = frame.format();
+        let chroma_shift = match format {
+            PixelFormat::YUV420P => Some((1, 1)),
+            PixelFormat::YUV422P => Some((1, 0)),
+            PixelFormat::YUV444P => Some((0, 0)),
+            PixelFormat::NV12 | PixelFormat::NV21 => None,
+            _ => return Err(VideoProcessingError::PixelFormatNotSupported { format, supported: vec![PixelFormat::YUV420P, PixelFormat::YUV422P, PixelFormat::YUV444P, PixelFormat::NV12, PixelFormat::NV21], frame: Some(Box::new(frame.describe())) })
+        };
+        let matrix = self.matrix_for(frame);
+        let range = frame.color_range().unwrap_or(ColorRange::Limited);
+        let (width, height) = (frame.width() as usize, frame.height() as usize);
+        let layouts = format.plane_layouts();
+
+        let buffers = frame.get_cpu_buffers()?;
+        if buffers.len() < layouts.len() { return Err(VideoProcessingError::FrameEmpty); }
+        let strides: Vec<usize> = buffers.iter().zip(&layouts).map(|(b, l)| b.len() / l.rows(height as u32).max(1) as usize).collect();
+
+        let mut rgba = vec![0u8; width * height * 4];
+        match chroma_shift {
+            Some(shift) => planar_yuv_to_rgba([&*buffers[0], &*buffers[1], &*buffers[2]], [strides[0], strides[1], strides[2]], (width, height), shift, matrix, range, &mut rgba)?,
+            None => nv12_to_rgba([&*buffers[0], &*buffers[1]], [strides[0], strides[1]], (width, height), format == PixelFormat::NV21, matrix, range, &mut rgba)?
+        }
+        Ok(rgba)
+    }
+
+    /// Expands a PAL8 frame to tightly packed RGBA
+    pub fn pal8_to_rgba(&self, frame: &mut VideoFrame) -> Result<Vec<u8>, VideoProcessingError> {
+        profile_span!("conversion", kind = "pal8_to_rgba", timestamp_us = frame.timestamp_us());
+        let format = frame.format();
+        if format != PixelFormat::PAL8 {
+            return Err(VideoProcessingError::PixelFormatNotSupported { format, supported: vec![PixelFormat::PAL8], frame: Some(Box::new(frame.describe())) });
+        }
+        let (width, height) = (frame.width() as usize, frame.height() as usize);
+        let palette = palette_to_rgba(frame.palette().ok_or(VideoProcessingError::FrameEmpty)?);
+
+        let buffers = frame.get_cpu_buffers()?;
+        let src = buffers.first().ok_or(VideoProcessingError::FrameEmpty)?;
+        let src_stride = src.len() / height.max(1);
+
+        let mut rgba = vec![0u8; width * height * 4];
+        for (y, row) in rgba.chunks_exact_mut(width * 4).enumerate() {
+            for (x, px) in row.chunks_exact_mut(4).enumerate() {
+                px.copy_from_slice(&palette[src[y * src_stride + x] as usize]);
+            }
+        }
+        Ok(rgba)
+    }
+}
+
+/// Copies the alpha channel to `dst` as 8-bit, from either the alpha plane or the interleaved components (scaled to 8 bits for deeper formats, clamped to 0..1 for float)
+pub fn extract_alpha_to(frame: &mut VideoFrame, dst: &mut [u8], dst_stride: usize) -> Result<(), VideoProcessingError> {
+    profile_span!("conversion", kind = "extract_alpha", timestamp_us = frame.timestamp_us());
+    let format = frame.format();
+    let (width, height) = (frame.width() as usize, frame.height() as usize);
+    let layouts = format.plane_layouts();
+    let Some((plane, layout)) = layouts.iter().enumerate().find(|(_, l)| l.components.contains('A')) else {
+        let supported = [PixelFormat::YUVA420P, PixelFormat::YUVA444P, PixelFormat::RGBA, PixelFormat::BGRA, PixelFormat::RGB32, PixelFormat::RGBA64BE, PixelFormat::RGBA64LE, PixelFormat::GBRAP10LE, PixelFormat::GBRAP12LE, PixelFormat::GBRAPF32LE, PixelFormat::AYUV64LE, PixelFormat::VUYA];
+        return Err(VideoProcessingError::PixelFormatNotSupported { format, supported: supported.to_vec(), frame: Some(Box::new(frame.describe())) });
+    };
+    if dst_stride < width || dst.len() < dst_stride * height.saturating_sub(1) + width {
+        return Err(VideoProcessingError::InvalidRegion { plane, rect: (0, 0, width as u32, height as u32) });
+    }
+    let sample_bytes = (layout.block_bytes as usize / layout.components.len()).max(1);
+    let pixel_bytes = layout.block_bytes as usize / layout.block_width as usize;
+    let offset = layout.components.find('A').unwrap_or_default() * sample_bytes;
+    let depth = format.bit_depth();
+    let alpha = |sample: &[u8]| -> u8 {
+        match (format, sample_bytes) {
+            (_, 1) => sample[0],
+            (PixelFormat::RGBA64BE, _) => sample[0],
+            (f, 4) if f.is_float() => (f32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]).clamp(0.0, 1.0) * 255.0).round() as u8,
+            // 10 and 12-bit planar alpha is in the low bits
+            (_, 2) if depth < 16 => (u16::from_le_bytes([sample[0], sample[1]]) >> (depth - 8)) as u8,
+            _ => sample[sample_bytes - 1]
+        }
+    };
+
+    let buffers = frame.get_cpu_buffers()?;
+    let src = buffers.get(plane).ok_or(VideoProcessingError::FrameEmpty)?;
+    let src_stride = src.len() / layout.rows(height as u32).max(1) as usize;
+    for y in 0..height {
+        let row = &src[y * src_stride..];
+        for (x, a) in dst[y * dst_stride..y * dst_stride + width].iter_mut().enumerate() {
+            *a = alpha(&row[x * pixel_bytes + offset..]);
+        }
+    }
+    Ok(())
+}
+
+/// Converts the ffmpeg palette (native endian 0xAARRGGBB) to RGBA bytes
+pub fn palette_to_rgba(palette: &[u8]) -> [[u8; 4]; 256] {
+    let mut ret = [[0u8; 4]; 256];
+    for (entry, c) in ret.iter_mut().zip(palette.chunks_exact(4)) {
+        let [a, r, g, b] = u32::from_ne_bytes([c[0], c[1], c[2], c[3]]).to_be_bytes();
+        *entry = [r, g, b, a];
+    }
+    ret
+}
+
+/// Reads normalized (Y, U, V) from a XV30LE word. 16-bit container formats are handled by `PixelFormat::normalize_sample`
+pub fn unpack_xv30(word: u32) -> [f32; 3] {
+    let c = |shift: u32| ((word >> shift) & 0x3ff) as f32 / 1023.0;
+    [c(10), c(0), c(20)]
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct ColorDescription {
+    pub space: Option<ColorSpace>,
+    pub trc: Option<ColorTrc>,
+    pub primaries: Option<ColorPrimaries>,
+    pub range: Option<ColorRange>,
+}
+
+impl ColorDescription {
+    /// Whether converting from `self` to `output` requires touching the pixels. Unspecified output values keep the input.
+    pub fn needs_conversion_to(&self, output: &ColorDescription) -> bool {
+        fn differs<T: PartialEq>(a: Option<T>, b: Option<T>) -> bool {
+            matches!((a, b), (Some(a), Some(b)) if a != b)
+        }
+        differs(self.space, output.space) ||
+        differs(self.trc, output.trc) ||
+        differs(self.primaries, output.primaries) ||
+        differs(self.range, output.range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn yuv_frames_to_rgba() {
+        for format in [PixelFormat::YUV420P, PixelFormat::YUV422P, PixelFormat::YUV444P, PixelFormat::NV12, PixelFormat::NV21] {
+            let mut frame = VideoFrame::solid_color(format, 6, 4, [255, 0, 0, 255]).unwrap();
+            let rgba = Converter::default().yuv_to_rgba(&mut frame).unwrap();
+            assert_eq!(rgba.len(), 6 * 4 * 4);
+            assert!(rgba.chunks_exact(4).all(|px| px[0] >= 253 && px[1] <= 2 && px[2] <= 2 && px[3] == 255), "{format:?}: {:?}", &rgba[..4]);
+        }
+        let mut frame = VideoFrame::solid_color(PixelFormat::RGBA, 2, 2, [0, 0, 0, 255]).unwrap();
+        assert!(matches!(Converter::default().yuv_to_rgba(&mut frame), Err(VideoProcessingError::PixelFormatNotSupported { .. })));
+    }
 
-}
\ No newline at end of file
+    #[test]
+    fn xv30_matches_the_16_bit_formats() {
+        // Y in bits 10..20, U in 0..10, V in 20..30, X in the top two bits
+        for value in [0u32, 1, 512, 1000, 1023] {
+            let word = (3 << 30) | (value << 20) | ((1023 - value) << 10) | (value / 2);
+            let [y, u, v] = unpack_xv30(word);
+            assert_eq!(y, PixelFormat::P010LE.normalize_sample(((1023 - value) << 6) as u16));
+            assert_eq!(u, PixelFormat::YUV444P10LE.normalize_sample((value / 2) as u16));
+            assert_eq!(v, PixelFormat::Y210LE.normalize_sample((value << 6) as u16));
+        }
+    }
+}
@@ -0,0 +1,216 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+use crate::types::*;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum YuvMatrix {
+    BT601,
+    BT709,
+    BT2020,
+}
+
+impl YuvMatrix {
+    /// Untagged content is assumed to be BT.601 for SD and BT.709 for HD
+    pub fn from_color_space(space: Option<ColorSpace>, height: u32) -> Self {
+        match space {
+            Some(ColorSpace::BT709) => Self::BT709,
+            Some(ColorSpace::BT470BG) | Some(ColorSpace::SMPTE170M) => Self::BT601,
+            Some(ColorSpace::BT2020NCL) | Some(ColorSpace::BT2020CL) => Self::BT2020,
+            None => if height > 576 { Self::BT709 } else { Self::BT601 }
+        }
+    }
+
+    /// (Kr, Kb)
+    pub fn coefficients(&self) -> (f32, f32) {
+        match self {
+            Self::BT601  => (0.299,  0.114),
+            Self::BT709  => (0.2126, 0.0722),
+            Self::BT2020 => (0.2627, 0.0593),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PackedLayout {
+    UYVY, // U0 Y0 V0 Y1
+    YUYV, // Y0 U0 Y1 V0
+}
+
+impl PackedLayout {
+    pub fn from_format(format: &PixelFormat) -> Option<Self> {
+        match format {
+            PixelFormat::UYVY422 => Some(Self::UYVY),
+            PixelFormat::YUYV422 => Some(Self::YUYV),
+            _ => None
+        }
+    }
+    // Offsets of Y0, U, Y1, V in a 4-byte macropixel
+    #[inline(always)]
+    fn offsets(&self) -> (usize, usize, usize, usize) {
+        match self {
+            Self::UYVY => (1, 0, 3, 2),
+            Self::YUYV => (0, 1, 2, 3),
+        }
+    }
+}
+
+// 16.16 fixed point coefficients of the YUV -> RGB transform, including the range expansion
+pub(super) struct RgbCoefficients { y: i32, rv: i32, gu: i32, gv: i32, bu: i32, y_offset: i32 }
+
+impl RgbCoefficients {
+    pub(super) fn new(matrix: YuvMatrix, range: ColorRange) -> Self {
+        let (kr, kb) = matrix.coefficients();
+        let kg = 1.0 - kr - kb;
+        let (y_scale, c_scale, y_offset) = match range {
+            ColorRange::Limited => (255.0 / 219.0, 255.0 / 224.0, 16),
+            ColorRange::Full    => (1.0, 1.0, 0),
+        };
+        let fx = |v: f32| (v * 65536.0).round() as i32;
+        Self {
+            y:  fx(y_scale),
+            rv: fx(2.0 * (1.0 - kr) * c_scale),
+            gu: fx(2.0 * kb * (1.0 - kb) / kg * c_scale),
+            gv: fx(2.0 * kr * (1.0 - kr) / kg * c_scale),
+            bu: fx(2.0 * (1.0 - kb) * c_scale),
+            y_offset,
+        }
+    }
+    #[inline(always)]
+    pub(super) fn to_rgb(&self, y: u8, u: u8, v: u8) -> [u8; 3] {
+        let y = (y as i32 - self.y_offset) * self.y;
+        let u = u as i32 - 128;
+        let v = v as i32 - 128;
+        let clamp = |x: i32| ((x + 32768) >> 16).clamp(0, 255) as u8;
+        [
+            clamp(y + self.rv * v),
+            clamp(y - self.gu * u - self.gv * v),
+            clamp(y + self.bu * u),
+        ]
+    }
+}
+
+/// Unpacks 8-bit packed 4:2:2 into separate Y, U and V planes (YUV422P layout).
+/// The loop works on whole macropixels without bounds checks inside, so it gets auto-vectorized.
+pub fn unpack_422_to_planar(src: &[u8], src_stride: usize, layout: PackedLayout, size: (usize, usize), y: &mut [u8], u: &mut [u8], v: &mut [u8]) {
+    let (oy0, ou, oy1, ov) = layout.offsets();
+    let (width, height) = size;
+    let half = width / 2;
+    for row in 0..height {
+        let src_row = &src[row * src_stride..row * src_stride + half * 4];
+        let y_row = &mut y[row * width..row * width + half * 2];
+        let u_row = &mut u[row * half..row * half + half];
+        let v_row = &mut v[row * half..row * half + half];
+        for (i, px) in src_row.chunks_exact(4).enumerate() {
+            y_row[i * 2]     = px[oy0];
+            y_row[i * 2 + 1] = px[oy1];
+            u_row[i] = px[ou];
+            v_row[i] = px[ov];
+        }
+    }
+}
+
+/// Packs separate Y, U and V planes (YUV422P layout) into 8-bit packed 4:2:2
+pub fn pack_planar_to_422(y: &[u8], u: &[u8], v: &[u8], size: (usize, usize), layout: PackedLayout, dst: &mut [u8], dst_stride: usize) {
+    let (oy0, ou, oy1, ov) = layout.offsets();
+    let (width, height) = size;
+    let half = width / 2;
+    for row in 0..height {
+        let dst_row = &mut dst[row * dst_stride..row * dst_stride + half * 4];
+        let y_row = &y[row * width..row * width + half * 2];
+        let u_row = &u[row * half..row * half + half];
+        let v_row = &v[row * half..row * half + half];
+        for (i, px) in dst_row.chunks_exact_mut(4).enumerate() {
+            px[oy0] = y_row[i * 2];
+            px[oy1] = y_row[i * 2 + 1];
+            px[ou]  = u_row[i];
+            px[ov]  = v_row[i];
+        }
+    }
+}
+
+/// Converts 8-bit packed 4:2:2 directly to tightly packed RGBA, without going through planar
+pub fn packed_422_to_rgba(src: &[u8], src_stride: usize, layout: PackedLayout, size: (usize, usize), matrix: YuvMatrix, range: ColorRange, dst: &mut [u8]) {
+    let (oy0, ou, oy1, ov) = layout.offsets();
+    let coeffs = RgbCoefficients::new(matrix, range);
+    let (width, height) = size;
+    let half = width / 2;
+    for row in 0..height {
+        let src_row = &src[row * src_stride..row * src_stride + half * 4];
+        let dst_row = &mut dst[row * width * 4..row * width * 4 + half * 8];
+        for (px, out) in src_row.chunks_exact(4).zip(dst_row.chunks_exact_mut(8)) {
+            let (u, v) = (px[ou], px[ov]);
+            let [r0, g0, b0] = coeffs.to_rgb(px[oy0], u, v);
+            let [r1, g1, b1] = coeffs.to_rgb(px[oy1], u, v);
+            out.copy_from_slice(&[r0, g0, b0, 255, r1, g1, b1, 255]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_rgba(layout: PackedLayout, yuv: (u8, u8, u8), matrix: YuvMatrix, range: ColorRange) -> [u8; 3] {
+        let (y, u, v) = yuv;
+        let mut src = [0u8; 4];
+        pack_planar_to_422(&[y, y], &[u], &[v], (2, 1), layout, &mut src, 4);
+        let mut rgba = [0u8; 8];
+        packed_422_to_rgba(&src, 4, layout, (2, 1), matrix, range, &mut rgba);
+        assert_eq!(rgba[..4], rgba[4..]);
+        [rgba[0], rgba[1], rgba[2]]
+    }
+
+    fn assert_close(a: [u8; 3], b: [u8; 3]) {
+        assert!(a.iter().zip(b).all(|(a, b)| a.abs_diff(b) <= 1), "{a:?} != {b:?}");
+    }
+
+    #[test]
+    fn round_trip() {
+        let (width, height) = (6, 3);
+        let y: Vec<u8> = (0..width * height).map(|x| (x * 7) as u8).collect();
+        let u: Vec<u8> = (0..width / 2 * height).map(|x| (x * 11 + 3) as u8).collect();
+        let v: Vec<u8> = (0..width / 2 * height).map(|x| (x * 13 + 5) as u8).collect();
+        for layout in [PackedLayout::UYVY, PackedLayout::YUYV] {
+            let stride = width * 2 + 4;
+            let mut packed = vec![0u8; stride * height];
+            pack_planar_to_422(&y, &u, &v, (width, height), layout, &mut packed, stride);
+            let (mut y2, mut u2, mut v2) = (vec![0u8; y.len()], vec![0u8; u.len()], vec![0u8; v.len()]);
+            unpack_422_to_planar(&packed, stride, layout, (width, height), &mut y2, &mut u2, &mut v2);
+            assert_eq!((y2, u2, v2), (y.clone(), u.clone(), v.clone()));
+        }
+        let mut packed = [0u8; 4];
+        pack_planar_to_422(&[1, 2], &[3], &[4], (2, 1), PackedLayout::UYVY, &mut packed, 4);
+        assert_eq!(packed, [3, 1, 4, 2]);
+        pack_planar_to_422(&[1, 2], &[3], &[4], (2, 1), PackedLayout::YUYV, &mut packed, 4);
+        assert_eq!(packed, [1, 3, 2, 4]);
+    }
+
+    #[test]
+    fn known_values() {
+        for layout in [PackedLayout::UYVY, PackedLayout::YUYV] {
+            for matrix in [YuvMatrix::BT601, YuvMatrix::BT709] {
+                assert_eq!(to_rgba(layout, (235, 128, 128), matrix, ColorRange::Limited), [255, 255, 255]);
+                assert_eq!(to_rgba(layout, (16, 128, 128), matrix, ColorRange::Limited), [0, 0, 0]);
+                assert_eq!(to_rgba(layout, (255, 128, 128), matrix, ColorRange::Full), [255, 255, 255]);
+            }
+            // Limited range red and green
+            assert_close(to_rgba(layout, (81, 90, 240), YuvMatrix::BT601, ColorRange::Limited), [255, 0, 0]);
+            assert_close(to_rgba(layout, (63, 102, 240), YuvMatrix::BT709, ColorRange::Limited), [255, 0, 0]);
+            assert_close(to_rgba(layout, (145, 54, 34), YuvMatrix::BT601, ColorRange::Limited), [0, 255, 0]);
+            assert_close(to_rgba(layout, (173, 42, 26), YuvMatrix::BT709, ColorRange::Limited), [0, 255, 0]);
+            // Full range red
+            assert_close(to_rgba(layout, (76, 85, 255), YuvMatrix::BT601, ColorRange::Full), [255, 0, 0]);
+            assert_close(to_rgba(layout, (54, 99, 255), YuvMatrix::BT709, ColorRange::Full), [255, 0, 0]);
+        }
+        // The same sample is a different color in the other matrix
+        assert_ne!(to_rgba(PackedLayout::UYVY, (81, 90, 240), YuvMatrix::BT709, ColorRange::Limited), to_rgba(PackedLayout::UYVY, (81, 90, 240), YuvMatrix::BT601, ColorRange::Limited));
+    }
+
+    #[test]
+    fn matrix_for_untagged() {
+        assert_eq!(YuvMatrix::from_color_space(None, 480), YuvMatrix::BT601);
+        assert_eq!(YuvMatrix::from_color_space(None, 1080), YuvMatrix::BT709);
+        assert_eq!(YuvMatrix::from_color_space(Some(ColorSpace::BT709), 480), YuvMatrix::BT709);
+    }
+}
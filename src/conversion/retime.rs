@@ -0,0 +1,81 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+use crate::util::{ frame_to_timestamp, timestamp_to_frame };
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum RetimeStrategy {
+    /// Each output frame is the source frame nearest to its timestamp, dropping or duplicating frames
+    #[default]
+    Nearest,
+    /// Output frames between two source frames are a weighted mix of both, see `blend_planes`
+    Blend,
+    /// Every source frame is kept and only the timestamps change, so the clip plays faster or slower
+    DurationRemap,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RetimeSource {
+    Frame(usize),
+    /// `weight` is the share of `to`, in 0..1
+    Blend { from: usize, to: usize, weight: f32 },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RetimedFrame {
+    pub timestamp_us: i64,
+    pub source: RetimeSource,
+}
+
+/// Plans the frame rate conversion of `frame_count` frames from `src_rate` to `dst_rate` (numerator/denominator, e.g. (30000, 1001)).
+/// Returns the output frames in order, the caller builds them from the referenced source frames.
+/// Unlike `FrameRetimer`, which enforces a constant rate on timestamps, this changes the number of frames of a clip.
+///
+/// ```
+/// use gpu_video::{ retime, RetimeStrategy, RetimeSource };
+/// let frames = retime(5, (30, 1), (24, 1), RetimeStrategy::Nearest);
+/// let sources: Vec<_> = frames.iter().map(|x| x.source).collect();
+/// assert_eq!(sources, [RetimeSource::Frame(0), RetimeSource::Frame(1), RetimeSource::Frame(2), RetimeSource::Frame(4)]);
+/// assert_eq!(frames[1].timestamp_us, 41667);
+/// ```
+pub fn retime(frame_count: usize, src_rate: (i32, i32), dst_rate: (i32, i32), strategy: RetimeStrategy) -> Vec<RetimedFrame> {
+    assert!(src_rate.0 > 0 && src_rate.1 > 0, "Invalid frame rate {src_rate:?}");
+    if frame_count == 0 { return Vec::new(); }
+
+    if strategy == RetimeStrategy::DurationRemap {
+        return (0..frame_count).map(|i| RetimedFrame { timestamp_us: frame_to_timestamp(i as i64, dst_rate), source: RetimeSource::Frame(i) }).collect();
+    }
+
+    // Position of output frame `i` in source frames is `i * src / dst`, kept as an exact fraction
+    let num = src_rate.0 as i128 * dst_rate.1 as i128;
+    let den = src_rate.1 as i128 * dst_rate.0 as i128;
+    let duration_us = frame_to_timestamp(frame_count as i64, src_rate);
+    let output_count = timestamp_to_frame(duration_us, dst_rate).max(1) as usize;
+    let last = frame_count - 1;
+
+    (0..output_count).map(|i| {
+        let timestamp_us = frame_to_timestamp(i as i64, dst_rate);
+        let source = match strategy {
+            RetimeStrategy::Blend => {
+                let pos = i as i128 * num;
+                let from = ((pos / den) as usize).min(last);
+                let weight = (pos % den) as f32 / den as f32;
+                if from == last || weight == 0.0 {
+                    RetimeSource::Frame(from)
+                } else {
+                    RetimeSource::Blend { from, to: from + 1, weight }
+                }
+            },
+            _ => RetimeSource::Frame((timestamp_to_frame(timestamp_us, src_rate).max(0) as usize).min(last))
+        };
+        RetimedFrame { timestamp_us, source }
+    }).collect()
+}
+
+/// Mixes two 8-bit planes of the same layout into `dst`, `weight` is the share of `b`
+pub fn blend_planes(a: &[u8], b: &[u8], weight: f32, dst: &mut [u8]) {
+    let weight = weight.clamp(0.0, 1.0);
+    for ((d, a), b) in dst.iter_mut().zip(a).zip(b) {
+        *d = (*a as f32 * (1.0 - weight) + *b as f32 * weight).round() as u8;
+    }
+}
@@ -0,0 +1,219 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+// Pure Rust scaling and YUV to RGB conversion, doesn't depend on swscale. Slower, but available in every build configuration.
+
+use super::packed::{ RgbCoefficients, YuvMatrix };
+use crate::types::*;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum ScaleFilter {
+    Nearest,
+    #[default]
+    Bilinear,
+    /// Box average of the covered source pixels, best for large downscales
+    Area,
+}
+
+/// Scales one 8-bit plane with `channels` interleaved components per pixel, e.g. 4 for RGBA or 1 for a luma plane.
+/// `dst` is tightly packed. `InvalidRegion` when either buffer is too small for its size.
+///
+/// ```
+/// use gpu_video::{ scale_plane, ScaleFilter };
+/// let src = [0u8, 100, 200, 255];
+/// let mut dst = [0u8; 2];
+/// scale_plane(&src, 4, (4, 1), &mut dst, (2, 1), 1, ScaleFilter::Area).unwrap();
+/// assert_eq!(dst, [50, 228]);
+/// ```
+pub fn scale_plane(src: &[u8], src_stride: usize, src_size: (usize, usize), dst: &mut [u8], dst_size: (usize, usize), channels: usize, filter: ScaleFilter) -> Result<(), VideoProcessingError> {
+    let ((sw, sh), (dw, dh)) = (src_size, dst_size);
+    if sw == 0 || sh == 0 || dw == 0 || dh == 0 || channels == 0 { return Ok(()); }
+    check_plane(src.len(), src_stride, sw * channels, sh, (sw, sh))?;
+    check_plane(dst.len(), dw * channels, dw * channels, dh, (dw, dh))?;
+    let (fx, fy) = (sw as f32 / dw as f32, sh as f32 / dh as f32);
+    let px = |x: usize, y: usize, c: usize| src[y * src_stride + x * channels + c] as f32;
+
+    for y in 0..dh {
+        let row = &mut dst[y * dw * channels..(y + 1) * dw * channels];
+        for x in 0..dw {
+            for c in 0..channels {
+                let v = match filter {
+                    ScaleFilter::Nearest => {
+                        px((((x as f32 + 0.5) * fx) as usize).min(sw - 1), (((y as f32 + 0.5) * fy) as usize).min(sh - 1), c)
+                    },
+                    ScaleFilter::Bilinear => {
+                        let sx = ((x as f32 + 0.5) * fx - 0.5).clamp(0.0, (sw - 1) as f32);
+                        let sy = ((y as f32 + 0.5) * fy - 0.5).clamp(0.0, (sh - 1) as f32);
+                        let (x0, y0) = (sx as usize, sy as usize);
+                        let (x1, y1) = ((x0 + 1).min(sw - 1), (y0 + 1).min(sh - 1));
+                        let (ax, ay) = (sx - x0 as f32, sy - y0 as f32);
+                        let top = px(x0, y0, c) * (1.0 - ax) + px(x1, y0, c) * ax;
+                        let bottom = px(x0, y1, c) * (1.0 - ax) + px(x1, y1, c) * ax;
+                        top * (1.0 - ay) + bottom * ay
+                    },
+                    ScaleFilter::Area => {
+                        let (x0, x1) = ((x as f32 * fx) as usize, (((x + 1) as f32 * fx).ceil() as usize).clamp(1, sw));
+                        let (y0, y1) = ((y as f32 * fy) as usize, (((y + 1) as f32 * fy).ceil() as usize).clamp(1, sh));
+                        let (x0, y0) = (x0.min(x1 - 1), y0.min(y1 - 1));
+                        let mut sum = 0.0;
+                        for sy in y0..y1 {
+                            for sx in x0..x1 {
+                                sum += px(sx, sy, c);
+                            }
+                        }
+                        sum / ((x1 - x0) * (y1 - y0)) as f32
+                    },
+                };
+                row[x * channels + c] = v.round().clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether a plane of `rows` rows of `row_bytes` bytes fits in `len` bytes with `stride`
+fn check_plane(len: usize, stride: usize, row_bytes: usize, rows: usize, size: (usize, usize)) -> Result<(), VideoProcessingError> {
+    if rows > 0 && (stride < row_bytes || len < stride * (rows - 1) + row_bytes) {
+        return Err(VideoProcessingError::InvalidRegion { plane: 0, rect: (0, 0, size.0 as u32, size.1 as u32) });
+    }
+    Ok(())
+}
+
+/// Converts 8-bit planar YUV to tightly packed RGBA. `chroma_shift` is the log2 subsampling of the chroma planes,
+/// e.g. (1, 1) for YUV420P, (1, 0) for YUV422P and (0, 0) for YUV444P. Chroma samples are replicated, not interpolated
+pub fn planar_yuv_to_rgba(planes: [&[u8]; 3], strides: [usize; 3], size: (usize, usize), chroma_shift: (u32, u32), matrix: YuvMatrix, range: ColorRange, dst: &mut [u8]) -> Result<(), VideoProcessingError> {
+    let (width, height) = size;
+    let chroma = ((width + (1 << chroma_shift.0) - 1) >> chroma_shift.0, (height + (1 << chroma_shift.1) - 1) >> chroma_shift.1);
+    check_plane(planes[0].len(), strides[0], width, height, size)?;
+    check_plane(planes[1].len(), strides[1], chroma.0, chroma.1, size)?;
+    check_plane(planes[2].len(), strides[2], chroma.0, chroma.1, size)?;
+    check_plane(dst.len(), width * 4, width * 4, height, size)?;
+    let coeffs = RgbCoefficients::new(matrix, range);
+    for (row, out) in dst.chunks_exact_mut(width * 4).take(height).enumerate() {
+        let y_row = &planes[0][row * strides[0]..];
+        let u_row = &planes[1][(row >> chroma_shift.1) * strides[1]..];
+        let v_row = &planes[2][(row >> chroma_shift.1) * strides[2]..];
+        for (x, px) in out.chunks_exact_mut(4).enumerate() {
+            let c = x >> chroma_shift.0;
+            let [r, g, b] = coeffs.to_rgb(y_row[x], u_row[c], v_row[c]);
+            px.copy_from_slice(&[r, g, b, 255]);
+        }
+    }
+    Ok(())
+}
+
+/// Converts NV12 (or NV21 with `swap_uv`) to tightly packed RGBA
+pub fn nv12_to_rgba(planes: [&[u8]; 2], strides: [usize; 2], size: (usize, usize), swap_uv: bool, matrix: YuvMatrix, range: ColorRange, dst: &mut [u8]) -> Result<(), VideoProcessingError> {
+    let ([y, uv], [y_stride, uv_stride]) = (planes, strides);
+    let (width, height) = size;
+    check_plane(y.len(), y_stride, width, height, size)?;
+    check_plane(uv.len(), uv_stride, width.div_ceil(2) * 2, height.div_ceil(2), size)?;
+    check_plane(dst.len(), width * 4, width * 4, height, size)?;
+    let coeffs = RgbCoefficients::new(matrix, range);
+    let (ou, ov) = if swap_uv { (1, 0) } else { (0, 1) };
+    for (row, out) in dst.chunks_exact_mut(width * 4).take(height).enumerate() {
+        let y_row = &y[row * y_stride..];
+        let uv_row = &uv[(row / 2) * uv_stride..];
+        for (x, px) in out.chunks_exact_mut(4).enumerate() {
+            let c = (x / 2) * 2;
+            let [r, g, b] = coeffs.to_rgb(y_row[x], uv_row[c + ou], uv_row[c + ov]);
+            px.copy_from_slice(&[r, g, b, 255]);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_buffers_are_rejected() {
+        let src = [0u8; 16];
+        let mut dst = [0u8; 4];
+        assert!(scale_plane(&src, 4, (4, 4), &mut dst, (2, 2), 1, ScaleFilter::Bilinear).is_ok());
+        // Source too short, stride smaller than a row, destination too short
+        assert!(matches!(scale_plane(&src[..15], 4, (4, 4), &mut dst, (2, 2), 1, ScaleFilter::Bilinear), Err(VideoProcessingError::InvalidRegion { .. })));
+        assert!(scale_plane(&src, 3, (4, 4), &mut dst, (2, 2), 1, ScaleFilter::Nearest).is_err());
+        assert!(scale_plane(&src, 4, (2, 2), &mut dst[..3], (2, 2), 1, ScaleFilter::Area).is_err());
+        assert!(scale_plane(&src, 8, (2, 2), &mut dst, (1, 1), 4, ScaleFilter::Area).is_ok());
+        assert!(scale_plane(&src, 8, (2, 3), &mut dst, (1, 1), 4, ScaleFilter::Area).is_err());
+
+        let mut rgba = [0u8; 4 * 4];
+        assert!(planar_yuv_to_rgba([&src[..4], &src[..1], &src[..1]], [2, 1, 1], (2, 2), (1, 1), YuvMatrix::BT709, ColorRange::Limited, &mut rgba).is_ok());
+        assert!(planar_yuv_to_rgba([&src[..3], &src[..1], &src[..1]], [2, 1, 1], (2, 2), (1, 1), YuvMatrix::BT709, ColorRange::Limited, &mut rgba).is_err());
+        assert!(planar_yuv_to_rgba([&src[..4], &src[..1], &src[..1]], [2, 1, 1], (2, 2), (0, 0), YuvMatrix::BT709, ColorRange::Limited, &mut rgba).is_err());
+        assert!(nv12_to_rgba([&src[..4], &src[..1]], [2, 2], (2, 2), false, YuvMatrix::BT709, ColorRange::Limited, &mut rgba).is_err());
+        assert!(nv12_to_rgba([&src[..4], &src[..2]], [2, 2], (2, 2), false, YuvMatrix::BT709, ColorRange::Limited, &mut rgba[..15]).is_err());
+    }
+
+    #[test]
+    fn scaling_filters() {
+        let src = [0u8, 100, 200, 255];
+        let mut dst = [0u8; 2];
+        scale_plane(&src, 4, (4, 1), &mut dst, (2, 1), 1, ScaleFilter::Nearest).unwrap();
+        assert_eq!(dst, [100, 255]);
+        scale_plane(&src, 4, (4, 1), &mut dst, (2, 1), 1, ScaleFilter::Bilinear).unwrap();
+        assert_eq!(dst, [50, 228]);
+        let mut up = [0u8; 4];
+        scale_plane(&[0, 255], 2, (2, 1), &mut up, (4, 1), 1, ScaleFilter::Bilinear).unwrap();
+        assert_eq!(up, [0, 64, 191, 255]);
+        // Channels are scaled independently
+        let mut rgba = [0u8; 4];
+        scale_plane(&[10, 20, 30, 40, 30, 40, 50, 60], 8, (2, 1), &mut rgba, (1, 1), 4, ScaleFilter::Area).unwrap();
+        assert_eq!(rgba, [20, 30, 40, 50]);
+    }
+
+    #[test]
+    fn yuv_known_values() {
+        let rgb = |y: u8, u: u8, v: u8, matrix| {
+            let mut rgba = [0u8; 4 * 4];
+            planar_yuv_to_rgba([&[y; 4], &[u], &[v]], [2, 1, 1], (2, 2), (1, 1), matrix, ColorRange::Limited, &mut rgba).unwrap();
+            let mut nv12 = [0u8; 4 * 4];
+            nv12_to_rgba([&[y; 4], &[u, v]], [2, 2], (2, 2), false, matrix, ColorRange::Limited, &mut nv12).unwrap();
+            assert_eq!(rgba, nv12);
+            assert!(rgba.chunks_exact(4).all(|px| px == &rgba[..4]));
+            [rgba[0], rgba[1], rgba[2]]
+        };
+        let close = |a: [u8; 3], b: [u8; 3]| a.iter().zip(b).all(|(a, b)| a.abs_diff(b) <= 1);
+        assert_eq!(rgb(235, 128, 128, YuvMatrix::BT709), [255, 255, 255]);
+        assert_eq!(rgb(16, 128, 128, YuvMatrix::BT601), [0, 0, 0]);
+        assert!(close(rgb(63, 102, 240, YuvMatrix::BT709), [255, 0, 0]));
+        assert!(close(rgb(145, 54, 34, YuvMatrix::BT601), [0, 255, 0]));
+
+        // NV21 has V first
+        let mut rgba = [0u8; 4];
+        nv12_to_rgba([&[63], &[240, 102]], [1, 2], (1, 1), true, YuvMatrix::BT709, ColorRange::Limited, &mut rgba).unwrap();
+        assert!(close([rgba[0], rgba[1], rgba[2]], [255, 0, 0]));
+    }
+
+    #[test]
+    fn matches_swscale() {
+        use crate::VideoFrameInterface;
+        // Solid colors, so the chroma interpolation of swscale doesn't matter
+        for color in [[255, 0, 0, 255], [0, 255, 0, 255], [20, 40, 220, 255], [128, 128, 128, 255], [250, 200, 10, 255]] {
+            let mut frame = crate::VideoFrame::solid_color(PixelFormat::YUV420P, 16, 8, color).unwrap();
+            let mut reference = ffmpeg_next::frame::Video::empty();
+            let mut ours = vec![0u8; 16 * 8 * 4];
+            {
+                let crate::VideoFrame::FfmpegVideoFrame(f) = &frame;
+                let mut scaler = ffmpeg_next::software::scaling::Context::get(ffmpeg_next::format::Pixel::YUV420P, 16, 8, ffmpeg_next::format::Pixel::RGBA, 16, 8, ffmpeg_next::software::scaling::Flags::BILINEAR | ffmpeg_next::software::scaling::Flags::ACCURATE_RND).unwrap();
+                unsafe {
+                    // Same matrix and range as the frame, BT.709 limited
+                    let coefficients = ffmpeg_next::ffi::sws_getCoefficients(ffmpeg_next::ffi::SWS_CS_ITU709 as i32);
+                    ffmpeg_next::ffi::sws_setColorspaceDetails(scaler.as_mut_ptr(), coefficients, 0, coefficients, 1, 0, 1 << 16, 1 << 16);
+                }
+                scaler.run(&f.avframe, &mut reference).unwrap();
+            }
+            let buffers = frame.get_cpu_buffers().unwrap();
+            let strides = [buffers[0].len() / 8, buffers[1].len() / 4, buffers[2].len() / 4];
+            planar_yuv_to_rgba([&*buffers[0], &*buffers[1], &*buffers[2]], strides, (16, 8), (1, 1), YuvMatrix::BT709, ColorRange::Limited, &mut ours).unwrap();
+            let stride = reference.stride(0);
+            for y in 0..8 {
+                let expected = &reference.data(0)[y * stride..y * stride + 16 * 4];
+                let row = &ours[y * 16 * 4..(y + 1) * 16 * 4];
+                assert!(expected.iter().zip(row).all(|(a, b)| a.abs_diff(*b) <= 2), "{color:?}: {expected:?} != {row:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,48 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+/// Size of the upright, square-pixel thumbnail which fits in `max_size`.
+/// `sar` is the sample aspect ratio of the source and `rotation` is the clockwise display rotation in degrees.
+pub fn thumbnail_size(width: u32, height: u32, sar: (i32, i32), rotation: i32, max_size: u32) -> (u32, u32) {
+    let sar = if sar.0 > 0 && sar.1 > 0 { sar.0 as f64 / sar.1 as f64 } else { 1.0 };
+    let (mut w, mut h) = (width as f64 * sar, height as f64);
+    if is_sideways(rotation) { std::mem::swap(&mut w, &mut h); }
+
+    let scale = (max_size as f64 / w.max(h)).min(1.0);
+    (((w * scale).round() as u32).max(1), ((h * scale).round() as u32).max(1))
+}
+
+/// Creates an upright, square-pixel RGBA thumbnail from a tightly packed RGBA image.
+/// Rotation and SAR correction are applied in a single resampling pass. Returns the pixels with the thumbnail size.
+pub fn make_thumbnail(rgba: &[u8], width: u32, height: u32, sar: (i32, i32), rotation: i32, max_size: u32) -> (Vec<u8>, u32, u32) {
+    let (out_w, out_h) = thumbnail_size(width, height, sar, rotation, max_size);
+    let rotation = rotation.rem_euclid(360) / 90 * 90;
+
+    // Size of the upright image before rotation
+    let (disp_w, disp_h) = if is_sideways(rotation) { (out_h, out_w) } else { (out_w, out_h) };
+
+    let mut ret = vec![0u8; (out_w * out_h * 4) as usize];
+    for oy in 0..out_h {
+        for ox in 0..out_w {
+            // Inverse of the clockwise rotation, to get coordinates in the unrotated image
+            let (dx, dy) = match rotation {
+                90  => (oy, out_w - 1 - ox),
+                180 => (out_w - 1 - ox, out_h - 1 - oy),
+                270 => (out_h - 1 - oy, ox),
+                _   => (ox, oy)
+            };
+            let sx = (((dx as f64 + 0.5) * width as f64 / disp_w as f64) as u32).min(width - 1);
+            let sy = (((dy as f64 + 0.5) * height as f64 / disp_h as f64) as u32).min(height - 1);
+            let src = ((sy * width + sx) * 4) as usize;
+            let dst = ((oy * out_w + ox) * 4) as usize;
+            if src + 4 <= rgba.len() {
+                ret[dst..dst + 4].copy_from_slice(&rgba[src..src + 4]);
+            }
+        }
+    }
+    (ret, out_w, out_h)
+}
+
+fn is_sideways(rotation: i32) -> bool {
+    matches!(rotation.rem_euclid(360) / 90, 1 | 3)
+}
@@ -0,0 +1,161 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+use crate::*;
+
+const GRID_W: usize = 64;
+const GRID_H: usize = 36;
+
+#[derive(Debug, Clone, Copy)]
+pub struct AnalysisFlags {
+    pub black_frames: bool,
+    pub freeze_frames: bool,
+    /// Normalized to 0..1. A frame is black when its mean luma is below `threshold`,
+    /// and frozen when the mean absolute difference to the previous frame is below `threshold / 10`.
+    pub threshold: f32,
+    /// Only analyze frames which are already in CPU memory, don't download hardware frames just for the analysis.
+    pub cpu_frames_only: bool,
+}
+impl Default for AnalysisFlags {
+    fn default() -> Self {
+        Self {
+            black_frames: false,
+            freeze_frames: false,
+            threshold: 0.1,
+            cpu_frames_only: false,
+        }
+    }
+}
+impl AnalysisFlags {
+    pub fn is_enabled(&self) -> bool { self.black_frames || self.freeze_frames }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampIssue {
+    /// Distance to the previous frame is larger than the expected frame interval
+    Gap { missing_frames: u32 },
+    /// Same timestamp as the previous frame
+    Duplicate,
+}
+
+/// Finds dropped and duplicated frames from the frame timestamps, e.g. to validate screen captures
+pub struct TimestampChecker {
+    frame_interval_us: f64,
+    previous: Option<i64>,
+}
+impl TimestampChecker {
+    pub fn new(fps: f64) -> Self {
+        Self { frame_interval_us: 1_000_000.0 / fps, previous: None }
+    }
+
+    /// Call for every frame in presentation order
+    pub fn push(&mut self, timestamp_us: i64) -> Option<TimestampIssue> {
+        let previous = self.previous.replace(timestamp_us)?;
+        let delta = (timestamp_us - previous) as f64;
+        if timestamp_us == previous {
+            Some(TimestampIssue::Duplicate)
+        } else if self.frame_interval_us.is_finite() && delta > self.frame_interval_us * 1.5 {
+            Some(TimestampIssue::Gap { missing_frames: (delta / self.frame_interval_us).round() as u32 - 1 })
+        } else {
+            None
+        }
+    }
+}
+
+pub(crate) struct FrameAnalyzer {
+    flags: AnalysisFlags,
+    previous: Option<Vec<u8>>,
+}
+
+impl FrameAnalyzer {
+    pub fn new(flags: AnalysisFlags) -> Self {
+        Self { flags, previous: None }
+    }
+
+    pub fn analyze(&mut self, frame: &mut VideoFrame) -> Vec<Diagnostic> {
+        let mut ret = Vec::new();
+        if self.flags.cpu_frames_only && frame.is_hardware() { return ret; }
+
+        let timestamp_us = frame.timestamp_us().unwrap_or_default();
+        let grid = match decimated_luma(frame) {
+            Some(x) => x,
+            None => return ret
+        };
+
+        let threshold = self.flags.threshold.clamp(0.0001, 1.0);
+        if self.flags.black_frames {
+            let mean = grid.iter().map(|&x| x as f32).sum::<f32>() / grid.len() as f32 / 255.0;
+            if mean < threshold {
+                ret.push(Diagnostic::BlackFrame { timestamp_us, confidence: 1.0 - mean / threshold });
+            }
+        }
+        if self.flags.freeze_frames {
+            if let Some(prev) = &self.previous {
+                let diff = grid.iter().zip(prev).map(|(&a, &b)| (a as f32 - b as f32).abs()).sum::<f32>() / grid.len() as f32 / 255.0;
+                let freeze_threshold = threshold / 10.0;
+                if diff < freeze_threshold {
+                    ret.push(Diagnostic::FrozenFrame { timestamp_us, confidence: 1.0 - diff / freeze_threshold });
+                }
+            }
+            self.previous = Some(grid);
+        }
+        ret
+    }
+}
+
+/// Samples the luma of the frame on a small grid, so the analysis cost doesn't depend on the frame size
+fn decimated_luma(frame: &mut VideoFrame) -> Option<Vec<u8>> {
+    let (width, height) = (frame.width() as usize, frame.height() as usize);
+    if width == 0 || height == 0 { return None; }
+    let format = frame.format();
+    let buffers = frame.get_cpu_buffers().ok()?;
+    let plane = buffers.first()?;
+    let stride = plane.len() / height;
+
+    let mut ret = Vec::with_capacity(GRID_W * GRID_H);
+    for gy in 0..GRID_H {
+        let y = gy * height / GRID_H;
+        for gx in 0..GRID_W {
+            let x = gx * width / GRID_W;
+            let row = y * stride;
+            let sample = |offset: usize| plane.get(row + offset).copied().unwrap_or_default();
+            ret.push(match format {
+                PixelFormat::RGBA | PixelFormat::RGB32 => {
+                    ((sample(x * 4) as u32 * 54 + sample(x * 4 + 1) as u32 * 183 + sample(x * 4 + 2) as u32 * 19) >> 8) as u8
+                },
+                PixelFormat::RGB24 => {
+                    ((sample(x * 3) as u32 * 54 + sample(x * 3 + 1) as u32 * 183 + sample(x * 3 + 2) as u32 * 19) >> 8) as u8
+                },
+                PixelFormat::BGRA => {
+                    ((sample(x * 4 + 2) as u32 * 54 + sample(x * 4 + 1) as u32 * 183 + sample(x * 4) as u32 * 19) >> 8) as u8
+                },
+                PixelFormat::UYVY422 => sample(x * 2 + 1),
+                PixelFormat::YUYV422 => sample(x * 2),
+                PixelFormat::AYUV64LE => sample(x * 8 + 3), // high byte of Y
+                PixelFormat::VUYA     => sample(x * 4 + 2),
+                PixelFormat::RGB48BE  => sample(x * 6 + 2), // high byte of G
+                PixelFormat::RGBA64BE => sample(x * 8 + 2), // high byte of G
+                PixelFormat::RGB48LE  => sample(x * 6 + 3),
+                PixelFormat::RGBA64LE => sample(x * 8 + 3),
+                PixelFormat::GRAY16BE => sample(x * 2),
+                // 16-bit little endian, take the high byte
+                PixelFormat::P010LE | PixelFormat::P012LE | PixelFormat::P016LE | PixelFormat::P210LE | PixelFormat::P212LE | PixelFormat::P216LE |
+                PixelFormat::P410LE | PixelFormat::P412LE | PixelFormat::P416LE |
+                PixelFormat::YUV420P16LE | PixelFormat::YUV422P16LE | PixelFormat::YUV444P16LE | PixelFormat::GRAY16LE | PixelFormat::GBRP16LE => sample(x * 2 + 1),
+                PixelFormat::Y210LE | PixelFormat::Y212LE => sample(x * 4 + 1),
+                PixelFormat::XV36LE => sample(x * 8 + 3),
+                PixelFormat::XV30LE => ((u32::from_le_bytes([sample(x * 4), sample(x * 4 + 1), sample(x * 4 + 2), sample(x * 4 + 3)]) >> 12) & 0xff) as u8,
+                PixelFormat::YUV420P10LE | PixelFormat::YUV422P10LE | PixelFormat::YUV444P10LE | PixelFormat::GBRP10LE | PixelFormat::GBRAP10LE => (u16::from_le_bytes([sample(x * 2), sample(x * 2 + 1)]) >> 2) as u8,
+                PixelFormat::YUV420P12LE | PixelFormat::YUV422P12LE | PixelFormat::YUV444P12LE | PixelFormat::GBRP12LE | PixelFormat::GBRAP12LE => (u16::from_le_bytes([sample(x * 2), sample(x * 2 + 1)]) >> 4) as u8,
+                PixelFormat::GBRP10BE => (u16::from_be_bytes([sample(x * 2), sample(x * 2 + 1)]) >> 2) as u8,
+                PixelFormat::GBRP12BE => (u16::from_be_bytes([sample(x * 2), sample(x * 2 + 1)]) >> 4) as u8,
+                PixelFormat::YUV420P14LE | PixelFormat::YUV422P14LE | PixelFormat::YUV444P14LE => (u16::from_le_bytes([sample(x * 2), sample(x * 2 + 1)]) >> 6) as u8,
+                PixelFormat::Unknown | PixelFormat::PAL8 => return None,
+                f if f.is_float() => return None,
+                _ => sample(x)
+            });
+        }
+    }
+    Some(ret)
+}
+
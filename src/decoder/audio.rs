@@ -0,0 +1,53 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+use super::*;
+
+/// Decodes a single audio stream to interleaved PCM, without demuxing or decoding anything else.
+pub struct AudioExtractor {
+    decoder: Decoder,
+    format: PcmFormat,
+    pub stream_index: usize,
+    pub sample_rate: u32,
+    pub channels: u32,
+}
+
+impl AudioExtractor {
+    /// Extracts `stream_index`, or the first audio stream when `None`
+    pub fn new(path: &str, stream_index: Option<usize>, format: PcmFormat) -> Result<Self, VideoProcessingError> {
+        Self::new_from_io(IoType::from(path), stream_index, format)
+    }
+
+    pub fn new_from_io(io: IoType, stream_index: Option<usize>, format: PcmFormat) -> Result<Self, VideoProcessingError> {
+        let mut decoder = Decoder::new_from_io(io, DecoderOptions { only_streams: stream_index.map(|x| vec![x]), ..Default::default() })?;
+        let stream = decoder.stream_infos().into_iter()
+            .find(|x| matches!(x.stream_type, StreamType::Audio) && stream_index.is_none_or(|i| i == x.index))
+            .ok_or(VideoProcessingError::AudioStreamNotFound)?;
+        // Turn off the other streams, so no video decoder or hw device is ever created
+        for s in decoder.streams() {
+            s.decode = s.index == stream.index;
+        }
+        Ok(Self {
+            decoder,
+            format,
+            stream_index: stream.index,
+            sample_rate: stream.sample_rate.unwrap_or_default(),
+            channels: stream.channels.unwrap_or_default(),
+        })
+    }
+
+    /// Next chunk of interleaved samples and its timestamp, `None` at the end of the stream
+    pub fn next_samples(&mut self) -> Option<Result<(Option<i64>, Vec<u8>), VideoProcessingError>> {
+        while let Some(frame) = self.decoder.next_frame() {
+            if let Frame::Audio(mut frame) = frame {
+                return Some(frame.to_pcm(self.format).map(|pcm| (frame.timestamp_us(), pcm)));
+            }
+        }
+        None
+    }
+
+    /// Sample-accurate, the next chunk starts exactly at `timestamp_us`
+    pub fn seek(&mut self, timestamp_us: i64) -> bool {
+        self.decoder.seek_audio_precise(timestamp_us) != SeekResult::Failed
+    }
+}
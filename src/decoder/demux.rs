@@ -0,0 +1,224 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+use super::ffmpeg::IoDeadline;
+use crate::support::ffmpeg_io::CustomIo;
+use ffmpeg_next::{ ffi, format, Packet };
+use std::sync::Arc;
+use std::sync::mpsc::{ self, Receiver, RecvTimeoutError, SyncSender, TryRecvError };
+use std::time::Instant;
+
+/// Packets read ahead by the demux thread
+const QUEUE_PACKETS: usize = 64;
+
+// The input context with everything its IO points to, dropped in this order on the demux thread
+struct SendInput {
+    context: format::context::Input,
+    _custom_io: Option<CustomIo>,
+    // Opaque pointer of the interrupt callback of `context`
+    _io_deadline: Arc<IoDeadline>,
+}
+unsafe impl Send for SendInput { }
+
+enum Command {
+    Seek { generation: u64, position: i64 },
+    Discard { stream_index: usize, discard: ffi::AVDiscard },
+}
+
+enum Event {
+    Packet(Packet),
+    Seeked(bool),
+    End,
+}
+
+/// Reads packets on a separate thread into a bounded queue, so IO stalls of slow sources overlap with decoding.
+/// Takes over the opened input context, the decoder keeps a copy of the stream info from `stream_info`
+pub struct BackgroundDemuxer {
+    commands: mpsc::Sender<Command>,
+    events: Receiver<(u64, Event)>,
+    // Bumped on every seek, packets read for an older position are dropped
+    generation: u64,
+    // Deadline of `read`, the one of the input context is only used to stop the thread
+    deadline: Option<Instant>,
+    io_deadline: Arc<IoDeadline>,
+}
+
+impl BackgroundDemuxer {
+    /// `custom_io` and `io_deadline` are what the IO of `context` points to, they're dropped after it on the demux thread.
+    /// Reads of the thread are interrupted only once the demuxer is dropped
+    pub fn new(context: format::context::Input, custom_io: Option<CustomIo>, io_deadline: Arc<IoDeadline>) -> std::io::Result<Self> {
+        let (commands, command_rx) = mpsc::channel();
+        let (event_tx, events) = mpsc::sync_channel(QUEUE_PACKETS);
+        io_deadline.set(None);
+        let input = SendInput { context, _custom_io: custom_io, _io_deadline: io_deadline.clone() };
+        std::thread::Builder::new().name("gpu-video demux".into()).spawn(move || run(input, command_rx, event_tx))?;
+        Ok(Self { commands, events, generation: 0, deadline: None, io_deadline })
+    }
+
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    /// Next packet in the original time base, `Eof` at the end of input.
+    /// `Exit` when nothing arrived before the deadline, the thread keeps reading and the packet is returned by the next call
+    pub fn read(&mut self) -> Result<Packet, ffmpeg_next::Error> {
+        loop {
+            let event = match self.deadline {
+                Some(deadline) => match self.events.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(x) => Ok(x),
+                    Err(RecvTimeoutError::Timeout) => return Err(ffmpeg_next::Error::Exit),
+                    Err(RecvTimeoutError::Disconnected) => Err(())
+                },
+                None => self.events.recv().map_err(|_| ())
+            };
+            match event {
+                Ok((generation, _)) if generation != self.generation => continue,
+                Ok((_, Event::Packet(packet))) => return Ok(packet),
+                Ok((_, Event::Seeked(_))) => continue,
+                Ok((_, Event::End)) | Err(_) => return Err(ffmpeg_next::Error::Eof)
+            }
+        }
+    }
+
+    /// Seeks the demux thread and waits for the result, discarding everything queued for the old position
+    pub fn seek(&mut self, position: i64) -> bool {
+        self.generation += 1;
+        if self.commands.send(Command::Seek { generation: self.generation, position }).is_err() {
+            return false;
+        }
+        loop {
+            match self.events.recv() {
+                Ok((generation, Event::Seeked(ok))) if generation == self.generation => return ok,
+                Ok(_) => continue,
+                Err(_) => return false
+            }
+        }
+    }
+
+    pub fn set_discard(&self, stream_index: usize, discard: ffi::AVDiscard) {
+        let _ = self.commands.send(Command::Discard { stream_index, discard });
+    }
+}
+
+impl Drop for BackgroundDemuxer {
+    // Aborts a read stuck on the network, the thread stops once it sees the disconnected channels
+    fn drop(&mut self) {
+        self.io_deadline.set(Some(Instant::now()));
+    }
+}
+
+/// Copy of the stream info of `input` without any IO, for the decoder while the demux thread owns the input.
+/// `iformat` of the copy points to the one of `input`, it must be reset to null before the copy is dropped
+pub unsafe fn stream_info(input: &format::context::Input) -> Result<format::context::Input, ffmpeg_next::Error> {
+    const ENOMEM: ffmpeg_next::Error = ffmpeg_next::Error::Other { errno: ffmpeg_next::error::ENOMEM };
+    let src = input.as_ptr();
+    let ctx = ffi::avformat_alloc_context();
+    if ctx.is_null() { return Err(ENOMEM); }
+    // Freed by its `Drop` on error, `iformat` is set last so `read_close` of the demuxer is never called on it
+    let info = format::context::Input::wrap(ctx);
+    (*ctx).duration = (*src).duration;
+    (*ctx).start_time = (*src).start_time;
+    (*ctx).bit_rate = (*src).bit_rate;
+    ffi::av_dict_copy(&mut (*ctx).metadata, (*src).metadata, 0);
+    for i in 0..(*src).nb_chapters as usize {
+        let from = *(*src).chapters.add(i);
+        let chapter = ffi::av_mallocz(std::mem::size_of::<ffi::AVChapter>()) as *mut ffi::AVChapter;
+        if chapter.is_null() { return Err(ENOMEM); }
+        (*chapter).id = (*from).id;
+        (*chapter).time_base = (*from).time_base;
+        (*chapter).start = (*from).start;
+        (*chapter).end = (*from).end;
+        ffi::av_dict_copy(&mut (*chapter).metadata, (*from).metadata, 0);
+        let ret = ffi::av_dynarray_add_nofree(&mut (*ctx).chapters as *mut _ as *mut std::ffi::c_void, &mut (*ctx).nb_chapters as *mut u32 as *mut std::ffi::c_int, chapter as *mut std::ffi::c_void);
+        if ret < 0 {
+            ffi::av_dict_free(&mut (*chapter).metadata);
+            ffi::av_free(chapter as *mut std::ffi::c_void);
+            return Err(ffmpeg_next::Error::from(ret));
+        }
+    }
+    for stream in input.streams() {
+        let from = stream.as_ptr();
+        let st = ffi::avformat_new_stream(ctx, std::ptr::null());
+        if st.is_null() { return Err(ENOMEM); }
+        let ret = ffi::avcodec_parameters_copy((*st).codecpar, (*from).codecpar);
+        if ret < 0 { return Err(ffmpeg_next::Error::from(ret)); }
+        (*st).id = (*from).id;
+        (*st).time_base = (*from).time_base;
+        (*st).start_time = (*from).start_time;
+        (*st).duration = (*from).duration;
+        (*st).nb_frames = (*from).nb_frames;
+        (*st).disposition = (*from).disposition;
+        (*st).discard = (*from).discard;
+        (*st).sample_aspect_ratio = (*from).sample_aspect_ratio;
+        (*st).avg_frame_rate = (*from).avg_frame_rate;
+        (*st).r_frame_rate = (*from).r_frame_rate;
+        ffi::av_dict_copy(&mut (*st).metadata, (*from).metadata, 0);
+        for j in 0..(*from).nb_side_data.max(0) as usize {
+            let sd = (*from).side_data.add(j);
+            let data = ffi::av_stream_new_side_data(st, (*sd).type_, (*sd).size as _);
+            if data.is_null() { return Err(ENOMEM); }
+            std::ptr::copy_nonoverlapping((*sd).data, data, (*sd).size as usize);
+        }
+    }
+    (*ctx).iformat = (*src).iformat;
+    Ok(info)
+}
+
+// Dropping the demuxer disconnects both channels, which stops the thread after its current read
+fn run(mut input: SendInput, commands: Receiver<Command>, events: SyncSender<(u64, Event)>) {
+    let mut generation = 0;
+    let mut ended = false;
+    let mut errors = 0;
+    loop {
+        let command = if ended {
+            match commands.recv() { Ok(x) => Some(x), Err(_) => return }
+        } else {
+            match commands.try_recv() {
+                Ok(x) => Some(x),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => return
+            }
+        };
+        match command {
+            Some(Command::Seek { generation: g, position }) => {
+                generation = g;
+                let ok = match input.context.seek(position, ..position) {
+                    Ok(()) => true,
+                    Err(e) => { log::error!("Failed to seek {:?}", e); false }
+                };
+                ended = false;
+                errors = 0;
+                if events.send((generation, Event::Seeked(ok))).is_err() { return; }
+                continue;
+            },
+            Some(Command::Discard { stream_index, discard }) => {
+                if let Some(mut stream) = input.context.stream_mut(stream_index) {
+                    unsafe { (*stream.as_mut_ptr()).discard = discard; }
+                }
+                continue;
+            },
+            None => { }
+        }
+
+        let mut packet = Packet::empty();
+        let event = match packet.read(&mut input.context) {
+            Ok(..) => {
+                errors = 0;
+                Event::Packet(packet)
+            },
+            Err(ffmpeg_next::Error::Eof) => {
+                ended = true;
+                Event::End
+            },
+            Err(e) => {
+                log::error!("Failed to read packet: {e:?}");
+                errors += 1;
+                // Don't spin forever on a broken input
+                if errors < 100 { continue; }
+                ended = true;
+                Event::End
+            }
+        };
+        if events.send((generation, event)).is_err() { return; }
+    }
+}
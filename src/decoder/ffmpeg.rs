@@ -15,97 +15,856 @@ pub enum OpenedDecoder {
 struct StreamInfo {
     decoder: Option<OpenedDecoder>,
     info: Stream,
+    descriptor: StreamDescriptor,
+    last_video_params: Option<VideoParams>,
+    format_converter: Option<FormatConverter>,
+    // Applies the look of the sidecar
+    look_graph: Option<crate::ColorConversionGraph>,
+    reorder: ReorderBuffer,
+    // Whether the demuxer drops all packets of this stream, follows `Stream::decode`
+    discarded: bool,
+    // In-band SPS/PPS (or VPS/SPS/PPS) of the last keyframe, to detect mid-stream changes
+    parameter_sets: Option<Vec<u8>>,
+    // Reference for `AudioFrame::sample_position`
+    start_time_us: i64,
+    // Timestamp of the last audio frame returned
+    last_audio_us: Option<i64>,
+    // Audio frames up to this timestamp were already returned before the device was lost, they are dropped when decoded again
+    skip_audio_until_us: Option<i64>,
+}
+
+impl StreamInfo {
+    /// Whether the packet carries different codec parameters than the previous ones, either as side data or in-band (Annex B)
+    fn check_parameter_sets(&mut self, packet: &ffmpeg_next::Packet) -> bool {
+        let new_extradata = packet.side_data().any(|sd| sd.kind() == ffmpeg_next::packet::side_data::Type::NewExtraData);
+        if !packet.is_key() { return new_extradata; }
+        let in_band = packet.data().map(|d| annexb_parameter_sets(&self.descriptor.codec_name, d)).unwrap_or_default();
+        if in_band.is_empty() { return new_extradata; }
+        let changed = self.parameter_sets.as_ref().is_some_and(|x| *x != in_band);
+        self.parameter_sets = Some(in_band);
+        new_extradata || changed
+    }
+}
+
+/// Concatenated parameter set NAL units of an Annex B packet, empty for length-prefixed packets and other codecs
+fn annexb_parameter_sets(codec: &str, data: &[u8]) -> Vec<u8> {
+    let is_parameter_set = |nal: &[u8]| match codec {
+        "h264" => matches!(nal[0] & 0x1f, 7 | 8),
+        "hevc" => matches!((nal[0] >> 1) & 0x3f, 32..=34),
+        _ => false
+    };
+    if !data.starts_with(&[0, 0, 1]) && !data.starts_with(&[0, 0, 0, 1]) { return Vec::new(); }
+
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 { starts.push(i + 3); i += 3; } else { i += 1; }
+    }
+    let mut ret = Vec::new();
+    for (n, &start) in starts.iter().enumerate() {
+        let end = starts.get(n + 1).map_or(data.len(), |&next| next - 3);
+        let nal = &data[start..end.max(start)];
+        if !nal.is_empty() && is_parameter_set(nal) {
+            ret.extend_from_slice(nal);
+        }
+    }
+    ret
+}
+
+enum DecodeStep {
+    Done(Option<Frame>),
+    Again,
+}
+
+/// Remembers `params` as the last decoded ones, returns the previous ones when they changed
+fn track_video_params(last: &mut Option<VideoParams>, params: VideoParams) -> Option<VideoParams> {
+    last.replace(params).filter(|old| *old != params)
+}
+
+/// Frames come out of the decoder in presentation order already, so they are passed through until a pts goes backwards.
+/// From then on up to the codec's reordering delay of frames is held back and returned sorted by the pts (e.g. B-frames in files with broken timestamps).
+/// The timestamps are never rewritten
+#[derive(Default)]
+struct ReorderBuffer {
+    frames: VecDeque<FfmpegVideoFrame>,
+    /// Largest timestamp returned so far
+    last_timestamp_us: Option<i64>,
+    reordering: bool,
+}
+
+impl ReorderBuffer {
+    fn push(&mut self, frame: FfmpegVideoFrame, delay: usize) -> Option<FfmpegVideoFrame> {
+        if !self.reordering {
+            match (frame.timestamp_us(), self.last_timestamp_us) {
+                (Some(ts), Some(last)) if ts < last => {
+                    // Already behind a returned frame, it can't be fixed anymore but the following ones can
+                    log::warn!("Non-monotonic timestamp {ts} after {last}, reordering the following frames");
+                    self.reordering = true;
+                },
+                _ => return Some(self.returned(frame))
+            }
+        }
+        // Frames without a timestamp keep their decode order
+        let ts = frame.timestamp_us().unwrap_or(i64::MAX);
+        let pos = self.frames.iter().position(|f| f.timestamp_us().unwrap_or(i64::MAX) > ts).unwrap_or(self.frames.len());
+        self.frames.insert(pos, frame);
+        if self.frames.len() > delay.max(1) { self.pop() } else { None }
+    }
+    fn pop(&mut self) -> Option<FfmpegVideoFrame> {
+        let frame = self.frames.pop_front()?;
+        Some(self.returned(frame))
+    }
+    fn returned(&mut self, frame: FfmpegVideoFrame) -> FfmpegVideoFrame {
+        if let Some(ts) = frame.timestamp_us() {
+            self.last_timestamp_us = Some(self.last_timestamp_us.map_or(ts, |last| last.max(ts)));
+        }
+        frame
+    }
+    fn clear(&mut self) {
+        self.frames.clear();
+        self.last_timestamp_us = None;
+    }
+}
+
+/// Converts software frames to `DecoderOptions::output_format` or `preferred_bit_depth`
+struct FormatConverter(ffmpeg_next::software::scaling::Context);
+unsafe impl Send for FormatConverter { }
+
+impl FormatConverter {
+    /// `space` is the matrix used when converting RGB to YUV, the output is tagged with it
+    fn convert(converter: &mut Option<Self>, frame: &frame::Video, target: format::Pixel, space: ColorSpace) -> Option<frame::Video> {
+        if frame.format() == target { return None; }
+        let (w, h) = (frame.width(), frame.height());
+        let matches = converter.as_ref().is_some_and(|c| {
+            let input = c.0.input();
+            let output = c.0.output();
+            input.format == frame.format() && input.width == w && input.height == h && output.format == target
+        });
+        let rgb_to_yuv = is_rgb(frame.format()) && !is_rgb(target);
+        if !matches {
+            *converter = ffmpeg_next::software::scaling::Context::get(frame.format(), w, h, target, w, h, ffmpeg_next::software::scaling::Flags::BILINEAR | ffmpeg_next::software::scaling::Flags::ACCURATE_RND)
+                .map_err(|e| log::error!("Failed to create format converter: {e:?}")).ok().map(Self);
+            if let (Some(c), true) = (converter.as_mut(), rgb_to_yuv) {
+                let cs = match space {
+                    ColorSpace::BT709 => ffi::SWS_CS_ITU709,
+                    ColorSpace::BT470BG | ColorSpace::SMPTE170M => ffi::SWS_CS_ITU601,
+                    ColorSpace::BT2020NCL | ColorSpace::BT2020CL => ffi::SWS_CS_BT2020,
+                } as i32;
+                unsafe {
+                    let coefficients = ffi::sws_getCoefficients(cs);
+                    ffi::sws_setColorspaceDetails(c.0.as_mut_ptr(), coefficients, 1, coefficients, 0, 0, 1 << 16, 1 << 16);
+                }
+            }
+        }
+        let mut output = frame::Video::empty();
+        if let Err(e) = converter.as_mut()?.0.run(frame, &mut output) {
+            log::error!("Failed to convert {:?} to {:?}: {e:?}", frame.format(), target);
+            return None;
+        }
+        unsafe { ffi::av_frame_copy_props(output.as_mut_ptr(), frame.as_ptr()); }
+        if rgb_to_yuv {
+            output.set_color_space(space.into());
+            output.set_color_range(ffmpeg_next::color::Range::MPEG);
+        }
+        Some(output)
+    }
+}
+
+fn is_rgb(format: format::Pixel) -> bool {
+    let desc = unsafe { ffi::av_pix_fmt_desc_get(format.into()) };
+    !desc.is_null() && unsafe { (*desc).flags } & ffi::AV_PIX_FMT_FLAG_RGB as u64 != 0
+}
+
+/// Whether the path is an image sequence pattern like `frame_%05d.png` or `frame_%d.png`.
+/// A width has to start with 0, so percent-encoded names like `clip%2d1.mp4` (`clip-1.mp4`) and URLs are opened as they are
+fn is_sequence_pattern(path: &str) -> bool {
+    if path.contains("://") { return false; }
+    let mut rest = path;
+    while let Some(pos) = rest.find('%') {
+        let spec = &rest[pos + 1..];
+        let width = spec.trim_start_matches(|c: char| c.is_ascii_digit());
+        let digits = &spec[..spec.len() - width.len()];
+        if width.starts_with('d') && (digits.is_empty() || (digits.len() > 1 && digits.starts_with('0'))) { return true; }
+        // `%%` is a literal percent sign
+        rest = spec.strip_prefix('%').unwrap_or(spec);
+    }
+    false
+}
+
+/// Consecutive hardware decode errors retried before switching to another device
+const MAX_HW_RETRIES: u32 = 2;
+/// Devices tried after hardware decode errors before decoding in software, including the first one
+const MAX_HW_DEVICES: u32 = 2;
+
+/// Wait before resending a packet after the `attempt`th consecutive hardware decode error, 10 ms doubling up to 80 ms
+fn hw_retry_backoff(attempt: u32) -> std::time::Duration {
+    std::time::Duration::from_millis(10 << attempt.saturating_sub(1).min(3))
+}
+
+/// Whether a failed hardware decode call points at the device or driver (out of memory, a failed driver call, an unsupported surface).
+/// Broken packets fail with `InvalidData` in software as well, so they don't count
+fn is_hw_device_error(e: ffmpeg_next::Error) -> bool {
+    use ffmpeg_next::error::{ EIO, ENOMEM, ENOSYS, EINVAL };
+    matches!(e, ffmpeg_next::Error::External | ffmpeg_next::Error::Bug | ffmpeg_next::Error::Bug2 | ffmpeg_next::Error::PatchWelcome |
+                ffmpeg_next::Error::Other { errno: EIO | ENOMEM | ENOSYS | EINVAL })
+}
+
+/// Lost GPU devices recovered before giving up, when `DecoderOptions::max_device_recoveries` is not set
+const DEFAULT_DEVICE_RECOVERIES: u32 = 3;
+
+/// Deadline checked by the interrupt callback of the input context, see `Decoder::next_frame_timeout`.
+/// Lock-free, ffmpeg calls the callback in every loop waiting for IO
+pub(super) struct IoDeadline {
+    // Nanoseconds since `epoch` plus one, 0 without a deadline
+    deadline: std::sync::atomic::AtomicU64,
+    epoch: std::time::Instant,
+    interrupted: std::sync::atomic::AtomicBool,
+}
+
+impl Default for IoDeadline {
+    fn default() -> Self {
+        Self { deadline: Default::default(), epoch: std::time::Instant::now(), interrupted: Default::default() }
+    }
+}
+
+impl IoDeadline {
+    pub(super) fn set(&self, deadline: Option<std::time::Instant>) {
+        let nanos = deadline.map_or(0, |x| x.saturating_duration_since(self.epoch).as_nanos() as u64 + 1);
+        self.deadline.store(nanos, std::sync::atomic::Ordering::Relaxed);
+    }
+    fn expired(&self) -> bool {
+        let deadline = self.deadline.load(std::sync::atomic::Ordering::Relaxed);
+        deadline != 0 && self.epoch.elapsed().as_nanos() as u64 + 1 >= deadline
+    }
+}
+
+/// Identifies a packet read from the input, to skip the ones already returned after reading is restarted from a keyframe
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PacketPosition {
+    stream: usize,
+    // Byte position, -1 when the demuxer doesn't know it
+    pos: i64,
+    dts: Option<i64>,
+}
+
+impl PacketPosition {
+    fn of(packet: &ffmpeg_next::Packet) -> Self {
+        Self { stream: packet.stream(), pos: packet.position() as i64, dts: packet.dts() }
+    }
+    /// Whether `self` was read before `last`, by the byte position or else the dts of the same stream
+    fn is_before(&self, last: &PacketPosition) -> bool {
+        if self.pos >= 0 && last.pos >= 0 {
+            self.pos < last.pos
+        } else {
+            self.stream != last.stream || self.dts < last.dts
+        }
+    }
+}
+
+unsafe extern "C" fn io_interrupt(opaque: *mut std::ffi::c_void) -> std::ffi::c_int {
+    let state = &*(opaque as *const IoDeadline);
+    let expired = state.expired();
+    if expired {
+        state.interrupted.store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+    expired as std::ffi::c_int
+}
+
+/// `custom_options` keys used by this backend itself, the rest is passed to the demuxer
+const RECOGNIZED_OPTIONS: &[&str] = &["hwaccel_device", "lowres", "threads", "skip_loop_filter"];
+
+/// Video codec context settings, from `DecoderOptions::quality_preset` and overridden by the `custom_options` of the same name.
+///
+/// | Preset         | lowres | threads           | skip_loop_filter | bit depth |
+/// |----------------|--------|-------------------|------------------|-----------|
+/// | `None`         | 0      | frame, up to 3    | default          | native    |
+/// | `Preview`      | 1      | slice, all cores  | all              | 8         |
+/// | `Balanced`     | 0      | frame, up to 3    | nonref           | native    |
+/// | `Full`         | 0      | frame, all cores  | default          | native    |
+///
+/// `lowres` halves the resolution per step and is limited to what the codec supports (e.g. MJPEG, JPEG 2000, ProRes),
+/// it's not used with hardware decoding. `threads` is the number of threads, 0 for all cores
+#[derive(Debug, Clone, Copy)]
+struct CodecSettings {
+    lowres: i32,
+    threading: ffmpeg_next::threading::Type,
+    // `None` derives the count from the prefetch depth
+    threads: Option<usize>,
+    skip_loop_filter: ffi::AVDiscard,
+}
+
+impl CodecSettings {
+    fn new(options: &DecoderOptions) -> Self {
+        use ffmpeg_next::threading::Type;
+        let mut settings = match options.quality_preset {
+            Some(QualityPreset::Preview) => Self { lowres: 1, threading: Type::Slice, threads: Some(0), skip_loop_filter: ffi::AVDiscard::AVDISCARD_ALL },
+            Some(QualityPreset::Balanced) => Self { lowres: 0, threading: Type::Frame, threads: None, skip_loop_filter: ffi::AVDiscard::AVDISCARD_NONREF },
+            Some(QualityPreset::Full) => Self { lowres: 0, threading: Type::Frame, threads: Some(0), skip_loop_filter: ffi::AVDiscard::AVDISCARD_DEFAULT },
+            None => Self { lowres: 0, threading: Type::Frame, threads: None, skip_loop_filter: ffi::AVDiscard::AVDISCARD_DEFAULT },
+        };
+        let custom = |key: &str| options.custom_options.get(key).map(|x| x.trim());
+        if let Some(lowres) = custom("lowres") {
+            match lowres.parse() {
+                Ok(x) => settings.lowres = x,
+                Err(_) => log::warn!("Invalid lowres {lowres:?}, expected 0-3")
+            }
+        }
+        if let Some(threads) = custom("threads") {
+            match threads {
+                "auto" => settings.threads = Some(0),
+                _ => match threads.parse() {
+                    Ok(x) => settings.threads = Some(x),
+                    Err(_) => log::warn!("Invalid threads {threads:?}, expected a number or auto")
+                }
+            }
+        }
+        if let Some(skip) = custom("skip_loop_filter") {
+            match discard_from_name(skip) {
+                Some(x) => settings.skip_loop_filter = x,
+                None => log::warn!("Invalid skip_loop_filter {skip:?}, expected none, default, nonref, bidir, nonintra, nonkey or all")
+            }
+        }
+        settings
+    }
+
+    fn describe(&self) -> Vec<(String, String)> {
+        let threading = match self.threading {
+            ffmpeg_next::threading::Type::Slice => "slice",
+            _ => "frame"
+        };
+        vec![
+            ("lowres".into(), self.lowres.to_string()),
+            ("threading".into(), threading.into()),
+            ("threads".into(), self.threads.map_or("auto".into(), |x| x.to_string())),
+            ("skip_loop_filter".into(), discard_name(self.skip_loop_filter).into()),
+        ]
+    }
+}
+
+const DISCARD_NAMES: &[(&str, ffi::AVDiscard)] = &[
+    ("none",     ffi::AVDiscard::AVDISCARD_NONE),
+    ("default",  ffi::AVDiscard::AVDISCARD_DEFAULT),
+    ("nonref",   ffi::AVDiscard::AVDISCARD_NONREF),
+    ("bidir",    ffi::AVDiscard::AVDISCARD_BIDIR),
+    ("nonintra", ffi::AVDiscard::AVDISCARD_NONINTRA),
+    ("nonkey",   ffi::AVDiscard::AVDISCARD_NONKEY),
+    ("all",      ffi::AVDiscard::AVDISCARD_ALL),
+];
+fn discard_from_name(name: &str) -> Option<ffi::AVDiscard> {
+    DISCARD_NAMES.iter().find(|x| x.0 == name).map(|x| x.1)
+}
+fn discard_name(discard: ffi::AVDiscard) -> &'static str {
+    DISCARD_NAMES.iter().find(|x| x.1 == discard).map_or("default", |x| x.0)
 }
 
 pub struct FfmpegDecoder {
     context: format::context::Input,
+    // `demuxer` owns the opened input and `context` is from `demux::stream_info`, it can't read or seek even after closing
+    stream_info_only: bool,
+    // Path or description of the input, for reports
+    input: String,
+    // Must be dropped after `context`
+    _custom_io: Option<crate::support::ffmpeg_io::CustomIo>,
+    // Must be dropped after `context`, it's the opaque pointer of its interrupt callback
+    io_deadline: std::sync::Arc<IoDeadline>,
     current_packet: ffmpeg_next::Packet,
+    // Whether `current_packet` was accepted by its decoder. It's kept and resent when the decoder returns EAGAIN
+    packet_sent: bool,
 
     packets_ended: bool,
 
     open_options: DecoderOptions,
 
-    stream_state: Vec<StreamInfo>
+    stream_state: Vec<StreamInfo>,
+
+    video_params_generation: u64,
+    diagnostics: Vec<Diagnostic>,
+    hw_device: Option<HWDeviceInfo>,
+    device_limits: Option<DeviceLimits>,
+    hw_errors: u32,
+    device_recoveries: u32,
+    // GPU of the video decoder, starts at `DecoderOptions::gpu_index` and moves on to the next one after repeated device errors
+    gpu_index: Option<usize>,
+    hw_devices_tried: u32,
+    // `ffmpeg_hw::lost_device_count` when the video decoder last checked for a device lost while downloading a frame
+    lost_devices_seen: usize,
+    // Last video keyframe read, `rewind_after_interrupt` restarts there
+    last_keyframe_us: Option<i64>,
+    // Video frames up to this timestamp were already returned before the device was lost, they are dropped when decoded again
+    skip_video_until_us: Option<i64>,
+    // Last packet read from `context`, reading resumes after it when a read was interrupted
+    last_packet: Option<PacketPosition>,
+    // A read was interrupted at the `set_io_deadline` deadline, the next one seeks back first
+    read_interrupted: bool,
+    // Packets up to this one were already returned before reading was restarted from a keyframe
+    skip_until_packet: Option<PacketPosition>,
+    error: Option<VideoProcessingError>,
+    software_fallback: bool,
+    prefetch_depth: Option<usize>,
+    leases: FrameLeases,
+    demuxer: Option<BackgroundDemuxer>,
+    codec_settings: CodecSettings,
+    // Packets of the audio streams not decoded because of `audio_demux_only`
+    audio_packets: Vec<PacketInfo>,
+    sidecar: Option<SidecarMetadata>,
 }
 
 impl DecoderInterface for FfmpegDecoder {
     fn streams(&mut self) -> Vec<&mut Stream> {
         self.stream_state.iter_mut().map(|x| &mut x.info).collect()
     }
+    fn stream_infos(&self) -> Vec<StreamDescriptor> {
+        self.stream_state.iter().map(|x| x.descriptor.clone()).collect()
+    }
+
+    fn video_params_generation(&self) -> u64 {
+        self.video_params_generation
+    }
+    fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.diagnostics)
+    }
+    fn hw_device(&self) -> Option<HWDeviceInfo> {
+        self.hw_device.clone()
+    }
+    fn backend_config(&self) -> BackendConfig {
+        BackendConfig {
+            backend: "ffmpeg".into(),
+            hw_device: self.hw_device.clone(),
+            device_limits: self.device_limits,
+            quality_preset: self.open_options.quality_preset,
+            settings: [
+                self.codec_settings.describe(),
+                vec![("bit_depth".into(), self.open_options.preferred_bit_depth.map_or("native".into(), |x| x.to_string()))]
+            ].concat(),
+        }
+    }
+    fn set_prefetch_depth(&mut self, depth: usize) {
+        self.prefetch_depth = Some(depth);
+    }
+    fn take_error(&mut self) -> Option<VideoProcessingError> {
+        self.error.take()
+    }
+    fn supported_output_formats(&self) -> Vec<PixelFormat> {
+        crate::frame::validated_formats()
+    }
+    fn take_audio_packets(&mut self) -> Vec<PacketInfo> {
+        std::mem::take(&mut self.audio_packets)
+    }
+    fn set_io_deadline(&mut self, deadline: Option<std::time::Instant>) -> bool {
+        match &mut self.demuxer {
+            Some(demuxer) => demuxer.set_deadline(deadline),
+            None => self.io_deadline.set(deadline)
+        }
+        self.io_deadline.interrupted.swap(false, std::sync::atomic::Ordering::Relaxed)
+    }
+    fn pipeline_report(&self) -> PipelineReport {
+        let name = |x: ffi::AVPixelFormat| format::Pixel::from(x).descriptor().map(|d| d.name().to_string());
+        let video = self.context.streams().best(media::Type::Video).map(|x| &self.stream_state[x.index()]);
+        let ctx = match video.and_then(|x| x.decoder.as_ref()) {
+            Some(OpenedDecoder::Video(decoder)) => Some(unsafe { &*decoder.as_ptr() }),
+            _ => None
+        };
+        // With hwaccel the codec context negotiates the hw format, `sw_pix_fmt` is what gets downloaded
+        let (hw_format, sw_format) = match ctx {
+            Some(ctx) if !ctx.hw_device_ctx.is_null() => (name(ctx.pix_fmt), name(ctx.sw_pix_fmt)),
+            Some(ctx) => (None, name(ctx.pix_fmt)),
+            None => (None, None)
+        };
+        let extension = std::path::Path::new(&self.input).extension().map(|x| x.to_string_lossy().to_string());
+        PipelineReport {
+            input: self.input.clone(),
+            extension,
+            container: self.context.format().name().to_string(),
+            backend: "ffmpeg".into(),
+            video_codec: video.map(|x| x.descriptor.codec_name.clone()),
+            hwaccel: self.hw_device.as_ref().map(|x| x.backend.clone()),
+            hw_device_name: self.hw_device.as_ref().and_then(|x| x.device_name.clone()),
+            hw_format,
+            sw_format,
+            software_fallback: self.software_fallback,
+            decode_size: video.and_then(|x| x.last_video_params).map(|p| (p.width, p.height)),
+            output_format: video.and_then(|x| x.format_converter.as_ref()).and_then(|c| format::Pixel::from(c.0.output().format).descriptor()).map(|d| d.name().to_string()),
+            frame_threads: ctx.map(|x| x.thread_count as usize),
+            prefetch_depth: self.prefetch_depth,
+            max_outstanding_frames: None,
+            buffered_frames: self.buffered_frames(),
+            versions: crate::runtime_versions(),
+        }
+    }
+    fn frame_leases(&self) -> FrameLeases {
+        self.leases.clone()
+    }
+    fn buffered_frames(&self) -> usize {
+        self.stream_state.iter().map(|x| x.reorder.frames.len()).sum()
+    }
+    fn pool_frames(&self) -> usize {
+        self.stream_state.iter().filter_map(|x| match &x.decoder { Some(OpenedDecoder::Video(decoder)) => Some(decoder), _ => None }).map(|decoder| unsafe {
+            let ctx = decoder.as_ptr();
+            // Every frame thread keeps the frame it's decoding
+            let threads = if (*ctx).active_thread_type & ffi::FF_THREAD_FRAME as i32 != 0 { (*ctx).thread_count.max(1) - 1 } else { 0 };
+            ((*ctx).refs.max(0) + (*ctx).has_b_frames.max(0) + threads) as usize
+        }).sum()
+    }
+
+    fn unknown_options(&self) -> Vec<(String, Option<String>)> {
+        use std::ffi::{ CStr, CString };
+        let ctx = unsafe { self.context.as_ptr() as *mut std::ffi::c_void };
+        let mut known: Vec<String> = RECOGNIZED_OPTIONS.iter().map(|x| x.to_string()).collect();
+        unsafe {
+            // Options of the format context and the demuxer
+            let classes = [(*self.context.as_ptr()).av_class, (*(*self.context.as_ptr()).iformat).priv_class];
+            for class in classes.into_iter().filter(|x| !x.is_null()) {
+                // av_opt_next expects an object starting with the class pointer
+                let class_ptr = &class as *const _ as *const std::ffi::c_void;
+                let mut opt = std::ptr::null();
+                loop {
+                    opt = ffi::av_opt_next(class_ptr, opt);
+                    if opt.is_null() { break; }
+                    known.push(CStr::from_ptr((*opt).name).to_string_lossy().into_owned());
+                }
+            }
+        }
+        self.open_options.custom_options.keys().filter(|key| {
+            if known.contains(key) { return false; }
+            // Also covers the options of the children, e.g. the protocol
+            let Ok(name) = CString::new(key.as_str()) else { return true; };
+            unsafe { ffi::av_opt_find(ctx, name.as_ptr(), std::ptr::null(), 0, ffi::AV_OPT_SEARCH_CHILDREN as i32).is_null() }
+        }).map(|key| {
+            let candidates: Vec<&str> = known.iter().map(String::as_str).collect();
+            (key.clone(), crate::util::did_you_mean(key, &candidates).map(str::to_owned))
+        }).collect()
+    }
+
+    fn close(&mut self) {
+        for state in &mut self.stream_state {
+            state.reorder.clear();
+            state.format_converter = None;
+            state.look_graph = None;
+            // Dropping the codec context releases its hw frames pool and device reference.
+            // Frames still held by the consumer keep their own references until dropped
+            match state.decoder.take() {
+                Some(OpenedDecoder::Video(mut decoder)) => decoder.flush(),
+                Some(OpenedDecoder::Audio(mut decoder)) => decoder.flush(),
+                None => { }
+            }
+        }
+        self.current_packet = ffmpeg_next::Packet::empty();
+        self.packet_sent = false;
+        self.packets_ended = true;
+        self.hw_device = None;
+        self.demuxer = None;
+    }
 
     fn seek(&mut self, timestamp_us: i64) -> bool {
         let position = timestamp_us.rescale((1, 1000000), rescale::TIME_BASE);
-        if let Err(e) = self.context.seek(position, ..position) {
+        if let Some(demuxer) = &mut self.demuxer {
+            if !demuxer.seek(position) { return false; }
+        } else if self.stream_info_only {
+            return false;
+        } else if let Err(e) = self.context.seek(position, ..position) {
             log::error!("Failed to seek {:?}", e);
             return false;
         }
+        self.flush_after_seek();
         true
     }
 
+    fn reset_to_start(&mut self) -> bool {
+        // Seeking to 0 can land on a later keyframe when the streams start later (e.g. MPEG-TS), anything up to the start time is fine here
+        let start = self.start_time_us();
+        if let Some(demuxer) = &mut self.demuxer {
+            if !demuxer.seek(start) { return false; }
+        } else if self.stream_info_only {
+            return false;
+        } else if let Err(e) = self.context.seek(start, ..start) {
+            // Raw streams without timestamps can still go back to the beginning of the file
+            log::debug!("Failed to seek to the start time {start}: {e:?}, seeking to the first byte");
+            let ret = unsafe { ffi::av_seek_frame(self.context.as_mut_ptr(), -1, 0, ffi::AVSEEK_FLAG_BYTE as i32) };
+            if ret < 0 {
+                log::error!("Failed to seek to the start: {:?}", ffmpeg_next::Error::from(ret));
+                return false;
+            }
+        }
+        self.flush_after_seek();
+        true
+    }
+
+    fn start_time_us(&self) -> i64 {
+        match unsafe { (*self.context.as_ptr()).start_time } {
+            ffi::AV_NOPTS_VALUE => 0,
+            x => x
+        }
+    }
+    fn seek_to_keyframe(&mut self, timestamp_us: i64) -> Option<i64> {
+        let stream_index = self.context.streams().best(media::Type::Video)?.index();
+        if !self.seek(timestamp_us) { return None; }
+        // Read up to the keyframe and keep it as the next packet to decode
+        loop {
+            match self.read_packet() {
+                Ok(packet) => {
+                    self.current_packet = packet;
+                    if self.current_packet.stream() != stream_index || !self.current_packet.is_key() { continue; }
+                    let time_base = self.context.stream(stream_index)?.time_base();
+                    self.current_packet.rescale_ts(time_base, (1, 1000000)); // rescale to microseconds
+                    self.packet_sent = false;
+                    return self.current_packet.pts().or(self.current_packet.dts());
+                },
+                Err(e) => {
+                    if e != ffmpeg_next::Error::Eof { log::error!("Failed to read packet: {e:?}"); }
+                    self.current_packet = ffmpeg_next::Packet::empty();
+                    return None;
+                }
+            }
+        }
+    }
+
+    fn next_packet(&mut self) -> Option<PacketInfo> {
+        match self.read_packet() {
+            Ok(mut packet) => {
+                let stream_index = packet.stream();
+                if let Some(stream) = self.context.stream(stream_index) {
+                    packet.rescale_ts(stream.time_base(), (1, 1000000)); // rescale to microseconds
+                }
+                let parameter_sets_changed = self.stream_state.get_mut(stream_index).is_some_and(|x| x.check_parameter_sets(&packet));
+                if parameter_sets_changed {
+                    self.diagnostics.push(Diagnostic::ParameterSetsChanged { stream_index, timestamp_us: packet.pts() });
+                }
+                Some(PacketInfo {
+                    stream_index,
+                    pts_us: packet.pts(),
+                    dts_us: packet.dts(),
+                    duration_us: if packet.duration() > 0 { Some(packet.duration()) } else { None },
+                    size: packet.size(),
+                    is_key: packet.is_key(),
+                    parameter_sets_changed,
+                })
+            },
+            Err(ffmpeg_next::Error::Eof) => None,
+            Err(e) => {
+                log::error!("Failed to read packet: {e:?}");
+                None
+            }
+        }
+    }
+
+    fn chapters(&self) -> Vec<Chapter> {
+        self.context.chapters().map(|ch| {
+            let tb = ch.time_base();
+            Chapter {
+                start_us: ch.start().rescale(tb, (1, 1000000)),
+                end_us: ch.end().rescale(tb, (1, 1000000)),
+                title: ch.metadata().get("title").map(str::to_owned),
+            }
+        }).collect()
+    }
+
     fn get_video_info(&self) -> Result<VideoInfo, VideoProcessingError> {
         if let Some(stream) = self.context.streams().best(media::Type::Video) {
-            let codec = codec::context::Context::from_parameters(stream.parameters())?;
-            if let Ok(video) = codec.decoder().video() {
-                let mut bitrate = video.bit_rate();
-                if bitrate == 0 { bitrate = self.context.bit_rate() as usize; }
-
-                let mut frames = stream.frames() as usize;
-                if frames == 0 { frames = (stream.duration() as f64 * f64::from(stream.time_base()) * f64::from(stream.rate())) as usize; }
-
-                return Ok(VideoInfo {
-                    duration_ms: stream.duration() as f64 * f64::from(stream.time_base()) * 1000.0,
-                    frame_count: frames,
-                    fps: f64::from(stream.rate()), // or avg_frame_rate?
-                    width: video.width(),
-                    height: video.height(),
-                    bitrate: bitrate as f64 / 1024.0 / 1024.0,
+            // Read directly from the codec parameters, opening a decoder just for this is expensive
+            let par = unsafe { &*stream.parameters().as_ptr() };
+
+            let mut bitrate = par.bit_rate as usize;
+            if bitrate == 0 { bitrate = self.context.bit_rate() as usize; }
+
+            let mut frames = stream.frames() as usize;
+            if frames == 0 { frames = (stream.duration() as f64 * f64::from(stream.time_base()) * f64::from(stream.rate())) as usize; }
+
+            // The stream could have been reconfigured mid-file, prefer the last decoded size
+            let (width, height) = match self.stream_state[stream.index()].last_video_params {
+                Some(p) => (p.width, p.height),
+                None => (par.width as u32, par.height as u32)
+            };
+
+            let (rotation, mirrored) = stream.side_data()
+                .find(|sd| sd.kind() == ffmpeg_next::packet::side_data::Type::DisplayMatrix && sd.data().len() >= 9 * 4)
+                .map(|sd| unsafe { crate::frame::display_orientation(sd.data().as_ptr() as *const i32) })
+                .unwrap_or_default();
+            let (display_width, display_height) = if rotation % 180 == 90 { (height, width) } else { (width, height) };
+            let dolby_vision = stream.side_data()
+                .find(|sd| ffi::AVPacketSideDataType::from(sd.kind()) == ffi::AVPacketSideDataType::AV_PKT_DATA_DOVI_CONF && sd.data().len() >= std::mem::size_of::<ffi::AVDOVIDecoderConfigurationRecord>())
+                .map(|sd| {
+                    let record = unsafe { &*(sd.data().as_ptr() as *const ffi::AVDOVIDecoderConfigurationRecord) };
+                    DolbyVisionConfig {
+                        profile: record.dv_profile,
+                        level: record.dv_level,
+                        rpu_present: record.rpu_present_flag != 0,
+                        el_present: record.el_present_flag != 0,
+                        bl_present: record.bl_present_flag != 0,
+                        bl_compatibility_id: record.dv_bl_signal_compatibility_id,
+                    }
                 });
+
+            // Stream tags take precedence over the container ones
+            let mut metadata = std::collections::HashMap::new();
+            for (k, v) in self.context.metadata().iter().chain(stream.metadata().iter()) {
+                metadata.insert(k.to_string(), v.to_string());
+            }
+            if let Some(sidecar) = &self.sidecar {
+                for (k, v) in &sidecar.values {
+                    metadata.entry(k.clone()).or_insert_with(|| v.clone());
+                }
+                if let Some(timecode) = &sidecar.timecode {
+                    metadata.entry("timecode".into()).or_insert_with(|| timecode.clone());
+                }
             }
+            // Clips edited on iPhones can lose `creation_time` and keep only the QuickTime tag, which has the local time zone
+            let created_at = ["creation_time", "com.apple.quicktime.creationdate"].iter()
+                .filter_map(|key| metadata.get(*key).map(|x| (key, x)))
+                .find_map(|(key, x)| {
+                    let parsed = crate::util::parse_creation_time(x);
+                    if parsed.is_none() { log::warn!("Unrecognized {key} format: {x}"); }
+                    parsed
+                });
+
+            return Ok(VideoInfo {
+                duration_ms: stream.duration() as f64 * f64::from(stream.time_base()) * 1000.0,
+                frame_count: frames,
+                fps: f64::from(stream.rate()), // or avg_frame_rate?
+                width,
+                height,
+                rotation,
+                mirrored,
+                display_width,
+                display_height,
+                bitrate: bitrate as f64 / 1024.0 / 1024.0,
+                created_at,
+                metadata,
+                color_space: crate::frame::color_space_from_ffmpeg(par.color_space.into()),
+                color_trc: crate::frame::color_trc_from_ffmpeg(par.color_trc.into()).or_else(|| self.sidecar.as_ref().and_then(|x| x.color_trc)),
+                color_primaries: crate::frame::color_primaries_from_ffmpeg(par.color_primaries.into()).or_else(|| self.sidecar.as_ref().and_then(|x| x.color_primaries)),
+                color_range: crate::frame::color_range_from_ffmpeg(par.color_range.into()),
+                dolby_vision,
+            });
+        }
+        Err(VideoProcessingError::NoVideoStream)
+    }
+
+    fn get_audio_info(&self) -> Result<AudioInfo, VideoProcessingError> {
+        let stream = self.context.streams().best(media::Type::Audio).ok_or(VideoProcessingError::AudioStreamNotFound)?;
+        let par = unsafe { &*stream.parameters().as_ptr() };
+        let mut duration_ms = stream.duration() as f64 * f64::from(stream.time_base()) * 1000.0;
+        if stream.duration() <= 0 && self.context.duration() > 0 {
+            duration_ms = self.context.duration() as f64 / 1000.0; // AV_TIME_BASE is microseconds
         }
-        Err(ffmpeg_next::Error::StreamNotFound.into())
+        Ok(AudioInfo {
+            duration_ms,
+            codec: stream.parameters().id().name().to_string(),
+            sample_rate: par.sample_rate as u32,
+            channels: par.ch_layout.nb_channels as u32,
+            bitrate: par.bit_rate as f64 / 1024.0 / 1024.0,
+        })
     }
 
     fn next_frame(&mut self) -> Option<Frame> {
+        loop {
+            if let DecodeStep::Done(frame) = self.decode_step() {
+                return frame;
+            }
+        }
+    }
+}
+
+impl FfmpegDecoder {
+    /// Reads, sends and receives once. `Again` when nothing was returned yet but decoding should continue,
+    /// e.g. a skipped packet or a frame held back for reordering
+    fn decode_step(&mut self) -> DecodeStep {
         let fetch_new_packet = unsafe { self.current_packet.is_empty() };
         if fetch_new_packet && !self.packets_ended {
+            self.update_discard();
+            profile_span!("packet_read");
+            let mut errors = 0;
             loop {
-                match self.current_packet.read(&mut self.context) {
-                    Ok(..) => { break; },
-                    Err(ffmpeg_next::Error::Eof) => {
-                        self.packets_ended = true;
-                        for state in &mut self.stream_state {
-                            match &mut state.decoder {
-                                Some(OpenedDecoder::Video(decoder)) => decoder.send_eof().unwrap(),
-                                Some(OpenedDecoder::Audio(decoder)) => decoder.send_eof().unwrap(),
-                                _ => { }
-                            }
+                match self.read_packet() {
+                    Ok(packet) => {
+                        self.current_packet = packet;
+                        let index = self.current_packet.stream();
+                        if let Some(stream) = self.context.stream(index) {
+                            self.current_packet.rescale_ts(stream.time_base(), (1, 1000000)); // rescale to microseconds
+                        }
+                        if self.current_packet.is_key() && self.stream_state.get(index).is_some_and(|x| matches!(x.info.stream_type, StreamType::Video)) {
+                            self.last_keyframe_us = self.current_packet.pts().or(self.current_packet.dts());
                         }
+                        if self.stream_state.get_mut(index).is_some_and(|x| x.check_parameter_sets(&self.current_packet)) {
+                            self.diagnostics.push(Diagnostic::ParameterSetsChanged { stream_index: index, timestamp_us: self.current_packet.pts() });
+                        }
+                        self.packet_sent = false;
+                        break;
+                    },
+                    // Interrupted at the `set_io_deadline` deadline, the next call continues after the last packet read
+                    Err(ffmpeg_next::Error::Exit) => return DecodeStep::Done(None),
+                    Err(ffmpeg_next::Error::Eof) => {
+                        self.end_packets();
                         break;
                     },
-                    Err(e) => { println!("other err {e:?}"); },
+                    Err(e) => {
+                        log::error!("Failed to read packet: {e:?}");
+                        errors += 1;
+                        // Don't spin forever on a broken input
+                        if errors >= 100 {
+                            self.end_packets();
+                            break;
+                        }
+                    },
                 }
             }
         }
 
+        if self.packets_ended && unsafe { self.current_packet.is_empty() } {
+            return DecodeStep::Done(self.drain_decoders());
+        }
+
         let stream = unsafe { ffmpeg_next::Stream::wrap(&self.context, self.current_packet.stream()) };
 
         let state = &mut self.stream_state[stream.index()];
 
-        if state.info.decode && state.decoder.is_none() {
+        let demux_only = self.open_options.audio_demux_only && matches!(state.info.stream_type, StreamType::Audio);
+        if state.info.decode && state.decoder.is_none() && !demux_only {
             let mut ctx = codec::context::Context::from_parameters(stream.parameters()).unwrap();
             state.decoder = match stream.parameters().medium() {
                 media::Type::Video => {
-                    ctx.set_threading(ffmpeg_next::threading::Config { kind: ffmpeg_next::threading::Type::Frame, count: 3 });
+                    let settings = self.codec_settings;
+                    let threads = if self.open_options.deterministic { 1 } else { settings.threads.unwrap_or_else(|| self.prefetch_depth.unwrap_or(3).clamp(1, 3)) };
+                    ctx.set_threading(ffmpeg_next::threading::Config { kind: settings.threading, count: threads });
+                    unsafe { (*ctx.as_mut_ptr()).skip_loop_filter = settings.skip_loop_filter; }
+                    if self.open_options.keyframes_only {
+                        unsafe { (*ctx.as_mut_ptr()).skip_frame = ffi::AVDiscard::AVDISCARD_NONKEY; }
+                    }
+                    if self.open_options.export_motion_vectors {
+                        unsafe { (*ctx.as_mut_ptr()).flags2 |= ffi::AV_CODEC_FLAG2_EXPORT_MVS as i32; }
+                    }
 
-                    // let mut hw_backend = String::new();
                     let mut codec = ffmpeg_next::decoder::find(ctx.id()).unwrap();
 
-                    if let Some(gpu_index) = self.open_options.gpu_index {
+                    let use_hw = !self.software_fallback && !self.open_options.deterministic && !self.open_options.export_motion_vectors;
+                    if let (Some(gpu_index), true) = (self.gpu_index, use_hw) {
                         let hwaccel_device = self.open_options.custom_options.get("hwaccel_device").cloned();
 
                         let hw = crate::support::ffmpeg_hw::init_device_for_decoding(gpu_index, unsafe { codec.as_mut_ptr() }, &mut ctx, hwaccel_device.as_deref()).unwrap();
-                        log::debug!("Selected HW backend {:?} ({}) with format {:?}", hw.1, hw.2, hw.3);
-                        // hw_backend = hw.2;
+                        log::debug!("Selected HW backend {:?} ({}) on device {:?} with format {:?}", hw.device_type, hw.backend, hw.device_name, hw.pix_fmt);
+                        let limits = DeviceLimits {
+                            min_size: (hw.min_size.0.max(0) as u32, hw.min_size.1.max(0) as u32),
+                            max_size: (hw.max_size.0.max(0) as u32, hw.max_size.1.max(0) as u32),
+                        };
+                        let size = unsafe { ((*ctx.as_ptr()).width as u32, (*ctx.as_ptr()).height as u32) };
+                        match (hw.pix_fmt, limits.check(size)) {
+                            (Some(pix_fmt), Ok(())) => {
+                                crate::support::ffmpeg_hw::set_preferred_hw_format(&mut ctx, pix_fmt);
+                                self.hw_device = Some(HWDeviceInfo { backend: hw.backend, device_name: hw.device_name });
+                                self.device_limits = Some(limits);
+                            },
+                            (Some(_), Err(e)) => {
+                                // Checked up front, the driver would otherwise fail with an unhelpful error on the first frame
+                                log::warn!("{e}, decoding in software");
+                                unsafe { ffi::av_buffer_unref(&mut (*ctx.as_mut_ptr()).hw_device_ctx); }
+                                self.diagnostics.push(Diagnostic::HardwareFallback { reason: e.to_string() });
+                            },
+                            (None, _) => { }
+                        }
+                    }
+
+                    if settings.lowres > 0 && self.hw_device.is_none() {
+                        let max_lowres = unsafe { (*codec.as_ptr()).max_lowres as i32 };
+                        unsafe { (*ctx.as_mut_ptr()).lowres = settings.lowres.min(max_lowres); }
                     }
 
                     Some(OpenedDecoder::Video(ctx.decoder().open_as(codec).and_then(|o| o.video()).unwrap()))
@@ -115,64 +874,500 @@ impl DecoderInterface for FfmpegDecoder {
             };
         }
 
+        let is_video = matches!(state.decoder, Some(OpenedDecoder::Video(_)));
+        let is_hw_video = self.hw_device.is_some() && is_video;
         let mut decoder = match state.decoder.as_mut() {
             Some(OpenedDecoder::Video(decoder)) => Some(&mut decoder.0),
             Some(OpenedDecoder::Audio(decoder)) => Some(&mut decoder.0),
             _ => None
         };
         if let Some(decoder) = decoder {
-            if fetch_new_packet && !self.packets_ended {
-                self.current_packet.rescale_ts(stream.time_base(), (1, 1000000)); // rescale to microseconds
-
-                if let Err(e) = decoder.send_packet(&self.current_packet) {
-                    log::error!("Decode error: {:?}", e);
-                    return None;
+            let mut decoder_full = false;
+            if !self.packet_sent && !self.packets_ended {
+                profile_span!("send_packet", stream = stream.index(), timestamp_us = self.current_packet.pts());
+                if is_hw_video && unsafe { crate::support::ffmpeg_hw::device_lost_since(&mut self.lost_devices_seen, (*decoder.as_ptr()).hw_device_ctx) } {
+                    let index = stream.index();
+                    return self.recover_device(index, "lost while downloading a frame".into());
+                }
+                // Always `None` outside of tests
+                let injected = crate::support::ffmpeg_hw::injected_failure().filter(|_| is_video);
+                let sent = match injected {
+                    Some(err) => Err(ffmpeg_next::Error::from(err)),
+                    None => decoder.send_packet(&self.current_packet)
+                };
+                match sent {
+                    Ok(_) => {
+                        self.packet_sent = true;
+                        self.hw_errors = 0;
+                    },
+                    // Decoder has frames pending, drain them first and resend this packet afterwards
+                    Err(ffmpeg_next::Error::Other { errno: ffmpeg_next::error::EAGAIN }) => { decoder_full = true; },
+                    Err(e) if (is_hw_video || injected.is_some()) && unsafe { crate::support::ffmpeg_hw::is_device_lost((*decoder.as_ptr()).hw_device_ctx, i32::from(e)) } => {
+                        let index = stream.index();
+                        return self.recover_device(index, format!("{e:?}"));
+                    },
+                    Err(e) if (is_hw_video || injected.is_some()) && is_hw_device_error(e) => {
+                        // GPU failures are often transient (e.g. a shared GPU running out of memory), so the packet is kept and resent
+                        // after giving the device a moment to recover
+                        self.hw_errors += 1;
+                        log::warn!("Hardware decode error ({}/{}): {:?}", self.hw_errors, MAX_HW_RETRIES, e);
+                        if self.hw_errors > MAX_HW_RETRIES {
+                            let index = stream.index();
+                            return self.switch_device(index, format!("{e:?}"));
+                        }
+                        std::thread::sleep(hw_retry_backoff(self.hw_errors));
+                        return DecodeStep::Again;
+                    },
+                    Err(e) => {
+                        log::error!("Decode error: {:?}", e);
+                        self.current_packet = ffmpeg_next::Packet::empty();
+                        return DecodeStep::Again;
+                    }
                 }
             }
             let mut frame = unsafe { ffmpeg_next::Frame::empty() };
-            if let Err(e) = decoder.receive_frame(&mut frame) {
-                self.current_packet = ffmpeg_next::Packet::empty();
-                if self.packets_ended { return None; }
-                return self.next_frame();
+            let received = {
+                profile_span!("receive_frame", stream = stream.index());
+                decoder.receive_frame(&mut frame)
+            };
+            if let Err(e) = received {
+                if self.packets_ended { return DecodeStep::Done(None); }
+                if decoder_full {
+                    // Nothing to drain but the packet still wasn't accepted, drop it instead of looping forever
+                    log::error!("Decoder refused packet and has no frames to output: {:?}", e);
+                    self.current_packet = ffmpeg_next::Packet::empty();
+                } else if self.packet_sent {
+                    // Current packet fully consumed
+                    self.current_packet = ffmpeg_next::Packet::empty();
+                }
+                return DecodeStep::Again;
             }
 
             match stream.parameters().medium() {
                 media::Type::Video => {
-                    Some(Frame::Video(FfmpegVideoFrame { avframe: frame::Video::from(frame), swframe: None }.into()))
+                    let index = stream.index();
+                    match self.video_frame(index, frame) {
+                        Some(frame) => DecodeStep::Done(Some(frame)),
+                        // Held back for reordering, decode more
+                        None => DecodeStep::Again
+                    }
                 },
                 media::Type::Audio => {
-                    Some(Frame::Audio(FfmpegAudioFrame { avframe: frame::Audio::from(frame) }.into()))
+                    match self.audio_frame(stream.index(), frame) {
+                        Some(frame) => DecodeStep::Done(Some(frame)),
+                        // Returned before the device was lost
+                        None => DecodeStep::Again
+                    }
                 },
                 // media::Type::Subtitle => {
                 //     Some(Frame::Subtitle(FfmpegSubtitleFrame {  }.into()))
                 // },
                 _ => {
                     self.current_packet = ffmpeg_next::Packet::empty();
-                    Some(Frame::Other)
+                    DecodeStep::Done(Some(Frame::Other))
                 }
             }
         } else {
+            if demux_only && !self.packets_ended {
+                let packet = &self.current_packet;
+                self.audio_packets.push(PacketInfo {
+                    stream_index: stream.index(),
+                    pts_us: packet.pts(),
+                    dts_us: packet.dts(),
+                    duration_us: if packet.duration() > 0 { Some(packet.duration()) } else { None },
+                    size: packet.size(),
+                    is_key: packet.is_key(),
+                    parameter_sets_changed: false,
+                });
+            }
             self.current_packet = ffmpeg_next::Packet::empty();
-            if self.packets_ended { return None; }
-            Some(Frame::Other)
+            if self.packets_ended { return DecodeStep::Done(None); }
+            DecodeStep::Done(Some(Frame::Other))
         }
     }
-}
 
-impl FfmpegDecoder {
-    pub fn new(mut path: &str, options: DecoderOptions) -> Result<Self, VideoProcessingError> {
-        ffmpeg_next::init()?;
+    #[cfg(feature = "ffmpeg-raw")]
+    pub(crate) fn input(&self) -> &format::context::Input {
+        &self.context
+    }
+
+    /// Streams with `decode` turned off aren't demuxed at all, so e.g. audio extraction doesn't read the video packets
+    fn update_discard(&mut self) {
+        for (i, state) in self.stream_state.iter_mut().enumerate() {
+            if state.discarded == state.info.decode { continue; }
+            state.discarded = !state.info.decode;
+            let discard = match (state.discarded, state.info.stream_type) {
+                (true, _) => ffi::AVDiscard::AVDISCARD_ALL,
+                (false, StreamType::Video) if self.open_options.keyframes_only => ffi::AVDiscard::AVDISCARD_NONKEY,
+                (false, _) => ffi::AVDiscard::AVDISCARD_DEFAULT,
+            };
+            if let Some(mut stream) = self.context.stream_mut(i) {
+                unsafe { (*stream.as_mut_ptr()).discard = discard; }
+            }
+            if let Some(demuxer) = &self.demuxer {
+                demuxer.set_discard(i, discard);
+            }
+        }
+    }
+
+    /// Reopens the video decoder on a fresh device after the GPU was lost (e.g. a driver timeout) and resumes from the last keyframe.
+    /// Gives up with `GPUDecodingFailed` after `max_device_recoveries` attempts
+    fn recover_device(&mut self, index: usize, reason: String) -> DecodeStep {
+        if let Some(OpenedDecoder::Video(decoder)) = self.stream_state[index].decoder.take() {
+            unsafe { crate::support::ffmpeg_hw::invalidate_device((*decoder.as_ptr()).hw_device_ctx); }
+        }
+        self.hw_device = None;
+        self.hw_errors = 0;
+        self.device_recoveries += 1;
+        let max = self.open_options.max_device_recoveries.unwrap_or(DEFAULT_DEVICE_RECOVERIES);
+        self.diagnostics.push(Diagnostic::DeviceLost { attempt: self.device_recoveries, max_attempts: max, reason: reason.clone() });
+        if self.device_recoveries > max {
+            log::error!("GPU device lost {} times, giving up: {reason}", self.device_recoveries);
+            self.error = Some(VideoProcessingError::GPUDecodingFailed);
+            self.close();
+            return DecodeStep::Done(None);
+        }
+        log::warn!("GPU device lost ({}/{max}): {reason}, reopening", self.device_recoveries);
+        self.resume_from_keyframe(index)
+    }
+
+    /// Reopens the video decoder on the next GPU after repeated hardware decode errors, or in software when
+    /// `MAX_HW_DEVICES` were tried or the device was named explicitly with `hwaccel_device`
+    fn switch_device(&mut self, index: usize, reason: String) -> DecodeStep {
+        self.stream_state[index].decoder = None;
+        self.hw_device = None;
+        self.hw_errors = 0;
+        self.hw_devices_tried += 1;
+        let named = self.open_options.custom_options.contains_key("hwaccel_device");
+        match self.gpu_index.filter(|_| !named && self.hw_devices_tried < MAX_HW_DEVICES) {
+            Some(gpu_index) => {
+                // A GPU index past the last one opens no device and decodes in software
+                log::warn!("Hardware decoding on GPU {gpu_index} keeps failing: {reason}, trying GPU {}", gpu_index + 1);
+                self.gpu_index = Some(gpu_index + 1);
+            },
+            None => {
+                log::warn!("Hardware decoding keeps failing: {reason}, decoding in software");
+                self.software_fallback = true;
+                self.diagnostics.push(Diagnostic::HardwareFallback { reason });
+            }
+        }
+        self.resume_from_keyframe(index)
+    }
+
+    /// Restarts decoding at the keyframe before the earliest of the last returned video and audio frames, so a reopened decoder
+    /// doesn't start in the middle of a GOP and the frames still inside the old one aren't lost.
+    /// The frames already returned are dropped when they are decoded again
+    fn resume_from_keyframe(&mut self, index: usize) -> DecodeStep {
+        let last_video = self.stream_state[index].reorder.last_timestamp_us;
+        let last_audio: Vec<Option<i64>> = self.stream_state.iter().map(|x| x.last_audio_us).collect();
+        let resume_us = last_audio.iter().flatten().copied().chain(last_video).min().unwrap_or_else(|| self.start_time_us());
+        if !self.seek(resume_us) {
+            self.error = Some(VideoProcessingError::GPUDecodingFailed);
+            return DecodeStep::Done(None);
+        }
+        self.skip_video_until_us = last_video;
+        for (state, last) in self.stream_state.iter_mut().zip(last_audio) {
+            state.skip_audio_until_us = last;
+        }
+        DecodeStep::Again
+    }
+
+    /// Next packet from the demux thread when `background_demux` is enabled, from the input context otherwise
+    fn read_packet(&mut self) -> Result<ffmpeg_next::Packet, ffmpeg_next::Error> {
+        if let Some(demuxer) = &mut self.demuxer {
+            let packet = demuxer.read();
+            if matches!(packet, Err(ffmpeg_next::Error::Exit)) {
+                self.io_deadline.interrupted.store(true, std::sync::atomic::Ordering::Relaxed);
+            }
+            return packet;
+        }
+        if self.stream_info_only {
+            return Err(ffmpeg_next::Error::Eof);
+        }
+        if std::mem::take(&mut self.read_interrupted) {
+            self.rewind_after_interrupt();
+        }
+        loop {
+            let mut packet = ffmpeg_next::Packet::empty();
+            let result = packet.read(&mut self.context);
+            // A packet read while the callback interrupted the IO is possibly truncated, it's read again by the next call
+            if matches!(result, Err(ffmpeg_next::Error::Exit)) || self.io_deadline.interrupted.load(std::sync::atomic::Ordering::Relaxed) {
+                unsafe {
+                    // The IO context stays at EOF after a failed read otherwise, and the next read would end the input
+                    let pb = (*self.context.as_mut_ptr()).pb;
+                    if !pb.is_null() {
+                        (*pb).eof_reached = 0;
+                        (*pb).error = 0;
+                    }
+                }
+                self.read_interrupted = true;
+                return Err(ffmpeg_next::Error::Exit);
+            }
+            result?;
+            let position = PacketPosition::of(&packet);
+            if let Some(last) = self.skip_until_packet {
+                if position == last || position.is_before(&last) {
+                    if position == last { self.skip_until_packet = None; }
+                    continue;
+                }
+                self.skip_until_packet = None;
+            }
+            self.last_packet = Some(position);
+            return Ok(packet);
+        }
+    }
+
+    /// An interrupted read can lose the packet it was reading (e.g. the MP4 demuxer moves on to the next sample),
+    /// so reading restarts from the last keyframe and skips the packets up to the last one returned
+    fn rewind_after_interrupt(&mut self) {
+        let Some(last) = self.last_packet else { return; };
+        let time_base = self.context.stream(last.stream).map_or(Rational(1, 1000000), |x| x.time_base());
+        let last_us = last.dts.map(|x| x.rescale(time_base, (1, 1000000)));
+        let position = self.last_keyframe_us.or(last_us).unwrap_or(0).rescale((1, 1000000), rescale::TIME_BASE);
+        match self.context.seek(position, ..position) {
+            Ok(()) => self.skip_until_packet = Some(last),
+            Err(e) => log::warn!("Failed to seek back after an interrupted read, a packet may be lost: {e:?}")
+        }
+    }
+
+    /// Returns the frames left in the decoders after the end of input, `None` once all are drained
+    /// Marks the input as finished and flushes the decoders so the buffered frames can still be drained
+    fn end_packets(&mut self) {
+        self.packets_ended = true;
+        for state in &mut self.stream_state {
+            let result = match &mut state.decoder {
+                Some(OpenedDecoder::Video(decoder)) => decoder.send_eof(),
+                Some(OpenedDecoder::Audio(decoder)) => decoder.send_eof(),
+                _ => Ok(())
+            };
+            if let Err(e) = result { log::error!("Failed to flush decoder: {e:?}"); }
+        }
+    }
+
+    fn drain_decoders(&mut self) -> Option<Frame> {
+        for index in 0..self.stream_state.len() {
+            loop {
+                let mut frame = unsafe { ffmpeg_next::Frame::empty() };
+                let received = match self.stream_state[index].decoder.as_mut() {
+                    Some(OpenedDecoder::Video(decoder)) => decoder.0.receive_frame(&mut frame).is_ok().then_some(true),
+                    Some(OpenedDecoder::Audio(decoder)) => decoder.0.receive_frame(&mut frame).is_ok().then_some(false),
+                    None => None
+                };
+                match received {
+                    Some(true) => if let Some(frame) = self.video_frame(index, frame) { return Some(frame); },
+                    Some(false) => if let Some(frame) = self.audio_frame(index, frame) { return Some(frame); },
+                    None => break
+                }
+            }
+        }
+        self.stream_state.iter_mut().find_map(|state| state.reorder.pop()).map(|frame| Frame::Video(frame.into()))
+    }
+
+    /// Wraps a decoded audio frame. The decoder already dropped the priming samples signalled by the `skip_samples` side data,
+    /// so the position is counted from the first audible sample. `None` for frames already returned before a lost device
+    fn audio_frame(&mut self, index: usize, frame: ffmpeg_next::Frame) -> Option<Frame> {
+        let avframe = frame::Audio::from(frame);
+        let state = &mut self.stream_state[index];
+        if let (Some(skip), Some(ts)) = (state.skip_audio_until_us, avframe.timestamp()) {
+            if ts <= skip { return None; }
+            state.skip_audio_until_us = None;
+        }
+        state.last_audio_us = avframe.timestamp().or(state.last_audio_us);
+        let rate = avframe.rate() as i64;
+        let start_us = state.start_time_us;
+        let sample_position = avframe.timestamp().filter(|_| rate > 0).map(|ts| ((ts - start_us) * rate + 500_000).div_euclid(1_000_000));
+        Some(Frame::Audio(FfmpegAudioFrame { avframe, sample_position }.into()))
+    }
+
+    /// Drops every packet and frame of the old position after the input was seeked
+    fn flush_after_seek(&mut self) {
+        self.current_packet = ffmpeg_next::Packet::empty();
+        self.packet_sent = false;
+        self.packets_ended = false;
+        self.skip_video_until_us = None;
+        self.last_packet = None;
+        self.read_interrupted = false;
+        self.skip_until_packet = None;
+        self.audio_packets.clear();
+        // Frames queued in the decoders belong to the old position
+        for state in &mut self.stream_state {
+            state.reorder.clear();
+            state.last_audio_us = None;
+            state.skip_audio_until_us = None;
+            match &mut state.decoder {
+                Some(OpenedDecoder::Video(decoder)) => decoder.flush(),
+                Some(OpenedDecoder::Audio(decoder)) => decoder.flush(),
+                None => { }
+            }
+        }
+    }
 
-        let mut options_avdict = Dictionary::new();
-        for (k, v) in &options.custom_options { options_avdict.set(&k, &v); }
-        if path.starts_with("fd:") {
-            options_avdict.set("fd", &path[3..]); 
-            path = "fd:".into();
+    /// The decoder only attaches the display matrix to frames of packets which carried it (the first one after opening or seeking),
+    /// copy the stream one so `rotation` is right on every frame
+    fn attach_display_matrix(&self, index: usize, avframe: &mut frame::Video) {
+        unsafe {
+            if !ffi::av_frame_get_side_data(avframe.as_ptr(), ffi::AVFrameSideDataType::AV_FRAME_DATA_DISPLAYMATRIX).is_null() { return; }
+            let Some(stream) = self.context.stream(index) else { return; };
+            let Some(matrix) = stream.side_data().find(|sd| sd.kind() == ffmpeg_next::packet::side_data::Type::DisplayMatrix && sd.data().len() >= 9 * 4) else { return; };
+            let sd = ffi::av_frame_new_side_data(avframe.as_mut_ptr(), ffi::AVFrameSideDataType::AV_FRAME_DATA_DISPLAYMATRIX, 9 * 4);
+            if !sd.is_null() {
+                std::ptr::copy_nonoverlapping(matrix.data().as_ptr(), (*sd).data, 9 * 4);
+            }
         }
-        let mut input_context = format::input_with_dictionary(&path, options_avdict)?;
+    }
+
+    /// Wraps a decoded video frame, detecting reconfiguration and applying the format or bit depth conversion.
+    /// `None` when the frame is held back in the reorder buffer, which only happens after the stream had non-monotonic timestamps
+    fn video_frame(&mut self, index: usize, frame: ffmpeg_next::Frame) -> Option<Frame> {
+        let mut avframe = frame::Video::from(frame);
+        self.attach_display_matrix(index, &mut avframe);
+        let state = &mut self.stream_state[index];
+        if let Some(sidecar) = &mut self.sidecar {
+            // The tags of the bitstream win, the sidecar only describes untagged streams
+            unsafe {
+                let ptr = avframe.as_mut_ptr();
+                if let Some(v) = sidecar.color_trc.filter(|_| (*ptr).color_trc == ffi::AVColorTransferCharacteristic::AVCOL_TRC_UNSPECIFIED) {
+                    (*ptr).color_trc = ffmpeg_next::color::TransferCharacteristic::from(v).into();
+                }
+                if let Some(v) = sidecar.color_primaries.filter(|_| (*ptr).color_primaries == ffi::AVColorPrimaries::AVCOL_PRI_UNSPECIFIED) {
+                    (*ptr).color_primaries = ffmpeg_next::color::Primaries::from(v).into();
+                }
+            }
+            let filter = sidecar.look.filter(|_| unsafe { (*avframe.as_ptr()).hw_frames_ctx.is_null() })
+                .and_then(|look| crate::cdl_filter(&look, avframe.format()));
+            if let Some(filter) = filter {
+                match crate::ColorConversionGraph::run(&mut state.look_graph, &avframe, &filter) {
+                    Ok(graded) => avframe = graded,
+                    Err(e) => {
+                        log::warn!("Failed to apply the sidecar look {filter}: {e}");
+                        sidecar.look = None;
+                    }
+                }
+            }
+        }
+        let delay = match &state.decoder {
+            Some(OpenedDecoder::Video(decoder)) => unsafe { (*decoder.as_ptr()).has_b_frames.max(0) as usize },
+            _ => 0
+        };
+        let mut frame = FfmpegVideoFrame { avframe, swframe: None, max_bit_depth: self.open_options.preferred_bit_depth, lease: Some(self.leases.acquire()) };
+        if let (Some(skip), Some(ts)) = (self.skip_video_until_us, frame.timestamp_us()) {
+            if ts <= skip { return None; }
+            self.skip_video_until_us = None;
+        }
+        let params = VideoParams { width: frame.width(), height: frame.height(), format: frame.format() };
+        if let Some(old) = track_video_params(&mut state.last_video_params, params) {
+            log::info!("Stream {} reconfigured from {:?} to {:?}", index, old, params);
+            self.video_params_generation += 1;
+            self.diagnostics.push(Diagnostic::StreamReconfigured { stream_index: index, timestamp_us: frame.timestamp_us(), old, new: params });
+        }
+        if !frame.is_hardware() {
+            let target = match (self.open_options.output_format, frame.max_bit_depth) {
+                (Some(format), _) => crate::frame::to_ffmpeg_format(format),
+                (None, Some(depth)) => crate::frame::reduced_depth_format(frame.avframe.format(), depth),
+                (None, None) => None
+            };
+            let space = self.open_options.output_color_space.unwrap_or(ColorSpace::BT709);
+            if let Some(converted) = target.and_then(|target| FormatConverter::convert(&mut state.format_converter, &frame.avframe, target, space)) {
+                frame.avframe = converted;
+            }
+        }
+        state.reorder.push(frame, delay).map(|frame| Frame::Video(frame.into()))
+    }
+
+    pub fn new(io: IoType, mut options: DecoderOptions) -> Result<Self, VideoProcessingError> {
+        crate::backend::init_ffmpeg()?;
+
+        if let Some(key) = options.decryption_key.as_ref().filter(|x| x.0.len() != 16) {
+            return Err(VideoProcessingError::InvalidDecryptionKey(format!("{} bytes, CENC keys are 16 bytes (32 hex digits)", key.0.len())));
+        }
+        let codec_settings = CodecSettings::new(&options);
+        if options.quality_preset == Some(QualityPreset::Preview) && options.preferred_bit_depth.is_none() {
+            options.preferred_bit_depth = Some(8);
+        }
+
+        let sidecar = match (&options.sidecar, &io) {
+            (Sidecar::Path(path), _) => Some(SidecarMetadata::load(path)?),
+            (Sidecar::Auto, IoType::FileOrUrl(path)) => SidecarMetadata::find_for(std::path::Path::new(path)).and_then(|x| {
+                SidecarMetadata::load(&x).map_err(|e| log::warn!("Failed to load the sidecar {x:?}: {e}")).ok()
+            }),
+            _ => None
+        };
+
+        let sequence_rate = options.sequence_frame_rate.unwrap_or((25, 1));
+        let is_sequence = matches!(&io, IoType::FileOrUrl(path) if is_sequence_pattern(path));
+        if (is_sequence || matches!(&io, IoType::FileList(_))) && (sequence_rate.0 <= 0 || sequence_rate.1 <= 0) {
+            return Err(VideoProcessingError::InvalidFrameRate(sequence_rate));
+        }
+        let avdict = || {
+            let mut dict = Dictionary::new();
+            if is_sequence {
+                // Option of the image2 demuxer, which ffmpeg picks for the pattern
+                dict.set("framerate", &format!("{}/{}", sequence_rate.0, sequence_rate.1));
+            }
+            for (k, v) in &options.custom_options { dict.set(k, v); }
+            if let Some(key) = &options.decryption_key { dict.set("decryption_key", &key.to_hex()); }
+            dict
+        };
+        let mut options_avdict = avdict();
+        let mut custom_io = None;
+        let input = match &io {
+            IoType::FileOrUrl(path) => path.clone(),
+            other => format!("{other:?}")
+        };
+        // Installed before opening, so `open_timeout` covers probing the input as well
+        let io_deadline = std::sync::Arc::new(IoDeadline::default());
+        io_deadline.set(options.open_timeout.map(|x| std::time::Instant::now() + x));
+        let interrupt = ffi::AVIOInterruptCB {
+            callback: Some(io_interrupt),
+            opaque: std::sync::Arc::as_ptr(&io_deadline) as *mut std::ffi::c_void,
+        };
+        let opened = match io {
+            IoType::FileOrUrl(path) => crate::support::ffmpeg_io::open_url(&path, options_avdict, interrupt),
+            IoType::FileDescriptor(fd) => {
+                options_avdict.set("fd", &fd.to_string());
+                crate::support::ffmpeg_io::open_url("fd:", options_avdict, interrupt)
+            },
+            IoType::Reader(reader) => crate::support::ffmpeg_io::open_input(reader, options_avdict, interrupt).map(|(ctx, io)| {
+                custom_io = Some(io);
+                ctx
+            }),
+            IoType::FileList(files) => crate::support::ffmpeg_io::open_file_list(&files, sequence_rate, options.scratch_dir.as_deref(), options_avdict, interrupt)
+        };
+        io_deadline.set(None);
+        let mut input_context = match (opened, options.open_timeout) {
+            (Ok(ctx), _) => ctx,
+            (Err(_), Some(timeout)) if io_deadline.interrupted.swap(false, std::sync::atomic::Ordering::Relaxed) => return Err(VideoProcessingError::TimedOut(timeout)),
+            (Err(e), _) => return Err(e)
+        };
+        if options.decryption_key.is_none() && !options.custom_options.contains_key("decryption_key") {
+            for stream in input_context.streams() {
+                if stream.side_data().any(|sd| ffi::AVPacketSideDataType::from(sd.kind()) == ffi::AVPacketSideDataType::AV_PKT_DATA_ENCRYPTION_INIT_INFO) {
+                    log::warn!("Stream {} is encrypted, set DecoderOptions::decryption_key to decode it", stream.index());
+                }
+            }
+        }
+        // Stream side data goes to the decoder with the first packet after opening and after every seek,
+        // the HEVC decoder needs the Dolby Vision configuration from it to parse the RPUs
+        unsafe { ffi::av_format_inject_global_side_data(input_context.as_mut_ptr()); }
 
         // format::context::input::dump(&input_context, 0, Some(path));
 
+        if options.keyframes_only {
+            // Let the demuxer drop the other packets where possible, the decoder skips the rest
+            for mut stream in input_context.streams_mut().filter(|x| x.parameters().medium() == media::Type::Video) {
+                unsafe { (*stream.as_mut_ptr()).discard = ffi::AVDiscard::AVDISCARD_NONKEY; }
+            }
+        }
+
+        let demuxer = if options.background_demux {
+            // The demux thread takes over the opened input, so it's never opened twice and pipes and live streams work too
+            let info = unsafe { super::demux::stream_info(&input_context)? };
+            let input = std::mem::replace(&mut input_context, info);
+            Some(BackgroundDemuxer::new(input, custom_io.take(), io_deadline.clone())?)
+        } else {
+            None
+        };
+
         let mut stream_state = Vec::new();
 
         for (i, stream) in input_context.streams().enumerate() {
@@ -188,28 +1383,497 @@ impl FfmpegDecoder {
             let rate = stream.rate();
             let time_base = stream.time_base();
 
+            let metadata = stream.metadata();
+            let params = stream.parameters();
+            let par = unsafe { &*params.as_ptr() };
+            let is_video = medium == media::Type::Video;
+            let is_audio = medium == media::Type::Audio;
+            let pixel_format = if is_video { crate::frame::pixel_format_from_raw(par.format) } else { None };
+            let bit_depth = if par.bits_per_raw_sample > 0 { Some(par.bits_per_raw_sample as u32) } else { pixel_format.map(|x| x.bit_depth()) };
+            let codec_name = params.id().name().to_string();
+            let (width, height) = if is_video { (Some(par.width as u32), Some(par.height as u32)) } else { (None, None) };
+            let (channels, sample_rate) = if is_audio { (Some(par.ch_layout.nb_channels as u32), Some(par.sample_rate as u32)) } else { (None, None) };
+            let descriptor = StreamDescriptor {
+                stream_type,
+                index: i,
+                codec_name: codec_name.clone(),
+                bitrate: par.bit_rate as usize,
+                language: metadata.get("language").map(|x| x.to_string()),
+                title: metadata.get("title").map(|x| x.to_string()),
+                width,
+                height,
+                avg_frame_rate: (avg_fps.0, avg_fps.1),
+                channels,
+                sample_rate,
+            };
+
             stream_state.push(StreamInfo {
                 decoder: None,
+                descriptor,
+                last_video_params: None,
+                reorder: ReorderBuffer::default(),
+                discarded: false,
+                parameter_sets: None,
+                format_converter: None,
+                look_graph: None,
+                start_time_us: if stream.start_time() == ffi::AV_NOPTS_VALUE { 0 } else { stream.start_time().rescale(time_base, (1, 1000000)) },
+                last_audio_us: None,
+                skip_audio_until_us: None,
                 info: Stream {
                     stream_type,
                     index: i,
                     avg_frame_rate: (avg_fps.0, avg_fps.1),
                     rate:           (rate.0, rate.1),
                     time_base:      (time_base.0, time_base.1),
+                    codec_name,
+                    width,
+                    height,
+                    pixel_format,
+                    sample_rate,
+                    channels,
+                    bit_depth,
 
-                    decode: true,
+                    decode: options.only_streams.as_ref().is_none_or(|x| x.contains(&i)),
                 }
             });
         }
 
         Ok(Self {
             context: input_context,
+            stream_info_only: demuxer.is_some(),
+            input,
+            _custom_io: custom_io,
+            io_deadline,
             current_packet: ffmpeg_next::Packet::empty(),
+            packet_sent: false,
 
             packets_ended: false,
+            gpu_index: options.gpu_index,
+            prefetch_depth: options.prefetch_depth,
             open_options: options,
 
-            stream_state
+            stream_state,
+
+            video_params_generation: 0,
+            diagnostics: Vec::new(),
+            hw_device: None,
+            device_limits: None,
+            hw_errors: 0,
+            device_recoveries: 0,
+            hw_devices_tried: 0,
+            lost_devices_seen: crate::support::ffmpeg_hw::lost_device_count(),
+            last_keyframe_us: None,
+            skip_video_until_us: None,
+            last_packet: None,
+            read_interrupted: false,
+            skip_until_packet: None,
+            error: None,
+            software_fallback: false,
+            leases: FrameLeases::default(),
+            demuxer,
+            codec_settings,
+            audio_packets: Vec::new(),
+            sidecar,
         })
     }
 }
+
+impl Drop for FfmpegDecoder {
+    fn drop(&mut self) {
+        if self.stream_info_only {
+            // Borrowed from the input owned by the demux thread, closing the copy must not call `read_close` of the demuxer
+            unsafe { (*self.context.as_mut_ptr()).iformat = std::ptr::null(); }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reconfiguration() {
+        let hd = VideoParams { width: 1920, height: 1080, format: PixelFormat::NV12 };
+        let uhd = VideoParams { width: 3840, height: 2160, format: PixelFormat::NV12 };
+        let mut last = None;
+        // The first frame isn't a change
+        assert_eq!(track_video_params(&mut last, hd), None);
+        assert_eq!(track_video_params(&mut last, hd), None);
+        assert_eq!(track_video_params(&mut last, uhd), Some(hd));
+        assert_eq!(last, Some(uhd));
+        assert_eq!(track_video_params(&mut last, VideoParams { format: PixelFormat::P010LE, ..uhd }), Some(uhd));
+    }
+
+    fn frame_at(ts: Option<i64>) -> FfmpegVideoFrame {
+        let mut avframe = frame::Video::new(format::Pixel::GRAY8, 2, 2);
+        unsafe {
+            (*avframe.as_mut_ptr()).pts = ts.unwrap_or(ffi::AV_NOPTS_VALUE);
+            (*avframe.as_mut_ptr()).best_effort_timestamp = ts.unwrap_or(ffi::AV_NOPTS_VALUE);
+        }
+        FfmpegVideoFrame { avframe, swframe: None, max_bit_depth: None, lease: None }
+    }
+
+    fn reorder(input: &[Option<i64>], delay: usize) -> Vec<Option<i64>> {
+        let mut buffer = ReorderBuffer::default();
+        let mut output = input.iter().filter_map(|ts| buffer.push(frame_at(*ts), delay)).map(|f| f.timestamp_us()).collect::<Vec<_>>();
+        while let Some(frame) = buffer.pop() { output.push(frame.timestamp_us()); }
+        output
+    }
+
+    #[test]
+    fn monotonic_frames_pass_through() {
+        let input = [Some(0), Some(40), Some(80), None, Some(120)];
+        let mut buffer = ReorderBuffer::default();
+        for ts in input {
+            // Not held back even though the codec has a reordering delay
+            assert_eq!(buffer.push(frame_at(ts), 2).map(|f| f.timestamp_us()), Some(ts));
+        }
+        assert_eq!(buffer.last_timestamp_us, Some(120));
+    }
+
+    #[test]
+    fn reorders_after_non_monotonic_pts() {
+        // The late frame is returned as it is, the ones after it are sorted and no timestamp is rewritten
+        let output = reorder(&[Some(0), Some(80), Some(40), Some(160), Some(120), Some(200)], 2);
+        assert_eq!(output, [Some(0), Some(80), Some(40), Some(120), Some(160), Some(200)]);
+    }
+
+    #[test]
+    fn clear_keeps_reordering() {
+        let mut buffer = ReorderBuffer::default();
+        for ts in [40, 0, 80] { buffer.push(frame_at(Some(ts)), 1); }
+        buffer.clear();
+        assert_eq!(buffer.last_timestamp_us, None);
+        assert!(buffer.push(frame_at(Some(1000)), 1).is_none());
+        assert_eq!(buffer.pop().unwrap().timestamp_us(), Some(1000));
+    }
+
+    #[test]
+    fn device_errors() {
+        use ffmpeg_next::error::{ EIO, ENOMEM, EAGAIN };
+        assert!(is_hw_device_error(ffmpeg_next::Error::External));
+        assert!(is_hw_device_error(ffmpeg_next::Error::Other { errno: EIO }));
+        assert!(is_hw_device_error(ffmpeg_next::Error::Other { errno: ENOMEM }));
+        // Broken input fails in software as well
+        assert!(!is_hw_device_error(ffmpeg_next::Error::InvalidData));
+        assert!(!is_hw_device_error(ffmpeg_next::Error::Eof));
+        assert!(!is_hw_device_error(ffmpeg_next::Error::Other { errno: EAGAIN }));
+    }
+
+    #[test]
+    fn converted_chroma_is_filtered() {
+        // Chroma alternating every column, subsampling has to average it instead of picking every other sample
+        let mut src = frame::Video::new(format::Pixel::YUV444P, 16, 16);
+        for plane in 0..3 {
+            let stride = src.stride(plane);
+            for (i, v) in src.data_mut(plane).iter_mut().enumerate() {
+                *v = if plane == 1 && (i % stride) % 2 == 1 { 255 } else if plane == 1 { 0 } else { 128 };
+            }
+        }
+        let mut converter = None;
+        let nv12 = FormatConverter::convert(&mut converter, &src, format::Pixel::NV12, ColorSpace::BT709).unwrap();
+        let uv = &nv12.data(1)[..16];
+        assert!(uv.chunks(2).all(|x| x[0].abs_diff(128) <= 8 && x[1].abs_diff(128) <= 2), "{uv:?}");
+        assert!(nv12.data(0)[..16].iter().all(|x| x.abs_diff(128) <= 1));
+    }
+
+    #[test]
+    fn every_packet_is_decoded() {
+        // High bitrate intra frames make the frame threads back-pressure with EAGAIN
+        let path = crate::util::test::temp_path("eagain.mov");
+        crate::util::test::write_test_clip(&path, crate::EncoderCodec::ProRes, (1920, 1080), 48, 24.0).unwrap();
+        let mut decoder = crate::Decoder::new(&path, DecoderOptions::default()).unwrap();
+        let expected = decoder.get_video_info().unwrap().frame_count;
+        let mut frames = 0;
+        while let Some(frame) = decoder.next_frame() {
+            if let Frame::Video(_) = frame { frames += 1; }
+        }
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(expected, 48);
+        assert_eq!(frames, expected);
+    }
+    fn wav(samples: usize) -> Vec<u8> {
+        let data = (0..samples).flat_map(|i| (((i % 100) as i16 - 50) * 300).to_le_bytes()).collect::<Vec<u8>>();
+        let mut wav = Vec::new();
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + data.len() as u32).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        // PCM, mono, 48 kHz, 16 bits
+        for field in [16u32.to_le_bytes().to_vec(), 1u16.to_le_bytes().to_vec(), 1u16.to_le_bytes().to_vec(), 48000u32.to_le_bytes().to_vec(), 96000u32.to_le_bytes().to_vec(), 2u16.to_le_bytes().to_vec(), 16u16.to_le_bytes().to_vec()] {
+            wav.extend_from_slice(&field);
+        }
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        wav.extend_from_slice(&data);
+        wav
+    }
+
+    /// Ends the input after `limit` bytes, either cleanly or with an error on every read
+    struct TruncatedReader { inner: std::io::Cursor<Vec<u8>>, limit: u64, fail: bool }
+    impl std::io::Read for TruncatedReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let remaining = self.limit.saturating_sub(self.inner.position()) as usize;
+            if remaining == 0 {
+                return if self.fail { Err(std::io::Error::other("broken input")) } else { Ok(0) };
+            }
+            let len = buf.len().min(remaining);
+            std::io::Read::read(&mut self.inner, &mut buf[..len])
+        }
+    }
+    impl std::io::Seek for TruncatedReader {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> { std::io::Seek::seek(&mut self.inner, pos) }
+    }
+
+    fn count_frames(decoder: &mut crate::Decoder) -> (usize, usize) {
+        let (mut video, mut audio) = (0, 0);
+        while let Some(frame) = decoder.next_frame() {
+            match frame {
+                Frame::Video(_) => video += 1,
+                Frame::Audio(_) => audio += 1,
+                Frame::Other => { }
+            }
+        }
+        (video, audio)
+    }
+
+    #[test]
+    fn audio_only_input() {
+        let io = IoType::Reader(Box::new(std::io::Cursor::new(wav(48000))));
+        let mut decoder = crate::Decoder::new_from_io(io, DecoderOptions::default()).unwrap();
+        assert!(matches!(decoder.get_video_info(), Err(VideoProcessingError::NoVideoStream)));
+        assert_eq!(decoder.get_audio_info().unwrap().sample_rate, 48000);
+        let (video, audio) = count_frames(&mut decoder);
+        assert_eq!(video, 0);
+        assert!(audio > 0);
+        assert!(decoder.next_frame().is_none());
+    }
+
+    #[test]
+    fn empty_input() {
+        let io = IoType::Reader(Box::new(std::io::Cursor::new(wav(0))));
+        let mut decoder = crate::Decoder::new_from_io(io, DecoderOptions::default()).unwrap();
+        assert!(matches!(decoder.get_video_info(), Err(VideoProcessingError::NoVideoStream)));
+        assert!(decoder.next_frame().is_none());
+    }
+
+    #[test]
+    fn video_only_input() {
+        let path = crate::util::test::temp_path("video-only.mp4");
+        crate::util::test::write_test_clip(&path, crate::EncoderCodec::H264, (320, 240), 12, 24.0).unwrap();
+        let mut decoder = crate::Decoder::new(&path, DecoderOptions::default()).unwrap();
+        assert!(matches!(decoder.get_audio_info(), Err(VideoProcessingError::AudioStreamNotFound)));
+        assert_eq!(count_frames(&mut decoder), (12, 0));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn read_errors_flush_the_decoders() {
+        let path = crate::util::test::temp_path("broken.ts");
+        crate::util::test::write_test_clip(&path, crate::EncoderCodec::H264, (320, 240), 48, 24.0).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let decode = |fail| {
+            let reader = TruncatedReader { inner: std::io::Cursor::new(data.clone()), limit: data.len() as u64 / 2, fail };
+            let mut decoder = crate::Decoder::new_from_io(IoType::Reader(Box::new(reader)), DecoderOptions::default()).unwrap();
+            count_frames(&mut decoder).0
+        };
+        // Giving up on a broken input must still return the frames buffered in the decoder, same as a clean end
+        let frames = decode(true);
+        assert!(frames > 0);
+        assert_eq!(frames, decode(false));
+    }
+
+    #[test]
+    fn sequence_patterns() {
+        assert!(is_sequence_pattern("frame_%05d.png"));
+        assert!(is_sequence_pattern("frame_%d.png"));
+        assert!(is_sequence_pattern("100%%_%03d.exr"));
+        // Percent-encoded characters and URLs aren't patterns
+        assert!(!is_sequence_pattern("clip%2d1.mp4"));
+        assert!(!is_sequence_pattern("clip%0d.mp4"));
+        assert!(!is_sequence_pattern("100%%d.png"));
+        assert!(!is_sequence_pattern("https://example.com/frame_%05d.png"));
+    }
+
+    #[test]
+    fn percent_encoded_name() {
+        let path = crate::util::test::temp_path("clip%2d1.mp4");
+        crate::util::test::write_test_clip(&path, crate::EncoderCodec::H264, (320, 240), 12, 24.0).unwrap();
+        let mut decoder = crate::Decoder::new(&path, DecoderOptions::default()).unwrap();
+        assert_eq!(count_frames(&mut decoder), (12, 0));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn invalid_sequence_rate() {
+        for rate in [(0, 1), (25, 0), (-25, 1)] {
+            let options = || DecoderOptions { sequence_frame_rate: Some(rate), ..Default::default() };
+            let list = IoType::FileList(vec![crate::util::test::temp_path("frame_0.png")]);
+            assert!(matches!(crate::Decoder::new_from_io(list, options()), Err(VideoProcessingError::InvalidFrameRate(x)) if x == rate));
+            let pattern = crate::util::test::temp_path("frame_%05d.png");
+            assert!(matches!(crate::Decoder::new(&pattern, options()), Err(VideoProcessingError::InvalidFrameRate(x)) if x == rate));
+        }
+    }
+    #[test]
+    fn quality_presets() {
+        let path = crate::util::test::temp_path("presets.mp4");
+        crate::util::test::write_test_clip(&path, crate::EncoderCodec::H264, (320, 240), 2, 24.0).unwrap();
+        let settings = |options: DecoderOptions| {
+            let config = crate::Decoder::new(&path, options).unwrap().backend_config();
+            assert_eq!(config.backend, "ffmpeg");
+            config.settings.into_iter().map(|(k, v)| format!("{k}={v}")).collect::<Vec<_>>().join(" ")
+        };
+        let preset = |x| DecoderOptions { quality_preset: x, ..Default::default() };
+        assert_eq!(settings(preset(None)),                          "lowres=0 threading=frame threads=auto skip_loop_filter=default bit_depth=native");
+        assert_eq!(settings(preset(Some(QualityPreset::Preview))),  "lowres=1 threading=slice threads=0 skip_loop_filter=all bit_depth=8");
+        assert_eq!(settings(preset(Some(QualityPreset::Balanced))), "lowres=0 threading=frame threads=auto skip_loop_filter=nonref bit_depth=native");
+        assert_eq!(settings(preset(Some(QualityPreset::Full))),     "lowres=0 threading=frame threads=0 skip_loop_filter=default bit_depth=native");
+
+        // Explicit options win over the preset
+        let mut options = preset(Some(QualityPreset::Preview));
+        options.preferred_bit_depth = Some(10);
+        options.custom_options.insert("lowres".into(), "0".into());
+        options.custom_options.insert("threads".into(), "2".into());
+        options.custom_options.insert("skip_loop_filter".into(), "nonkey".into());
+        assert_eq!(settings(options), "lowres=0 threading=slice threads=2 skip_loop_filter=nonkey bit_depth=10");
+        let _ = std::fs::remove_file(&path);
+    }
+    #[test]
+    fn rotated_hdr_hevc() {
+        use crate::{ ColorPrimaries, ColorSpace, ColorTrc };
+        // Same as an iPhone HDR recording without Dolby Vision: 10-bit HEVC, BT.2020 with HLG, in a MOV with a 90° display matrix
+        let encoded = crate::util::test::temp_path("hdr-encoded.mov");
+        let path = crate::util::test::temp_path("hdr-rotated.mov");
+        let mut params = crate::EncoderParams::new(crate::EncoderCodec::H265, 128, 72, PixelFormat::YUV420P10LE, 30.0);
+        params.out_color_space = Some(ColorSpace::BT2020NCL);
+        params.out_color_trc = Some(ColorTrc::AribStdB67);
+        params.out_color_primaries = Some(ColorPrimaries::BT2020);
+        let mut encoder = crate::Encoder::new(IoType::from(encoded.as_str()), params).unwrap();
+        for _ in 0..10 {
+            encoder.encode_frame(&mut crate::util::test::synthetic_frame(PixelFormat::YUV420P10LE, 128, 72).unwrap()).unwrap();
+        }
+        encoder.finish().unwrap();
+        crate::edit_metadata(&encoded, Some(&path), &crate::MetadataEdits { rotation: Some(90), ..Default::default() }).unwrap();
+        let _ = std::fs::remove_file(&encoded);
+
+        let mut decoder = crate::Decoder::new(&path, DecoderOptions { output_format: Some(PixelFormat::P010LE), ..Default::default() }).unwrap();
+        let info = decoder.get_video_info().unwrap();
+        assert_eq!((info.width, info.height, info.rotation), (128, 72, 90));
+        assert_eq!(info.color_space, Some(ColorSpace::BT2020NCL));
+        assert_eq!(info.color_primaries, Some(ColorPrimaries::BT2020));
+        assert_eq!(info.color_trc, Some(ColorTrc::AribStdB67));
+        assert!(info.dolby_vision.is_none());
+
+        let mut frames = 0;
+        while let Some(frame) = decoder.next_frame() {
+            let Frame::Video(frame) = frame else { continue; };
+            assert_eq!(frame.format(), PixelFormat::P010LE);
+            assert_eq!(frame.rotation(), 90);
+            assert_eq!(frame.color_space(), Some(ColorSpace::BT2020NCL));
+            assert_eq!(frame.color_primaries(), Some(ColorPrimaries::BT2020));
+            assert_eq!(frame.color_trc(), Some(ColorTrc::AribStdB67));
+            assert!(frame.dovi_rpu().is_none());
+            frames += 1;
+        }
+        assert_eq!(frames, 10);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn sidecar_color_and_look() {
+        use crate::{ ColorPrimaries, ColorSpace, ColorTrc };
+        let sidecar = crate::util::test::temp_path("sidecar-look.xml");
+        std::fs::write(&sidecar, r#"<NonRealTimeMeta>
+            <Item name="CaptureGammaEquation" value="rec2100-hlg"/>
+            <Item name="CaptureColorPrimaries" value="rec2020"/>
+            <Slope>0.5 0.5 0.5</Slope>
+        </NonRealTimeMeta>"#).unwrap();
+        let options = |with_sidecar: bool| DecoderOptions { sidecar: if with_sidecar { Sidecar::Path(sidecar.clone().into()) } else { Sidecar::None }, ..Default::default() };
+        // Mean of the luma plane and the color tags of every frame
+        let decode = |path: &str, with_sidecar: bool| {
+            let mut decoder = crate::Decoder::new(path, options(with_sidecar)).unwrap();
+            let info = decoder.get_video_info().unwrap();
+            let mut frames = Vec::new();
+            while let Some(frame) = decoder.next_frame() {
+                let Frame::Video(mut frame) = frame else { continue; };
+                let (format, trc, primaries) = (frame.format(), frame.color_trc(), frame.color_primaries());
+                let buffers = frame.get_cpu_buffers().unwrap();
+                let mean = buffers[0].iter().map(|x| *x as f64).sum::<f64>() / buffers[0].len() as f64;
+                frames.push((format, trc, primaries, mean));
+            }
+            (info.color_trc, info.color_primaries, frames)
+        };
+
+        // Tags of the bitstream win, the look is still applied
+        let tagged = crate::util::test::temp_path("sidecar-tagged.mp4");
+        let mut params = crate::EncoderParams::new(crate::EncoderCodec::H264, 64, 48, PixelFormat::YUV420P, 25.0);
+        params.out_color_space = Some(ColorSpace::BT709);
+        params.out_color_trc = Some(ColorTrc::BT709);
+        params.out_color_primaries = Some(ColorPrimaries::BT709);
+        let mut encoder = crate::Encoder::new(IoType::from(tagged.as_str()), params).unwrap();
+        for _ in 0..5 {
+            encoder.encode_frame(&mut crate::VideoFrame::solid_color(PixelFormat::RGBA, 64, 48, [220, 200, 180, 255]).unwrap()).unwrap();
+        }
+        encoder.finish().unwrap();
+        let (trc, primaries, plain) = decode(&tagged, false);
+        let (graded_trc, graded_primaries, graded) = decode(&tagged, true);
+        assert_eq!((trc, primaries), (Some(ColorTrc::BT709), Some(ColorPrimaries::BT709)));
+        assert_eq!((graded_trc, graded_primaries), (trc, primaries));
+        assert_eq!((plain.len(), graded.len()), (5, 5));
+        for (plain, graded) in plain.iter().zip(&graded) {
+            assert_eq!((graded.0, graded.1, graded.2), (PixelFormat::YUV420P, Some(ColorTrc::BT709), Some(ColorPrimaries::BT709)));
+            assert!(graded.3 < plain.3 * 0.7, "{graded:?} {plain:?}");
+        }
+
+        // Untagged streams get the sidecar description
+        let untagged = crate::util::test::temp_path("sidecar-untagged.nut");
+        crate::util::test::write_av_test_clip(&untagged, 3).unwrap();
+        assert_eq!(decode(&untagged, false).0, None);
+        let (trc, primaries, frames) = decode(&untagged, true);
+        assert_eq!((trc, primaries), (Some(ColorTrc::AribStdB67), Some(ColorPrimaries::BT2020)));
+        assert_eq!(frames.len(), 3);
+        assert!(frames.iter().all(|x| (x.1, x.2) == (Some(ColorTrc::AribStdB67), Some(ColorPrimaries::BT2020))));
+
+        for path in [&sidecar, &tagged, &untagged] {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    #[test]
+    fn hw_errors_switch_devices() {
+        use std::sync::atomic::{ AtomicUsize, Ordering };
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        fn fail_six_packets() -> Option<i32> {
+            // Three failures per device, starting in the middle of the clip
+            (10..16).contains(&CALLS.fetch_add(1, Ordering::Relaxed)).then_some(-ffmpeg_next::error::EIO)
+        }
+        assert_eq!((1..=5).map(hw_retry_backoff).map(|x| x.as_millis()).collect::<Vec<_>>(), [10, 20, 40, 80, 80]);
+
+        let path = crate::util::test::temp_path("hw-errors.mp4");
+        crate::util::test::write_test_clip(&path, crate::EncoderCodec::H264, (160, 120), 48, 24.0).unwrap();
+        let options = || DecoderOptions { gpu_index: Some(0), ..Default::default() };
+        let fallbacks = |decoder: &FfmpegDecoder| decoder.diagnostics.iter().filter(|x| matches!(x, Diagnostic::HardwareFallback { .. })).count();
+        let decode = |decoder: &mut FfmpegDecoder| std::iter::from_fn(|| decoder.next_frame())
+            .filter_map(|x| match x { Frame::Video(v) => v.timestamp_us(), _ => None })
+            .collect::<Vec<_>>();
+
+        let mut decoder = FfmpegDecoder::new(IoType::from(path.as_str()), options()).unwrap();
+        let expected = decode(&mut decoder);
+        assert_eq!(expected.len(), 48);
+        assert_eq!(fallbacks(&decoder), 0);
+
+        CALLS.store(0, Ordering::Relaxed);
+        crate::support::ffmpeg_hw::set_hw_failure_hook(Some(fail_six_packets));
+        let mut decoder = FfmpegDecoder::new(IoType::from(path.as_str()), options()).unwrap();
+        let decoded = decode(&mut decoder);
+        crate::support::ffmpeg_hw::set_hw_failure_hook(None);
+        let _ = std::fs::remove_file(&path);
+
+        // The first three errors move to the next GPU, the next three fall back to software, no frame is lost or repeated
+        assert_eq!(decoded, expected);
+        assert_eq!(decoder.gpu_index, Some(1));
+        assert_eq!(decoder.hw_devices_tried, MAX_HW_DEVICES);
+        assert!(decoder.software_fallback);
+        assert_eq!(fallbacks(&decoder), 1);
+    }
+}
@@ -2,17 +2,292 @@
 // Copyright © 2023 Adrian <adrian.eddy at gmail>
 
 mod ffmpeg; use ffmpeg::*;
+mod analysis; pub use analysis::{ AnalysisFlags, TimestampChecker, TimestampIssue };
+mod audio; pub use audio::*;
+mod demux; use demux::BackgroundDemuxer;
+mod sidecar; pub use sidecar::*;
 
 use crate::*;
 use crate::types::VideoProcessingError;
 
-use std::collections::HashMap;
+use std::collections::{ HashMap, VecDeque };
+use std::time::{ Duration, Instant };
 
 #[derive(Default, Debug)]
 pub struct DecoderOptions {
+    /// Enables hardware decoding on the GPU with this index. `hwaccel_device` in `custom_options` overrides the device
     pub gpu_index: Option<usize>,
-    pub ranges_ms: Vec<(f32, f32)>,
+    /// Time ranges to decode, `next_frame` seeks to the start of the next one when the current one ends and returns `None` after the last.
+    /// Overlapping ranges are merged, an empty vec decodes the whole file
+    pub ranges_ms: Vec<(f64, f64)>,
     pub custom_options: HashMap<String, String>,
+    /// Maximum bits per component of the returned frames, e.g. `Some(8)` for previews. `None` keeps the native depth.
+    /// Software frames are converted to the format with the same layout and fewer bits (e.g. P010 to NV12, P016 to P010 for `Some(10)`).
+    /// Hardware frames are downloaded in that format when the GPU supports it
+    /// and converted right after the download otherwise, so `format()` of the returned frames never reports more than this.
+    /// Float frames (e.g. EXR sequences) always keep their precision
+    pub preferred_bit_depth: Option<u32>,
+    /// Converts software frames to this format before they are returned, e.g. NV12 or P010 so hardware encoders can take them directly.
+    /// Takes precedence over `preferred_bit_depth`
+    pub output_format: Option<PixelFormat>,
+    /// Matrix used when `output_format` converts RGB to YUV, BT.709 when `None`. The frames are tagged with it
+    pub output_color_space: Option<ColorSpace>,
+    /// Decode a reduced resolution frame during open, available from `Decoder::take_poster_frame`
+    pub decode_poster_frame: bool,
+    /// Timestamp of the poster frame, the first frame is used when `None`
+    pub poster_timestamp_us: Option<i64>,
+    /// Decode only the keyframes, much faster when building a visual index of a long file
+    pub keyframes_only: bool,
+    /// Single-threaded software decoding, so re-runs produce byte-identical frames (e.g. for golden image tests).
+    /// Ignores `gpu_index` and is usually several times slower
+    pub deterministic: bool,
+    /// Exports the motion vectors of the codec (MPEG-1/2/4, H.263, H.264) to `FfmpegVideoFrame::motion_vectors`.
+    /// Hardware decoders don't export them, so this decodes in software
+    pub export_motion_vectors: bool,
+    /// Frame rate of image sequences, i.e. a `frame_%05d.png` pattern or `IoType::FileList`. 25 fps when `None`, `InvalidFrameRate` when a part isn't positive
+    /// DPX and EXR frames keep their depth, e.g. `GBRP10LE` (`GBRP10BE` from big-endian DPX), `RGB48LE` or the scene-linear `GBRPF32LE`
+    pub sequence_frame_rate: Option<(i32, i32)>,
+    /// Fail with `TimedOut` when the input can't be opened and probed within this time, e.g. an unresponsive network share
+    pub open_timeout: Option<Duration>,
+    /// Key of CENC encrypted MP4/MOV streams, passed to the demuxer's `decryption_key` option.
+    /// Takes precedence over `custom_options["decryption_key"]`
+    pub decryption_key: Option<DecryptionKey>,
+    /// Directory for temporary files of the backend, e.g. the list of an image sequence. The system temp directory when `None`,
+    /// set it where that isn't writable (sandboxes). The RED and BRAW SDKs would use it as their scratch folder, they aren't in this build
+    pub scratch_dir: Option<std::path::PathBuf>,
+    /// Camera metadata file to load with the clip. Its timecode, tags and color description fill in what the clip doesn't have, its look is applied to the frames
+    pub sidecar: Sidecar,
+    /// Read the packets on a separate thread into a bounded queue, so IO stalls of network sources overlap with decoding.
+    /// The thread takes over the opened input, so it works with every `IoType`
+    pub background_demux: bool,
+    /// Indices of the streams to decode, the others aren't demuxed at all. Same as turning off `Stream::decode`
+    pub only_streams: Option<Vec<usize>>,
+    /// Demux the audio streams without decoding them, their packets are collected for `Decoder::take_audio_packets`.
+    /// For audio timing (e.g. sync checks) in video-only processing, without the cost of decoding the audio
+    pub audio_demux_only: bool,
+    pub analysis: AnalysisFlags,
+    pub diagnostics: Option<DiagnosticsCallback>,
+
+    // Precise seek budget, the closest frame reachable within the limits is returned when exceeded
+    pub max_preroll_frames: Option<usize>,
+    pub max_preroll_ms: Option<u64>,
+
+    /// Limits the number of decoded video frames alive at once, including the ones buffered inside the decoder and its frame pool.
+    /// Once reached, `next_frame` returns `None` and `try_next_frame` returns `WouldBlock` until the consumer drops a frame
+    pub max_outstanding_frames: Option<usize>,
+    /// Initial `Decoder::set_prefetch_depth`. The ffmpeg backend uses it for the frame threads of the video decoder, 3 when `None`
+    pub prefetch_depth: Option<usize>,
+
+    /// Times the decoder is reopened on a fresh device after the GPU was lost (e.g. a driver timeout) before failing with `GPUDecodingFailed`. 3 when `None`
+    pub max_device_recoveries: Option<u32>,
+
+    /// Speed/quality trade-off mapped to the settings of the backend, see `QualityPreset`. `None` keeps the backend defaults
+    pub quality_preset: Option<QualityPreset>,
+
+    /// Fail with `UnknownOptions` instead of emitting `Diagnostic::UnknownOption` for unrecognized `custom_options` keys, useful in CI
+    pub strict_options: bool,
+}
+
+/// Options of the decoder opened for the poster frame, software only and in the reduced resolution mode
+fn poster_options(options: &DecoderOptions) -> DecoderOptions {
+    let mut custom_options: HashMap<String, String> = options.custom_options.iter()
+        .filter(|(k, _)| !matches!(k.as_str(), "hwaccel_device" | "lowres"))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    custom_options.insert("lowres".into(), POSTER_LOWRES.to_string());
+    DecoderOptions {
+        custom_options,
+        preferred_bit_depth: options.preferred_bit_depth,
+        output_format: options.output_format,
+        output_color_space: options.output_color_space,
+        sequence_frame_rate: options.sequence_frame_rate,
+        open_timeout: options.open_timeout,
+        decryption_key: options.decryption_key.clone(),
+        scratch_dir: options.scratch_dir.clone(),
+        ..Default::default()
+    }
+}
+
+impl DecoderOptions {
+    /// `ranges_ms` in microseconds, sorted and with overlapping or touching ranges merged. Empty ranges are dropped
+    ///
+    /// ```
+    /// use gpu_video::DecoderOptions;
+    /// let options = DecoderOptions { ranges_ms: vec![(5000.0, 6000.0), (1000.0, 2000.0), (1500.0, 3000.0), (4000.0, 4000.0)], ..Default::default() };
+    /// assert_eq!(options.merged_ranges_us(), [(1_000_000, 3_000_000), (5_000_000, 6_000_000)]);
+    /// ```
+    pub fn merged_ranges_us(&self) -> Vec<(i64, i64)> {
+        let mut ranges = self.ranges_ms.iter()
+            .map(|&(start, end)| ((start.max(0.0) * 1000.0).round() as i64, (end * 1000.0).round() as i64))
+            .filter(|(start, end)| end > start)
+            .collect::<Vec<_>>();
+        ranges.sort_unstable();
+        let mut ret: Vec<(i64, i64)> = Vec::with_capacity(ranges.len());
+        for (start, end) in ranges {
+            match ret.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => ret.push((start, end))
+            }
+        }
+        ret
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SeekResult {
+    Exact { achieved_us: i64 },
+    /// Preroll budget was exceeded or the stream ended before reaching the target
+    Approximate { achieved_us: i64 },
+    Failed,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DecoderStats {
+    pub seeks: u64,
+    pub approximate_seeks: u64,
+    pub preroll_frames_total: u64,
+    pub last_preroll_frames: u64,
+    pub max_preroll_frames: u64,
+    /// Times the GPU device was lost during decoding, see `Diagnostic::DeviceLost`
+    pub device_recoveries: u64,
+}
+
+/// Resolution of a single decoded frame, see `Decoder::next_frame_at_mode`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum DecodeMode {
+    #[default]
+    Full,
+    Half,
+    Quarter,
+    Eighth,
+    Sixteenth,
+}
+
+impl DecodeMode {
+    /// Divisor of both dimensions
+    pub fn factor(&self) -> u32 {
+        match self {
+            DecodeMode::Full    => 1,
+            DecodeMode::Half    => 2,
+            DecodeMode::Quarter => 4,
+            DecodeMode::Eighth  => 8,
+            DecodeMode::Sixteenth => 16,
+        }
+    }
+}
+
+/// Decoding speed/quality trade-off in backend independent terms, see `DecoderOptions::quality_preset`.
+/// Options set explicitly in `custom_options` (e.g. `lowres`) or `preferred_bit_depth` take precedence over the preset.
+/// The settings each backend ended up with are in `BackendConfig::settings`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum QualityPreset {
+    /// Fastest decoding for scrubbing and proxies: reduced resolution where the codec supports it and 8-bit frames
+    Preview,
+    /// Full resolution, skips the in-loop filters on frames no other frame references
+    Balanced,
+    /// Bit exact decoding at full resolution and native bit depth
+    Full,
+}
+
+/// Compressed packet read without decoding, e.g. for bitrate graphs
+#[derive(Debug, Clone, Copy)]
+pub struct PacketInfo {
+    pub stream_index: usize,
+    pub pts_us: Option<i64>,
+    pub dts_us: Option<i64>,
+    pub duration_us: Option<i64>,
+    /// Compressed size in bytes
+    pub size: usize,
+    pub is_key: bool,
+    /// The codec parameters (e.g. SPS/PPS) changed at this packet, see `Diagnostic::ParameterSetsChanged`
+    pub parameter_sets_changed: bool,
+}
+
+/// Frame size limits of the decoding device, in pixels
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DeviceLimits {
+    pub min_size: (u32, u32),
+    pub max_size: (u32, u32),
+}
+
+impl DeviceLimits {
+    /// `ExceedsDeviceLimits` when `size` is too large for the device, `BelowDeviceMinimum` when it's too small. Unknown limits (zero) are not checked
+    pub fn check(&self, size: (u32, u32)) -> Result<(), VideoProcessingError> {
+        let (max_w, max_h) = self.max_size;
+        let (min_w, min_h) = self.min_size;
+        let too_big = (max_w > 0 && size.0 > max_w) || (max_h > 0 && size.1 > max_h);
+        let too_small = size.0 < min_w || size.1 < min_h;
+        if too_big {
+            return Err(VideoProcessingError::ExceedsDeviceLimits { needed: size, limit: self.max_size, suggestion: "decode in software or on a newer GPU".into() });
+        }
+        if too_small {
+            return Err(VideoProcessingError::BelowDeviceMinimum { needed: size, minimum: self.min_size });
+        }
+        Ok(())
+    }
+}
+
+/// Backend and device actually used for decoding, see `Decoder::backend_config`
+#[derive(Debug, Clone, Default)]
+pub struct BackendConfig {
+    pub backend: String,
+    pub hw_device: Option<HWDeviceInfo>,
+    pub device_limits: Option<DeviceLimits>,
+    pub quality_preset: Option<QualityPreset>,
+    /// Effective backend settings as (name, value), e.g. `("lowres", "1")`
+    pub settings: Vec<(String, String)>,
+}
+
+/// Effective decode pipeline in one place, for support bundles. See `Decoder::pipeline_report`
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PipelineReport {
+    pub input: String,
+    pub extension: Option<String>,
+    pub container: String,
+    pub backend: String,
+    pub video_codec: Option<String>,
+    pub hwaccel: Option<String>,
+    pub hw_device_name: Option<String>,
+    /// Format the hardware decoder outputs, e.g. `cuda` or `videotoolbox_vld`
+    pub hw_format: Option<String>,
+    /// Format of the frames in system memory
+    pub sw_format: Option<String>,
+    /// Hardware decoding was requested but the stream is decoded in software
+    pub software_fallback: bool,
+    pub decode_size: Option<(u32, u32)>,
+    /// `DecoderOptions::output_format` or the reduced depth format, when frames are converted before they are returned
+    pub output_format: Option<String>,
+    pub frame_threads: Option<usize>,
+    pub prefetch_depth: Option<usize>,
+    pub max_outstanding_frames: Option<usize>,
+    pub buffered_frames: usize,
+    pub versions: RuntimeVersions,
+}
+
+impl std::fmt::Display for PipelineReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let opt = |x: &Option<String>| x.clone().unwrap_or_else(|| "-".into());
+        let num = |x: Option<usize>| x.map(|x| x.to_string()).unwrap_or_else(|| "-".into());
+        writeln!(f, "input:          {} (extension {}, container {})", self.input, opt(&self.extension), self.container)?;
+        writeln!(f, "backend:        {}, codec {}", self.backend, opt(&self.video_codec))?;
+        writeln!(f, "hwaccel:        {} on {}{}", opt(&self.hwaccel), opt(&self.hw_device_name), if self.software_fallback { " (fell back to software)" } else { "" })?;
+        writeln!(f, "formats:        hw {}, sw {}, output {}", opt(&self.hw_format), opt(&self.sw_format), opt(&self.output_format))?;
+        writeln!(f, "decode size:    {}", self.decode_size.map(|(w, h)| format!("{w}x{h}")).unwrap_or_else(|| "-".into()))?;
+        writeln!(f, "threads:        {}, prefetch depth {}", num(self.frame_threads), num(self.prefetch_depth))?;
+        writeln!(f, "frames:         {} buffered, limit {}", self.buffered_frames, num(self.max_outstanding_frames))?;
+        write!(f, "{}", self.versions)
+    }
+}
+
+/// Result of `Decoder::close`
+#[derive(Debug, Clone, Default)]
+pub struct CloseReport {
+    pub elapsed: Duration,
+    /// Frames still held by the consumer when the timeout expired. They stay valid and release their
+    /// buffers and device references when dropped
+    pub outstanding_frames: usize,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -30,43 +305,1120 @@ pub struct Stream {
     pub time_base: (i32, i32),
     pub avg_frame_rate: (i32, i32),
     pub rate: (i32, i32),
+    pub codec_name: String,
+
+    // Video
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// `None` for audio and for coded formats without a `PixelFormat`
+    pub pixel_format: Option<PixelFormat>,
+
+    // Audio
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u32>,
+
+    /// Bits per sample of the coded stream when the container or codec reports it
+    pub bit_depth: Option<u32>,
 
     pub decode: bool,
 }
 
+#[derive(Debug, Clone)]
+pub struct StreamDescriptor {
+    pub stream_type: StreamType,
+    pub index: usize,
+    pub codec_name: String,
+    pub bitrate: usize,
+    pub language: Option<String>,
+    pub title: Option<String>,
+
+    // Video
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub avg_frame_rate: (i32, i32),
+
+    // Audio
+    pub channels: Option<u32>,
+    pub sample_rate: Option<u32>,
+}
+
 #[enum_delegate::register]
 pub trait DecoderInterface {
     fn streams(&mut self) -> Vec<&mut Stream>;
+    fn stream_infos(&self) -> Vec<StreamDescriptor>;
+    /// `timestamp_us` is already clamped to the stream range by `Decoder`.
+    /// Must drop every frame queued for the old position, so the next frame comes from the new one
     fn seek(&mut self, timestamp_us: i64) -> bool;
+    /// Seeks to the keyframe at or before `timestamp_us` and returns its timestamp
+    fn seek_to_keyframe(&mut self, timestamp_us: i64) -> Option<i64>;
+    /// Seeks to the very beginning of the input, so the next frame is its first frame
+    fn reset_to_start(&mut self) -> bool;
+    /// Timestamp where the input starts, e.g. later than 0 for MPEG-TS. 0 when unknown
+    fn start_time_us(&self) -> i64;
 
     fn next_frame(&mut self) -> Option<Frame>;
+    /// Reads the next packet of any stream without decoding it
+    fn next_packet(&mut self) -> Option<PacketInfo>;
 
+    /// `NoVideoStream` for inputs without video, e.g. audio-only files
     fn get_video_info(&self) -> Result<VideoInfo, VideoProcessingError>;
+    fn get_audio_info(&self) -> Result<AudioInfo, VideoProcessingError>;
+    fn chapters(&self) -> Vec<Chapter>;
+
+    /// Incremented every time the decoded video dimensions or format change
+    fn video_params_generation(&self) -> u64;
+    fn take_diagnostics(&mut self) -> Vec<Diagnostic>;
+    /// Device used for hardware decoding, available once the video decoder was opened by `next_frame`
+    fn hw_device(&self) -> Option<HWDeviceInfo>;
+
+    /// Counter of the video frames created by this backend and not dropped yet
+    fn frame_leases(&self) -> FrameLeases;
+    /// Frames decoded but held back internally, e.g. for reordering
+    fn buffered_frames(&self) -> usize;
+    /// Estimate of the frames held inside the codec (reference frames, reordering delay and frame threads),
+    /// which the consumer can't release and aren't counted by `frame_leases`
+    fn pool_frames(&self) -> usize;
+
+    /// Available once the video decoder was opened by `next_frame`
+    fn backend_config(&self) -> BackendConfig;
+    fn set_prefetch_depth(&mut self, depth: usize);
+    /// Aborts blocking IO once `deadline` passes, `None` clears it. Returns whether the previous deadline interrupted a read.
+    /// An interrupted `next_frame` returns `None` and keeps its state, so the next call continues where it stopped
+    fn set_io_deadline(&mut self, deadline: Option<Instant>) -> bool;
+    /// Formats the frames of this backend can be in, each with working `format()`, `plane_layouts` and `get_cpu_buffers`
+    fn supported_output_formats(&self) -> Vec<PixelFormat>;
+    /// Error which ended decoding, `next_frame` returns `None` after it
+    fn take_error(&mut self) -> Option<VideoProcessingError>;
+    /// Audio packets demuxed by `next_frame` since the last call, see `DecoderOptions::audio_demux_only`
+    fn take_audio_packets(&mut self) -> Vec<PacketInfo>;
+    fn pipeline_report(&self) -> PipelineReport;
+
+    /// `custom_options` keys nothing in the backend consumes, with the closest recognized key
+    fn unknown_options(&self) -> Vec<(String, Option<String>)>;
+
+    /// Releases the codec contexts, buffered frames and hw device references. `next_frame` returns `None` afterwards
+    fn close(&mut self);
 }
 
 pub struct Decoder {
-    inner: DecoderBackend
+    inner: DecoderBackend,
+    analyzer: Option<analysis::FrameAnalyzer>,
+    diagnostics: Option<DiagnosticsCallback>,
+    preroll_limits: (Option<usize>, Option<u64>),
+    // Frames already decoded by the backend, returned by `next_frame` first
+    pending_frames: VecDeque<Frame>,
+    poster_frame: Option<VideoFrame>,
+    stats: DecoderStats,
+    leases: FrameLeases,
+    max_outstanding_frames: Option<usize>,
+    // Whether `FrameBudgetLow` was reported since the count last dropped below the threshold
+    budget_low: bool,
+    // Whether `Diagnostic::Pipeline` was emitted, on the first video frame
+    pipeline_reported: bool,
+    // See `position_us`. Audio frames only move it when there's no video stream
+    position_us: i64,
+    has_video: bool,
+    closed: bool,
+    // `DecoderOptions::merged_ranges_us` and the index of the range being decoded, all ranges are done when it's past the end
+    ranges_us: Vec<(i64, i64)>,
+    range_index: usize,
+    // Start time and last frame timestamp of the input, read on the first seek
+    seek_bounds_us: Option<(i64, Option<i64>)>,
 }
 
+/// Poster frames are downscaled by this factor in each dimension
+const POSTER_SCALE: u32 = 8;
+/// `lowres` of the poster frame decoder, 2^3 = `POSTER_SCALE`
+const POSTER_LOWRES: u32 = 3;
+/// `Decoder::seek_audio_precise` starts decoding this much before the target
+pub const AUDIO_SEEK_PREROLL_US: i64 = 100_000;
+/// `Decoder::seek_audio_precise` gives up when the video is this far past the target without audio, e.g. at a gap in the audio
+pub const AUDIO_SEEK_MAX_VIDEO_LEAD_US: i64 = 1_000_000;
+
 impl Decoder {
     pub fn new(path: &str, options: DecoderOptions) -> Result<Self, VideoProcessingError> {
-        Ok(Self {
-            inner: DecoderBackend::FfmpegDecoder(FfmpegDecoder::new(path, options)?)
-        })
+        Self::new_from_io(IoType::from(path), options)
+    }
+
+    pub fn new_from_io(io: IoType, options: DecoderOptions) -> Result<Self, VideoProcessingError> {
+        crate::backend::ensure_available("ffmpeg")?;
+        let analyzer = if options.analysis.is_enabled() { Some(analysis::FrameAnalyzer::new(options.analysis)) } else { None };
+        let diagnostics = options.diagnostics.clone();
+        let preroll_limits = (options.max_preroll_frames, options.max_preroll_ms);
+        let poster = options.decode_poster_frame.then(|| (options.poster_timestamp_us, poster_options(&options)));
+        // Paths can be opened a second time for the poster frame, custom IO can't
+        let reopen = match &io {
+            IoType::FileOrUrl(path) => Some(IoType::FileOrUrl(path.clone())),
+            IoType::FileList(files) => Some(IoType::FileList(files.clone())),
+            _ => None
+        };
+        let max_outstanding_frames = options.max_outstanding_frames;
+        let strict_options = options.strict_options;
+        let ranges_us = options.merged_ranges_us();
+        let inner = DecoderBackend::FfmpegDecoder(FfmpegDecoder::new(io, options)?);
+        let unknown_options = inner.unknown_options();
+        let has_video = inner.stream_infos().iter().any(|x| matches!(x.stream_type, StreamType::Video));
+        let mut decoder = Self {
+            leases: inner.frame_leases(),
+            max_outstanding_frames,
+            budget_low: false,
+            pipeline_reported: false,
+            position_us: 0,
+            has_video,
+            closed: false,
+            inner,
+            analyzer,
+            diagnostics,
+            preroll_limits,
+            pending_frames: VecDeque::new(),
+            poster_frame: None,
+            stats: DecoderStats::default(),
+            ranges_us,
+            range_index: 0,
+            seek_bounds_us: None,
+        };
+        if !unknown_options.is_empty() {
+            if strict_options {
+                return Err(VideoProcessingError::UnknownOptions(unknown_options));
+            }
+            for (key, suggestion) in unknown_options {
+                decoder.emit_diagnostic(&Diagnostic::UnknownOption { key, suggestion });
+            }
+        }
+        if let Some((timestamp_us, options)) = poster {
+            decoder.poster_frame = match reopen {
+                Some(io) => Self::decode_poster_frame_separately(io, timestamp_us, options),
+                None => decoder.decode_poster_frame(timestamp_us),
+            };
+        }
+        if let Some(&(start, _)) = decoder.ranges_us.first().filter(|x| x.0 > 0) {
+            // Frames kept from the poster frame decoding are before the range
+            decoder.pending_frames.clear();
+            decoder.position_us = start;
+            decoder.inner.seek(start);
+        }
+        Ok(decoder)
+    }
+
+    /// Decodes the poster frame with a second decoder in the reduced resolution mode of the codec (`lowres`), so the main decoder
+    /// keeps its settings and position. Codecs without that mode are decoded in full resolution and downscaled
+    fn decode_poster_frame_separately(io: IoType, timestamp_us: Option<i64>, options: DecoderOptions) -> Option<VideoFrame> {
+        let mut decoder = FfmpegDecoder::new(io, options).map_err(|e| log::error!("Failed to open the poster frame decoder: {e:?}")).ok()?;
+        let full_width = decoder.get_video_info().ok()?.width;
+        for stream in decoder.streams() {
+            stream.decode = matches!(stream.stream_type, StreamType::Video);
+        }
+        if let Some(ts) = timestamp_us {
+            if !decoder.seek(ts) { return None; }
+        }
+        let mut frame = std::iter::from_fn(|| decoder.next_frame()).find_map(|f| match f { Frame::Video(v) => Some(v), _ => None })?;
+        // What `lowres` didn't reduce yet
+        let factor = (frame.width() * POSTER_SCALE / full_width.max(1)).max(1);
+        if factor == 1 { return Some(frame); }
+        frame.downscaled(factor).map_err(|e| log::error!("Failed to downscale the poster frame: {e:?}")).ok()
+    }
+
+    /// Poster frame of custom IO, which can only be opened once. Decoded with the main decoder and downscaled,
+    /// the frames decoded on the way are returned by `next_frame` or the decoder is moved back to the start
+    fn decode_poster_frame(&mut self, timestamp_us: Option<i64>) -> Option<VideoFrame> {
+        if let Some(ts) = timestamp_us {
+            if !self.inner.seek(ts) { return None; }
+        }
+        let mut frame = None;
+        while let Some(f) = self.inner.next_frame() {
+            match f {
+                Frame::Video(v) => { frame = Some(v); break; },
+                // Keep everything decoded before the first video frame, so the main decode position isn't affected
+                other => if timestamp_us.is_none() { self.pending_frames.push_back(other); }
+            }
+        }
+        let mut frame = frame?;
+        let poster = frame.downscaled(POSTER_SCALE).map_err(|e| log::error!("Failed to downscale the poster frame: {e:?}")).ok();
+        if timestamp_us.is_some() {
+            // A range starting later is seeked to by `new_from_io` anyway
+            if self.ranges_us.first().is_none_or(|x| x.0 <= 0) {
+                self.inner.seek(0);
+            }
+        } else {
+            self.pending_frames.push_back(Frame::Video(frame));
+        }
+        poster
+    }
+    /// Reduced resolution frame decoded during open when `DecoderOptions::decode_poster_frame` is set
+    pub fn take_poster_frame(&mut self) -> Option<VideoFrame> {
+        self.poster_frame.take()
     }
 
     pub fn streams(&mut self) -> Vec<&mut Stream> {
         self.inner.streams()
     }
+    pub fn stream_infos(&self) -> Vec<StreamDescriptor> {
+        self.inner.stream_infos()
+    }
+    /// Out of range timestamps are clamped for all backends: the ones before the start time of the input seek to the start,
+    /// the ones past the end to the last frame
+    pub fn seek(&mut self, timestamp_us: i64) -> bool {
+        self.pending_frames.clear();
+        self.stats.seeks += 1;
+        let timestamp_us = self.clamp_seek_timestamp(timestamp_us);
+        self.position_us = timestamp_us;
+        self.sync_range_index(timestamp_us);
+        self.inner.seek(timestamp_us)
+    }
+    /// Restarts from the first frame, e.g. for looping playback. Unlike `seek(0)` it seeks to the start time of the input instead of
+    /// timestamp 0, which lands on a later keyframe when the streams start later, and skips reading the stream info to clamp the timestamp
+    pub fn reset_to_start(&mut self) -> bool {
+        if let Some(&(start, _)) = self.ranges_us.first().filter(|x| x.0 > 0) {
+            return self.seek(start);
+        }
+        self.range_index = 0;
+        self.pending_frames.clear();
+        self.stats.seeks += 1;
+        self.position_us = 0;
+        self.inner.reset_to_start()
+    }
+    /// Continues with the first range ending after `timestamp_us`, seeking past the last range ends the decoding
+    fn sync_range_index(&mut self, timestamp_us: i64) {
+        self.range_index = self.ranges_us.iter().position(|x| x.1 > timestamp_us).unwrap_or(self.ranges_us.len());
+    }
+    fn clamp_seek_timestamp(&mut self, timestamp_us: i64) -> i64 {
+        let (start_us, last_us) = *self.seek_bounds_us.get_or_insert_with(|| {
+            let start_us = self.inner.start_time_us();
+            // The durations are counted from the start time
+            let duration_us = match self.inner.get_video_info() {
+                Ok(info) if info.duration_ms > 0.0 && info.fps > 0.0 => Some((info.duration_ms * 1000.0 - 1_000_000.0 / info.fps).max(0.0) as i64),
+                _ => self.inner.get_audio_info().ok().filter(|x| x.duration_ms > 0.0).map(|x| (x.duration_ms * 1000.0) as i64)
+            };
+            (start_us, duration_us.map(|x| start_us + x))
+        });
+        let timestamp_us = timestamp_us.max(start_us);
+        last_us.map_or(timestamp_us, |last| timestamp_us.min(last))
+    }
+    /// Lands exactly on the keyframe at or before `timestamp_us`, so a stream copy can start there.
+    /// Returns the keyframe timestamp, the next video frame is that keyframe.
+    pub fn seek_to_keyframe_at_or_before(&mut self, timestamp_us: i64) -> Option<i64> {
+        self.pending_frames.clear();
+        self.stats.seeks += 1;
+        let timestamp_us = self.clamp_seek_timestamp(timestamp_us);
+        self.position_us = timestamp_us;
+        self.sync_range_index(timestamp_us);
+        let keyframe_us = self.inner.seek_to_keyframe(timestamp_us);
+        if let Some(ts) = keyframe_us {
+            self.position_us = ts;
+        }
+        keyframe_us
+    }
+    /// Seeks to the preceding keyframe and decodes up to the video frame containing `timestamp_us`.
+    /// That frame is returned by the next call to `next_frame`. Out of range timestamps are clamped like in `seek`
+    pub fn seek_precise(&mut self, timestamp_us: i64) -> SeekResult {
+        let timestamp_us = self.clamp_seek_timestamp(timestamp_us);
+        if !self.seek(timestamp_us) {
+            return SeekResult::Failed;
+        }
+        let (max_frames, max_ms) = self.preroll_limits;
+        let started = Instant::now();
+        let mut preroll = 0usize;
+        let mut closest: Option<(Frame, i64)> = None;
+        let result = loop {
+            let frame = match self.inner.next_frame() {
+                Some(Frame::Video(v)) => v,
+                Some(_) => continue,
+                None => break None
+            };
+            let Some(ts) = frame.timestamp_us() else { continue; };
+            if ts + frame.duration_us().unwrap_or(1).max(1) > timestamp_us {
+                break Some((Frame::Video(frame), ts, true));
+            }
+            preroll += 1;
+            closest = Some((Frame::Video(frame), ts));
+            let over_frames = max_frames.is_some_and(|max| preroll >= max);
+            let over_time = max_ms.is_some_and(|max| started.elapsed().as_millis() as u64 >= max);
+            if over_frames || over_time {
+                break closest.take().map(|(f, ts)| (f, ts, false));
+            }
+        };
+        self.forward_diagnostics();
+        let result = result.or_else(|| closest.map(|(f, ts)| (f, ts, false)));
+
+        self.stats.last_preroll_frames = preroll as u64;
+        self.stats.preroll_frames_total += preroll as u64;
+        self.stats.max_preroll_frames = self.stats.max_preroll_frames.max(preroll as u64);
+
+        match result {
+            Some((frame, achieved_us, exact)) => {
+                // Preroll frames are discarded, only the returned frame goes through the analyzer in `next_frame`
+                self.pending_frames.push_back(frame);
+                if exact {
+                    SeekResult::Exact { achieved_us }
+                } else {
+                    self.stats.approximate_seeks += 1;
+                    SeekResult::Approximate { achieved_us }
+                }
+            }
+            None => SeekResult::Failed
+        }
+    }
+    /// Sample-accurate audio seek. Seeks `AUDIO_SEEK_PREROLL_US` earlier so the decoder converges (e.g. Opus needs 80 ms),
+    /// discards the samples before `timestamp_us` and trims the first audio frame to start exactly at it.
+    /// That frame is returned by `next_frame` after the video frames decoded on the way, see `AudioFrameInterface::sample_position`
+    /// for the achieved position. Fails right away when no audio stream is decoded
+    pub fn seek_audio_precise(&mut self, timestamp_us: i64) -> SeekResult {
+        if !self.inner.streams().iter().any(|x| matches!(x.stream_type, StreamType::Audio) && x.decode) {
+            return SeekResult::Failed;
+        }
+        let timestamp_us = self.clamp_seek_timestamp(timestamp_us);
+        if !self.seek((timestamp_us - AUDIO_SEEK_PREROLL_US).max(0)) {
+            return SeekResult::Failed;
+        }
+        self.position_us = timestamp_us;
+        let mut result = SeekResult::Failed;
+        while let Some(frame) = self.inner.next_frame() {
+            let frame = match frame {
+                Frame::Audio(frame) => frame,
+                Frame::Video(frame) => {
+                    let past_target = frame.timestamp_us().is_some_and(|ts| ts > timestamp_us + AUDIO_SEEK_MAX_VIDEO_LEAD_US);
+                    self.pending_frames.push_back(Frame::Video(frame));
+                    if past_target { break; }
+                    continue;
+                },
+                _ => continue
+            };
+            let (Some(ts), rate) = (frame.timestamp_us(), frame.sample_rate() as i64) else { continue; };
+            if rate <= 0 { continue; }
+            let skip = ((timestamp_us - ts) * rate + 500_000).div_euclid(1_000_000).max(0) as usize;
+            if skip >= frame.samples() { continue; }
+            let frame = if skip > 0 {
+                match frame.trimmed_start(skip) {
+                    Ok(x) => x,
+                    Err(e) => { log::error!("Failed to trim the audio frame: {e:?}"); break; }
+                }
+            } else {
+                frame
+            };
+            let achieved_us = frame.timestamp_us().unwrap_or(ts);
+            // Starts after the target when the stream has a gap there
+            result = if achieved_us - timestamp_us < 1_000_000 / rate + 1 { SeekResult::Exact { achieved_us } } else { SeekResult::Approximate { achieved_us } };
+            self.pending_frames.push_back(Frame::Audio(frame));
+            break;
+        }
+        self.forward_diagnostics();
+        if matches!(result, SeekResult::Approximate { .. }) {
+            self.stats.approximate_seeks += 1;
+        }
+        result
+    }
+    /// Reads the next compressed packet without decoding. Packets read this way are not decoded by `next_frame`,
+    /// so don't mix the two without seeking in between
+    pub fn next_packet(&mut self) -> Option<PacketInfo> {
+        self.pending_frames.clear();
+        let packet = self.inner.next_packet();
+        self.forward_diagnostics();
+        packet
+    }
+    pub fn stats(&self) -> &DecoderStats {
+        &self.stats
+    }
+    /// Audio packets read by `next_frame` since the last call, in demux order, when `DecoderOptions::audio_demux_only` is set.
+    /// Collected until taken, so call it regularly. Seeking drops the ones not taken yet
+    pub fn take_audio_packets(&mut self) -> Vec<PacketInfo> {
+        self.inner.take_audio_packets()
+    }
+    /// Playhead position: the timestamp of the most recently returned video frame (audio frame for inputs without video),
+    /// or the target of the last seek until a frame is returned after it
+    pub fn position_us(&self) -> i64 {
+        self.position_us
+    }
+    /// `None` at the end of the stream, and also while `max_outstanding_frames` is reached. Use `try_next_frame` to tell these apart
     pub fn next_frame(&mut self) -> Option<Frame> {
-        self.inner.next_frame()
+        if let Err(e) = self.check_frame_budget() {
+            log::warn!("{e}");
+            return None;
+        }
+        self.decode_next()
+    }
+    /// Like `next_frame`, with the video frame returned at the resolution of `mode`, e.g. `Eighth` while scrubbing and `Full` for the paused frame.
+    /// Can change on every call without reopening. The ffmpeg backend decodes at full resolution and returns a downscaled CPU copy
+    pub fn next_frame_at_mode(&mut self, mode: DecodeMode) -> Option<Frame> {
+        match self.next_frame()? {
+            Frame::Video(mut v) if mode != DecodeMode::Full => match v.downscaled(mode.factor()) {
+                Ok(scaled) => Some(Frame::Video(scaled)),
+                Err(e) => {
+                    log::error!("Failed to downscale the frame to {mode:?}: {e:?}");
+                    Some(Frame::Video(v))
+                }
+            },
+            frame => Some(frame)
+        }
+    }
+    /// Like `try_next_frame`, but gives up with `TimedOut` when no frame is available within `timeout`, e.g. for stalled network sources.
+    /// Blocking IO is aborted at the deadline. Decoding itself can't be interrupted, a frame finished after the deadline
+    /// is kept and returned by the next call. The decoder stays usable after a timeout, including seeking. The next call continues
+    /// with the packet that was being read, seekable inputs are read again from the last keyframe as the demuxer may have skipped it
+    pub fn next_frame_timeout(&mut self, timeout: Duration) -> Result<Option<Frame>, VideoProcessingError> {
+        if !self.pending_frames.is_empty() {
+            return Ok(self.decode_next());
+        }
+        self.check_frame_budget()?;
+        let started = Instant::now();
+        self.inner.set_io_deadline(Some(started + timeout));
+        let frame = self.next_frame_in_ranges();
+        let interrupted = self.inner.set_io_deadline(None);
+        if interrupted || started.elapsed() > timeout {
+            if let Some(frame) = frame {
+                self.pending_frames.push_back(frame);
+            }
+            self.forward_diagnostics();
+            return Err(VideoProcessingError::TimedOut(timeout));
+        }
+        let frame = self.process_frame(frame);
+        self.frame_or_error(frame)
+    }
+    /// Like `next_frame`, but returns `WouldBlock` instead of waiting when `max_outstanding_frames` is reached
+    pub fn try_next_frame(&mut self) -> Result<Option<Frame>, VideoProcessingError> {
+        self.check_frame_budget()?;
+        let frame = self.decode_next();
+        self.frame_or_error(frame)
+    }
+    /// `WouldBlock` when decoding another frame would exceed `max_outstanding_frames`. Frames already decoded are always returned,
+    /// and it never fails while the consumer holds no frames, as only dropping them can lower the count
+    fn check_frame_budget(&self) -> Result<(), VideoProcessingError> {
+        let Some(limit) = self.max_outstanding_frames.filter(|_| self.pending_frames.is_empty()) else { return Ok(()); };
+        let leased = self.leases.outstanding();
+        let outstanding = leased + self.inner.pool_frames();
+        if outstanding >= limit && leased > self.inner.buffered_frames() {
+            return Err(VideoProcessingError::WouldBlock { outstanding, limit });
+        }
+        Ok(())
+    }
+    /// `None` from the backend is an error instead of the end of the stream when decoding failed, e.g. with `GPUDecodingFailed`
+    fn frame_or_error(&mut self, frame: Option<Frame>) -> Result<Option<Frame>, VideoProcessingError> {
+        match frame {
+            None => self.inner.take_error().map_or(Ok(None), Err),
+            frame => Ok(frame)
+        }
+    }
+    /// Video frames returned by this decoder (or buffered inside it) that weren't dropped yet, plus the estimated frames in the codec's pool
+    pub fn outstanding_frames(&self) -> usize {
+        self.leases.outstanding() + self.inner.pool_frames()
+    }
+    fn decode_next(&mut self) -> Option<Frame> {
+        let frame = self.pending_frames.pop_front().or_else(|| self.next_frame_in_ranges());
+        self.process_frame(frame)
+    }
+    /// Next frame of the backend within `ranges_us`. Frames before the current range (the preroll after seeking) are discarded,
+    /// the first video frame past its end (audio frame without video) moves on to the next range
+    fn next_frame_in_ranges(&mut self) -> Option<Frame> {
+        if self.ranges_us.is_empty() {
+            return self.inner.next_frame();
+        }
+        let mut next = None;
+        loop {
+            let &(start, end) = self.ranges_us.get(self.range_index)?;
+            let frame = match next.take() {
+                Some(frame) => frame,
+                None => self.inner.next_frame()?
+            };
+            let (ts, duration, drives) = match &frame {
+                Frame::Video(v) => (v.timestamp_us(), v.duration_us().unwrap_or(1).max(1), true),
+                Frame::Audio(a) => (a.timestamp_us(), 1, !self.has_video),
+            };
+            let Some(ts) = ts else { return Some(frame); };
+            if ts + duration <= start { continue; }
+            if ts < end { return Some(frame); }
+            if !drives { continue; }
+
+            self.range_index += 1;
+            let &(next_start, _) = self.ranges_us.get(self.range_index)?;
+            if ts < next_start {
+                self.inner.seek(next_start);
+            } else {
+                // Already in or past the next range, e.g. after a gap in the stream
+                next = Some(frame);
+            }
+        }
+    }
+    /// Emits the backend diagnostics and runs the analysis of a frame about to be returned
+    fn process_frame(&mut self, mut frame: Option<Frame>) -> Option<Frame> {
+        self.forward_diagnostics();
+        let timestamp_us = match &frame {
+            Some(Frame::Video(v)) => v.timestamp_us(),
+            Some(Frame::Audio(a)) if !self.has_video => a.timestamp_us(),
+            _ => None
+        };
+        if let Some(ts) = timestamp_us {
+            self.position_us = ts;
+        }
+        if let (Some(analyzer), Some(Frame::Video(v))) = (self.analyzer.as_mut(), frame.as_mut()) {
+            for event in analyzer.analyze(v) {
+                self.emit_diagnostic(&event);
+            }
+        }
+        if let Some(limit) = self.max_outstanding_frames {
+            let outstanding = self.outstanding_frames();
+            let low = outstanding + (limit / 10).max(1) >= limit;
+            if low && !self.budget_low {
+                self.emit_diagnostic(&Diagnostic::FrameBudgetLow { outstanding, limit });
+            }
+            self.budget_low = low;
+        }
+        if !self.pipeline_reported && matches!(frame, Some(Frame::Video(_))) {
+            // After the first frame, so the negotiated formats are known
+            self.pipeline_reported = true;
+            self.emit_diagnostic(&Diagnostic::Pipeline(Box::new(self.pipeline_report())));
+        }
+        frame
+    }
+    fn forward_diagnostics(&mut self) {
+        for event in self.inner.take_diagnostics() {
+            if matches!(event, Diagnostic::DeviceLost { .. }) {
+                self.stats.device_recoveries += 1;
+            }
+            self.emit_diagnostic(&event);
+        }
+    }
+    fn emit_diagnostic(&self, event: &Diagnostic) {
+        match &self.diagnostics {
+            Some(cb) => (cb.0)(event),
+            None => log::info!("{event:?}")
+        }
     }
     pub fn get_video_info(&mut self) -> Result<VideoInfo, VideoProcessingError> {
         self.inner.get_video_info()
     }
+    pub fn get_audio_info(&mut self) -> Result<AudioInfo, VideoProcessingError> {
+        self.inner.get_audio_info()
+    }
+    pub fn chapters(&self) -> Vec<Chapter> {
+        self.inner.chapters()
+    }
+    pub fn hw_device(&self) -> Option<HWDeviceInfo> {
+        self.inner.hw_device()
+    }
+    pub fn backend_config(&self) -> BackendConfig {
+        self.inner.backend_config()
+    }
+    /// Pixel formats this decoder can return and read back, validated at runtime. Frames in other formats
+    /// (e.g. exotic hardware surfaces) report `PixelFormat::Unknown`, use `DecoderOptions::output_format` to get one of these
+    pub fn supported_output_formats(&self) -> Vec<PixelFormat> {
+        self.inner.supported_output_formats()
+    }
+    /// Input context of the ffmpeg backend, for avformat calls this crate doesn't wrap. Seeking or reading packets through it
+    /// desyncs the decoder. With `DecoderOptions::background_demux` it's a copy of the stream info without IO, the input is owned by the demux thread
+    #[cfg(feature = "ffmpeg-raw")]
+    pub fn as_ffmpeg_input(&self) -> Option<&ffmpeg_next::format::context::Input> {
+        match &self.inner {
+            DecoderBackend::FfmpegDecoder(x) => Some(x.input())
+        }
+    }
+    /// Everything the decoder selected: backend, hwaccel, negotiated formats, pool sizes and library versions
+    pub fn pipeline_report(&self) -> PipelineReport {
+        PipelineReport {
+            max_outstanding_frames: self.max_outstanding_frames,
+            ..self.inner.pipeline_report()
+        }
+    }
+    /// Limits how many frames the backend decodes ahead of the consumer, can be changed at any time to throttle a slow sink.
+    /// The ffmpeg backend decodes on demand, where this limits the frame threads (each keeps one frame in flight). The thread count of
+    /// an open codec can't change, so it only applies to video decoders opened afterwards: the first one is opened by the first
+    /// `next_frame`, later ones after a lost GPU or a fallback to software decoding. Use `DecoderOptions::prefetch_depth` to set it from the start
+    pub fn set_prefetch_depth(&mut self, depth: usize) {
+        self.inner.set_prefetch_depth(depth);
+    }
+    /// Decodes the remaining video frames and reports the timestamps where frames were dropped or duplicated
+    pub fn find_timestamp_issues(&mut self) -> Result<Vec<(i64, TimestampIssue)>, VideoProcessingError> {
+        let mut checker = TimestampChecker::new(self.get_video_info()?.fps);
+        let mut ret = Vec::new();
+        while let Some(frame) = self.next_frame() {
+            if let Frame::Video(v) = frame {
+                if let Some(ts) = v.timestamp_us() {
+                    if let Some(issue) = checker.push(ts) {
+                        ret.push((ts, issue));
+                    }
+                }
+            }
+        }
+        Ok(ret)
+    }
+    /// Cheap way to check if the surfaces allocated for the previous frames need to be reallocated
+    pub fn video_params_generation(&self) -> u64 {
+        self.inner.video_params_generation()
+    }
+
+    /// Tears the decoder down, releasing the backend resources and waiting up to `timeout` for the consumer to drop the returned frames.
+    /// Dropping the decoder does the same without waiting
+    pub fn close(mut self, timeout: Duration) -> Result<(), CloseReport> {
+        let report = self.shutdown(timeout);
+        if report.outstanding_frames == 0 { Ok(()) } else { Err(report) }
+    }
+    fn shutdown(&mut self, timeout: Duration) -> CloseReport {
+        let started = Instant::now();
+        self.closed = true;
+        self.pending_frames.clear();
+        self.poster_frame = None;
+        self.inner.close();
+        let outstanding_frames = self.leases.wait_released(timeout);
+        if outstanding_frames > 0 {
+            log::debug!("Decoder closed with {outstanding_frames} frames still alive");
+        }
+        CloseReport { elapsed: started.elapsed(), outstanding_frames }
+    }
+}
+
+impl Drop for Decoder {
+    fn drop(&mut self) {
+        if !self.closed {
+            self.shutdown(Duration::ZERO);
+        }
+    }
 }
 
 #[enum_delegate::implement(DecoderInterface)]
 pub enum DecoderBackend {
     FfmpegDecoder(FfmpegDecoder)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn first_video_frame(decoder: &mut Decoder) -> VideoFrame {
+        std::iter::from_fn(|| decoder.next_frame()).find_map(|f| match f { Frame::Video(v) => Some(v), _ => None }).unwrap()
+    }
+
+    #[test]
+    fn supported_output_formats() {
+        let path = crate::util::test::temp_path("output-formats.mp4");
+        crate::util::test::write_test_clip(&path, crate::EncoderCodec::H264, (64, 48), 2, 25.0).unwrap();
+        let mut decoder = Decoder::new(&path, DecoderOptions::default()).unwrap();
+        let supported = decoder.supported_output_formats();
+        let frame = first_video_frame(&mut decoder);
+        assert!(supported.contains(&frame.format()));
+
+        for format in [PixelFormat::NV12, PixelFormat::P010LE, PixelFormat::RGBA, PixelFormat::BGRA, PixelFormat::YUV422P10LE, PixelFormat::GBRP12LE, PixelFormat::RGB48LE] {
+            assert!(supported.contains(&format), "{format:?}");
+            let mut decoder = Decoder::new(&path, DecoderOptions { output_format: Some(format), ..Default::default() }).unwrap();
+            let mut frame = first_video_frame(&mut decoder);
+            assert_eq!(frame.format(), format);
+            assert_eq!(frame.get_cpu_buffers().unwrap().len(), format.plane_layouts().len(), "{format:?}");
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn prefetch_depth() {
+        let path = crate::util::test::temp_path("prefetch.mp4");
+        crate::util::test::write_test_clip(&path, crate::EncoderCodec::H264, (320, 240), 12, 24.0).unwrap();
+        let mut decoder = Decoder::new(&path, DecoderOptions { prefetch_depth: Some(1), ..Default::default() }).unwrap();
+        first_video_frame(&mut decoder);
+        assert_eq!(decoder.pipeline_report().frame_threads, Some(1));
+        // The open codec keeps its threads
+        decoder.set_prefetch_depth(3);
+        first_video_frame(&mut decoder);
+        let report = decoder.pipeline_report();
+        assert_eq!((report.prefetch_depth, report.frame_threads), (Some(3), Some(1)));
+        drop(decoder);
+
+        // Before the first frame it still applies
+        let mut decoder = Decoder::new(&path, DecoderOptions::default()).unwrap();
+        decoder.set_prefetch_depth(2);
+        first_video_frame(&mut decoder);
+        assert_eq!(decoder.pipeline_report().frame_threads, Some(2));
+        drop(decoder);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn deterministic_decoding() {
+        let path = crate::util::test::temp_path("deterministic.mp4");
+        crate::util::test::write_test_clip(&path, crate::EncoderCodec::H264, (320, 240), 24, 24.0).unwrap();
+        let hashes = || {
+            let mut decoder = Decoder::new(&path, DecoderOptions { deterministic: true, gpu_index: Some(0), ..Default::default() }).unwrap();
+            let mut hashes = Vec::new();
+            while let Some(frame) = decoder.next_frame() {
+                if let Frame::Video(mut v) = frame {
+                    hashes.push(crate::util::test::frame_hash(&mut v).unwrap());
+                }
+            }
+            // Hardware decoding is skipped even though a GPU was requested
+            assert!(decoder.hw_device().is_none());
+            hashes
+        };
+        let (first, second) = (hashes(), hashes());
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(first.len(), 24);
+        assert_eq!(first, second);
+        // Every frame of the clip has a different color
+        assert!(first.windows(2).all(|w| w[0] != w[1]));
+    }
+
+    #[test]
+    fn device_limits() {
+        let limits = DeviceLimits { min_size: (64, 32), max_size: (4096, 2304) };
+        assert!(limits.check((1920, 1080)).is_ok());
+        assert!(limits.check((4096, 2304)).is_ok());
+        assert!(limits.check((64, 32)).is_ok());
+        assert!(matches!(limits.check((8192, 4320)), Err(VideoProcessingError::ExceedsDeviceLimits { needed: (8192, 4320), limit: (4096, 2304), .. })));
+        // One dimension is enough
+        assert!(matches!(limits.check((4097, 100)), Err(VideoProcessingError::ExceedsDeviceLimits { .. })));
+        assert!(matches!(limits.check((1920, 2305)), Err(VideoProcessingError::ExceedsDeviceLimits { .. })));
+        assert!(matches!(limits.check((48, 48)), Err(VideoProcessingError::BelowDeviceMinimum { needed: (48, 48), minimum: (64, 32) })));
+        assert!(matches!(limits.check((128, 16)), Err(VideoProcessingError::BelowDeviceMinimum { .. })));
+        // Unknown limits aren't checked
+        let unknown = DeviceLimits { min_size: (0, 0), max_size: (0, 0) };
+        assert!(unknown.check((16384, 16384)).is_ok());
+        assert!(unknown.check((1, 1)).is_ok());
+        assert!(DeviceLimits { min_size: (0, 0), max_size: (0, 2160) }.check((16384, 2160)).is_ok());
+    }
+
+    #[test]
+    fn poster_frame_is_reduced() {
+        // ProRes has a reduced resolution mode, H.264 is downscaled after decoding
+        for (codec, name) in [(EncoderCodec::ProRes, "poster.mov"), (EncoderCodec::H264, "poster.mp4")] {
+            let path = crate::util::test::temp_path(name);
+            crate::util::test::write_test_clip(&path, codec, (1920, 1080), 24, 24.0).unwrap();
+
+            let mut decoder = Decoder::new(&path, DecoderOptions { decode_poster_frame: true, poster_timestamp_us: Some(500_000), ranges_ms: vec![(250.0, 1000.0)], ..Default::default() }).unwrap();
+            let poster = decoder.take_poster_frame().unwrap();
+            assert_eq!(poster.width(), 240, "{codec:?}");
+            assert!((134..=135).contains(&poster.height()), "{codec:?} {}", poster.height());
+            // The main decoder keeps its full resolution settings and starts at the range
+            let frame = first_video_frame(&mut decoder);
+            assert_eq!((frame.width(), frame.height()), (1920, 1080));
+            assert_eq!(frame.timestamp_us(), Some(250_000));
+
+            let mut decoder = Decoder::new(&path, DecoderOptions::default()).unwrap();
+            assert!(decoder.take_poster_frame().is_none());
+            let _ = std::fs::remove_file(&path);
+        }
+    }
+
+    #[test]
+    fn poster_frame_from_custom_io() {
+        let path = crate::util::test::temp_path("poster-io.mp4");
+        crate::util::test::write_test_clip(&path, EncoderCodec::H264, (640, 360), 24, 24.0).unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        let mut decoder = Decoder::new_from_io(IoType::Reader(Box::new(file)), DecoderOptions { decode_poster_frame: true, poster_timestamp_us: Some(500_000), ..Default::default() }).unwrap();
+        assert_eq!(decoder.take_poster_frame().unwrap().width(), 80);
+        assert_eq!(first_video_frame(&mut decoder).timestamp_us(), Some(0));
+        drop(decoder);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn background_demux_of_reader() {
+        let path = crate::util::test::temp_path("background_demux.mp4");
+        crate::util::test::write_test_clip(&path, EncoderCodec::H264, (160, 120), 24, 24.0).unwrap();
+        let decode = |decoder: &mut Decoder| {
+            let mut hashes = Vec::new();
+            while let Some(frame) = decoder.next_frame() {
+                if let Frame::Video(mut v) = frame { hashes.push(crate::util::test::frame_hash(&mut v).unwrap()); }
+            }
+            hashes
+        };
+        let mut direct = Decoder::new(&path, DecoderOptions::default()).unwrap();
+        let expected = decode(&mut direct);
+        // A reader can't be opened a second time, the demux thread reads from the same input
+        let file = std::fs::File::open(&path).unwrap();
+        let mut decoder = Decoder::new_from_io(IoType::Reader(Box::new(file)), DecoderOptions { background_demux: true, ..Default::default() }).unwrap();
+        let (info, expected_info) = (decoder.get_video_info().unwrap(), direct.get_video_info().unwrap());
+        assert_eq!((info.duration_ms, info.frame_count, info.fps), (expected_info.duration_ms, expected_info.frame_count, expected_info.fps));
+        assert_eq!(info.metadata, expected_info.metadata);
+        assert_eq!(decoder.stream_infos().len(), direct.stream_infos().len());
+        assert_eq!(decode(&mut decoder), expected);
+        assert!(decoder.reset_to_start());
+        assert_eq!(first_video_frame(&mut decoder).timestamp_us(), Some(0));
+        drop(decoder);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(expected.len(), 24);
+    }
+
+    /// Serves `data` over HTTP with range requests. While `stall` is set, a response stops before byte `stall_at`
+    fn serve_stalling(data: Vec<u8>, stall_at: usize, stall: std::sync::Arc<std::sync::atomic::AtomicBool>) -> String {
+        use std::io::{ BufRead, Write };
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/clip.mp4", listener.local_addr().unwrap());
+        let data = std::sync::Arc::new(data);
+        std::thread::spawn(move || for mut conn in listener.incoming().flatten() {
+            let (data, stall) = (data.clone(), stall.clone());
+            std::thread::spawn(move || {
+                let mut reader = std::io::BufReader::new(conn.try_clone().unwrap());
+                let mut start = 0;
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap_or(0) == 0 { return; }
+                    if let Some(range) = line.to_ascii_lowercase().strip_prefix("range: bytes=") {
+                        start = range.split('-').next().and_then(|x| x.trim().parse().ok()).unwrap_or(0);
+                    }
+                    if line == "\r\n" { break; }
+                }
+                let len = data.len();
+                if start >= len {
+                    let _ = write!(conn, "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{len}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+                    return;
+                }
+                let header = format!("HTTP/1.1 206 Partial Content\r\nContent-Type: video/mp4\r\nAccept-Ranges: bytes\r\nContent-Range: bytes {start}-{}/{len}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", len - 1, len - start);
+                if conn.write_all(header.as_bytes()).is_err() { return; }
+                let mut pos = start;
+                while pos < len {
+                    while pos == stall_at && stall.load(std::sync::atomic::Ordering::Relaxed) {
+                        std::thread::sleep(Duration::from_millis(10));
+                    }
+                    let end = if pos < stall_at { stall_at.min(pos + 4096) } else { len.min(pos + 4096) };
+                    if conn.write_all(&data[pos..end]).is_err() { return; }
+                    pos = end;
+                }
+            });
+        });
+        url
+    }
+
+    #[test]
+    fn timeout_then_continue_or_seek() {
+        use std::sync::{ Arc, atomic::{ AtomicBool, Ordering } };
+        let path = crate::util::test::temp_path("stalling.mp4");
+        crate::util::test::write_test_clip(&path, EncoderCodec::H264, (320, 240), 48, 24.0).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        let timeout = Duration::from_millis(300);
+        fn push_video(hashes: &mut Vec<u64>, frame: Option<Frame>) -> bool {
+            match frame {
+                Some(Frame::Video(mut v)) => { hashes.push(crate::util::test::frame_hash(&mut v).unwrap()); true },
+                Some(_) => true,
+                None => false
+            }
+        }
+        let mut expected = Vec::new();
+        let mut direct = Decoder::new(&path, DecoderOptions::default()).unwrap();
+        while push_video(&mut expected, direct.next_frame()) { }
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(expected.len(), 48);
+        // Decodes until the server stalls in the middle of the file, the moov box is at the end and read at opening
+        let decode_until_stall = |url: &str, hashes: &mut Vec<u64>| {
+            let mut decoder = Decoder::new(url, DecoderOptions { open_timeout: Some(Duration::from_secs(5)), ..Default::default() }).unwrap();
+            loop {
+                match decoder.next_frame_timeout(timeout) {
+                    Ok(frame) => assert!(push_video(hashes, frame), "ended at the stall"),
+                    Err(VideoProcessingError::TimedOut(t)) => { assert_eq!(t, timeout); break; },
+                    Err(e) => panic!("{e:?}")
+                }
+            }
+            decoder
+        };
+        let stall = Arc::new(AtomicBool::new(true));
+        let stall_at = data.len() / 2;
+        let url = serve_stalling(data, stall_at, stall.clone());
+
+        // The next call continues where it stopped, without losing or repeating a frame
+        let mut hashes = Vec::new();
+        let mut decoder = decode_until_stall(&url, &mut hashes);
+        assert!(!hashes.is_empty() && hashes.len() < 48);
+        stall.store(false, Ordering::Relaxed);
+        while push_video(&mut hashes, decoder.next_frame_timeout(Duration::from_secs(5)).unwrap()) { }
+        assert_eq!(hashes, expected);
+
+        // Seeking right after the timeout
+        stall.store(true, Ordering::Relaxed);
+        let mut decoder = decode_until_stall(&url, &mut Vec::new());
+        stall.store(false, Ordering::Relaxed);
+        assert!(decoder.reset_to_start());
+        let mut hashes = Vec::new();
+        while push_video(&mut hashes, decoder.next_frame_timeout(Duration::from_secs(5)).unwrap()) { }
+        assert_eq!(hashes, expected);
+    }
+
+    fn pcm_s16(frame: &mut AudioFrame) -> Vec<i16> {
+        frame.to_pcm(PcmFormat::S16).unwrap().chunks_exact(2).map(|x| i16::from_le_bytes([x[0], x[1]])).collect()
+    }
+
+    #[test]
+    fn seek_audio_precise_matches_linear_decode() {
+        let path = crate::util::test::temp_path("audio_seek.nut");
+        crate::util::test::write_av_test_clip(&path, 100).unwrap();
+        let mut decoder = Decoder::new(&path, DecoderOptions::default()).unwrap();
+        let mut linear = Vec::new();
+        while let Some(frame) = decoder.next_frame() {
+            if let Frame::Audio(mut a) = frame { linear.extend(pcm_s16(&mut a)); }
+        }
+        assert_eq!(linear.len(), 100 * 1920);
+        assert!(linear.iter().enumerate().all(|(n, &x)| x == (n % 30000) as i16));
+
+        for target in [1_234_567, 2_000_000, 3_010_417] {
+            assert!(matches!(decoder.seek_audio_precise(target), SeekResult::Exact { .. }));
+            // The video decoded on the way comes first
+            let mut video = Vec::new();
+            let mut audio = loop {
+                match decoder.next_frame().unwrap() {
+                    Frame::Video(v) => video.push(v.timestamp_us().unwrap()),
+                    Frame::Audio(a) => break a,
+                    _ => { }
+                }
+            };
+            assert!(!video.is_empty() && video.iter().all(|&ts| ts <= target + AUDIO_SEEK_MAX_VIDEO_LEAD_US));
+            let first = ((target * 48000 + 500_000) / 1_000_000) as usize;
+            assert_eq!(audio.sample_position(), Some(first as i64));
+            let mut samples = pcm_s16(&mut audio);
+            while samples.len() < 4800 {
+                if let Frame::Audio(mut a) = decoder.next_frame().unwrap() { samples.extend(pcm_s16(&mut a)); }
+            }
+            assert_eq!(samples[..4800], linear[first..first + 4800]);
+        }
+
+        // Nothing to seek to without a decoded audio stream, the position stays
+        decoder.reset_to_start();
+        decoder.streams()[1].decode = false;
+        assert!(matches!(decoder.seek_audio_precise(2_000_000), SeekResult::Failed));
+        assert_eq!(first_video_frame(&mut decoder).timestamp_us(), Some(0));
+        drop(decoder);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn recovers_from_a_lost_device() {
+        use std::sync::atomic::{ AtomicUsize, Ordering };
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        fn lose_device_once() -> Option<i32> {
+            // In the middle of a GOP, with frames still inside the decoder
+            (CALLS.fetch_add(1, Ordering::Relaxed) == 30).then_some(crate::support::ffmpeg_hw::DEVICE_REMOVED)
+        }
+        fn decode(path: &str) -> (Vec<i64>, Vec<i16>, u64) {
+            let mut decoder = Decoder::new(path, DecoderOptions::default()).unwrap();
+            let (mut video, mut audio) = (Vec::new(), Vec::new());
+            while let Some(frame) = decoder.next_frame() {
+                match frame {
+                    Frame::Video(v) => video.push(v.timestamp_us().unwrap()),
+                    Frame::Audio(mut a) => audio.extend(pcm_s16(&mut a)),
+                    _ => { }
+                }
+            }
+            (video, audio, decoder.stats().device_recoveries)
+        }
+        let paths = [crate::util::test::temp_path("device_lost.mp4"), crate::util::test::temp_path("device_lost.nut")];
+        crate::util::test::write_test_clip(&paths[0], EncoderCodec::H264, (160, 120), 48, 24.0).unwrap();
+        crate::util::test::write_av_test_clip(&paths[1], 50).unwrap();
+        for path in &paths {
+            let expected = decode(path);
+            assert_eq!(expected.2, 0);
+            CALLS.store(0, Ordering::Relaxed);
+            crate::support::ffmpeg_hw::set_hw_failure_hook(Some(lose_device_once));
+            let recovered = decode(path);
+            crate::support::ffmpeg_hw::set_hw_failure_hook(None);
+            let _ = std::fs::remove_file(path);
+            // Nothing lost and nothing returned twice
+            assert_eq!(recovered.0, expected.0, "{path}");
+            assert_eq!(recovered.1, expected.1, "{path}");
+            assert_eq!(recovered.2, 1);
+        }
+    }
+
+    #[test]
+    fn frame_budget_doesnt_block() {
+        let path = crate::util::test::temp_path("budget.mp4");
+        crate::util::test::write_test_clip(&path, EncoderCodec::H264, (320, 240), 48, 24.0).unwrap();
+        let mut decoder = Decoder::new(&path, DecoderOptions { max_outstanding_frames: Some(24), ..Default::default() }).unwrap();
+        // A single thread holding every returned frame gets `None` at the limit instead of a deadlock
+        let mut held = Vec::new();
+        while let Some(frame) = decoder.next_frame() {
+            if let Frame::Video(v) = frame { held.push(v); }
+        }
+        assert!(!held.is_empty() && held.len() < 48);
+        assert!(decoder.outstanding_frames() >= 24);
+        assert!(matches!(decoder.try_next_frame(), Err(VideoProcessingError::WouldBlock { limit: 24, .. })));
+        // Dropping the frames lets it continue where it stopped
+        let last = held.last().unwrap().timestamp_us();
+        held.clear();
+        let next = first_video_frame(&mut decoder);
+        assert!(next.timestamp_us() > last);
+        drop(next);
+        drop(decoder);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn close_while_decoding() {
+        let path = crate::util::test::temp_path("close.mp4");
+        crate::util::test::write_test_clip(&path, EncoderCodec::H264, (320, 240), 24, 24.0).unwrap();
+        for i in 0..40 {
+            let mut decoder = Decoder::new(&path, DecoderOptions { max_outstanding_frames: Some(8), ..Default::default() }).unwrap();
+            // Stop at a different frame every time and keep a few frames alive across the close on some iterations
+            let mut held = Vec::new();
+            for _ in 0..1 + i % 10 {
+                let frame = first_video_frame(&mut decoder);
+                if i % 3 == 0 && held.len() < 2 { held.push(frame); }
+            }
+            let releaser = std::thread::spawn(move || { std::thread::sleep(Duration::from_millis(5)); drop(held); });
+            let closed = decoder.close(Duration::from_secs(5));
+            releaser.join().unwrap();
+            assert!(closed.is_ok(), "iteration {i}: {closed:?}");
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn close_reports_held_frames() {
+        let path = crate::util::test::temp_path("close-held.mp4");
+        crate::util::test::write_test_clip(&path, EncoderCodec::H264, (320, 240), 24, 24.0).unwrap();
+        let mut decoder = Decoder::new(&path, DecoderOptions::default()).unwrap();
+        let mut frame = first_video_frame(&mut decoder);
+        let report = decoder.close(Duration::from_millis(10)).unwrap_err();
+        assert_eq!(report.outstanding_frames, 1);
+        // Still valid after the decoder is gone
+        assert_eq!(frame.width(), 320);
+        assert!(frame.get_cpu_buffers().is_ok_and(|planes| !planes.is_empty()));
+        drop(frame);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn seeks_are_clamped_to_the_input() {
+        // MPEG-TS starts at 1.4 s
+        let path = crate::util::test::temp_path("clamp.ts");
+        crate::util::test::write_test_clip(&path, EncoderCodec::H264, (320, 240), 48, 24.0).unwrap();
+        let mut decoder = Decoder::new(&path, DecoderOptions::default()).unwrap();
+        let start = decoder.inner.start_time_us();
+        assert!(start > 0);
+        assert_eq!(decoder.clamp_seek_timestamp(-1), start);
+        assert_eq!(decoder.clamp_seek_timestamp(0), start);
+        assert_eq!(decoder.clamp_seek_timestamp(start + 500_000), start + 500_000);
+        let last = decoder.clamp_seek_timestamp(i64::MAX);
+        assert!((last - (start + 47 * 1_000_000 / 24)).abs() <= 1000, "{last}");
+        // Read once
+        assert!(decoder.seek_bounds_us.is_some());
+
+        assert!(decoder.seek(0));
+        assert_eq!(first_video_frame(&mut decoder).timestamp_us(), Some(start));
+        assert!(decoder.seek(i64::MAX));
+        assert!(first_video_frame(&mut decoder).timestamp_us().is_some_and(|ts| ts > start));
+        drop(decoder);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn unknown_options() {
+        let path = crate::util::test::temp_path("options.mp4");
+        crate::util::test::write_test_clip(&path, EncoderCodec::H264, (320, 240), 12, 24.0).unwrap();
+        let options = || DecoderOptions {
+            // A typo, something unrelated, a backend option and an option of the demuxer
+            custom_options: [("thread", "2"), ("zzzzzzzzzz", "1"), ("lowres", "0"), ("ignore_editlist", "1")].into_iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            ..Default::default()
+        };
+
+        let reported = std::sync::Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let sink = reported.clone();
+        let diagnostics = DiagnosticsCallback(std::sync::Arc::new(move |event| if let Diagnostic::UnknownOption { key, suggestion } = event { sink.lock().push((key.clone(), suggestion.clone())); }));
+        let decoder = Decoder::new(&path, DecoderOptions { diagnostics: Some(diagnostics), ..options() }).unwrap();
+        drop(decoder);
+        let mut reported = reported.lock().clone();
+        reported.sort();
+        assert_eq!(reported, [("thread".to_string(), Some("threads".to_string())), ("zzzzzzzzzz".to_string(), None)]);
+
+        match Decoder::new(&path, DecoderOptions { strict_options: true, ..options() }) {
+            Err(VideoProcessingError::UnknownOptions(mut keys)) => {
+                keys.sort();
+                assert_eq!(keys.iter().map(|x| x.0.as_str()).collect::<Vec<_>>(), ["thread", "zzzzzzzzzz"]);
+            },
+            other => panic!("Expected UnknownOptions, got {:?}", other.err())
+        }
+        // Nothing unknown is fine in strict mode
+        assert!(Decoder::new(&path, DecoderOptions { strict_options: true, ..Default::default() }).is_ok());
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,280 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+use crate::types::*;
+use std::collections::HashMap;
+use std::path::{ Path, PathBuf };
+
+/// Metadata file recorded next to the clip by the camera, e.g. RED `.rmd` or the Sony `M01.XML`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Sidecar {
+    #[default]
+    None,
+    /// Looks for `<clip>.rmd`, `<clip>M01.XML` and `<clip>.xml` next to the clip, nothing happens when there's none
+    Auto,
+    /// Opening the clip fails when this file can't be read
+    Path(PathBuf),
+}
+
+/// Keys of the values below, in order of preference: Sony XML `Item` names, then RED RMD and ASC CDL elements.
+/// Names are compared ignoring case, but otherwise exactly
+const TIMECODE_KEYS:  &[&str] = &["StartTimecode", "Timecode"];
+const TRC_KEYS:       &[&str] = &["CaptureGammaEquation", "GammaCurve"];
+const PRIMARIES_KEYS: &[&str] = &["CaptureColorPrimaries", "ColorSpace"];
+
+/// Values read from a sidecar file. They fill in `VideoInfo::metadata` and the color description where the clip has no tag of its own
+#[derive(Debug, Clone, Default)]
+pub struct SidecarMetadata {
+    pub path: PathBuf,
+    /// `name`/`value` attribute pairs, `Element.attribute` for other attributes and `Element` for text content
+    pub values: HashMap<String, String>,
+    /// `HH:MM:SS:FF`, `;` before the frames for drop frame
+    pub timecode: Option<String>,
+    pub color_trc: Option<ColorTrc>,
+    pub color_primaries: Option<ColorPrimaries>,
+    /// On-set look, applied to frames decoded in software. Hardware frames are returned as they are
+    pub look: Option<Cdl>,
+}
+
+/// ASC CDL: `out = clamp(in * slope + offset) ^ power` per channel, then the saturation around the Rec. 709 luma
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cdl {
+    pub slope: [f32; 3],
+    pub offset: [f32; 3],
+    pub power: [f32; 3],
+    pub saturation: f32,
+}
+impl Default for Cdl {
+    fn default() -> Self {
+        Self { slope: [1.0; 3], offset: [0.0; 3], power: [1.0; 3], saturation: 1.0 }
+    }
+}
+impl Cdl {
+    pub fn is_identity(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl SidecarMetadata {
+    pub fn load(path: &Path) -> Result<Self, VideoProcessingError> {
+        let text = std::fs::read_to_string(path)?;
+        let values = parse_xml_values(&text);
+
+        let find = |keys: &[&str]| keys.iter()
+            .filter_map(|key| values.iter().find(|(k, _)| k.eq_ignore_ascii_case(key)).map(|(_, v)| v.as_str()))
+            .collect::<Vec<_>>();
+        let timecode = find(TIMECODE_KEYS).into_iter().find(|x| is_timecode(x)).map(str::to_owned);
+        let color_trc = find(TRC_KEYS).into_iter().find_map(|x| trc_from_name(&x.to_ascii_lowercase()));
+        let color_primaries = find(PRIMARIES_KEYS).into_iter().find_map(|x| primaries_from_name(&x.to_ascii_lowercase()));
+        let look = parse_cdl(|key| find(&[key]).first().copied());
+
+        Ok(Self { path: path.to_owned(), values, timecode, color_trc, color_primaries, look })
+    }
+
+    /// Sidecar of the clip for `Sidecar::Auto`
+    pub fn find_for(clip: &Path) -> Option<PathBuf> {
+        let stem = clip.file_stem()?.to_string_lossy();
+        let dir = clip.parent().unwrap_or(Path::new(""));
+        [format!("{stem}.rmd"), format!("{stem}.RMD"), format!("{stem}M01.XML"), format!("{stem}M01.xml"), format!("{stem}.xml"), format!("{stem}.XML")]
+            .into_iter()
+            .map(|x| dir.join(x))
+            .find(|x| x.is_file())
+    }
+}
+
+fn is_timecode(v: &str) -> bool {
+    let b = v.as_bytes();
+    b.len() == 11 && [2, 5, 8].iter().all(|&i| matches!(b[i], b':' | b';' | b'.')) &&
+        b.iter().enumerate().all(|(i, c)| [2, 5, 8].contains(&i) || c.is_ascii_digit())
+}
+
+/// The `Slope`, `Offset`, `Power` and `Saturation` elements of an ASC CDL, which RMD files carry too. `None` without any of them
+fn parse_cdl<'a>(get: impl Fn(&str) -> Option<&'a str>) -> Option<Cdl> {
+    let numbers = |key: &str| -> Option<Result<Vec<f32>, ()>> {
+        get(key).map(|x| x.split_whitespace().map(|x| x.parse::<f32>().map_err(|_| ())).collect())
+    };
+    let triplet = |key: &str, default: [f32; 3]| match numbers(key) {
+        None => Some(default),
+        Some(Ok(x)) if x.len() == 3 => Some([x[0], x[1], x[2]]),
+        Some(_) => {
+            log::warn!("Invalid sidecar CDL {key}: {:?}", get(key));
+            None
+        }
+    };
+    if ["Slope", "Offset", "Power", "Saturation"].iter().all(|x| get(x).is_none()) {
+        return None;
+    }
+    let saturation = match numbers("Saturation") {
+        None => 1.0,
+        Some(Ok(x)) if x.len() == 1 => x[0],
+        Some(_) => {
+            log::warn!("Invalid sidecar CDL Saturation: {:?}", get("Saturation"));
+            return None;
+        }
+    };
+    let cdl = Cdl {
+        slope: triplet("Slope", [1.0; 3])?,
+        offset: triplet("Offset", [0.0; 3])?,
+        power: triplet("Power", [1.0; 3])?,
+        saturation,
+    };
+    Some(cdl).filter(|x| !x.is_identity())
+}
+
+fn trc_from_name(v: &str) -> Option<ColorTrc> {
+    Some(match v {
+        _ if v.contains("hlg") => ColorTrc::AribStdB67,
+        _ if v.contains("2084") || v == "pq" || v.contains("-pq") || v.contains("_pq") => ColorTrc::SMPTE2084,
+        _ if v.contains("srgb") => ColorTrc::SRGB,
+        _ if v.contains("linear") => ColorTrc::Linear,
+        _ if v.contains("709") => ColorTrc::BT709,
+        _ => {
+            log::debug!("Unmapped sidecar transfer: {v}");
+            return None;
+        }
+    })
+}
+
+fn primaries_from_name(v: &str) -> Option<ColorPrimaries> {
+    Some(match v {
+        _ if v.contains("2020") => ColorPrimaries::BT2020,
+        _ if v.contains("p3") && (v.contains("d65") || v.contains("display")) => ColorPrimaries::DisplayP3,
+        _ if v.contains("p3") || v.contains("dci") => ColorPrimaries::DCIP3,
+        _ if v.contains("709") => ColorPrimaries::BT709,
+        _ => return None
+    })
+}
+
+/// Flat key/value view of an XML document, enough for the metadata files of cameras
+fn parse_xml_values(text: &str) -> HashMap<String, String> {
+    let mut ret = HashMap::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let Some(end) = rest.find('>') else { break; };
+        let tag = &rest[..end];
+        rest = &rest[end + 1..];
+        if tag.starts_with(['?', '!', '/']) { continue; }
+
+        let self_closing = tag.ends_with('/');
+        let tag = tag.trim_end_matches('/');
+        let name_end = tag.find(char::is_whitespace).unwrap_or(tag.len());
+        let name = &tag[..name_end];
+        let attributes = parse_attributes(&tag[name_end..]);
+
+        let get = |key: &str| attributes.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone());
+        if let (Some(key), Some(value)) = (get("name"), get("value")) {
+            ret.insert(key, value);
+        } else {
+            for (k, v) in attributes {
+                ret.insert(format!("{name}.{k}"), v);
+            }
+        }
+        if !self_closing {
+            let content = rest[..rest.find('<').unwrap_or(rest.len())].trim();
+            if !content.is_empty() {
+                ret.insert(name.to_string(), unescape(content));
+            }
+        }
+    }
+    ret
+}
+
+fn parse_attributes(mut s: &str) -> Vec<(String, String)> {
+    let mut ret = Vec::new();
+    while let Some(eq) = s.find('=') {
+        let key = s[..eq].trim();
+        let value = s[eq + 1..].trim_start();
+        let Some(quote) = value.chars().next().filter(|x| *x == '"' || *x == '\'') else { break; };
+        let Some(len) = value[1..].find(quote) else { break; };
+        ret.push((key.to_string(), unescape(&value[1..1 + len])));
+        s = &value[len + 2..];
+    }
+    ret
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&apos;", "'").replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xml_values() {
+        let values = parse_xml_values(r#"<?xml version="1.0" encoding="UTF-8"?>
+            <!-- comment -->
+            <NonRealTimeMeta xmlns="urn:schemas-professionalDisc:nonRealTimeMeta:ver.2.00" lastUpdate='2023-01-02'>
+                <Item name="CaptureGammaEquation" value="s-log3"/>
+                <Device manufacturer="Sony" modelName="ILCE-7SM3" />
+                <Title>A &amp; B &lt;1&gt;</Title>
+                <Empty></Empty>
+            </NonRealTimeMeta>"#);
+        assert_eq!(values.get("CaptureGammaEquation").map(String::as_str), Some("s-log3"));
+        assert_eq!(values.get("NonRealTimeMeta.lastUpdate").map(String::as_str), Some("2023-01-02"));
+        assert_eq!(values.get("Device.manufacturer").map(String::as_str), Some("Sony"));
+        assert_eq!(values.get("Device.modelName").map(String::as_str), Some("ILCE-7SM3"));
+        assert_eq!(values.get("Title").map(String::as_str), Some("A & B <1>"));
+        assert!(!values.contains_key("Empty"));
+        assert!(!values.contains_key("Item.name"));
+        assert!(!values.keys().any(|x| x.starts_with(['?', '!', '/'])));
+
+        // Truncated documents keep what was read before
+        assert_eq!(parse_xml_values("<Timecode>01:00:00:00</Timecode><Broken attr=\"x"), HashMap::from([("Timecode".to_string(), "01:00:00:00".to_string())]));
+        assert!(parse_attributes(r#" a="1" b='2' c=3"#).iter().map(|(k, v)| (k.as_str(), v.as_str())).eq([("a", "1"), ("b", "2")]));
+    }
+
+    fn load(name: &str, text: &str) -> SidecarMetadata {
+        let path = crate::util::test::temp_path(name);
+        std::fs::write(&path, text).unwrap();
+        let ret = SidecarMetadata::load(Path::new(&path)).unwrap();
+        let _ = std::fs::remove_file(&path);
+        ret
+    }
+
+    #[test]
+    fn exact_keys() {
+        let sony = load("sidecar-M01.XML", r#"<NonRealTimeMeta>
+            <Item name="CaptureGammaEquation" value="rec2100-hlg"/>
+            <Item name="CaptureColorPrimaries" value="rec2020"/>
+            <Item name="StartTimecode" value="10:00:00;02"/>
+        </NonRealTimeMeta>"#);
+        assert_eq!(sony.color_trc, Some(ColorTrc::AribStdB67));
+        assert_eq!(sony.color_primaries, Some(ColorPrimaries::BT2020));
+        assert_eq!(sony.timecode.as_deref(), Some("10:00:00;02"));
+        assert_eq!(sony.look, None);
+
+        // Keys only containing the names aren't color or timecode values
+        let other = load("sidecar-other.xml", r#"<Clip>
+            <Item name="MonitorGammaLut" value="pq"/>
+            <Item name="ViewingGamut" value="rec2020"/>
+            <OutputColorSpace>rec709</OutputColorSpace>
+            <EndTimecode>10:00:10:00</EndTimecode>
+            <Timecode>not a timecode</Timecode>
+        </Clip>"#);
+        assert_eq!((other.color_trc, other.color_primaries, other.timecode), (None, None, None));
+        assert_eq!(other.values.get("MonitorGammaLut").map(String::as_str), Some("pq"));
+    }
+
+    #[test]
+    fn rmd_look() {
+        let rmd = load("sidecar.rmd", r#"<RMD>
+            <GammaCurve>BT1886</GammaCurve>
+            <ColorSpace>Rec2020</ColorSpace>
+            <ColorCorrection id="look">
+                <SOPNode><Slope>1.1 1.0 0.9</Slope><Offset>0.0 -0.02 0.01</Offset><Power>1.0 1.0 1.2</Power></SOPNode>
+                <SatNode><Saturation>0.8</Saturation></SatNode>
+            </ColorCorrection>
+        </RMD>"#);
+        assert_eq!(rmd.color_primaries, Some(ColorPrimaries::BT2020));
+        assert_eq!(rmd.color_trc, None);
+        assert_eq!(rmd.look, Some(Cdl { slope: [1.1, 1.0, 0.9], offset: [0.0, -0.02, 0.01], power: [1.0, 1.0, 1.2], saturation: 0.8 }));
+
+        // Missing nodes are the identity, invalid ones drop the look
+        assert_eq!(load("sidecar-sat.rmd", "<Saturation>0</Saturation>").look, Some(Cdl { saturation: 0.0, ..Default::default() }));
+        assert_eq!(load("sidecar-identity.rmd", "<Slope>1 1 1</Slope><Power>1 1 1</Power>").look, None);
+        assert_eq!(load("sidecar-short.rmd", "<Slope>1.1 1.0</Slope>").look, None);
+        assert_eq!(load("sidecar-nan.rmd", "<Slope>1.1 x 1.0</Slope>").look, None);
+    }
+}
@@ -1,2 +1,531 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 // Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+use super::*;
+use crate::types::{ Chapter, ColorRange };
+use crate::support::ffmpeg_hw;
+use crate::frame::VideoFrameInterface;
+use ffmpeg_next::{ format::Pixel, software::scaling, Rational, Rescale };
+
+pub struct FfmpegEncoder {
+    params: EncoderParams,
+    octx: ffmpeg_next::format::context::Output,
+    video: Option<VideoOutput>,
+    attachments: Vec<Attachment>,
+    header_written: bool,
+    finished: bool,
+    force_keyframe: bool,
+}
+
+struct VideoOutput {
+    encoder: ffmpeg_next::encoder::video::Encoder,
+    stream_index: usize,
+    format: Pixel,
+    size: (u32, u32),
+    time_base: Rational,
+    next_pts: i64,
+    to_encoder: Option<CachedScaler>,
+    /// `color_conversion_filter` from the input to the output color description
+    color_filter: Option<String>,
+    color_graph: Option<crate::ColorConversionGraph>,
+    // Only used with `EncoderParams::overlay`
+    to_rgba: Option<CachedScaler>,
+}
+
+/// Scaler reused while the input format and size stay the same
+struct CachedScaler {
+    input: (Pixel, u32, u32),
+    context: scaling::Context,
+}
+
+impl CachedScaler {
+    fn run(slot: &mut Option<Self>, src: &ffmpeg_next::frame::Video, format: Pixel, size: (u32, u32)) -> Result<ffmpeg_next::frame::Video, crate::VideoProcessingError> {
+        let input = (src.format(), src.width(), src.height());
+        if slot.as_ref().is_none_or(|x| x.input != input) {
+            let context = scaling::Context::get(input.0, input.1, input.2, format, size.0, size.1, scaling::Flags::BILINEAR)?;
+            *slot = Some(Self { input, context });
+        }
+        let mut dst = ffmpeg_next::frame::Video::empty();
+        slot.as_mut().ok_or(crate::VideoProcessingError::EncoderConverterEmpty)?.context.run(src, &mut dst)?;
+        unsafe { ffmpeg_next::ffi::av_frame_copy_props(dst.as_mut_ptr(), src.as_ptr()); }
+        Ok(dst)
+    }
+}
+
+impl FfmpegEncoder {
+    pub fn new(output: IoType, params: EncoderParams) -> Result<Self, VideoProcessingError> {
+        crate::backend::init_ffmpeg()?;
+        let octx = match &output {
+            IoType::FileOrUrl(path) if params.faststart && !can_reopen(path) => {
+                return Err(VideoProcessingError::InvalidEncoderConfig { field: "faststart", reason: format!("{path} can't be opened again for the second pass"), allowed: "file outputs".into() });
+            },
+            IoType::FileOrUrl(path) => ffmpeg_next::format::output(path)?,
+            _ => return Err(VideoProcessingError::InvalidEncoderConfig { field: "output", reason: format!("{output:?} can't be written"), allowed: "file paths and URLs".into() })
+        };
+        Ok(Self { params, octx, video: None, attachments: Vec::new(), header_written: false, finished: false, force_keyframe: false })
+    }
+
+    /// Hardware encoders which only take GPU surfaces aren't fed by this path, the software encoder is used instead
+    fn find_encoder(&self) -> Result<(ffmpeg_next::Codec, Vec<Pixel>), VideoProcessingError> {
+        let encoders = self.params.codec.encoders(self.params.use_gpu);
+        let device = self.params.custom_options.get("hwaccel_device").map(String::as_str);
+        let (name, is_hw, _) = ffmpeg_hw::find_working_encoder(&encoders, device);
+        let sw_formats = |codec: &mut ffmpeg_next::Codec| -> Vec<Pixel> {
+            let ptr = unsafe { codec.as_mut_ptr() };
+            unsafe { if (*ptr).pix_fmts.is_null() { Vec::new() } else { ffmpeg_hw::pix_formats_to_vec((*ptr).pix_fmts) } }
+                .into_iter()
+                .filter(|x| !ffmpeg_hw::is_hardware_format((*x).into()))
+                .collect()
+        };
+        let mut codec = ffmpeg_next::encoder::find_by_name(name).ok_or(VideoProcessingError::EncoderNotFound)?;
+        let mut formats = sw_formats(&mut codec);
+        if is_hw && formats.is_empty() {
+            let (sw_name, _) = encoders.last().ok_or(VideoProcessingError::EncoderNotFound)?;
+            log::debug!("{name} only takes hardware frames, using {sw_name}");
+            codec = ffmpeg_next::encoder::find_by_name(sw_name).ok_or(VideoProcessingError::EncoderNotFound)?;
+            formats = sw_formats(&mut codec);
+        }
+        Ok((codec, formats))
+    }
+
+    /// `input` tags the stream where the params don't specify the output color description
+    fn open_video_stream(&mut self, input: &crate::ColorDescription) -> Result<usize, VideoProcessingError> {
+        let already = |reason: &str| Err(VideoProcessingError::InvalidEncoderConfig { field: "streams", reason: reason.into(), allowed: "one video stream, added before the first frame".into() });
+        if self.video.is_some() { return already("the video stream is already added"); }
+        if self.header_written { return already("the header is already written"); }
+
+        let params = &self.params;
+        let (codec, formats) = self.find_encoder()?;
+        let device = params.custom_options.get("hwaccel_device").map(String::as_str);
+        params.validate(&crate::codec_capabilities(codec.name(), true, device)?)?;
+        let wanted = crate::frame::to_ffmpeg_format(params.format).ok_or(VideoProcessingError::UnknownPixelFormat(params.format))?;
+        let format = if formats.is_empty() { wanted } else { ffmpeg_hw::find_best_matching_codec(wanted, &formats) };
+        if format != wanted {
+            log::info!("{} doesn't support {wanted:?}, encoding {format:?}", codec.name());
+        }
+
+        let frame_rate = Rational::from(params.frame_rate as f64);
+        let time_base = params.time_base.map_or(frame_rate.invert(), |(num, den)| Rational::new(num as i32, den as i32));
+
+        let mut ctx = ffmpeg_next::codec::context::Context::new_with_codec(codec).encoder().video()?;
+        ctx.set_width(params.width);
+        ctx.set_height(params.height);
+        ctx.set_format(format);
+        ctx.set_time_base(time_base);
+        ctx.set_frame_rate(Some(frame_rate));
+        if let Some(gop) = params.gop_size {
+            ctx.set_gop(gop);
+        }
+        // Bitrates are in Mbps, the rate control buffer holds one second at the maximum rate
+        let bps = |mbps: f64| (mbps * 1_000_000.0) as i64;
+        unsafe {
+            let ptr = ctx.as_mut_ptr();
+            match params.bitrate {
+                Bitrate::Constant(mbps) => {
+                    (*ptr).bit_rate = bps(mbps);
+                    (*ptr).rc_min_rate = bps(mbps);
+                    (*ptr).rc_max_rate = bps(mbps);
+                    (*ptr).rc_buffer_size = bps(mbps) as i32;
+                },
+                Bitrate::Variable((min, max)) => {
+                    (*ptr).bit_rate = bps((min + max) / 2.0);
+                    (*ptr).rc_min_rate = bps(min);
+                    (*ptr).rc_max_rate = bps(max);
+                    (*ptr).rc_buffer_size = bps(max) as i32;
+                },
+                Bitrate::QScale(q) => {
+                    let (qmin, qmax) = qscale_range(codec.name());
+                    if !(qmin..=qmax).contains(&q) {
+                        return Err(VideoProcessingError::InvalidEncoderConfig { field: "bitrate", reason: format!("qscale {q} is outside of the range of {}", codec.name()), allowed: format!("{qmin} - {qmax}") });
+                    }
+                    (*ptr).flags |= ffmpeg_next::ffi::AV_CODEC_FLAG_QSCALE as i32;
+                    (*ptr).global_quality = (q * ffmpeg_next::ffi::FF_QP2LAMBDA as f64) as i32;
+                },
+            }
+        }
+        if self.octx.format().flags().contains(ffmpeg_next::format::Flags::GLOBAL_HEADER) {
+            unsafe { (*ctx.as_mut_ptr()).flags |= ffmpeg_next::ffi::AV_CODEC_FLAG_GLOBAL_HEADER as i32; }
+        }
+        crate::set_color_tags(&mut ctx, &crate::ColorDescription {
+            space: params.out_color_space.or(input.space),
+            trc: params.out_color_trc.or(input.trc),
+            primaries: params.out_color_primaries.or(input.primaries),
+            range: None,
+        });
+        let out_range = params.out_color_range.or(params.color_range_full.then_some(ColorRange::Full));
+        let range = out_range.or(input.range).unwrap_or(ColorRange::Limited);
+        set_color_range(&mut ctx, range);
+        let color_filter = crate::color_conversion_filter(input, &crate::ColorDescription {
+            space: params.out_color_space,
+            trc: params.out_color_trc,
+            primaries: params.out_color_primaries,
+            range: out_range,
+        });
+        if let Some(filter) = &color_filter {
+            log::debug!("Converting colors with {filter}");
+        }
+        let format = ctx.format();
+
+        let mut options = ffmpeg_next::Dictionary::new();
+        // x264 only pads to a constant rate with HRD signaling
+        if matches!(params.bitrate, Bitrate::Constant(_)) && codec.name() == "libx264" {
+            options.set("nal-hrd", "cbr");
+        }
+        for (k, v) in &params.custom_options { options.set(k, v); }
+        let encoder = ctx.open_as_with(codec, options)?;
+
+        let mut stream = self.octx.add_stream(codec)?;
+        stream.set_parameters(&encoder);
+        stream.set_time_base(time_base);
+        let stream_index = stream.index();
+
+        self.video = Some(VideoOutput {
+            encoder,
+            stream_index,
+            format,
+            size: (params.width, params.height),
+            time_base,
+            next_pts: 0,
+            to_encoder: None,
+            color_filter,
+            color_graph: None,
+            to_rgba: None,
+        });
+        Ok(stream_index)
+    }
+
+    fn write_header(&mut self) -> Result<(), VideoProcessingError> {
+        if self.header_written { return Ok(()); }
+        write_chapters(&mut self.octx, &self.params.chapters)?;
+        add_attachments(&mut self.octx, &self.attachments)?;
+        set_container_metadata(&mut self.octx, &self.params.metadata);
+        let options = muxer_options(&self.octx, &self.params.custom_options, self.params.faststart)?;
+        self.octx.write_header_with(options)?;
+        self.header_written = true;
+        Ok(())
+    }
+
+    fn write_packets(&mut self) -> Result<(), VideoProcessingError> {
+        let Some(video) = self.video.as_mut() else { return Ok(()); };
+        // The muxer can change the stream time base when writing the header
+        let stream_time_base = self.octx.stream(video.stream_index).map_or(video.time_base, |x| x.time_base());
+        let mut packet = ffmpeg_next::Packet::empty();
+        while video.encoder.receive_packet(&mut packet).is_ok() {
+            packet.set_stream(video.stream_index);
+            packet.rescale_ts(video.time_base, stream_time_base);
+            packet.write_interleaved(&mut self.octx)?;
+        }
+        Ok(())
+    }
+}
+
+impl VideoOutput {
+    /// Converts the frame to the output color description, then to the encoder format and size, through RGBA when there's an overlay
+    fn prepare(&mut self, src: &ffmpeg_next::frame::Video, overlay: Option<&mut OverlayCallback>, timestamp_us: Option<i64>, fps: f64) -> Result<ffmpeg_next::frame::Video, VideoProcessingError> {
+        let converted;
+        let src = match &self.color_filter {
+            Some(filter) => { converted = crate::ColorConversionGraph::run(&mut self.color_graph, src, filter)?; &converted },
+            None => src
+        };
+        match overlay {
+            Some(callback) => {
+                let mut rgba = CachedScaler::run(&mut self.to_rgba, src, Pixel::RGBA, self.size)?;
+                let stride = rgba.stride(0);
+                callback(OverlayContext {
+                    rgba: rgba.data_mut(0),
+                    width: self.size.0,
+                    height: self.size.1,
+                    stride,
+                    timestamp_us,
+                    timecode: timecode(timestamp_us.unwrap_or_default(), fps),
+                });
+                CachedScaler::run(&mut self.to_encoder, &rgba, self.format, self.size)
+            },
+            None if (src.format(), src.width(), src.height()) == (self.format, self.size.0, self.size.1) => {
+                let mut frame = ffmpeg_next::frame::Video::empty();
+                let err = unsafe { ffmpeg_next::ffi::av_frame_ref(frame.as_mut_ptr(), src.as_ptr()) };
+                if err < 0 { return Err(ffmpeg_next::Error::from(err).into()); }
+                Ok(frame)
+            },
+            None => CachedScaler::run(&mut self.to_encoder, src, self.format, self.size)
+        }
+    }
+}
+
+impl EncoderInterface for FfmpegEncoder {
+    fn params(&self) -> &EncoderParams {
+        &self.params
+    }
+
+    fn add_video_stream(&mut self) -> Result<usize, VideoProcessingError> {
+        self.open_video_stream(&crate::ColorDescription::default())
+    }
+
+    fn encode_frame(&mut self, frame: &mut VideoFrame) -> Result<(), VideoProcessingError> {
+        if self.finished { return Err(VideoProcessingError::EncoderFinished); }
+        if self.video.is_none() {
+            self.open_video_stream(&crate::ColorDescription { space: frame.color_space(), trc: frame.color_trc(), primaries: frame.color_primaries(), range: frame.color_range() })?;
+        }
+        self.write_header()?;
+
+        let timestamp_us = frame.timestamp_us();
+        let fps = self.params.frame_rate as f64;
+        let VideoFrame::FfmpegVideoFrame(src) = frame;
+        let src = src.cpu_frame()?;
+        let video = self.video.as_mut().ok_or(VideoProcessingError::NoOutputContext)?;
+        let mut out = video.prepare(src, self.params.overlay.as_mut(), timestamp_us, fps)?;
+
+        // Timestamps have to increase, frames without one follow the previous frame
+        let pts = timestamp_us.map_or(video.next_pts, |ts| ts.rescale((1, 1_000_000), video.time_base).max(video.next_pts));
+        video.next_pts = pts + 1;
+        out.set_pts(Some(pts));
+        out.set_kind(if std::mem::take(&mut self.force_keyframe) { ffmpeg_next::picture::Type::I } else { ffmpeg_next::picture::Type::None });
+        video.encoder.send_frame(&out)?;
+        self.write_packets()
+    }
+
+    fn force_keyframe(&mut self) {
+        self.force_keyframe = true;
+    }
+
+    fn attach_file(&mut self, attachment: Attachment) -> Result<(), VideoProcessingError> {
+        if !is_matroska(&self.octx) {
+            return Err(VideoProcessingError::InvalidEncoderConfig { field: "attachments", reason: format!("{} can't store attachments", self.octx.format().name()), allowed: "Matroska outputs (.mkv, .mka)".into() });
+        }
+        if self.header_written {
+            return Err(VideoProcessingError::InvalidEncoderConfig { field: "attachments", reason: format!("{} was attached after the header was written", attachment.name), allowed: "attachments added before the first frame".into() });
+        }
+        self.attachments.push(attachment);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), VideoProcessingError> {
+        if self.finished || !self.header_written { return Ok(()); }
+        self.write_packets()?;
+        unsafe {
+            let err = ffmpeg_next::ffi::av_interleaved_write_frame(self.octx.as_mut_ptr(), std::ptr::null_mut());
+            if err < 0 { return Err(ffmpeg_next::Error::from(err).into()); }
+            let pb = (*self.octx.as_ptr()).pb;
+            if !pb.is_null() { ffmpeg_next::ffi::avio_flush(pb); }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), VideoProcessingError> {
+        if self.finished { return Ok(()); }
+        self.finished = true;
+        self.write_header()?;
+        if let Some(video) = self.video.as_mut() {
+            video.encoder.send_eof()?;
+        }
+        self.write_packets()?;
+        self.octx.write_trailer()?;
+        Ok(())
+    }
+}
+
+/// Adds the chapters to the output container. Must be called before writing the header
+pub fn write_chapters(octx: &mut ffmpeg_next::format::context::Output, chapters: &[Chapter]) -> Result<(), crate::VideoProcessingError> {
+    for (id, ch) in chapters.iter().enumerate() {
+        octx.add_chapter(id as i64, (1, 1000000), ch.start_us, ch.end_us, ch.title.as_deref().unwrap_or_default())?;
+    }
+    Ok(())
+}
+
+/// Valid `Bitrate::QScale` values of the encoder. The context defaults can't be used, libx264, libx265 and NVENC set `qmin`/`qmax` to -1
+fn qscale_range(encoder: &str) -> (f64, f64) {
+    match encoder {
+        "libx264" | "libx265" => (0.0, 51.0),
+        _ if ["_nvenc", "_amf", "_qsv", "_vaapi"].iter().any(|x| encoder.ends_with(x)) => (0.0, 51.0),
+        _ if encoder.ends_with("_videotoolbox") => (1.0, 100.0),
+        // MPEG-style quantizer scale, also used by ProRes and DNxHR
+        _ => (1.0, 31.0),
+    }
+}
+
+/// Legacy encoders (e.g. mjpeg) only signal full range through the deprecated yuvj formats
+pub fn pixel_format_for_range(format: ffmpeg_next::format::Pixel, range: ColorRange, supported: &[ffmpeg_next::format::Pixel]) -> ffmpeg_next::format::Pixel {
+    use ffmpeg_next::format::Pixel;
+    let full = match format {
+        Pixel::YUV420P => Pixel::YUVJ420P,
+        Pixel::YUV422P => Pixel::YUVJ422P,
+        Pixel::YUV444P => Pixel::YUVJ444P,
+        _ => return format
+    };
+    if range == ColorRange::Full && !supported.contains(&format) && supported.contains(&full) { full } else { format }
+}
+
+/// Sets the codec context range and picks the matching pixel format. The stream parameters copied from the context are tagged the same way
+pub fn set_color_range(ctx: &mut ffmpeg_next::encoder::video::Video, range: ColorRange) {
+    let supported = unsafe {
+        let codec = (*ctx.as_ptr()).codec;
+        if codec.is_null() || (*codec).pix_fmts.is_null() { Vec::new() } else { crate::support::ffmpeg_hw::pix_formats_to_vec((*codec).pix_fmts) }
+    };
+    ctx.set_color_range(range.into());
+    let format = pixel_format_for_range(ctx.format(), range, &supported);
+    if format != ctx.format() {
+        ctx.set_format(format);
+    }
+}
+
+pub fn is_matroska(octx: &ffmpeg_next::format::context::Output) -> bool {
+    let name = octx.format().name();
+    name.contains("matroska") || name.contains("webm")
+}
+
+/// Adds an attachment stream for every file. Must be called before writing the header
+pub fn add_attachments(octx: &mut ffmpeg_next::format::context::Output, attachments: &[Attachment]) -> Result<(), crate::VideoProcessingError> {
+    use ffmpeg_next::ffi::*;
+    if attachments.is_empty() { return Ok(()); }
+    if !is_matroska(octx) {
+        log::warn!("Attachments are only supported in Matroska, {} skipped", attachments.len());
+        return Ok(());
+    }
+    for att in attachments {
+        let mut stream = octx.add_stream(ffmpeg_next::codec::Id::None)?;
+        unsafe {
+            let par = (*stream.as_mut_ptr()).codecpar;
+            (*par).codec_type = AVMediaType::AVMEDIA_TYPE_ATTACHMENT;
+            (*par).codec_id = match att.mime.as_str() {
+                "font/ttf" | "application/x-truetype-font" => AVCodecID::AV_CODEC_ID_TTF,
+                "font/otf" | "application/vnd.ms-opentype" => AVCodecID::AV_CODEC_ID_OTF,
+                _ => AVCodecID::AV_CODEC_ID_BIN_DATA,
+            };
+            // Attachment contents are stored in the extradata
+            let extradata = av_mallocz(att.data.len() + AV_INPUT_BUFFER_PADDING_SIZE as usize) as *mut u8;
+            if extradata.is_null() { return Err(ffmpeg_next::Error::Other { errno: ffmpeg_next::error::ENOMEM }.into()); }
+            std::ptr::copy_nonoverlapping(att.data.as_ptr(), extradata, att.data.len());
+            (*par).extradata = extradata;
+            (*par).extradata_size = att.data.len() as i32;
+        }
+        let mut metadata = ffmpeg_next::Dictionary::new();
+        metadata.set("filename", &att.name);
+        metadata.set("mimetype", &att.mime);
+        stream.set_metadata(metadata);
+    }
+    Ok(())
+}
+
+/// Container tags and the default track flags. Must be called before writing the header
+pub fn set_container_metadata(octx: &mut ffmpeg_next::format::context::Output, metadata: &HashMap<String, String>) {
+    let mut dict = ffmpeg_next::Dictionary::new();
+    for (k, v) in metadata { dict.set(k, v); }
+    octx.set_metadata(dict);
+
+    // Mark the first video and audio tracks as default, players otherwise may not select any
+    let mut has_default = Vec::new();
+    for mut stream in octx.streams_mut() {
+        let medium = stream.parameters().medium();
+        if matches!(medium, ffmpeg_next::media::Type::Video | ffmpeg_next::media::Type::Audio) && !has_default.contains(&medium) {
+            has_default.push(medium);
+            unsafe { (*stream.as_mut_ptr()).disposition |= ffmpeg_next::ffi::AV_DISPOSITION_DEFAULT as i32; }
+        }
+    }
+}
+
+pub fn is_mp4(octx: &ffmpeg_next::format::context::Output) -> bool {
+    octx.format().name().split(',').any(|x| matches!(x, "mp4" | "mov" | "ipod" | "3gp"))
+}
+
+/// Whether the output can seek back, which moving the index to the front needs
+pub fn is_seekable(octx: &ffmpeg_next::format::context::Output) -> bool {
+    unsafe {
+        let pb = (*octx.as_ptr()).pb;
+        !pb.is_null() && (*pb).seekable & ffmpeg_next::ffi::AVIO_SEEKABLE_NORMAL as i32 != 0
+    }
+}
+
+/// Whether `url` can be opened again by the muxer, which the faststart second pass does to move the index.
+/// Pipes, stdout and network outputs can't
+pub fn can_reopen(url: &str) -> bool {
+    if url == "-" { return false; }
+    let Ok(url) = std::ffi::CString::new(url) else { return false; };
+    unsafe {
+        let protocol = ffmpeg_next::ffi::avio_find_protocol_name(url.as_ptr());
+        !protocol.is_null() && std::ffi::CStr::from_ptr(protocol).to_bytes() == b"file"
+    }
+}
+
+fn octx_url(octx: &ffmpeg_next::format::context::Output) -> String {
+    unsafe {
+        let url = (*octx.as_ptr()).url;
+        if url.is_null() { String::new() } else { std::ffi::CStr::from_ptr(url).to_string_lossy().into_owned() }
+    }
+}
+
+/// Muxer options for writing the header. Matroska gets the cues (written on keyframes) at the front, so seeking works without reading the whole file.
+/// With `faststart`, MP4 gets the moov atom at the front (rewritten in a second pass when finishing), so playback can start while downloading
+pub fn muxer_options(octx: &ffmpeg_next::format::context::Output, custom_options: &HashMap<String, String>, faststart: bool) -> Result<ffmpeg_next::Dictionary<'static>, crate::VideoProcessingError> {
+    let mut dict = ffmpeg_next::Dictionary::new();
+    if is_matroska(octx) {
+        dict.set("cues_to_front", "1");
+    }
+    let mut movflags = custom_options.get("movflags").cloned().unwrap_or_default();
+    if faststart && is_mp4(octx) && !movflags.contains("faststart") {
+        let custom_io = unsafe { (*octx.as_ptr()).flags & ffmpeg_next::ffi::AVFMT_FLAG_CUSTOM_IO as i32 != 0 };
+        if custom_io || !is_seekable(octx) || !can_reopen(&octx_url(octx)) {
+            return Err(crate::VideoProcessingError::InvalidEncoderConfig { field: "faststart", reason: "the output can't be opened again for the second pass".into(), allowed: "file outputs".into() });
+        }
+        movflags.push_str("+faststart");
+    }
+    for (k, v) in custom_options { dict.set(k, v); }
+    if !movflags.is_empty() {
+        dict.set("movflags", &movflags);
+    }
+    Ok(dict)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::PixelFormat;
+
+    #[test]
+    fn qscale_ranges() {
+        assert_eq!(qscale_range("libx264"), (0.0, 51.0));
+        assert_eq!(qscale_range("libx265"), (0.0, 51.0));
+        assert_eq!(qscale_range("hevc_nvenc"), (0.0, 51.0));
+        assert_eq!(qscale_range("h264_qsv"), (0.0, 51.0));
+        assert_eq!(qscale_range("hevc_videotoolbox"), (1.0, 100.0));
+        assert_eq!(qscale_range("prores_ks"), (1.0, 31.0));
+        assert_eq!(qscale_range("dnxhd"), (1.0, 31.0));
+
+        // The whole range is accepted by the encoder, values outside of it are reported instead of clamped
+        let path = crate::util::test::temp_path("qscale.mp4");
+        let open = |q: f64| {
+            let mut params = EncoderParams::new(EncoderCodec::H264, 320, 240, PixelFormat::YUV420P, 25.0);
+            params.bitrate = Bitrate::QScale(q);
+            let ret = Encoder::new(IoType::from(path.as_str()), params).and_then(|mut x| x.add_video_stream());
+            let _ = std::fs::remove_file(&path);
+            ret
+        };
+        for q in [0.0, 23.0, 51.0] {
+            assert!(open(q).is_ok(), "qscale {q}");
+        }
+        for q in [51.5, 1000.0] {
+            assert!(matches!(open(q), Err(VideoProcessingError::InvalidEncoderConfig { field: "bitrate", .. })), "qscale {q}");
+        }
+    }
+
+    #[test]
+    fn constant_bitrate() {
+        const FRAMES: usize = 10;
+        const FPS: f32 = 25.0;
+        const MBPS: f64 = 10.0;
+        let path = crate::util::test::temp_path("constant-bitrate.mp4");
+        let mut params = EncoderParams::new(EncoderCodec::H264, 1280, 720, PixelFormat::YUV420P, FPS);
+        params.bitrate = Bitrate::Constant(MBPS);
+        let mut encoder = Encoder::new(IoType::from(path.as_str()), params).unwrap();
+        for i in 0..FRAMES {
+            let shade = (i * 255 / FRAMES) as u8;
+            encoder.encode_frame(&mut VideoFrame::solid_color(PixelFormat::RGBA, 1280, 720, [shade, 128, 255 - shade, 255]).unwrap()).unwrap();
+        }
+        encoder.finish().unwrap();
+
+        // Solid colors compress to almost nothing, the rate control has to pad up to the constant rate
+        let bytes = std::fs::metadata(&path).unwrap().len();
+        let _ = std::fs::remove_file(&path);
+        let mbps = bytes as f64 * 8.0 / (FRAMES as f64 / FPS as f64) / 1_000_000.0;
+        assert!((mbps - MBPS).abs() <= MBPS * 0.2, "{mbps:.2} Mbps");
+    }
+}
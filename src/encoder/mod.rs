@@ -1,15 +1,145 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 // Copyright © 2023 Adrian <adrian.eddy at gmail>
 
+mod overlay; pub use overlay::*;
+mod ffmpeg; pub use ffmpeg::*;
+mod segments; pub use segments::*;
+mod negotiation; pub use negotiation::*;
+
 use std::collections::HashMap;
+use crate::types::{ IoType, VideoProcessingError };
+use crate::frame::{ VideoFrame, VideoFrameInterface };
+
+#[enum_delegate::register]
+pub trait EncoderInterface {
+    fn params(&self) -> &EncoderParams;
+    /// Adds the video stream described by the params and opens its encoder. Returns the stream index
+    fn add_video_stream(&mut self) -> Result<usize, VideoProcessingError>;
+    fn encode_frame(&mut self, frame: &mut VideoFrame) -> Result<(), VideoProcessingError>;
+    /// Encodes the next frame as a keyframe
+    fn force_keyframe(&mut self);
+    /// Embeds a file in the output, before the header is written
+    fn attach_file(&mut self, attachment: Attachment) -> Result<(), VideoProcessingError>;
+    /// Writes the packets held back by the muxer for interleaving and flushes the output, the encoder keeps going
+    fn flush(&mut self) -> Result<(), VideoProcessingError>;
+    /// Flushes the encoder and writes the trailer. Does nothing when already finished
+    fn finish(&mut self) -> Result<(), VideoProcessingError>;
+}
 
 pub struct Encoder {
+    inner: EncoderBackend,
+    segments: Option<SegmentRebaser>,
+    // Input timestamp of the next frame of the current segment, for frames without one
+    next_segment_input_us: i64,
+}
+
+impl Encoder {
+    /// Creates the output. Nothing is written until the first frame or `finish`
+    pub fn new(output: IoType, params: EncoderParams) -> Result<Self, VideoProcessingError> {
+        crate::backend::ensure_available("ffmpeg")?;
+        Ok(Self { inner: EncoderBackend::FfmpegEncoder(FfmpegEncoder::new(output, params)?), segments: None, next_segment_input_us: 0 })
+    }
+    pub fn params(&self) -> &EncoderParams {
+        self.inner.params()
+    }
+    /// Opens the video encoder before the first frame, so configuration errors are reported early.
+    /// Otherwise `encode_frame` does it, tagging the stream with the color description of the first frame where the params have none
+    pub fn add_video_stream(&mut self) -> Result<usize, VideoProcessingError> {
+        self.inner.add_video_stream()
+    }
+    /// Embeds a file (font, LUT, the source sidecar) in the output. Requires a Matroska output and has to be called before the first frame
+    pub fn attach_file(&mut self, name: &str, mime: &str, bytes: &[u8]) -> Result<(), VideoProcessingError> {
+        self.inner.attach_file(Attachment { name: name.into(), mime: mime.into(), data: bytes.to_vec() })
+    }
+    /// Frames are converted and scaled to the params format and size. Hardware frames are downloaded first.
+    /// The frame timestamps are kept, frames without one follow the previous frame
+    pub fn encode_frame(&mut self, frame: &mut VideoFrame) -> Result<(), VideoProcessingError> {
+        if let Some(segments) = self.segments.as_mut() {
+            let duration_us = frame.duration_us().filter(|x| *x > 0).unwrap_or((1_000_000.0 / self.inner.params().frame_rate as f64).round() as i64);
+            let timestamp_us = frame.timestamp_us().unwrap_or(self.next_segment_input_us);
+            self.next_segment_input_us = timestamp_us + duration_us;
+            // The output GOP doesn't follow the source, only intra-only codecs start every frame with a keyframe
+            let rebased = segments.rebase_video(timestamp_us, duration_us, self.inner.params().codec.max_gop_size() == 1);
+            frame.set_timestamp_us(Some(rebased.timestamp_us));
+            if rebased.force_keyframe {
+                self.inner.force_keyframe();
+            }
+        }
+        self.inner.encode_frame(frame)
+    }
+    /// Starts a new source when concatenating several of them (playlists, intros/outros). `expected_start_us` is the timestamp
+    /// of its first frame, e.g. the source start time. Its frames are shifted to start where the previous segment ended,
+    /// so the output stays continuous and monotonic, and the segment starts with a keyframe
+    pub fn begin_segment(&mut self, expected_start_us: i64) {
+        self.segments.get_or_insert_with(SegmentRebaser::new).begin_segment(expected_start_us);
+        self.next_segment_input_us = expected_start_us;
+    }
+    /// Ends the segment started with `begin_segment`, returns its duration in the output
+    pub fn end_segment(&mut self) -> i64 {
+        self.segments.as_mut().map_or(0, |x| x.end_segment())
+    }
+    /// Writes out what the muxer buffered so far, e.g. so a growing file can be read while encoding.
+    /// Frames still inside the codec (its lookahead) stay there until `finish`
+    pub fn flush(&mut self) -> Result<(), VideoProcessingError> {
+        self.inner.flush()
+    }
+    /// Writes the buffered frames and the trailer. Dropping the encoder does the same, without reporting errors
+    pub fn finish(mut self) -> Result<(), VideoProcessingError> {
+        self.inner.finish()
+    }
+}
+
+impl Drop for Encoder {
+    fn drop(&mut self) {
+        if let Err(e) = self.inner.finish() {
+            log::error!("Failed to finish the encoder: {e:?}");
+        }
+    }
+}
 
+#[enum_delegate::implement(EncoderInterface)]
+pub enum EncoderBackend {
+    FfmpegEncoder(FfmpegEncoder)
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum EncoderCodec {
     H264, H265, ProRes, DNxHR, PNG, EXR
 }
+
+impl EncoderCodec {
+    /// ffmpeg encoders in order of preference, with whether each is a hardware encoder. The software one is always last
+    pub fn encoders(&self, use_gpu: bool) -> Vec<(&'static str, bool)> {
+        let hw: &[&'static str] = match self {
+            EncoderCodec::H264 if cfg!(target_os = "macos") => &["h264_videotoolbox"],
+            EncoderCodec::H264 => &["h264_nvenc", "h264_amf", "h264_qsv", "h264_vaapi"],
+            EncoderCodec::H265 if cfg!(target_os = "macos") => &["hevc_videotoolbox"],
+            EncoderCodec::H265 => &["hevc_nvenc", "hevc_amf", "hevc_qsv", "hevc_vaapi"],
+            EncoderCodec::ProRes if cfg!(target_os = "macos") => &["prores_videotoolbox"],
+            _ => &[]
+        };
+        let sw = match self {
+            EncoderCodec::H264   => "libx264",
+            EncoderCodec::H265   => "libx265",
+            EncoderCodec::ProRes => "prores_ks",
+            EncoderCodec::DNxHR  => "dnxhd",
+            EncoderCodec::PNG    => "png",
+            EncoderCodec::EXR    => "exr",
+        };
+        let mut ret: Vec<_> = if use_gpu { hw.iter().map(|x| (*x, true)).collect() } else { Vec::new() };
+        ret.push((sw, false));
+        ret
+    }
+
+    /// Largest keyframe interval, intra-only codecs make every frame a keyframe
+    pub fn max_gop_size(&self) -> u32 {
+        match self {
+            EncoderCodec::H264 | EncoderCodec::H265 => i32::MAX as u32,
+            EncoderCodec::ProRes | EncoderCodec::DNxHR | EncoderCodec::PNG | EncoderCodec::EXR => 1,
+        }
+    }
+}
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Bitrate {
     Constant(f64), // in Mbps
     Variable((f64, f64)), // min, max in Mbps
@@ -17,19 +147,297 @@ pub enum Bitrate {
 }
 
 pub struct EncoderParams {
-    width: u32,
-    height: u32,
-    format: crate::types::PixelFormat,
-    bitrate: Bitrate,
-    codec: EncoderCodec,
-    use_gpu: bool,
-    frame_rate: f32,
-    time_base: Option<(u32, u32)>,
-    custom_options: HashMap<String, String>,
-
-    color_range_full: bool,
-    // color_space: Option<ColorSpace>,
-    // color_trc: Option<ColorTrc>,
-    // color_primaries: Option<ColorPrimaries>,
+    pub width: u32,
+    pub height: u32,
+    /// Format of the encoded frames, the closest one the encoder supports is used otherwise
+    pub format: crate::types::PixelFormat,
+    pub bitrate: Bitrate,
+    pub codec: EncoderCodec,
+    pub use_gpu: bool,
+    pub frame_rate: f32,
+    /// Maximum number of frames between keyframes, the codec default when `None`
+    pub gop_size: Option<u32>,
+    /// Codec time base, `1 / frame_rate` when `None`
+    pub time_base: Option<(u32, u32)>,
+    /// Passed to both the codec and the muxer, e.g. `preset` or `movflags`
+    pub custom_options: HashMap<String, String>,
+
+    pub color_range_full: bool,
+
+    // Output color tagging. When these differ from the input, frames are converted before encoding
+    pub out_color_space: Option<crate::types::ColorSpace>,
+    pub out_color_trc: Option<crate::types::ColorTrc>,
+    pub out_color_primaries: Option<crate::types::ColorPrimaries>,
+    pub out_color_range: Option<crate::types::ColorRange>,
+
     // aspect_ratio: Option<(u32, u32)>,
+
+    /// Chapter markers written to the output container (MP4, MOV, MKV)
+    pub chapters: Vec<crate::types::Chapter>,
+
+    /// Container tags
+    pub metadata: HashMap<String, String>,
+    /// Moves the MP4 index to the front when finishing, for progressive download. The index is moved in a second pass
+    /// which opens the output again, so it requires a file output, pipes and network outputs are rejected
+    pub faststart: bool,
+
+    /// Called for every frame after the pixel format conversion and before encoding.
+    /// The RGBA round trip is only done when this is set.
+    pub overlay: Option<OverlayCallback>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    pub name: String,
+    pub mime: String,
+    pub data: Vec<u8>,
+}
+
+impl EncoderParams {
+    /// 20 Mbps on the CPU, limited range and no container extras
+    pub fn new(codec: EncoderCodec, width: u32, height: u32, format: crate::types::PixelFormat, frame_rate: f32) -> Self {
+        Self {
+            width,
+            height,
+            format,
+            bitrate: Bitrate::Constant(20.0),
+            codec,
+            use_gpu: false,
+            frame_rate,
+            gop_size: None,
+            time_base: None,
+            custom_options: HashMap::new(),
+            color_range_full: false,
+            out_color_space: None,
+            out_color_trc: None,
+            out_color_primaries: None,
+            out_color_range: None,
+            chapters: Vec::new(),
+            metadata: HashMap::new(),
+            faststart: false,
+            overlay: None,
+        }
+    }
+
+    /// Output range, `out_color_range` takes precedence over `color_range_full`
+    pub fn color_range(&self) -> crate::types::ColorRange {
+        self.out_color_range.unwrap_or(if self.color_range_full { crate::types::ColorRange::Full } else { crate::types::ColorRange::Limited })
+    }
+
+    /// Checks the parameters against the encoder capabilities, so invalid combinations are reported
+    /// before the first frame instead of as ffmpeg error codes when writing
+    pub fn validate(&self, caps: &crate::CodecCapabilities) -> Result<(), VideoProcessingError> {
+        let invalid = |field, reason: String, allowed: String| Err(VideoProcessingError::InvalidEncoderConfig { field, reason, allowed });
+
+        if self.width == 0 || self.height == 0 {
+            return invalid("size", format!("{}x{} is empty", self.width, self.height), "non-zero width and height".into());
+        }
+        if let Some((sub_w, sub_h)) = crate::frame::chroma_subsampling(self.format) {
+            if self.width % sub_w != 0 || self.height % sub_h != 0 {
+                return invalid("size", format!("{}x{} is not a multiple of the {:?} chroma subsampling", self.width, self.height, self.format), format!("width multiple of {sub_w}, height multiple of {sub_h}"));
+            }
+        }
+        if !caps.devices.is_empty() && !caps.supports_size(self.width, self.height) {
+            let ranges = caps.devices.iter().map(|d| format!("{}: {}x{} - {}x{}", d.backend, d.min_size.0, d.min_size.1, d.max_size.0, d.max_size.1)).collect::<Vec<_>>();
+            return invalid("size", format!("{}x{} is outside of the hardware limits", self.width, self.height), ranges.join(", "));
+        }
+
+        // Unsupported formats are converted to the closest supported one, which is only an error when it loses precision
+        let supported: Vec<_> = crate::types::PixelFormat::ALL.iter().copied()
+            .filter(|x| crate::frame::to_ffmpeg_format(*x).is_some_and(|f| caps.pixel_formats.contains(&format!("{f:?}"))))
+            .collect();
+        if !caps.pixel_formats.is_empty() && !supported.contains(&self.format) && !supported.iter().any(|x| x.bit_depth() >= self.format.bit_depth()) {
+            return invalid("format", format!("{:?} has {} bits, {} supports at most {}", self.format, self.format.bit_depth(), caps.codec, supported.iter().map(|x| x.bit_depth()).max().unwrap_or_default()), caps.pixel_formats.join(", "));
+        }
+
+        match self.bitrate {
+            Bitrate::Constant(x) if x <= 0.0 || !x.is_finite() => {
+                return invalid("bitrate", format!("constant bitrate {x} Mbps"), "> 0".into());
+            },
+            Bitrate::Variable((min, max)) if min < 0.0 || max <= 0.0 || min > max || !min.is_finite() || !max.is_finite() => {
+                return invalid("bitrate", format!("variable bitrate {min} - {max} Mbps"), "0 <= min <= max, max > 0".into());
+            },
+            Bitrate::QScale(q) if q < 0.0 || !q.is_finite() => {
+                return invalid("bitrate", format!("qscale {q}"), ">= 0".into());
+            },
+            _ => { }
+        }
+
+        if let Some(gop) = self.gop_size {
+            if gop == 0 || gop > self.codec.max_gop_size() {
+                return invalid("gop_size", format!("{gop} frames"), format!("1 - {}", self.codec.max_gop_size()));
+            }
+        }
+
+        if self.frame_rate <= 0.0 || !self.frame_rate.is_finite() {
+            return invalid("frame_rate", format!("{}", self.frame_rate), "> 0".into());
+        }
+        if let Some((num, den)) = self.time_base {
+            if num == 0 || den == 0 {
+                return invalid("time_base", format!("{num}/{den}"), "non-zero numerator and denominator".into());
+            }
+            // Every frame needs a distinct timestamp
+            if (den as f64 / num as f64) < self.frame_rate as f64 - 0.001 {
+                return invalid("time_base", format!("{num}/{den} can't represent {} fps", self.frame_rate), format!("time base of at most 1/{}", self.frame_rate.ceil()));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::PixelFormat;
+
+    fn x264() -> crate::CodecCapabilities {
+        crate::CodecCapabilities {
+            codec: "libx264".into(),
+            is_encoder: true,
+            profiles: Vec::new(),
+            pixel_formats: ["YUV420P", "YUV422P", "YUV444P", "NV12"].map(String::from).to_vec(),
+            devices: Vec::new(),
+        }
+    }
+    fn params() -> EncoderParams {
+        EncoderParams::new(EncoderCodec::H264, 1920, 1080, PixelFormat::YUV420P, 30.0)
+    }
+    fn invalid_field(params: &EncoderParams, caps: &crate::CodecCapabilities) -> Option<&'static str> {
+        match params.validate(caps) {
+            Ok(()) => None,
+            Err(VideoProcessingError::InvalidEncoderConfig { field, .. }) => Some(field),
+            Err(e) => panic!("unexpected error {e:?}"),
+        }
+    }
+
+    #[test]
+    fn valid_configs() {
+        assert_eq!(invalid_field(&params(), &x264()), None);
+        // Converted to the closest supported format without losing precision
+        assert_eq!(invalid_field(&EncoderParams { format: PixelFormat::RGBA, ..params() }, &x264()), None);
+        assert_eq!(invalid_field(&EncoderParams { gop_size: Some(250), time_base: Some((1, 90000)), ..params() }, &x264()), None);
+        assert_eq!(invalid_field(&EncoderParams { bitrate: Bitrate::Variable((0.0, 50.0)), ..params() }, &x264()), None);
+        assert_eq!(invalid_field(&EncoderParams { gop_size: Some(1), ..EncoderParams::new(EncoderCodec::ProRes, 1920, 1080, PixelFormat::YUV422P10LE, 24.0) }, &crate::CodecCapabilities { pixel_formats: Vec::new(), ..x264() }), None);
+    }
+
+    #[test]
+    fn invalid_configs() {
+        let sw = x264();
+        let nvenc = crate::CodecCapabilities {
+            devices: vec![crate::DeviceCapabilities { backend: "cuda".into(), min_size: (144, 144), max_size: (4096, 4096), hw_formats: Vec::new(), sw_formats: Vec::new() }],
+            ..x264()
+        };
+        let prores = EncoderParams::new(EncoderCodec::ProRes, 1920, 1080, PixelFormat::YUV422P10LE, 24.0);
+        let matrix: Vec<(EncoderParams, &crate::CodecCapabilities, &str)> = vec![
+            (EncoderParams { width: 0, ..params() }, &sw, "size"),
+            (EncoderParams { width: 1921, ..params() }, &sw, "size"),
+            (EncoderParams { height: 1081, ..params() }, &sw, "size"),
+            (EncoderParams { width: 8192, height: 4320, ..params() }, &nvenc, "size"),
+            (EncoderParams { width: 64, height: 64, ..params() }, &nvenc, "size"),
+            (EncoderParams { format: PixelFormat::P010LE, ..params() }, &sw, "format"),
+            (EncoderParams { format: PixelFormat::YUV444P16LE, ..params() }, &sw, "format"),
+            (EncoderParams { bitrate: Bitrate::Constant(0.0), ..params() }, &sw, "bitrate"),
+            (EncoderParams { bitrate: Bitrate::Constant(f64::NAN), ..params() }, &sw, "bitrate"),
+            (EncoderParams { bitrate: Bitrate::Variable((20.0, 10.0)), ..params() }, &sw, "bitrate"),
+            (EncoderParams { bitrate: Bitrate::QScale(-1.0), ..params() }, &sw, "bitrate"),
+            (EncoderParams { gop_size: Some(0), ..params() }, &sw, "gop_size"),
+            (EncoderParams { gop_size: Some(12), ..prores }, &sw, "gop_size"),
+            (EncoderParams { frame_rate: 0.0, ..params() }, &sw, "frame_rate"),
+            (EncoderParams { frame_rate: f32::INFINITY, ..params() }, &sw, "frame_rate"),
+            (EncoderParams { time_base: Some((0, 30)), ..params() }, &sw, "time_base"),
+            (EncoderParams { time_base: Some((1, 25)), ..params() }, &sw, "time_base"),
+        ];
+        for (params, caps, field) in &matrix {
+            assert_eq!(invalid_field(params, caps), Some(*field), "{}x{} {:?} {:?} gop {:?} fps {} tb {:?}", params.width, params.height, params.format, params.bitrate, params.gop_size, params.frame_rate, params.time_base);
+        }
+    }
+    #[test]
+    fn attachments() {
+        let path = crate::util::test::temp_path("attachments.mkv");
+        let mut encoder = Encoder::new(IoType::from(path.as_str()), EncoderParams::new(EncoderCodec::H264, 320, 240, PixelFormat::YUV420P, 30.0)).unwrap();
+        encoder.attach_file("look.cube", "application/octet-stream", b"LUT_3D_SIZE 2").unwrap();
+        let mut frame = VideoFrame::solid_color(PixelFormat::RGBA, 320, 240, [20, 40, 60, 255]).unwrap();
+        encoder.encode_frame(&mut frame).unwrap();
+        assert!(encoder.attach_file("late.txt", "text/plain", b"late").is_err());
+        encoder.finish().unwrap();
+
+        let ictx = ffmpeg_next::format::input(&path).unwrap();
+        let attachment = ictx.streams().find(|x| x.parameters().medium() == ffmpeg_next::media::Type::Attachment).unwrap();
+        assert_eq!(attachment.metadata().get("filename"), Some("look.cube"));
+        assert_eq!(attachment.metadata().get("mimetype"), Some("application/octet-stream"));
+        let data = unsafe {
+            let par = attachment.parameters().as_ptr();
+            std::slice::from_raw_parts((*par).extradata, (*par).extradata_size as usize).to_vec()
+        };
+        drop(ictx);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(data, b"LUT_3D_SIZE 2");
+
+        let path = crate::util::test::temp_path("attachments.mp4");
+        let mut encoder = Encoder::new(IoType::from(path.as_str()), EncoderParams::new(EncoderCodec::H264, 320, 240, PixelFormat::YUV420P, 30.0)).unwrap();
+        assert!(matches!(encoder.attach_file("look.cube", "application/octet-stream", b""), Err(VideoProcessingError::InvalidEncoderConfig { field: "attachments", .. })));
+        drop(encoder);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn concatenated_segments() {
+        let clips = [(crate::util::test::temp_path("segment1.ts"), 15), (crate::util::test::temp_path("segment2.ts"), 10)];
+        for (path, frames) in &clips {
+            crate::util::test::write_test_clip(path, EncoderCodec::H264, (320, 240), *frames, 30.0).unwrap();
+        }
+        let path = crate::util::test::temp_path("segments.mp4");
+        let mut encoder = Encoder::new(IoType::from(path.as_str()), EncoderParams::new(EncoderCodec::H264, 320, 240, PixelFormat::YUV420P, 30.0)).unwrap();
+        let mut durations = Vec::new();
+        for (clip, _) in &clips {
+            let mut decoder = crate::Decoder::new(clip, Default::default()).unwrap();
+            let mut frames: Vec<VideoFrame> = std::iter::from_fn(|| decoder.next_frame()).filter_map(|f| match f { crate::Frame::Video(v) => Some(v), _ => None }).collect();
+            // MPEG-TS starts at 1.4 s, every segment has to be moved back to where the previous one ended
+            encoder.begin_segment(frames[0].timestamp_us().unwrap());
+            for frame in &mut frames {
+                encoder.encode_frame(frame).unwrap();
+            }
+            durations.push(encoder.end_segment());
+        }
+        encoder.finish().unwrap();
+
+        let mut decoder = crate::Decoder::new(&path, Default::default()).unwrap();
+        let mut frames: Vec<(i64, bool)> = Vec::new();
+        while let Some(frame) = decoder.next_frame() {
+            if let crate::Frame::Video(mut v) = frame {
+                frames.push((v.timestamp_us().unwrap(), v.describe().key_frame));
+            }
+        }
+        drop(decoder);
+        for (clip, _) in &clips { let _ = std::fs::remove_file(clip); }
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(durations.iter().map(|x| (x + 500) / 1000).collect::<Vec<_>>(), [500, 333]);
+        assert_eq!(frames.len(), 25);
+        assert!(frames.windows(2).all(|w| w[1].0 > w[0].0), "{frames:?}");
+        assert!(frames[0].0 < 1000 && frames[15].1, "{frames:?}");
+        // The output lasts as long as both sources
+        let duration = frames[24].0 - frames[0].0 + 33_333;
+        assert!((duration - durations.iter().sum::<i64>()).abs() < 1000, "{duration} {durations:?}");
+    }
+
+    #[test]
+    fn faststart() {
+        for url in ["pipe:1", "-", "http://127.0.0.1/out.mp4", "tcp://127.0.0.1:9000"] {
+            let params = EncoderParams { faststart: true, ..EncoderParams::new(EncoderCodec::H264, 320, 240, PixelFormat::YUV420P, 30.0) };
+            assert!(matches!(Encoder::new(IoType::from(url), params), Err(VideoProcessingError::InvalidEncoderConfig { field: "faststart", .. })), "{url}");
+        }
+        assert!(can_reopen("out.mp4") && can_reopen("file:out.mp4"));
+
+        let path = crate::util::test::temp_path("faststart.mp4");
+        let mut encoder = Encoder::new(IoType::from(path.as_str()), EncoderParams { faststart: true, ..EncoderParams::new(EncoderCodec::H264, 320, 240, PixelFormat::YUV420P, 30.0) }).unwrap();
+        for _ in 0..5 {
+            encoder.encode_frame(&mut VideoFrame::solid_color(PixelFormat::RGBA, 320, 240, [20, 40, 60, 255]).unwrap()).unwrap();
+        }
+        encoder.finish().unwrap();
+        let data = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let position = |atom: &[u8]| data.windows(4).position(|x| x == atom).unwrap();
+        assert!(position(b"moov") < position(b"mdat"));
+    }
 }
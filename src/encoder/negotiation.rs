@@ -0,0 +1,229 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+use crate::support::ffmpeg_hw;
+use crate::types::PixelFormat;
+use ffmpeg_next::format::Pixel;
+
+/// Rough relative cost of getting the input frames to the encoder, for warnings in export dialogs
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ConversionCost {
+    /// Frames are passed as they are
+    None,
+    /// Same samples in a different arrangement (YUV420P to NV12, RGBA to BGRA), or converted by the encoder device
+    Cheap,
+    /// Bit depth or chroma subsampling change, or scaling
+    Moderate,
+    /// RGB and YUV, float or palette conversion on the CPU
+    Expensive,
+}
+
+impl ConversionCost {
+    /// Cost of a CPU conversion between the formats
+    pub fn between(from: PixelFormat, to: PixelFormat) -> Self {
+        if from == to { return Self::None; }
+        if from == PixelFormat::Unknown || to == PixelFormat::Unknown || from.is_float() || to.is_float() { return Self::Expensive; }
+        if color_model(from) != color_model(to) { return Self::Expensive; }
+        if from.bit_depth() == to.bit_depth() && crate::frame::chroma_subsampling(from) == crate::frame::chroma_subsampling(to) {
+            Self::Cheap
+        } else {
+            Self::Moderate
+        }
+    }
+}
+
+fn color_model(format: PixelFormat) -> u8 {
+    use crate::types::PlaneRole::*;
+    match format.plane_layouts().first().map(|x| x.role()) {
+        Some(Rgb | Red | Green | Blue) => 1,
+        Some(PaletteIndex) => 2,
+        _ => 0
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ProcessingDevice {
+    Cpu,
+    Gpu,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FormatConversion {
+    pub from: PixelFormat,
+    pub to: PixelFormat,
+    /// `Gpu` when the frames are uploaded as they are and the encoder device converts them
+    pub device: ProcessingDevice,
+}
+
+/// What happens to the frames between the caller and the encoder, see `EncoderParams::negotiate`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NegotiationReport {
+    /// Encoder implementation, e.g. `hevc_nvenc` or `libx265`. `None` when this build has none for the codec
+    pub encoder: Option<String>,
+    /// Device type of a hardware encoder, e.g. `cuda`
+    pub hardware_device: Option<String>,
+    /// Format the encoder receives
+    pub encoder_format: PixelFormat,
+    /// `None` when the input is passed as is
+    pub conversion: Option<FormatConversion>,
+    /// Whether the input size differs from the output size
+    pub scaling: bool,
+    /// Decoder hardware backends (names as in `supported_gpu_backends`) whose frames can be encoded without a copy through the CPU
+    pub zero_copy_from: Vec<String>,
+    pub cost: ConversionCost,
+}
+
+impl NegotiationReport {
+    pub fn supports_zero_copy_from(&self, backend: &str) -> bool {
+        self.zero_copy_from.iter().any(|x| x.eq_ignore_ascii_case(backend))
+    }
+}
+
+impl super::EncoderParams {
+    /// Reports how frames of `input_format` and `input_size` would reach the encoder for these params, without an output
+    /// or opening the encoder. No device is created either: a hardware encoder is reported when its device was already
+    /// created in this process or worked in an earlier launch (see `WarmStart`), the software encoder otherwise.
+    /// The formats the hardware frames context accepts are only known for devices created in this process
+    pub fn negotiate(&self, input_format: PixelFormat, input_size: (u32, u32)) -> NegotiationReport {
+        let scaling = input_size != (self.width, self.height);
+        let mut report = NegotiationReport {
+            encoder: None,
+            hardware_device: None,
+            encoder_format: PixelFormat::Unknown,
+            conversion: None,
+            scaling,
+            zero_copy_from: Vec::new(),
+            cost: ConversionCost::Expensive,
+        };
+        if crate::backend::init_ffmpeg().is_err() { return report; }
+
+        let device = self.custom_options.get("hwaccel_device").map(String::as_str);
+        let Some((name, device_type)) = ffmpeg_hw::find_known_encoder(&self.codec.encoders(self.use_gpu), device) else { return report; };
+        let Some(mut codec) = ffmpeg_next::encoder::find_by_name(name) else { return report; };
+        let ptr = unsafe { codec.as_mut_ptr() };
+
+        let codec_formats: Vec<Pixel> = unsafe { if (*ptr).pix_fmts.is_null() { Vec::new() } else { ffmpeg_hw::pix_formats_to_vec((*ptr).pix_fmts) } }
+            .into_iter()
+            .filter(|x| !ffmpeg_hw::is_hardware_format((*x).into()))
+            .collect();
+        let device_formats = device_type.map(|x| ffmpeg_hw::device_sw_formats(x, device)).unwrap_or_default();
+
+        report.encoder = Some(name.to_string());
+        report.hardware_device = device_type.map(ffmpeg_hw::type_name);
+        if device_type.is_some() {
+            report.zero_copy_from = ffmpeg_hw::codec_hw_frame_types(ptr);
+        }
+
+        let (target, conversion_device) = select_format(crate::frame::to_ffmpeg_format(input_format), &codec_formats, &device_formats);
+        report.encoder_format = PixelFormat::ALL.iter().copied().find(|x| crate::frame::to_ffmpeg_format(*x) == Some(target)).unwrap_or(PixelFormat::Unknown);
+
+        let format_cost = if report.encoder_format == input_format {
+            ConversionCost::None
+        } else {
+            report.conversion = Some(FormatConversion { from: input_format, to: report.encoder_format, device: conversion_device });
+            match conversion_device {
+                ProcessingDevice::Gpu => ConversionCost::Cheap,
+                ProcessingDevice::Cpu => ConversionCost::between(input_format, report.encoder_format),
+            }
+        };
+        report.cost = if scaling { format_cost.max(ConversionCost::Moderate) } else { format_cost };
+        report
+    }
+}
+
+/// Format the encoder receives and where the input is converted to it, in the same order as the encoder setup:
+/// the codec formats, then the formats the hardware frames context takes and converts on the device
+fn select_format(input: Option<Pixel>, codec_formats: &[Pixel], device_formats: &[Pixel]) -> (Pixel, ProcessingDevice) {
+    let codec_target = |x: Pixel| if codec_formats.is_empty() { x } else { ffmpeg_hw::find_best_matching_codec(x, codec_formats) };
+    match input {
+        Some(x) if codec_formats.contains(&x) => (x, ProcessingDevice::Cpu),
+        Some(x) if device_formats.contains(&x) => (codec_target(x), ProcessingDevice::Gpu),
+        _ if !device_formats.is_empty() => (ffmpeg_hw::find_best_matching_codec(input.unwrap_or(Pixel::None), device_formats), ProcessingDevice::Cpu),
+        _ => (codec_target(input.unwrap_or(Pixel::None)), ProcessingDevice::Cpu),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ EncoderCodec, EncoderParams };
+    use PixelFormat::*;
+
+    #[test]
+    fn conversion_costs() {
+        for (from, to, cost) in [
+            (NV12, NV12, ConversionCost::None),
+            (YUV420P, NV12, ConversionCost::Cheap),
+            (YUV420P10LE, P010LE, ConversionCost::Cheap),
+            (RGBA, BGRA, ConversionCost::Cheap),
+            (YUV422P10LE, P010LE, ConversionCost::Moderate),
+            (NV12, P010LE, ConversionCost::Moderate),
+            (RGB48LE, RGB24, ConversionCost::Moderate),
+            (RGBF16, P010LE, ConversionCost::Expensive),
+            (RGBA, NV12, ConversionCost::Expensive),
+            (GRAY8, RGBA, ConversionCost::Expensive),
+            (Unknown, NV12, ConversionCost::Expensive),
+        ] {
+            assert_eq!(ConversionCost::between(from, to), cost, "{from:?} to {to:?}");
+        }
+    }
+
+    #[test]
+    fn format_selection() {
+        // e.g. NVENC: YUV codec formats, the CUDA frames context also takes RGB and converts it on the GPU
+        let codec = [Pixel::NV12, Pixel::P010LE, Pixel::YUV444P];
+        let device = [Pixel::NV12, Pixel::P010LE, Pixel::RGBA, Pixel::BGRA];
+        assert_eq!(select_format(Some(Pixel::P010LE), &codec, &device), (Pixel::P010LE, ProcessingDevice::Cpu));
+        let (target, device_used) = select_format(Some(Pixel::BGRA), &codec, &device);
+        assert_eq!(device_used, ProcessingDevice::Gpu);
+        assert!(codec.contains(&target));
+        // Neither takes it, converted on the CPU to what the device takes
+        let (target, device_used) = select_format(Some(Pixel::YUV422P10LE), &codec, &device);
+        assert_eq!(device_used, ProcessingDevice::Cpu);
+        assert!(device.contains(&target));
+        // Software encoders
+        assert_eq!(select_format(Some(Pixel::YUV422P10LE), &[Pixel::YUV422P10LE, Pixel::YUV444P10LE], &[]).0, Pixel::YUV422P10LE);
+        assert_eq!(select_format(Some(Pixel::RGB48LE), &[], &[]), (Pixel::RGB48LE, ProcessingDevice::Cpu));
+        assert!(matches!(select_format(Some(Pixel::P010LE), &[Pixel::YUV422P10LE, Pixel::YUV444P10LE], &[]).0, Pixel::YUV422P10LE | Pixel::YUV444P10LE));
+    }
+
+    #[test]
+    fn software_matrix() {
+        let negotiate = |codec, input, size| EncoderParams::new(codec, 1920, 1080, YUV420P, 25.0).negotiate(input, size);
+
+        let report = negotiate(EncoderCodec::H264, YUV420P, (1920, 1080));
+        assert_eq!(report.encoder.as_deref(), Some("libx264"));
+        assert_eq!(report.hardware_device, None);
+        assert!(report.zero_copy_from.is_empty());
+        assert_eq!((report.encoder_format, report.conversion, report.cost), (YUV420P, None, ConversionCost::None));
+
+        let report = negotiate(EncoderCodec::H264, YUV420P, (3840, 2160));
+        assert!(report.scaling);
+        assert_eq!(report.cost, ConversionCost::Moderate);
+
+        let report = negotiate(EncoderCodec::H264, RGBA, (1920, 1080));
+        let conversion = report.conversion.unwrap();
+        assert_eq!((conversion.from, conversion.device), (RGBA, ProcessingDevice::Cpu));
+        assert_eq!(conversion.to, report.encoder_format);
+        assert_eq!(report.cost, ConversionCost::Expensive);
+
+        // ProRes is 4:2:2 or 4:4:4 at 10 bits
+        let report = negotiate(EncoderCodec::ProRes, P010LE, (1920, 1080));
+        assert_eq!(report.encoder.as_deref(), Some("prores_ks"));
+        assert_eq!(report.encoder_format.bit_depth(), 10);
+        assert_eq!(report.cost, ConversionCost::Moderate);
+        assert_eq!(negotiate(EncoderCodec::ProRes, YUV422P10LE, (1920, 1080)).cost, ConversionCost::None);
+        assert_eq!(negotiate(EncoderCodec::ProRes, RGBF16, (1920, 1080)).cost, ConversionCost::Expensive);
+    }
+
+    #[test]
+    fn unknown_devices_are_not_created() {
+        let mut params = EncoderParams::new(EncoderCodec::H264, 1280, 720, NV12, 30.0);
+        params.use_gpu = true;
+        // Never created nor hinted, so only the software encoder is known to work
+        params.custom_options.insert("hwaccel_device".into(), "gpu-video-negotiation-test".into());
+        let report = params.negotiate(NV12, (1280, 720));
+        assert_eq!(report.encoder.as_deref(), Some("libx264"));
+        assert_eq!(report.hardware_device, None);
+    }
+}
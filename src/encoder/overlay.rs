@@ -0,0 +1,135 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+pub type OverlayCallback = Box<dyn FnMut(OverlayContext) + Send>;
+
+/// Mutable RGBA view of the frame being encoded.
+/// When the encode format is YUV, the frame is converted to RGBA before the callback and back after it.
+pub struct OverlayContext<'a> {
+    pub rgba: &'a mut [u8],
+    pub width: u32,
+    pub height: u32,
+    pub stride: usize,
+    pub timestamp_us: Option<i64>,
+    pub timecode: String,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Anchor {
+    TopLeft, TopCenter, TopRight,
+    CenterLeft, Center, CenterRight,
+    BottomLeft, BottomCenter, BottomRight,
+}
+
+impl Anchor {
+    fn position(&self, size: (u32, u32), frame_size: (u32, u32), margin: u32) -> (i64, i64) {
+        let (w, h) = (size.0 as i64, size.1 as i64);
+        let (fw, fh) = (frame_size.0 as i64, frame_size.1 as i64);
+        let m = margin as i64;
+        let x = match self {
+            Anchor::TopLeft | Anchor::CenterLeft | Anchor::BottomLeft => m,
+            Anchor::TopCenter | Anchor::Center | Anchor::BottomCenter => (fw - w) / 2,
+            Anchor::TopRight | Anchor::CenterRight | Anchor::BottomRight => fw - w - m,
+        };
+        let y = match self {
+            Anchor::TopLeft | Anchor::TopCenter | Anchor::TopRight => m,
+            Anchor::CenterLeft | Anchor::Center | Anchor::CenterRight => (fh - h) / 2,
+            Anchor::BottomLeft | Anchor::BottomCenter | Anchor::BottomRight => fh - h - m,
+        };
+        (x, y)
+    }
+}
+
+/// Formats the timestamp as `HH:MM:SS:FF` non-drop-frame timecode
+pub fn timecode(timestamp_us: i64, fps: f64) -> String {
+    let fps_int = fps.round().max(1.0) as i64;
+    let frames = (timestamp_us as f64 * fps / 1_000_000.0).round() as i64;
+    let ff = frames % fps_int;
+    let total_secs = frames / fps_int;
+    format!("{:02}:{:02}:{:02}:{:02}", total_secs / 3600, (total_secs / 60) % 60, total_secs % 60, ff)
+}
+
+// 3x5 bitmap font, each row is 3 bits (MSB is the leftmost pixel)
+fn glyph(c: char) -> Option<[u8; 5]> {
+    Some(match c {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        ';' => [0b000, 0b010, 0b000, 0b010, 0b100],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        ' ' => [0; 5],
+        _ => return None
+    })
+}
+
+/// Renders `text` with the embedded bitmap font. Only digits and timecode separators are supported.
+pub fn draw_text(ctx: &mut OverlayContext, text: &str, anchor: Anchor, scale: u32, color: [u8; 4], background: Option<[u8; 4]>) {
+    let scale = scale.max(1);
+    let chars: Vec<[u8; 5]> = text.chars().filter_map(glyph).collect();
+    if chars.is_empty() { return; }
+    let text_w = (chars.len() as u32 * 4 - 1) * scale;
+    let text_h = 5 * scale;
+    let pad = scale;
+    let (x0, y0) = anchor.position((text_w + pad * 2, text_h + pad * 2), (ctx.width, ctx.height), scale * 2);
+
+    if let Some(bg) = background {
+        fill_rect(ctx, x0, y0, text_w + pad * 2, text_h + pad * 2, bg);
+    }
+    for (i, g) in chars.iter().enumerate() {
+        let gx = x0 + pad as i64 + (i as u32 * 4 * scale) as i64;
+        for (row, bits) in g.iter().enumerate() {
+            for col in 0..3 {
+                if bits & (0b100 >> col) != 0 {
+                    fill_rect(ctx, gx + (col * scale) as i64, y0 + pad as i64 + (row as u32 * scale) as i64, scale, scale, color);
+                }
+            }
+        }
+    }
+}
+
+pub fn draw_timecode(ctx: &mut OverlayContext, anchor: Anchor, scale: u32) {
+    let tc = ctx.timecode.clone();
+    draw_text(ctx, &tc, anchor, scale, [255, 255, 255, 255], Some([0, 0, 0, 160]));
+}
+
+/// Alpha-blends an RGBA image onto the frame. `opacity` is multiplied with the image's own alpha.
+pub fn blend_watermark(ctx: &mut OverlayContext, image: &[u8], image_size: (u32, u32), anchor: Anchor, margin: u32, opacity: f32) {
+    let (x0, y0) = anchor.position(image_size, (ctx.width, ctx.height), margin);
+    let opacity = opacity.clamp(0.0, 1.0);
+    for y in 0..image_size.1 as i64 {
+        for x in 0..image_size.0 as i64 {
+            let src_i = ((y * image_size.0 as i64 + x) * 4) as usize;
+            if src_i + 4 > image.len() { return; }
+            let src = &image[src_i..src_i + 4];
+            blend_pixel(ctx, x0 + x, y0 + y, [src[0], src[1], src[2], (src[3] as f32 * opacity) as u8]);
+        }
+    }
+}
+
+fn fill_rect(ctx: &mut OverlayContext, x: i64, y: i64, w: u32, h: u32, color: [u8; 4]) {
+    for yy in y..y + h as i64 {
+        for xx in x..x + w as i64 {
+            blend_pixel(ctx, xx, yy, color);
+        }
+    }
+}
+
+fn blend_pixel(ctx: &mut OverlayContext, x: i64, y: i64, color: [u8; 4]) {
+    if x < 0 || y < 0 || x >= ctx.width as i64 || y >= ctx.height as i64 { return; }
+    let i = y as usize * ctx.stride + x as usize * 4;
+    if i + 4 > ctx.rgba.len() { return; }
+    let a = color[3] as u32;
+    for c in 0..3 {
+        ctx.rgba[i + c] = ((color[c] as u32 * a + ctx.rgba[i + c] as u32 * (255 - a)) / 255) as u8;
+    }
+    ctx.rgba[i + 3] = (a + ctx.rgba[i + 3] as u32 * (255 - a) / 255) as u8;
+}
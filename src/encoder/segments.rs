@@ -0,0 +1,102 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+/// Rebased timestamp of a video frame
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RebasedVideo {
+    pub timestamp_us: i64,
+    /// First frame of a segment which isn't a keyframe in the source, the encoder has to force one
+    pub force_keyframe: bool,
+}
+
+/// Timestamp bookkeeping for feeding the encoder from several sources back to back (playlists, intros/outros).
+/// Every segment is shifted to start where the previous one ended, so the output timeline is continuous and monotonic.
+#[derive(Debug, Clone, Default)]
+pub struct SegmentRebaser {
+    // Output timestamp where the next segment starts
+    next_start_us: i64,
+    // Added to the input timestamps of the current segment
+    offset_us: i64,
+    segment_start_us: i64,
+    segment_end_us: i64,
+    last_video_us: Option<i64>,
+    last_audio_us: Option<i64>,
+    needs_keyframe: bool,
+}
+
+impl SegmentRebaser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `expected_start_us` is the input timestamp of the first frame of the segment, e.g. the source start time
+    pub fn begin_segment(&mut self, expected_start_us: i64) {
+        self.segment_start_us = self.next_start_us;
+        self.segment_end_us = self.next_start_us;
+        self.offset_us = self.next_start_us - expected_start_us;
+        self.needs_keyframe = true;
+    }
+
+    /// Returns the segment duration in the output timeline
+    pub fn end_segment(&mut self) -> i64 {
+        self.next_start_us = self.segment_end_us;
+        self.segment_end_us - self.segment_start_us
+    }
+
+    pub fn rebase_video(&mut self, timestamp_us: i64, duration_us: i64, is_keyframe: bool) -> RebasedVideo {
+        let ts = Self::monotonic(&mut self.last_video_us, (timestamp_us + self.offset_us).max(self.segment_start_us));
+        self.segment_end_us = self.segment_end_us.max(ts + duration_us.max(0));
+        let force_keyframe = std::mem::take(&mut self.needs_keyframe) && !is_keyframe;
+        RebasedVideo { timestamp_us: ts, force_keyframe }
+    }
+
+    /// `None` for samples before the segment start, e.g. the AAC priming samples of every source after the first.
+    /// The encoder adds its own priming once, at the start of the output
+    pub fn rebase_audio(&mut self, timestamp_us: i64, duration_us: i64) -> Option<i64> {
+        let ts = timestamp_us + self.offset_us;
+        if ts + duration_us.max(0) <= self.segment_start_us {
+            return None;
+        }
+        let ts = Self::monotonic(&mut self.last_audio_us, ts.max(self.segment_start_us));
+        self.segment_end_us = self.segment_end_us.max(ts + duration_us.max(0));
+        Some(ts)
+    }
+
+    fn monotonic(last: &mut Option<i64>, ts: i64) -> i64 {
+        let ts = match *last {
+            Some(prev) if ts <= prev => prev + 1,
+            _ => ts
+        };
+        *last = Some(ts);
+        ts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segments_are_continuous() {
+        let mut rebaser = SegmentRebaser::new();
+        rebaser.begin_segment(1_400_000);
+        let first: Vec<_> = (0..3).map(|i| rebaser.rebase_video(1_400_000 + i * 40_000, 40_000, i == 0)).collect();
+        assert_eq!(first.iter().map(|x| x.timestamp_us).collect::<Vec<_>>(), [0, 40_000, 80_000]);
+        assert!(first.iter().all(|x| !x.force_keyframe));
+        assert_eq!(rebaser.end_segment(), 120_000);
+
+        // Starts on a non-keyframe, with the AAC priming samples before the start
+        rebaser.begin_segment(0);
+        let second = rebaser.rebase_video(0, 40_000, false);
+        assert_eq!(second, RebasedVideo { timestamp_us: 120_000, force_keyframe: true });
+        assert!(!rebaser.rebase_video(40_000, 40_000, false).force_keyframe);
+        assert_eq!(rebaser.rebase_audio(-21_333, 21_333), None);
+        assert_eq!(rebaser.rebase_audio(0, 21_333), Some(120_000));
+        assert_eq!(rebaser.end_segment(), 80_000);
+
+        // Timestamps going backwards inside a segment are kept monotonic
+        rebaser.begin_segment(0);
+        assert_eq!(rebaser.rebase_video(0, 40_000, true).timestamp_us, 200_000);
+        assert_eq!(rebaser.rebase_video(-5_000, 40_000, false).timestamp_us, 200_001);
+    }
+}
@@ -0,0 +1,197 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+// Perceptual fingerprints for detecting the same essence in files with different containers, codecs or metadata.
+
+use crate::*;
+
+#[derive(Debug, Clone, Copy)]
+pub struct FingerprintOptions {
+    /// Number of evenly spaced positions sampled from the video (and audio)
+    pub frames: usize,
+    /// Resolution the sampled frames are decoded at, where the codec can decode at a lower resolution (`lowres`).
+    /// The luma is reduced to 9x8 for hashing either way
+    pub mode: DecodeMode,
+}
+
+/// In `Fingerprint::frame_hashes` at positions where no frame could be decoded, skipped by `Fingerprint::similarity`
+pub const MISSING_FRAME_HASH: u64 = u64::MAX;
+/// In `Fingerprint::audio_peaks` at positions where no audio could be decoded, skipped by `Fingerprint::similarity`
+pub const MISSING_AUDIO_PEAK: u8 = u8::MAX;
+
+impl Default for FingerprintOptions {
+    fn default() -> Self {
+        Self { frames: 16, mode: DecodeMode::Eighth }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Fingerprint {
+    pub duration_ms: f64,
+    /// 64-bit difference hash of the luma of each sampled frame, `MISSING_FRAME_HASH` where the seek failed
+    pub frame_hashes: Vec<u64>,
+    /// Peak level (0-254) at each sampled position, `MISSING_AUDIO_PEAK` where the seek failed. `None` for inputs without audio
+    pub audio_peaks: Option<Vec<u8>>,
+}
+
+impl Fingerprint {
+    /// 1.0 for the same essence, close to 0.0 for unrelated clips. Transcodes and rewraps usually score above 0.9
+    pub fn similarity(&self, other: &Fingerprint) -> f32 {
+        let frames = self.frame_hashes.iter().zip(&other.frame_hashes)
+            .filter(|(a, b)| **a != MISSING_FRAME_HASH && **b != MISSING_FRAME_HASH)
+            .map(|(a, b)| 1.0 - (a ^ b).count_ones() as f32 / 64.0).collect::<Vec<_>>();
+        let video = if frames.is_empty() { 0.0 } else { frames.iter().sum::<f32>() / frames.len() as f32 };
+
+        let longer = self.duration_ms.max(other.duration_ms);
+        let duration = if longer > 0.0 { (self.duration_ms.min(other.duration_ms) / longer) as f32 } else { 1.0 };
+
+        let audio = match (&self.audio_peaks, &other.audio_peaks) {
+            (Some(a), Some(b)) => {
+                let diffs = a.iter().zip(b).filter(|(a, b)| **a != MISSING_AUDIO_PEAK && **b != MISSING_AUDIO_PEAK).map(|(a, b)| (*a as f32 - *b as f32).abs()).collect::<Vec<_>>();
+                (!diffs.is_empty()).then(|| 1.0 - diffs.iter().sum::<f32>() / (diffs.len() as f32 * 254.0))
+            },
+            _ => None
+        };
+        match audio {
+            Some(audio) => video * 0.7 + audio * 0.2 + duration * 0.1,
+            None => video * 0.875 + duration * 0.125
+        }
+    }
+}
+
+/// Decodes `options.frames` evenly spaced frames of `input` and hashes them, together with the duration and the audio peaks at the same positions
+pub fn fingerprint(input: &str, options: FingerprintOptions) -> Result<Fingerprint, VideoProcessingError> {
+    // Only the luma is hashed, it's taken as decoded instead of converting every frame. `lowres` is limited to what the codec supports
+    let lowres = options.mode.factor().trailing_zeros();
+    let open = |output_format| Decoder::new(input, DecoderOptions {
+        output_format,
+        preferred_bit_depth: Some(8),
+        custom_options: [("lowres".to_string(), lowres.to_string())].into(),
+        max_preroll_frames: Some(120),
+        ..Default::default()
+    });
+    let mut decoder = open(None)?;
+    // Packed and RGB formats have no luma plane, those are converted
+    let video_format = decoder.streams().into_iter().find(|x| matches!(x.stream_type, StreamType::Video)).and_then(|x| x.pixel_format);
+    if !video_format.is_some_and(|x| x.plane_layouts().first().is_some_and(|p| p.role() == PlaneRole::Luma)) {
+        decoder = open(Some(PixelFormat::GRAY8))?;
+    }
+    for stream in decoder.streams() {
+        stream.decode = matches!(stream.stream_type, StreamType::Video);
+    }
+    let info = decoder.get_video_info()?;
+    let duration_us = (info.duration_ms * 1000.0) as i64;
+    let positions: Vec<i64> = (0..options.frames).map(|i| (duration_us as f64 * (i as f64 + 0.5) / options.frames as f64) as i64).collect();
+
+    let mut frame_hashes = Vec::with_capacity(positions.len());
+    for &ts in &positions {
+        // Every position gets an entry, so the hashes of two inputs stay aligned
+        let mut hash = MISSING_FRAME_HASH;
+        if decoder.seek_precise(ts) != SeekResult::Failed {
+            while let Some(frame) = decoder.next_frame() {
+                if let Frame::Video(mut v) = frame {
+                    hash = difference_hash(&mut v)?;
+                    break;
+                }
+            }
+        }
+        frame_hashes.push(hash);
+    }
+
+    let audio_peaks = match AudioExtractor::new(input, None, PcmFormat::S16) {
+        Ok(mut extractor) => {
+            let mut peaks = Vec::with_capacity(positions.len());
+            for &ts in &positions {
+                let pcm = if extractor.seek(ts) { extractor.next_samples() } else { None };
+                peaks.push(match pcm {
+                    Some(Ok((_, pcm))) => {
+                        let peak = pcm.chunks_exact(2).map(|x| i16::from_le_bytes([x[0], x[1]]).unsigned_abs()).max().unwrap_or_default();
+                        (peak as u32 * 254 / 32768) as u8
+                    },
+                    _ => MISSING_AUDIO_PEAK
+                });
+            }
+            Some(peaks)
+        },
+        Err(VideoProcessingError::AudioStreamNotFound) => None,
+        Err(e) => return Err(e)
+    };
+
+    Ok(Fingerprint { duration_ms: info.duration_ms, frame_hashes, audio_peaks })
+}
+
+/// dHash: the luma reduced to 9x8, one bit per horizontally adjacent pair telling whether the brightness increases
+fn difference_hash(frame: &mut VideoFrame) -> Result<u64, VideoProcessingError> {
+    let format = frame.format();
+    if frame.plane_role(0) != Some(PlaneRole::Luma) || format.bit_depth() != 8 {
+        return Err(VideoProcessingError::PixelFormatNotSupported { format, supported: vec![PixelFormat::GRAY8, PixelFormat::YUV420P], frame: Some(Box::new(frame.describe())) });
+    }
+    let (width, height) = (frame.width() as usize, frame.height() as usize);
+    let buffers = frame.get_cpu_buffers()?;
+    let luma = buffers.first().ok_or(VideoProcessingError::FrameEmpty)?;
+    let stride = luma.len() / height.max(1);
+
+    let mut small = [0u8; 9 * 8];
+    scale_plane(luma, stride, (width, height), &mut small, (9, 8), 1, ScaleFilter::Area)?;
+    let mut hash = 0u64;
+    for row in small.chunks_exact(9) {
+        for pair in row.windows(2) {
+            hash = (hash << 1) | (pair[0] < pair[1]) as u64;
+        }
+    }
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Moving waves, with the directions swapped for `other`
+    fn write_clip(path: &str, codec: EncoderCodec, other: bool) {
+        let format = if codec == EncoderCodec::ProRes { PixelFormat::YUV422P10LE } else { PixelFormat::YUV420P };
+        let mut encoder = Encoder::new(IoType::from(path), EncoderParams::new(codec, 160, 120, format, 24.0)).unwrap();
+        for i in 0..48 {
+            let mut frame = VideoFrame::solid_color(PixelFormat::RGBA, 160, 120, [0, 0, 0, 255]).unwrap();
+            let buffers = frame.get_cpu_buffers().unwrap();
+            let stride = buffers[0].len() / 120;
+            for (y, row) in buffers[0].chunks_exact_mut(stride).enumerate() {
+                for (x, px) in row[..160 * 4].chunks_exact_mut(4).enumerate() {
+                    let (a, b) = if other { (y as f32 / 20.0, x as f32 / 40.0) } else { (x as f32 / 20.0, y as f32 / 40.0) };
+                    let v = ((a + i as f32 * 0.2).sin() + (b - i as f32 * 0.1).cos()) * 60.0 + 128.0;
+                    px[..3].fill(v as u8);
+                }
+            }
+            encoder.encode_frame(&mut frame).unwrap();
+        }
+        encoder.finish().unwrap();
+    }
+
+    #[test]
+    fn transcodes_are_similar() {
+        let paths = [crate::util::test::temp_path("fingerprint.mp4"), crate::util::test::temp_path("fingerprint.mov"), crate::util::test::temp_path("fingerprint_other.mp4")];
+        write_clip(&paths[0], EncoderCodec::H264, false);
+        write_clip(&paths[1], EncoderCodec::ProRes, false);
+        write_clip(&paths[2], EncoderCodec::H264, true);
+        let prints: Vec<Fingerprint> = paths.iter().map(|x| fingerprint(x, FingerprintOptions::default()).unwrap()).collect();
+        for path in &paths { let _ = std::fs::remove_file(path); }
+
+        assert_eq!(prints[0].frame_hashes.len(), 16);
+        assert!(!prints[0].frame_hashes.contains(&MISSING_FRAME_HASH));
+        assert_eq!(prints[0].similarity(&prints[0]), 1.0);
+        assert!(prints[0].similarity(&prints[1]) > 0.9, "{}", prints[0].similarity(&prints[1]));
+        assert!(prints[0].similarity(&prints[2]) < 0.75, "{}", prints[0].similarity(&prints[2]));
+    }
+
+    #[test]
+    fn missing_positions_stay_aligned() {
+        let a = Fingerprint { duration_ms: 1000.0, frame_hashes: vec![1, 2, 3, 4], audio_peaks: Some(vec![10, 20, 30, 40]) };
+        // The second position failed to decode, the others are compared at the same positions
+        let b = Fingerprint { duration_ms: 1000.0, frame_hashes: vec![1, MISSING_FRAME_HASH, 3, 4], audio_peaks: Some(vec![10, MISSING_AUDIO_PEAK, 30, 40]) };
+        assert!((a.similarity(&b) - 1.0).abs() < 1e-6);
+        let shifted = Fingerprint { duration_ms: 1000.0, frame_hashes: vec![1, 3, 4], audio_peaks: Some(vec![10, 30, 40]) };
+        assert!(a.similarity(&shifted) < 1.0);
+        let nothing = Fingerprint { duration_ms: 1000.0, frame_hashes: vec![MISSING_FRAME_HASH; 4], audio_peaks: Some(vec![MISSING_AUDIO_PEAK; 4]) };
+        assert!(a.similarity(&nothing) < 0.5);
+    }
+}
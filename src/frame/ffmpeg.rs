@@ -14,45 +14,513 @@ macro_rules! ffmpeg {
 
 pub struct FfmpegVideoFrame {
     pub(crate) avframe: ffmpeg_next::frame::Video,
-    pub(crate) swframe: Option<ffmpeg_next::frame::Video>
+    pub(crate) swframe: Option<ffmpeg_next::frame::Video>,
+    /// Hardware frames are downloaded in a lower bit depth format when the GPU offers one
+    pub(crate) max_bit_depth: Option<u32>,
+    /// Counts this frame towards `DecoderOptions::max_outstanding_frames` until dropped
+    pub(crate) lease: Option<super::FrameLease>,
 }
 
-impl VideoFrameInterface for FfmpegVideoFrame {
-    fn width(&self)  -> u32 { self.avframe.width() }
-    fn height(&self) -> u32 { self.avframe.height() }
-    fn timestamp_us(&self) -> Option<i64> { self.avframe.timestamp() }
+impl FfmpegVideoFrame {
+    /// Allocates a CPU frame with uninitialized contents
+    pub fn new_cpu(format: PixelFormat, width: u32, height: u32) -> Result<Self, crate::VideoProcessingError> {
+        let pixel = to_ffmpeg_format(format).ok_or(crate::VideoProcessingError::UnknownPixelFormat(format))?;
+        let avframe = ffmpeg_next::frame::Video::new(pixel, width, height);
+        if unsafe { avframe.is_empty() } { return Err(crate::VideoProcessingError::FrameEmpty); }
+        Ok(Self { avframe, swframe: None, max_bit_depth: None, lease: None })
+    }
 
-    fn format(&self) -> PixelFormat {
-        let mut sw_format = self.avframe.format();
+    pub fn set_timestamp_us(&mut self, timestamp_us: Option<i64>) {
+        self.avframe.set_pts(timestamp_us);
+        if let Some(sw) = self.swframe.as_mut() {
+            sw.set_pts(timestamp_us);
+        }
+    }
+
+    /// Allocates a CPU frame filled with the RGBA color. YUV formats are tagged and filled as BT.709 limited range.
+    pub fn solid_color(format: PixelFormat, width: u32, height: u32, color: [u8; 4]) -> Result<Self, crate::VideoProcessingError> {
+        use ffmpeg_next::ffi::*;
+
+        let mut frame = Self::new_cpu(format, width, height)?;
+        let ptr = unsafe { frame.avframe.as_mut_ptr() };
+        let desc = unsafe { av_pix_fmt_desc_get((*ptr).format) };
+        if desc.is_null() { return Err(crate::VideoProcessingError::UnknownPixelFormat(format)); }
+        let desc = unsafe { &*desc };
+
+        let is_rgb = desc.flags & AV_PIX_FMT_FLAG_RGB as u64 != 0;
+        let is_be  = desc.flags & AV_PIX_FMT_FLAG_BE as u64 != 0;
+        let [r, g, b, a] = color.map(|x| x as f32 / 255.0);
+
+        // Normalized component values, limited range for YUV
+        let values = if is_rgb {
+            [r, g, b, a]
+        } else {
+            let (kr, kb) = crate::YuvMatrix::BT709.coefficients();
+            let y = kr * r + (1.0 - kr - kb) * g + kb * b;
+            let u = (b - y) / (2.0 * (1.0 - kb));
+            let v = (r - y) / (2.0 * (1.0 - kr));
+            frame.avframe.set_color_space(ffmpeg_next::color::Space::BT709);
+            frame.avframe.set_color_range(ffmpeg_next::color::Range::MPEG);
+            [(16.0 + 219.0 * y) / 255.0, (128.0 + 224.0 * u) / 255.0, (128.0 + 224.0 * v) / 255.0, a]
+        };
+
+        for c in 0..desc.nb_components as usize {
+            let comp = &desc.comp[c];
+            let is_chroma = !is_rgb && (c == 1 || c == 2);
+            let (w, h) = if is_chroma {
+                (-((-(width as i32)) >> desc.log2_chroma_w), -((-(height as i32)) >> desc.log2_chroma_h))
+            } else {
+                (width as i32, height as i32)
+            };
+            // Alpha is the last component for gray+alpha formats
+            let value = if !is_rgb && desc.nb_components == 2 && c == 1 { values[3] } else { values[c] };
+            let max = ((1u32 << comp.depth) - 1) as f32;
+            let sample = ((value * max).round().clamp(0.0, max) as u32) << comp.shift;
+
+            unsafe {
+                let data = (*ptr).data[comp.plane as usize];
+                let stride = (*ptr).linesize[comp.plane as usize] as isize;
+                for y in 0..h as isize {
+                    for x in 0..w as isize {
+                        let p = data.offset(y * stride + x * comp.step as isize + comp.offset as isize);
+                        if comp.depth + comp.shift <= 8 {
+                            let mask = (((1u32 << comp.depth) - 1) << comp.shift) as u8;
+                            *p = (*p & !mask) | sample as u8;
+                        } else {
+                            // Read-modify-write, so components sharing bytes (e.g. XV30) are preserved
+                            let mask = (((1u32 << comp.depth) - 1) << comp.shift) as u16;
+                            let bytes = [*p, *p.offset(1)];
+                            let old = if is_be { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) };
+                            let new = (old & !mask) | sample as u16;
+                            let bytes = if is_be { new.to_be_bytes() } else { new.to_le_bytes() };
+                            *p = bytes[0];
+                            *p.offset(1) = bytes[1];
+                        }
+                    }
+                }
+            }
+        }
+        Ok(frame)
+    }
+
+    fn orientation(&self) -> (i32, bool) {
+        unsafe {
+            use ffmpeg_next::ffi::*;
+            let sd = av_frame_get_side_data(self.avframe.as_ptr(), AVFrameSideDataType::AV_FRAME_DATA_DISPLAYMATRIX);
+            if sd.is_null() || (*sd).size < 9 * 4 { return (0, false); }
+            display_orientation((*sd).data as *const i32)
+        }
+    }
+
+    /// Motion vectors of this frame, when the decoder was opened with `DecoderOptions::export_motion_vectors`.
+    /// `None` for intra frames and when the codec doesn't export them
+    pub fn motion_vectors(&self) -> Option<Vec<MotionVector>> {
         unsafe {
             use ffmpeg_next::ffi::*;
+            let sd = av_frame_get_side_data(self.avframe.as_ptr(), AVFrameSideDataType::AV_FRAME_DATA_MOTION_VECTORS);
+            if sd.is_null() || (*sd).data.is_null() { return None; }
+            let count = (*sd).size as usize / std::mem::size_of::<AVMotionVector>();
+            let vectors = std::slice::from_raw_parts((*sd).data as *const AVMotionVector, count);
+            Some(vectors.iter().map(|mv| MotionVector {
+                source: mv.source,
+                block_size: (mv.w, mv.h),
+                src: (mv.src_x, mv.src_y),
+                dst: (mv.dst_x, mv.dst_y),
+                motion: (mv.motion_x, mv.motion_y),
+                motion_scale: mv.motion_scale,
+            }).collect())
+        }
+    }
+
+    /// The decoded frame, a GPU surface for hardware frames
+    #[cfg(feature = "ffmpeg-raw")]
+    pub fn raw_frame(&self) -> &ffmpeg_next::frame::Video {
+        &self.avframe
+    }
+    /// CPU copy of a hardware frame, once `get_cpu_buffers` or another CPU access downloaded it
+    #[cfg(feature = "ffmpeg-raw")]
+    pub fn raw_sw_frame(&self) -> Option<&ffmpeg_next::frame::Video> {
+        self.swframe.as_ref().filter(|x| unsafe { !x.is_empty() })
+    }
+
+    pub fn downscaled(&mut self, factor: u32) -> Result<Self, crate::VideoProcessingError> {
+        let max_bit_depth = self.max_bit_depth;
+        let src = self.cpu_frame()?;
+        let (w, h) = ((src.width() / factor.max(1)).max(2) & !1, (src.height() / factor.max(1)).max(2) & !1);
+        let mut scaler = ffmpeg_next::software::scaling::Context::get(src.format(), src.width(), src.height(), src.format(), w, h, ffmpeg_next::software::scaling::Flags::AREA)?;
+        let mut avframe = ffmpeg_next::frame::Video::empty();
+        scaler.run(src, &mut avframe)?;
+        unsafe { ffmpeg_next::ffi::av_frame_copy_props(avframe.as_mut_ptr(), src.as_ptr()); }
+        Ok(Self { avframe, swframe: None, max_bit_depth, lease: None })
+    }
+
+    /// Format the GPU surfaces are downloaded to, the `sw_format` of the hardware frames context
+    fn hw_sw_format(&self) -> Option<Pixel> {
+        unsafe {
             let hwctx = (*self.avframe.as_ptr()).hw_frames_ctx;
-            if !hwctx.is_null() {
-                let hwfc = (*hwctx).data as *const AVHWFramesContext;
-                if !hwfc.is_null() {
-                    sw_format = Pixel::from((*hwfc).sw_format);
+            if hwctx.is_null() { return None; }
+            let hwfc = (*hwctx).data as *const ffmpeg_next::ffi::AVHWFramesContext;
+            if hwfc.is_null() { return None; }
+            Some(Pixel::from((*hwfc).sw_format))
+        }
+    }
+
+    /// Lower depth format hardware frames are downloaded in when `max_bit_depth` asks for it, e.g. NV12 for P010 at 8 bits
+    fn reduced_download_format(&self) -> Option<Pixel> {
+        let depth = self.max_bit_depth?;
+        reduced_depth_format(self.hw_sw_format()?, depth)
+    }
+
+    /// CPU-accessible frame, hardware frames are downloaded once and kept in `swframe`
+    pub(crate) fn cpu_frame(&mut self) -> Result<&mut ffmpeg_next::frame::Video, crate::VideoProcessingError> {
+        if !self.is_hardware() {
+            return Ok(&mut self.avframe);
+        }
+        let reduced_format = self.reduced_download_format();
+        let sw_frame = self.swframe.get_or_insert_with(ffmpeg_next::frame::Video::empty); // TODO use buffer pool
+        if unsafe { sw_frame.is_empty() } {
+            profile_span!("hw_transfer", timestamp_us = self.avframe.timestamp());
+            let mut convert_to = None;
+            if let Some(target) = reduced_format {
+                let hw_formats = unsafe { crate::support::ffmpeg_hw::get_transfer_formats_from_gpu(self.avframe.as_mut_ptr()) };
+                if hw_formats.contains(&target) {
+                    unsafe { (*sw_frame.as_mut_ptr()).format = ffmpeg_next::ffi::AVPixelFormat::from(target) as i32; }
+                } else {
+                    log::debug!("GPU can't download to {target:?}, converting after the transfer. Available: {hw_formats:?}");
+                    convert_to = Some(target);
+                }
+            }
+            // retrieve data from GPU to CPU
+            let err = unsafe { ffmpeg_next::ffi::av_hwframe_transfer_data(sw_frame.as_mut_ptr(), self.avframe.as_mut_ptr(), 0) };
+            if err < 0 {
+                unsafe {
+                    // A lost device is only noticed here when it happened between decode calls, the decoder reopens on its next packet
+                    let frames_ctx = (*self.avframe.as_ptr()).hw_frames_ctx;
+                    let device_ref = if frames_ctx.is_null() { std::ptr::null_mut() } else { (*((*frames_ctx).data as *mut ffmpeg_next::ffi::AVHWFramesContext)).device_ref };
+                    if crate::support::ffmpeg_hw::is_device_lost(device_ref, err) {
+                        crate::support::ffmpeg_hw::mark_device_lost(device_ref);
+                    }
                 }
+                return Err(crate::VideoProcessingError::FromHWTransferError(err));
             }
+            ffmpeg!(ffmpeg_next::ffi::av_frame_copy_props(sw_frame.as_mut_ptr(), self.avframe.as_mut_ptr()); FromHWTransferError);
+
+            // `format()` already reports the reduced format, so the buffers have to match it
+            if let Some(target) = convert_to {
+                let (w, h) = (sw_frame.width(), sw_frame.height());
+                let mut scaler = ffmpeg_next::software::scaling::Context::get(sw_frame.format(), w, h, target, w, h, ffmpeg_next::software::scaling::Flags::BILINEAR | ffmpeg_next::software::scaling::Flags::ACCURATE_RND)?;
+                let mut converted = ffmpeg_next::frame::Video::empty();
+                scaler.run(sw_frame, &mut converted)?;
+                unsafe { ffmpeg_next::ffi::av_frame_copy_props(converted.as_mut_ptr(), sw_frame.as_ptr()); }
+                *sw_frame = converted;
+            }
+        }
+        Ok(sw_frame)
+    }
+}
+
+/// Clockwise rotation in degrees and whether the image is mirrored horizontally before rotating, from a display matrix.
+/// A vertical flip (e.g. a camera mounted upside down) is reported as a mirror with a 180° rotation
+pub(crate) unsafe fn display_orientation(matrix: *const i32) -> (i32, bool) {
+    let mut m = [0i32; 9];
+    std::ptr::copy_nonoverlapping(matrix, m.as_mut_ptr(), 9);
+    // Negative determinant of the 2x2 part means the matrix contains a reflection
+    let mirrored = (m[0] as i64) * (m[4] as i64) - (m[1] as i64) * (m[3] as i64) < 0;
+    if mirrored {
+        ffmpeg_next::ffi::av_display_matrix_flip(m.as_mut_ptr(), 1, 0);
+    }
+    // Display matrix rotation is counter-clockwise, we report clockwise
+    let theta = ffmpeg_next::ffi::av_display_rotation_get(m.as_ptr());
+    let rotation = if theta.is_nan() { 0 } else { (-theta.round() as i32).rem_euclid(360) };
+    (rotation, mirrored)
+}
+
+/// Formats sharing a layout and subsampling, by increasing bit depth
+const DEPTH_FAMILIES: &[&[(u32, Pixel)]] = &[
+    &[(8, Pixel::NV12), (10, Pixel::P010LE), (12, Pixel::P012LE), (16, Pixel::P016LE)],
+    &[(8, Pixel::NV16), (10, Pixel::P210LE), (12, Pixel::P212LE), (16, Pixel::P216LE)],
+    &[(8, Pixel::NV24), (10, Pixel::P410LE), (12, Pixel::P412LE), (16, Pixel::P416LE)],
+    &[(8, Pixel::YUV420P), (10, Pixel::YUV420P10LE), (12, Pixel::YUV420P12LE), (14, Pixel::YUV420P14LE), (16, Pixel::YUV420P16LE)],
+    &[(8, Pixel::YUV422P), (10, Pixel::YUV422P10LE), (12, Pixel::YUV422P12LE), (14, Pixel::YUV422P14LE), (16, Pixel::YUV422P16LE)],
+    &[(8, Pixel::YUV444P), (10, Pixel::YUV444P10LE), (12, Pixel::YUV444P12LE), (14, Pixel::YUV444P14LE), (16, Pixel::YUV444P16LE)],
+    &[(8, Pixel::YUYV422), (10, Pixel::Y210LE), (12, Pixel::Y212LE)],
+    // Packed 4:4:4 with an alpha or padding component
+    &[(8, Pixel::VUYA), (10, Pixel::XV30LE), (12, Pixel::XV36LE), (16, Pixel::AYUV64LE)],
+    &[(8, Pixel::RGB24), (16, Pixel::RGB48LE), (16, Pixel::RGB48BE)],
+    &[(8, Pixel::RGBA), (16, Pixel::RGBA64LE), (16, Pixel::RGBA64BE)],
+    // There's no 8-bit planar GBR `PixelFormat`, so those end up packed
+    &[(8, Pixel::RGB24), (10, Pixel::GBRP10LE), (12, Pixel::GBRP12LE), (16, Pixel::GBRP16LE)],
+    &[(8, Pixel::RGB24), (10, Pixel::GBRP10BE), (12, Pixel::GBRP12BE)],
+    &[(8, Pixel::RGBA), (10, Pixel::GBRAP10LE), (12, Pixel::GBRAP12LE)],
+    &[(8, Pixel::GRAY8), (16, Pixel::GRAY16LE), (16, Pixel::GRAY16BE)],
+];
+
+/// Format with the same layout and subsampling and at most `max_bit_depth` bits, when `format` has more, e.g. NV12 for P010 at 8 bits
+/// and P010 for P016 at 10 bits. Depths below 8 give the 8-bit format. Float formats (EXR) are kept, values above 1.0 would be clipped
+pub(crate) fn reduced_depth_format(format: Pixel, max_bit_depth: u32) -> Option<Pixel> {
+    let family = DEPTH_FAMILIES.iter().find(|x| x[1..].iter().any(|(_, f)| *f == format))?;
+    let depth = family.iter().find(|(_, f)| *f == format)?.0;
+    if depth <= max_bit_depth { return None; }
+    family.iter().rev().find(|(d, _)| *d <= max_bit_depth).or(family.first()).map(|(_, f)| *f)
+}
+
+pub(crate) fn color_space_from_ffmpeg(v: ffmpeg_next::color::Space) -> Option<ColorSpace> {
+    use ffmpeg_next::color::Space;
+    match v {
+        Space::BT709      => Some(ColorSpace::BT709),
+        Space::BT470BG    => Some(ColorSpace::BT470BG),
+        Space::SMPTE170M  => Some(ColorSpace::SMPTE170M),
+        Space::BT2020NCL  => Some(ColorSpace::BT2020NCL),
+        Space::BT2020CL   => Some(ColorSpace::BT2020CL),
+        _ => None
+    }
+}
+pub(crate) fn color_range_from_ffmpeg(v: ffmpeg_next::color::Range) -> Option<ColorRange> {
+    use ffmpeg_next::color::Range;
+    match v {
+        Range::MPEG => Some(ColorRange::Limited),
+        Range::JPEG => Some(ColorRange::Full),
+        _ => None
+    }
+}
+pub(crate) fn color_primaries_from_ffmpeg(v: ffmpeg_next::color::Primaries) -> Option<ColorPrimaries> {
+    use ffmpeg_next::color::Primaries;
+    match v {
+        Primaries::BT709     => Some(ColorPrimaries::BT709),
+        Primaries::BT470BG   => Some(ColorPrimaries::BT470BG),
+        Primaries::SMPTE170M => Some(ColorPrimaries::SMPTE170M),
+        Primaries::BT2020    => Some(ColorPrimaries::BT2020),
+        Primaries::SMPTE431  => Some(ColorPrimaries::DCIP3),
+        Primaries::SMPTE432  => Some(ColorPrimaries::DisplayP3),
+        _ => None
+    }
+}
+pub(crate) fn color_trc_from_ffmpeg(v: ffmpeg_next::color::TransferCharacteristic) -> Option<ColorTrc> {
+    use ffmpeg_next::color::TransferCharacteristic as Trc;
+    match v {
+        // BT.2020 SDR uses the BT.709 curve
+        Trc::BT709 | Trc::BT2020_10 | Trc::BT2020_12 => Some(ColorTrc::BT709),
+        Trc::SMPTE170M    => Some(ColorTrc::SMPTE170M),
+        Trc::Linear       => Some(ColorTrc::Linear),
+        Trc::IEC61966_2_1 => Some(ColorTrc::SRGB),
+        Trc::GAMMA22      => Some(ColorTrc::Gamma22),
+        Trc::SMPTE2084    => Some(ColorTrc::SMPTE2084),
+        Trc::ARIB_STD_B67 => Some(ColorTrc::AribStdB67),
+        _ => None
+    }
+}
+
+/// Horizontal and vertical chroma subsampling factors, e.g. (2, 2) for 4:2:0
+pub(crate) fn chroma_subsampling(format: PixelFormat) -> Option<(u32, u32)> {
+    let desc = unsafe { ffmpeg_next::ffi::av_pix_fmt_desc_get(to_ffmpeg_format(format)?.into()) };
+    if desc.is_null() { return None; }
+    let desc = unsafe { &*desc };
+    Some((1 << desc.log2_chroma_w, 1 << desc.log2_chroma_h))
+}
+
+lazy_static::lazy_static! {
+    static ref VALIDATED_FORMATS: Vec<PixelFormat> = PixelFormat::ALL.iter().copied().filter(|x| validate_format(*x)).collect();
+}
+
+/// Formats which survive a round trip through a CPU frame: `format()` reports them back and `get_cpu_buffers`
+/// returns every plane of `plane_layouts` with enough bytes. Checked once, on first use
+pub(crate) fn validated_formats() -> Vec<PixelFormat> {
+    VALIDATED_FORMATS.clone()
+}
+
+fn validate_format(format: PixelFormat) -> bool {
+    // Odd size, so the rounding of subsampled planes is covered too
+    let (width, height) = (18, 11);
+    let Ok(mut frame) = FfmpegVideoFrame::new_cpu(format, width, height) else { return false; };
+    if frame.format() != format { return false; }
+    let layouts = format.plane_layouts();
+    let Ok(buffers) = frame.get_cpu_buffers() else { return false; };
+    let valid = buffers.len() >= layouts.len() && layouts.iter().zip(&buffers).all(|(layout, buffer)| {
+        let rows = layout.rows(height) as usize;
+        rows > 0 && buffer.len() / rows >= layout.row_bytes(width)
+    });
+    if !valid {
+        log::debug!("Pixel format {format:?} failed validation");
+    }
+    valid
+}
+
+/// `AVCodecParameters::format` of a video stream, `None` for formats without a `PixelFormat`
+pub(crate) fn pixel_format_from_raw(format: i32) -> Option<PixelFormat> {
+    PixelFormat::ALL.iter().copied().find(|x| to_ffmpeg_format(*x).is_some_and(|p| ffmpeg_next::ffi::AVPixelFormat::from(p) as i32 == format))
+}
+
+pub(crate) fn to_ffmpeg_format(format: PixelFormat) -> Option<Pixel> {
+    Some(match format {
+        PixelFormat::AYUV64LE    => Pixel::AYUV64LE,
+        PixelFormat::VUYA        => Pixel::VUYA,
+        PixelFormat::NV12        => Pixel::NV12,
+        PixelFormat::NV21        => Pixel::NV21,
+        PixelFormat::NV16        => Pixel::NV16,
+        PixelFormat::NV24        => Pixel::NV24,
+        PixelFormat::NV42        => Pixel::NV42,
+        PixelFormat::P010LE      => Pixel::P010LE,
+        PixelFormat::P012LE      => Pixel::P012LE,
+        PixelFormat::P016LE      => Pixel::P016LE,
+        PixelFormat::P210LE      => Pixel::P210LE,
+        PixelFormat::P212LE      => Pixel::P212LE,
+        PixelFormat::P216LE      => Pixel::P216LE,
+        PixelFormat::P410LE      => Pixel::P410LE,
+        PixelFormat::P412LE      => Pixel::P412LE,
+        PixelFormat::P416LE      => Pixel::P416LE,
+        PixelFormat::Y210LE      => Pixel::Y210LE,
+        PixelFormat::Y212LE      => Pixel::Y212LE,
+        PixelFormat::XV30LE      => Pixel::XV30LE,
+        PixelFormat::XV36LE      => Pixel::XV36LE,
+        PixelFormat::GRAY8       => Pixel::GRAY8,
+        PixelFormat::GRAY16LE    => Pixel::GRAY16LE,
+        PixelFormat::GRAY16BE    => Pixel::GRAY16BE,
+        PixelFormat::RGB24       => Pixel::RGB24,
+        PixelFormat::RGB32       => Pixel::RGB32,
+        PixelFormat::RGB48BE     => Pixel::RGB48BE,
+        PixelFormat::RGBA        => Pixel::RGBA,
+        PixelFormat::BGRA        => Pixel::BGRA,
+        PixelFormat::RGBA64BE    => Pixel::RGBA64BE,
+        PixelFormat::RGB48LE     => Pixel::RGB48LE,
+        PixelFormat::RGBA64LE    => Pixel::RGBA64LE,
+        // Packed 3-channel half float only exists since ffmpeg 7.1, these frames come from the RAW SDKs and aren't converted by ffmpeg
+        PixelFormat::RGBF16      => return None,
+        PixelFormat::GBRP10LE    => Pixel::GBRP10LE,
+        PixelFormat::GBRP12LE    => Pixel::GBRP12LE,
+        PixelFormat::GBRP10BE    => Pixel::GBRP10BE,
+        PixelFormat::GBRP12BE    => Pixel::GBRP12BE,
+        PixelFormat::GBRP16LE    => Pixel::GBRP16LE,
+        PixelFormat::GBRAP10LE   => Pixel::GBRAP10LE,
+        PixelFormat::GBRAP12LE   => Pixel::GBRAP12LE,
+        PixelFormat::GBRPF32LE   => Pixel::GBRPF32LE,
+        PixelFormat::GBRAPF32LE  => Pixel::GBRAPF32LE,
+        PixelFormat::GRAYF32LE   => Pixel::GRAYF32LE,
+        PixelFormat::YUV420P     => Pixel::YUV420P,
+        PixelFormat::YUV420P10LE => Pixel::YUV420P10LE,
+        PixelFormat::YUV420P12LE => Pixel::YUV420P12LE,
+        PixelFormat::YUV420P14LE => Pixel::YUV420P14LE,
+        PixelFormat::YUV420P16LE => Pixel::YUV420P16LE,
+        PixelFormat::YUV422P     => Pixel::YUV422P,
+        PixelFormat::YUV422P10LE => Pixel::YUV422P10LE,
+        PixelFormat::YUV422P12LE => Pixel::YUV422P12LE,
+        PixelFormat::YUV422P14LE => Pixel::YUV422P14LE,
+        PixelFormat::YUV422P16LE => Pixel::YUV422P16LE,
+        PixelFormat::YUV444P     => Pixel::YUV444P,
+        PixelFormat::YUV444P10LE => Pixel::YUV444P10LE,
+        PixelFormat::YUV444P12LE => Pixel::YUV444P12LE,
+        PixelFormat::YUV444P14LE => Pixel::YUV444P14LE,
+        PixelFormat::YUV444P16LE => Pixel::YUV444P16LE,
+        PixelFormat::UYVY422     => Pixel::UYVY422,
+        PixelFormat::YUYV422     => Pixel::YUYV422,
+        PixelFormat::YUVA420P    => Pixel::YUVA420P,
+        PixelFormat::YUVA444P    => Pixel::YUVA444P,
+        PixelFormat::PAL8        => Pixel::PAL8,
+        PixelFormat::Unknown     => return None,
+    })
+}
+
+impl VideoFrameInterface for FfmpegVideoFrame {
+    fn width(&self)  -> u32 { self.avframe.width() }
+    fn height(&self) -> u32 { self.avframe.height() }
+    fn timestamp_us(&self) -> Option<i64> { self.avframe.timestamp() }
+    fn duration_us(&self) -> Option<i64> {
+        // Packets are rescaled to microseconds before decoding, so the duration is in microseconds as well
+        let duration = unsafe { (*self.avframe.as_ptr()).duration };
+        if duration > 0 { Some(duration) } else { None }
+    }
+    fn color_space(&self) -> Option<ColorSpace> {
+        color_space_from_ffmpeg(self.avframe.color_space())
+    }
+    fn color_range(&self) -> Option<ColorRange> {
+        color_range_from_ffmpeg(self.avframe.color_range())
+    }
+    fn color_primaries(&self) -> Option<ColorPrimaries> {
+        color_primaries_from_ffmpeg(unsafe { (*self.avframe.as_ptr()).color_primaries }.into())
+    }
+    fn color_trc(&self) -> Option<ColorTrc> {
+        color_trc_from_ffmpeg(unsafe { (*self.avframe.as_ptr()).color_trc }.into())
+    }
+    fn sample_aspect_ratio(&self) -> (i32, i32) {
+        let sar = self.avframe.aspect_ratio();
+        (sar.0, sar.1)
+    }
+    fn rotation(&self) -> i32 {
+        self.orientation().0
+    }
+    fn mirrored(&self) -> bool {
+        self.orientation().1
+    }
+    fn plane_count(&self) -> usize {
+        self.format().plane_layouts().len()
+    }
+    fn plane_role(&self, plane: usize) -> Option<PlaneRole> {
+        self.format().plane_layouts().get(plane).map(PlaneLayout::role)
+    }
+    fn palette(&self) -> Option<&[u8]> {
+        // ffmpeg stores the palette in the second plane as 256 native endian 0xAARRGGBB values
+        if self.avframe.format() != Pixel::PAL8 { return None; }
+        unsafe {
+            let data = (*self.avframe.as_ptr()).data[1];
+            if data.is_null() { return None; }
+            Some(std::slice::from_raw_parts(data, 256 * 4))
         }
+    }
+    fn dovi_rpu(&self) -> Option<&[u8]> {
+        unsafe {
+            use ffmpeg_next::ffi::*;
+            let sd = av_frame_get_side_data(self.avframe.as_ptr(), AVFrameSideDataType::AV_FRAME_DATA_DOVI_RPU_BUFFER);
+            if sd.is_null() || (*sd).data.is_null() || (*sd).size == 0 { return None; }
+            Some(std::slice::from_raw_parts((*sd).data, (*sd).size as usize))
+        }
+    }
+    fn is_hardware(&self) -> bool { unsafe { !(*self.avframe.as_ptr()).hw_frames_ctx.is_null() } }
+
+    fn format(&self) -> PixelFormat {
+        // Hardware frames report the format of their CPU buffers
+        let sw_format = self.reduced_download_format().or_else(|| self.hw_sw_format()).unwrap_or_else(|| self.avframe.format());
 
         match sw_format {
             Pixel::AYUV64LE    => PixelFormat::AYUV64LE,
+            Pixel::VUYA        => PixelFormat::VUYA,
             Pixel::NV12        => PixelFormat::NV12,
             Pixel::NV21        => PixelFormat::NV21,
             Pixel::NV16        => PixelFormat::NV16,
             Pixel::NV24        => PixelFormat::NV24,
             Pixel::NV42        => PixelFormat::NV42,
             Pixel::P010LE      => PixelFormat::P010LE,
+            Pixel::P012LE      => PixelFormat::P012LE,
             Pixel::P016LE      => PixelFormat::P016LE,
             Pixel::P210LE      => PixelFormat::P210LE,
+            Pixel::P212LE      => PixelFormat::P212LE,
             Pixel::P216LE      => PixelFormat::P216LE,
             Pixel::P410LE      => PixelFormat::P410LE,
+            Pixel::P412LE      => PixelFormat::P412LE,
             Pixel::P416LE      => PixelFormat::P416LE,
+            Pixel::Y210LE      => PixelFormat::Y210LE,
+            Pixel::Y212LE      => PixelFormat::Y212LE,
+            Pixel::XV30LE      => PixelFormat::XV30LE,
+            Pixel::XV36LE      => PixelFormat::XV36LE,
+            Pixel::GRAY8       => PixelFormat::GRAY8,
+            Pixel::GRAY16LE    => PixelFormat::GRAY16LE,
+            Pixel::GRAY16BE    => PixelFormat::GRAY16BE,
+            Pixel::RGB24       => PixelFormat::RGB24,
             Pixel::RGB32       => PixelFormat::RGB32,
             Pixel::RGB48BE     => PixelFormat::RGB48BE,
             Pixel::RGBA        => PixelFormat::RGBA,
             Pixel::BGRA        => PixelFormat::BGRA,
             Pixel::RGBA64BE    => PixelFormat::RGBA64BE,
+            Pixel::RGB48LE     => PixelFormat::RGB48LE,
+            Pixel::RGBA64LE    => PixelFormat::RGBA64LE,
+            Pixel::GBRP10LE    => PixelFormat::GBRP10LE,
+            Pixel::GBRP12LE    => PixelFormat::GBRP12LE,
+            Pixel::GBRP10BE    => PixelFormat::GBRP10BE,
+            Pixel::GBRP12BE    => PixelFormat::GBRP12BE,
+            Pixel::GBRP16LE    => PixelFormat::GBRP16LE,
+            Pixel::GBRAP10LE   => PixelFormat::GBRAP10LE,
+            Pixel::GBRAP12LE   => PixelFormat::GBRAP12LE,
+            Pixel::GBRPF32LE   => PixelFormat::GBRPF32LE,
+            Pixel::GBRAPF32LE  => PixelFormat::GBRAPF32LE,
+            Pixel::GRAYF32LE   => PixelFormat::GRAYF32LE,
             Pixel::YUV420P     => PixelFormat::YUV420P,
             Pixel::YUVJ420P    => PixelFormat::YUV420P, // TODO: range
             Pixel::YUV420P10LE => PixelFormat::YUV420P10LE,
@@ -72,6 +540,10 @@ impl VideoFrameInterface for FfmpegVideoFrame {
             Pixel::YUV444P14LE => PixelFormat::YUV444P14LE,
             Pixel::YUV444P16LE => PixelFormat::YUV444P16LE,
             Pixel::UYVY422     => PixelFormat::UYVY422,
+            Pixel::YUVA420P    => PixelFormat::YUVA420P,
+            Pixel::YUVA444P    => PixelFormat::YUVA444P,
+            Pixel::PAL8        => PixelFormat::PAL8,
+            Pixel::YUYV422     => PixelFormat::YUYV422,
             #[cfg(any(target_os = "macos", target_os = "ios"))]
             Pixel::VIDEOTOOLBOX => {
                 let pix_fmt = unsafe { mac_ffi::CVPixelBufferGetPixelFormatType((*self.avframe.as_ptr()).data[3] as mac_ffi::CVPixelBufferRef) };
@@ -96,6 +568,13 @@ impl VideoFrameInterface for FfmpegVideoFrame {
                     b"sv44" => PixelFormat::P416LE,  // kCVPixelFormatType_444YpCbCr16BiPlanarVideoRange |
                     b"444f" => PixelFormat::NV24,    // kCVPixelFormatType_444YpCbCr8BiPlanarFullRange   |
                     b"444v" => PixelFormat::NV24,    // kCVPixelFormatType_444YpCbCr8BiPlanarVideoRange  |
+                    b"RGBA" => PixelFormat::RGBA,    // kCVPixelFormatType_32RGBA                        | 32 bit RGBA
+                    b"b48r" => PixelFormat::RGB48BE, // kCVPixelFormatType_48RGB                         | 48 bit RGB, 16-bit big-endian samples
+                    b"l64r" => PixelFormat::RGBA64LE,// kCVPixelFormatType_64RGBALE                      | 64 bit RGBA, 16-bit little-endian samples
+                    b"L008" => PixelFormat::GRAY8,   // kCVPixelFormatType_OneComponent8                 | 8 bit one component
+                    b"L016" => PixelFormat::GRAY16LE,// kCVPixelFormatType_OneComponent16                | 16 bit one component
+                    b"b16g" => PixelFormat::GRAY16BE,// kCVPixelFormatType_16Gray                         | 16 bit grayscale, big-endian samples
+                    // CoreVideo has no 12-bit biplanar or Y210/XV30 style packed formats, 12-bit content comes in the 16-bit "sv22" and "sv44"
                     _ => { log::error!("Unknown VT pixel format: {pix_fmt:08x}"); PixelFormat::Unknown }
                 }
             },
@@ -112,6 +591,11 @@ impl VideoFrameInterface for FfmpegVideoFrame {
                 match desc.Format {
                     DXGI_FORMAT_NV12               => PixelFormat::NV12,
                     DXGI_FORMAT_P010               => PixelFormat::P010LE,
+                    DXGI_FORMAT_P016               => PixelFormat::P016LE,
+                    DXGI_FORMAT_Y210               => PixelFormat::Y210LE,
+                    DXGI_FORMAT_Y216               => PixelFormat::Y212LE,
+                    DXGI_FORMAT_Y410               => PixelFormat::XV30LE,
+                    DXGI_FORMAT_Y416               => PixelFormat::XV36LE,
                     DXGI_FORMAT_B8G8R8A8_UNORM     => PixelFormat::BGRA,
                     // DXGI_FORMAT_R16G16B16A16_FLOAT => PixelFormat::RGBAF16,
                     DXGI_FORMAT_420_OPAQUE         => PixelFormat::YUV420P,
@@ -157,22 +641,7 @@ impl VideoFrameInterface for FfmpegVideoFrame {
     }
 
     fn get_cpu_buffers(&mut self) -> Result<Vec<&mut [u8]>, crate::VideoProcessingError> {
-        let input_frame =
-            if unsafe { !(*self.avframe.as_mut_ptr()).hw_frames_ctx.is_null() } {
-                if self.swframe.is_none() {
-                    self.swframe = Some(ffmpeg_next::frame::Video::empty()); // TODO use buffer pool
-                }
-                let sw_frame = self.swframe.as_mut().unwrap();
-
-                // let hw_formats = Some(unsafe { crate::support::ffmpeg_hw::get_transfer_formats_from_gpu(self.avframe.as_mut_ptr()) });
-                // log::debug!("Hardware transfer formats from GPU: {:?}", hw_formats);
-                // retrieve data from GPU to CPU
-                ffmpeg!(ffmpeg_next::ffi::av_hwframe_transfer_data(sw_frame.as_mut_ptr(), self.avframe.as_mut_ptr(), 0); FromHWTransferError);
-                ffmpeg!(ffmpeg_next::ffi::av_frame_copy_props(sw_frame.as_mut_ptr(), self.avframe.as_mut_ptr()); FromHWTransferError);
-                sw_frame
-            } else {
-                &mut self.avframe
-            };
+        let input_frame = self.cpu_frame()?;
         let mut ret = Vec::new();
         for index in 0..input_frame.planes() {
             // TODO: plane dimensions
@@ -183,6 +652,64 @@ impl VideoFrameInterface for FfmpegVideoFrame {
         Ok(ret)
     }
 
+    fn get_cpu_strides(&mut self) -> Result<Vec<usize>, crate::VideoProcessingError> {
+        let input_frame = self.cpu_frame()?;
+        Ok((0..input_frame.planes()).map(|index| input_frame.stride(index)).collect())
+    }
+
+    fn copy_region_to(&mut self, plane: usize, rect: (u32, u32, u32, u32), dst: &mut [u8], dst_stride: usize) -> Result<(u32, u32, u32, u32), crate::VideoProcessingError> {
+        use ffmpeg_next::ffi::*;
+
+        let (width, height) = (self.width(), self.height());
+        let role = self.plane_role(plane);
+        let frame = self.cpu_frame()?;
+        let fmt = AVPixelFormat::from(frame.format());
+        let desc = unsafe { av_pix_fmt_desc_get(fmt) };
+        if desc.is_null() || plane >= frame.planes() {
+            return Err(crate::VideoProcessingError::InvalidRegion { plane, rect });
+        }
+        let desc = unsafe { &*desc };
+
+        // Planes holding chroma can only be addressed on the subsampling grid (even coordinates for 4:2:0 and packed 4:2:2)
+        // Alpha planes are always full resolution
+        let has_chroma = match role {
+            Some(role) => matches!(role, PlaneRole::ChromaU | PlaneRole::ChromaV | PlaneRole::ChromaUV | PlaneRole::PackedYuv),
+            None => desc.flags & AV_PIX_FMT_FLAG_RGB as u64 == 0 && (1..desc.nb_components.min(3) as usize).any(|c| desc.comp[c].plane as usize == plane)
+        };
+        let (align_w, align_h) = if has_chroma { (1u32 << desc.log2_chroma_w, 1u32 << desc.log2_chroma_h) } else { (1, 1) };
+
+        let (x, y, w, h) = rect;
+        if w == 0 || h == 0 || x.checked_add(w).is_none_or(|r| r > width) || y.checked_add(h).is_none_or(|b| b > height) {
+            return Err(crate::VideoProcessingError::InvalidRegion { plane, rect });
+        }
+        let x0 = x / align_w * align_w;
+        let y0 = y / align_h * align_h;
+        let x1 = (x + w).div_ceil(align_w) * align_w;
+        let y1 = (y + h).div_ceil(align_h) * align_h;
+        let rounded = (x0, y0, x1.min(width) - x0, y1.min(height) - y0);
+
+        let offset   = unsafe { av_image_get_linesize(fmt, x0 as i32, plane as i32) };
+        let row_end  = unsafe { av_image_get_linesize(fmt, x1.min(width) as i32, plane as i32) };
+        if offset < 0 || row_end < offset {
+            return Err(crate::VideoProcessingError::InvalidRegion { plane, rect });
+        }
+        let (offset, row_bytes) = (offset as usize, (row_end - offset) as usize);
+        let shift_h = if has_chroma { desc.log2_chroma_h } else { 0 };
+        let (row0, rows) = ((y0 >> shift_h) as usize, (rounded.3 as usize).div_ceil(1 << shift_h));
+
+        if row_bytes > dst_stride || dst.len() < dst_stride * (rows - 1) + row_bytes {
+            return Err(crate::VideoProcessingError::InvalidRegion { plane, rect });
+        }
+
+        let stride = frame.stride(plane);
+        let src = frame.data(plane);
+        for row in 0..rows {
+            let start = (row0 + row) * stride + offset;
+            dst[row * dst_stride..row * dst_stride + row_bytes].copy_from_slice(&src[start..start + row_bytes]);
+        }
+        Ok(rounded)
+    }
+
     fn get_gpu_texture(&mut self, plane: usize) -> Option<TextureDescription> {
         if unsafe { !(*self.avframe.as_mut_ptr()).hw_frames_ctx.is_null() } {
             match self.avframe.format() {
@@ -235,10 +762,85 @@ impl VideoFrameInterface for FfmpegVideoFrame {
             None
         }
     }
+
+    fn describe(&mut self) -> FrameDescription {
+        let is_hardware = self.is_hardware();
+        // Don't download the frame just to describe it
+        let cpu_frame = if is_hardware { self.swframe.as_ref() } else { Some(&self.avframe) };
+        let planes = cpu_frame.map(|frame| (0..frame.planes()).map(|i| PlaneDescription {
+            stride: frame.stride(i),
+            width: frame.plane_width(i),
+            height: frame.plane_height(i),
+            size: frame.stride(i) * frame.plane_height(i) as usize,
+        }).collect()).unwrap_or_default();
+
+        let ptr = self.avframe.as_ptr();
+        let (primaries, trc, dts) = unsafe { ((*ptr).color_primaries, (*ptr).color_trc, (*ptr).pkt_dts) };
+        let side_data = unsafe {
+            (0..(*ptr).nb_side_data as usize).map(|i| format!("{:?}", ffmpeg_next::frame::side_data::Type::from((**(*ptr).side_data.add(i)).type_))).collect()
+        };
+
+        FrameDescription {
+            backend: "ffmpeg".into(),
+            width: self.width(),
+            height: self.height(),
+            format: format!("{:?}", self.format()),
+            hw_format: if is_hardware { Some(format!("{:?}", self.avframe.format())) } else { None },
+            is_hardware,
+            planes,
+            color_space: self.color_space().map(|x| format!("{x:?}")),
+            color_range: self.color_range().map(|x| format!("{x:?}")),
+            color_primaries: Some(format!("{:?}", ffmpeg_next::color::Primaries::from(primaries))),
+            color_trc: Some(format!("{:?}", ffmpeg_next::color::TransferCharacteristic::from(trc))),
+            sample_aspect_ratio: self.sample_aspect_ratio(),
+            rotation: self.rotation(),
+            mirrored: self.mirrored(),
+            pts: self.avframe.pts(),
+            dts: if dts == ffmpeg_next::ffi::AV_NOPTS_VALUE { None } else { Some(dts) },
+            duration_us: self.duration_us(),
+            key_frame: self.avframe.is_key(),
+            metadata: self.avframe.metadata().iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            side_data,
+            buffer_source: match (is_hardware, self.swframe.is_some()) {
+                (true, true)  => "hw frames pool, downloaded to CPU".into(),
+                (true, false) => "hw frames pool".into(),
+                _             => "decoder buffer pool".into(),
+            },
+        }
+    }
 }
 
 pub struct FfmpegAudioFrame {
-    pub(crate) avframe: ffmpeg_next::frame::Audio
+    pub(crate) avframe: ffmpeg_next::frame::Audio,
+    pub(crate) sample_position: Option<i64>,
+}
+
+impl FfmpegAudioFrame {
+    pub fn trimmed_start(&self, samples: usize) -> Result<Self, crate::VideoProcessingError> {
+        let src = &self.avframe;
+        let samples = samples.min(src.samples());
+        let remaining = src.samples() - samples;
+        let mut layout = src.channel_layout();
+        if layout.is_empty() {
+            layout = ffmpeg_next::ChannelLayout::default(src.channels() as i32);
+        }
+        let mut avframe = ffmpeg_next::frame::Audio::new(src.format(), remaining, layout);
+        unsafe {
+            let err = ffmpeg_next::ffi::av_samples_copy((*avframe.as_mut_ptr()).extended_data, (*src.as_ptr()).extended_data, 0, samples as i32, remaining as i32, src.channels() as i32, src.format().into());
+            if err < 0 { return Err(ffmpeg_next::Error::from(err).into()); }
+            ffmpeg_next::ffi::av_frame_copy_props(avframe.as_mut_ptr(), src.as_ptr());
+        }
+        avframe.set_rate(src.rate());
+        // Timestamps of the decoded frames are in microseconds
+        let offset_us = (samples as i64 * 1_000_000) / src.rate().max(1) as i64;
+        if let Some(ts) = src.timestamp() {
+            unsafe {
+                (*avframe.as_mut_ptr()).pts = ts + offset_us;
+                (*avframe.as_mut_ptr()).best_effort_timestamp = ts + offset_us;
+            }
+        }
+        Ok(Self { avframe, sample_position: self.sample_position.map(|x| x + samples as i64) })
+    }
 }
 
 impl AudioFrameInterface for FfmpegAudioFrame {
@@ -248,6 +850,34 @@ impl AudioFrameInterface for FfmpegAudioFrame {
     fn buffer_size(&self) -> u32 {
         0
     }
+    fn sample_rate(&self) -> u32 { self.avframe.rate() }
+    fn channels(&self) -> u32 { self.avframe.channels() as u32 }
+    fn samples(&self) -> usize { self.avframe.samples() }
+    fn sample_position(&self) -> Option<i64> { self.sample_position }
+
+    fn to_pcm(&mut self, format: PcmFormat) -> Result<Vec<u8>, crate::VideoProcessingError> {
+        use ffmpeg_next::format::{ Sample, sample::Type };
+        let target = match format {
+            PcmFormat::S16 => Sample::I16(Type::Packed),
+            PcmFormat::S32 => Sample::I32(Type::Packed),
+            PcmFormat::F32 => Sample::F32(Type::Packed),
+        };
+        let mut layout = self.avframe.channel_layout();
+        if layout.is_empty() {
+            layout = ffmpeg_next::ChannelLayout::default(self.avframe.channels() as i32);
+            self.avframe.set_channel_layout(layout);
+        }
+        let len = self.samples() * self.channels() as usize * format.bytes_per_sample();
+        if self.avframe.format() == target {
+            return Ok(self.avframe.data(0)[..len].to_vec());
+        }
+        let rate = self.avframe.rate();
+        let mut resampler = ffmpeg_next::software::resampling::Context::get(self.avframe.format(), layout, rate, target, layout, rate)?;
+        let mut output = ffmpeg_next::frame::Audio::empty();
+        resampler.run(&self.avframe, &mut output)?;
+        let len = output.samples() * self.channels() as usize * format.bytes_per_sample();
+        Ok(output.data(0)[..len].to_vec())
+    }
 }
 
 #[cfg(any(target_os = "macos", target_os = "ios"))]
@@ -263,3 +893,78 @@ mod mac_ffi {
         pub fn CVPixelBufferGetPixelFormatType(pixelBuffer: CVPixelBufferRef) -> u32;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reduced_depth_keeps_the_layout() {
+        assert_eq!(reduced_depth_format(Pixel::P010LE, 8), Some(Pixel::NV12));
+        assert_eq!(reduced_depth_format(Pixel::P216LE, 8), Some(Pixel::NV16));
+        assert_eq!(reduced_depth_format(Pixel::YUV444P12LE, 8), Some(Pixel::YUV444P));
+        assert_eq!(reduced_depth_format(Pixel::Y210LE, 8), Some(Pixel::YUYV422));
+        assert_eq!(reduced_depth_format(Pixel::XV30LE, 8), Some(Pixel::VUYA));
+        assert_eq!(reduced_depth_format(Pixel::XV36LE, 8), Some(Pixel::VUYA));
+        assert_eq!(reduced_depth_format(Pixel::AYUV64LE, 8), Some(Pixel::VUYA));
+        assert_eq!(reduced_depth_format(Pixel::RGB48BE, 8), Some(Pixel::RGB24));
+        assert_eq!(reduced_depth_format(Pixel::GRAY16LE, 8), Some(Pixel::GRAY8));
+        assert_eq!(reduced_depth_format(Pixel::GRAY16BE, 8), Some(Pixel::GRAY8));
+        assert_eq!(reduced_depth_format(Pixel::GBRAP12LE, 4), Some(Pixel::RGBA));
+    }
+
+    #[test]
+    fn reduced_depth_above_8_bits() {
+        assert_eq!(reduced_depth_format(Pixel::P016LE, 10), Some(Pixel::P010LE));
+        assert_eq!(reduced_depth_format(Pixel::P016LE, 12), Some(Pixel::P012LE));
+        assert_eq!(reduced_depth_format(Pixel::P016LE, 14), Some(Pixel::P012LE));
+        assert_eq!(reduced_depth_format(Pixel::YUV420P16LE, 14), Some(Pixel::YUV420P14LE));
+        assert_eq!(reduced_depth_format(Pixel::YUV422P12LE, 10), Some(Pixel::YUV422P10LE));
+        assert_eq!(reduced_depth_format(Pixel::XV36LE, 10), Some(Pixel::XV30LE));
+        assert_eq!(reduced_depth_format(Pixel::AYUV64LE, 12), Some(Pixel::XV36LE));
+        assert_eq!(reduced_depth_format(Pixel::Y212LE, 10), Some(Pixel::Y210LE));
+        assert_eq!(reduced_depth_format(Pixel::GBRP16LE, 12), Some(Pixel::GBRP12LE));
+        assert_eq!(reduced_depth_format(Pixel::GBRP12BE, 10), Some(Pixel::GBRP10BE));
+        assert_eq!(reduced_depth_format(Pixel::GBRP10BE, 8), Some(Pixel::RGB24));
+        assert_eq!(reduced_depth_format(Pixel::RGBA64LE, 12), Some(Pixel::RGBA));
+    }
+
+    #[test]
+    fn native_depth_is_kept() {
+        assert_eq!(reduced_depth_format(Pixel::P010LE, 10), None);
+        assert_eq!(reduced_depth_format(Pixel::P010LE, 16), None);
+        assert_eq!(reduced_depth_format(Pixel::NV12, 8), None);
+        assert_eq!(reduced_depth_format(Pixel::RGB48LE, 16), None);
+        assert_eq!(reduced_depth_format(Pixel::GBRPF32LE, 8), None);
+        assert_eq!(reduced_depth_format(Pixel::YUV420P, 4), None);
+    }
+    #[test]
+    fn big_endian_dpx_formats() {
+        for format in [PixelFormat::GBRP10BE, PixelFormat::GBRP12BE] {
+            let mut frame = FfmpegVideoFrame::new_cpu(format, 16, 8).unwrap();
+            assert_eq!(frame.format(), format);
+            assert_eq!(frame.get_cpu_buffers().unwrap().len(), format.plane_layouts().len());
+        }
+    }
+    #[test]
+    fn validated_formats_round_trip() {
+        let formats = validated_formats();
+        assert!(!formats.contains(&PixelFormat::Unknown));
+        for format in [PixelFormat::NV12, PixelFormat::P010LE, PixelFormat::YUV420P, PixelFormat::YUV444P16LE, PixelFormat::RGBA, PixelFormat::BGRA,
+                       PixelFormat::RGB48LE, PixelFormat::GBRP10BE, PixelFormat::GBRPF32LE, PixelFormat::GRAY16BE, PixelFormat::PAL8] {
+            assert!(formats.contains(&format), "{format:?}");
+        }
+        // The only variants without a format of their own in ffmpeg: RGB32 is an alias of BGRA or ARGB, there's no packed half float RGB
+        let missing: Vec<_> = PixelFormat::ALL.iter().filter(|x| !formats.contains(x)).collect();
+        assert!(missing.iter().all(|x| matches!(x, PixelFormat::RGB32 | PixelFormat::RGBF16)), "{missing:?}");
+
+        // The contract holds at sizes other than the validation one too
+        for format in formats {
+            let mut frame = crate::util::test::synthetic_frame(format, 7, 5).unwrap();
+            assert_eq!(frame.format(), format);
+            // PAL8 has the palette in an extra buffer
+            assert!(frame.get_cpu_buffers().unwrap().len() >= format.plane_layouts().len(), "{format:?}");
+            crate::util::test::frame_hash(&mut frame).unwrap();
+        }
+    }
+}
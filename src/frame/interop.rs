@@ -0,0 +1,58 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+use super::*;
+use crate::VideoProcessingError;
+
+const SUPPORTED: [PixelFormat; 5] = [PixelFormat::GRAY8, PixelFormat::RGB24, PixelFormat::RGBA, PixelFormat::BGRA, PixelFormat::RGB32];
+
+impl VideoFrame {
+    /// Copies the pixels without the stride padding. Returns the buffer and the number of channels.
+    /// BGRA is swizzled to RGBA, so the result is always gray, RGB or RGBA.
+    fn tightly_packed(&mut self) -> Result<(Vec<u8>, usize), VideoProcessingError> {
+        let format = self.format();
+        let channels = match format {
+            PixelFormat::GRAY8 => 1,
+            PixelFormat::RGB24 => 3,
+            PixelFormat::RGBA | PixelFormat::BGRA => 4,
+            // Native endian ARGB in ffmpeg, which is BGRA in memory on little endian
+            PixelFormat::RGB32 if cfg!(target_endian = "little") => 4,
+            _ => return Err(VideoProcessingError::PixelFormatNotSupported { format, supported: SUPPORTED.to_vec(), frame: None })
+        };
+        let (width, height) = (self.width() as usize, self.height() as usize);
+        let buffers = self.get_cpu_buffers()?;
+        let plane = buffers.first().ok_or(VideoProcessingError::FrameEmpty)?;
+        if height == 0 { return Ok((Vec::new(), channels)); }
+        let stride = plane.len() / height;
+
+        let row_size = width * channels;
+        let mut ret = Vec::with_capacity(row_size * height);
+        for y in 0..height {
+            ret.extend_from_slice(&plane[y * stride..y * stride + row_size]);
+        }
+        if matches!(format, PixelFormat::BGRA | PixelFormat::RGB32) {
+            ret.chunks_exact_mut(4).for_each(|px| px.swap(0, 2));
+        }
+        Ok((ret, channels))
+    }
+
+    /// Copies a CPU frame to an H×W×C array. Hardware frames are downloaded first
+    #[cfg(feature = "ndarray")]
+    pub fn to_ndarray(&mut self) -> Result<ndarray::Array3<u8>, VideoProcessingError> {
+        let (width, height) = (self.width() as usize, self.height() as usize);
+        let (data, channels) = self.tightly_packed()?;
+        Ok(ndarray::Array3::from_shape_vec((height, width, channels), data).expect("buffer size matches the shape"))
+    }
+
+    /// Copies a CPU frame to a Luma8, Rgb8 or Rgba8 image. Hardware frames are downloaded first
+    #[cfg(feature = "image")]
+    pub fn to_image(&mut self) -> Result<image::DynamicImage, VideoProcessingError> {
+        let (width, height) = (self.width(), self.height());
+        let (data, channels) = self.tightly_packed()?;
+        Ok(match channels {
+            1 => image::DynamicImage::ImageLuma8(image::ImageBuffer::from_raw(width, height, data).ok_or(VideoProcessingError::FrameEmpty)?),
+            3 => image::DynamicImage::ImageRgb8(image::ImageBuffer::from_raw(width, height, data).ok_or(VideoProcessingError::FrameEmpty)?),
+            _ => image::DynamicImage::ImageRgba8(image::ImageBuffer::from_raw(width, height, data).ok_or(VideoProcessingError::FrameEmpty)?),
+        })
+    }
+}
@@ -0,0 +1,35 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+use parking_lot::{ Condvar, Mutex };
+use std::sync::Arc;
+
+/// Counts the decoded frames still alive, including the ones buffered inside the decoder
+#[derive(Clone, Default)]
+pub struct FrameLeases(Arc<(Mutex<usize>, Condvar)>);
+
+/// Held by every counted frame, released when the frame is dropped
+pub struct FrameLease(Arc<(Mutex<usize>, Condvar)>);
+
+impl FrameLeases {
+    pub fn acquire(&self) -> FrameLease {
+        *self.0.0.lock() += 1;
+        FrameLease(self.0.clone())
+    }
+    pub fn outstanding(&self) -> usize {
+        *self.0.0.lock()
+    }
+    /// Waits up to `timeout` for all frames to be dropped, returns the number still alive
+    pub fn wait_released(&self, timeout: std::time::Duration) -> usize {
+        let mut count = self.0.0.lock();
+        self.0.1.wait_while_for(&mut count, |n| *n > 0, timeout);
+        *count
+    }
+}
+
+impl Drop for FrameLease {
+    fn drop(&mut self) {
+        *self.0.0.lock() -= 1;
+        self.0.1.notify_all();
+    }
+}
@@ -2,20 +2,119 @@
 // Copyright © 2023 Adrian <adrian.eddy at gmail>
 
 mod ffmpeg; pub use ffmpeg::*;
+mod lease; pub use lease::*;
+#[cfg(any(feature = "ndarray", feature = "image"))]
+mod interop;
 use crate::types::*;
 
 pub struct TextureDescription {
     pub texture: HWTexture,
 }
 
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PlaneDescription {
+    pub stride: usize,
+    pub width: u32,
+    pub height: u32,
+    pub size: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct FrameDescription {
+    pub backend: String,
+    pub width: u32,
+    pub height: u32,
+    pub format: String,
+    pub hw_format: Option<String>,
+    pub is_hardware: bool,
+    pub planes: Vec<PlaneDescription>,
+    pub color_space: Option<String>,
+    pub color_range: Option<String>,
+    pub color_primaries: Option<String>,
+    pub color_trc: Option<String>,
+    pub sample_aspect_ratio: (i32, i32),
+    pub rotation: i32,
+    pub mirrored: bool,
+    pub pts: Option<i64>,
+    pub dts: Option<i64>,
+    pub duration_us: Option<i64>,
+    pub key_frame: bool,
+    pub metadata: Vec<(String, String)>,
+    pub side_data: Vec<String>,
+    pub buffer_source: String,
+}
+
+impl std::fmt::Display for FrameDescription {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Frame ({})", self.backend)?;
+        writeln!(f, "  size:         {}x{} (SAR {}:{}, rotation {}°{})", self.width, self.height, self.sample_aspect_ratio.0, self.sample_aspect_ratio.1, self.rotation, if self.mirrored { ", mirrored" } else { "" })?;
+        match &self.hw_format {
+            Some(hw) => writeln!(f, "  format:       {} (hardware: {hw})", self.format)?,
+            None     => writeln!(f, "  format:       {}", self.format)?,
+        }
+        for (i, p) in self.planes.iter().enumerate() {
+            writeln!(f, "  plane {i}:      {}x{}, stride {}, {} bytes", p.width, p.height, p.stride, p.size)?;
+        }
+        let opt = |x: &Option<String>| x.clone().unwrap_or_else(|| "unspecified".into());
+        writeln!(f, "  color:        space {}, range {}, primaries {}, trc {}", opt(&self.color_space), opt(&self.color_range), opt(&self.color_primaries), opt(&self.color_trc))?;
+        writeln!(f, "  timestamps:   pts {:?}, dts {:?}, duration {:?} us", self.pts, self.dts, self.duration_us)?;
+        writeln!(f, "  key frame:    {}", self.key_frame)?;
+        writeln!(f, "  buffers:      {}", self.buffer_source)?;
+        writeln!(f, "  side data:    {:?}", self.side_data)?;
+        write!(f,   "  metadata:     {:?}", self.metadata)
+    }
+}
+
+/// Motion vector of a block, exported by the codec with `DecoderOptions::export_motion_vectors`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MotionVector {
+    /// Negative when the reference frame is in the past, positive when it's in the future
+    pub source: i32,
+    pub block_size: (u8, u8),
+    /// Center of the block in the reference frame
+    pub src: (i16, i16),
+    /// Center of the block in this frame
+    pub dst: (i16, i16),
+    /// Motion in 1 / `motion_scale` pixels, more precise than `dst - src`
+    pub motion: (i32, i32),
+    pub motion_scale: u16,
+}
+
 #[enum_delegate::register]
 pub trait VideoFrameInterface {
     fn width(&self) -> u32;
     fn height(&self) -> u32;
     fn timestamp_us(&self) -> Option<i64>;
+    fn duration_us(&self) -> Option<i64>;
     fn format(&self) -> PixelFormat;
+    fn color_space(&self) -> Option<ColorSpace>;
+    fn color_range(&self) -> Option<ColorRange>;
+    fn color_primaries(&self) -> Option<ColorPrimaries>;
+    fn color_trc(&self) -> Option<ColorTrc>;
+    fn sample_aspect_ratio(&self) -> (i32, i32);
+    fn rotation(&self) -> i32;
+    /// Mirrored horizontally before applying `rotation`
+    fn mirrored(&self) -> bool;
+    fn is_hardware(&self) -> bool;
+    /// Number of planes returned by `get_cpu_buffers`
+    fn plane_count(&self) -> usize;
+    fn plane_role(&self, plane: usize) -> Option<PlaneRole>;
+    /// 256 palette entries of `PixelFormat::PAL8` frames, each a native endian 0xAARRGGBB u32
+    fn palette(&self) -> Option<&[u8]>;
+    /// Raw Dolby Vision RPU of this frame (the payload of the HEVC NAL unit type 62), for the application's own Dolby Vision processing
+    fn dovi_rpu(&self) -> Option<&[u8]>;
+    /// Planes on the CPU, hardware frames are downloaded first. Rows are padded, each buffer is the stride times the rows of the plane
     fn get_cpu_buffers(&mut self) -> Result<Vec<&mut [u8]>, crate::VideoProcessingError>;
+    /// Row stride in bytes of every plane returned by `get_cpu_buffers`. For hardware frames it's the stride of the downloaded copy,
+    /// which can differ from the one of the GPU surface
+    fn get_cpu_strides(&mut self) -> Result<Vec<usize>, crate::VideoProcessingError>;
+    /// Copies `rect` (x, y, width, height in frame pixels) of a single plane to `dst`, without copying the rest of the frame on the CPU.
+    /// For planes with subsampled or packed chroma, the rect is expanded to the subsampling grid. Returns the rect that was actually copied.
+    fn copy_region_to(&mut self, plane: usize, rect: (u32, u32, u32, u32), dst: &mut [u8], dst_stride: usize) -> Result<(u32, u32, u32, u32), crate::VideoProcessingError>;
     fn get_gpu_texture(&mut self, plane: usize) -> Option<TextureDescription>;
+    fn describe(&mut self) -> FrameDescription;
 }
 
 #[enum_delegate::implement(VideoFrameInterface)]
@@ -23,10 +122,52 @@ pub enum VideoFrame {
     FfmpegVideoFrame(FfmpegVideoFrame)
 }
 
+impl VideoFrame {
+    /// CPU-backed frame filled with a single color, useful for tests and as a placeholder while decoding is pending
+    pub fn solid_color(format: PixelFormat, width: u32, height: u32, color: [u8; 4]) -> Result<VideoFrame, crate::VideoProcessingError> {
+        Ok(FfmpegVideoFrame::solid_color(format, width, height, color)?.into())
+    }
+
+    /// Changes the presentation timestamp, e.g. to place the frame on a constant rate grid before encoding
+    pub fn set_timestamp_us(&mut self, timestamp_us: Option<i64>) {
+        match self {
+            VideoFrame::FfmpegVideoFrame(f) => f.set_timestamp_us(timestamp_us),
+        }
+    }
+
+    /// CPU copy of the frame in the same pixel format, with both dimensions divided by `factor`
+    pub fn downscaled(&mut self, factor: u32) -> Result<VideoFrame, crate::VideoProcessingError> {
+        match self {
+            VideoFrame::FfmpegVideoFrame(f) => Ok(f.downscaled(factor)?.into()),
+        }
+    }
+}
+
 #[enum_delegate::register]
 pub trait AudioFrameInterface {
     fn timestamp_us(&self) -> Option<i64>;
     fn buffer_size(&self) -> u32;
+    fn sample_rate(&self) -> u32;
+    fn channels(&self) -> u32;
+    /// Number of samples per channel
+    fn samples(&self) -> usize;
+    /// Converts the samples to interleaved PCM in `format`, keeping the sample rate and channels
+    fn to_pcm(&mut self, format: PcmFormat) -> Result<Vec<u8>, crate::VideoProcessingError>;
+    /// Index of the first sample counted from the start of the stream, after the encoder priming samples
+    fn sample_position(&self) -> Option<i64>;
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PcmFormat {
+    S16, S32, F32
+}
+impl PcmFormat {
+    pub fn bytes_per_sample(&self) -> usize {
+        match self {
+            Self::S16 => 2,
+            Self::S32 | Self::F32 => 4,
+        }
+    }
 }
 
 #[enum_delegate::implement(AudioFrameInterface)]
@@ -34,6 +175,15 @@ pub enum AudioFrame {
     FfmpegAudioFrame(FfmpegAudioFrame)
 }
 
+impl AudioFrame {
+    /// Copy without the first `samples` samples, with the timestamp and sample position moved accordingly
+    pub fn trimmed_start(&self, samples: usize) -> Result<AudioFrame, crate::VideoProcessingError> {
+        match self {
+            AudioFrame::FfmpegAudioFrame(f) => Ok(f.trimmed_start(samples)?.into()),
+        }
+    }
+}
+
 pub enum Frame {
     Video(VideoFrame),
     Audio(AudioFrame),
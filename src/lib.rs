@@ -1,15 +1,45 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 // Copyright © 2023 Adrian <adrian.eddy at gmail>
 
+#[macro_use]
+pub mod profiling;
+
 mod support {
     pub mod ffmpeg_hw;
+    pub mod ffmpeg_io;
 }
 
 mod decoder;
 mod encoder;
 mod frame;
 mod conversion;
+mod transcoder;
 mod types;
+mod backend;
+mod capabilities;
+mod timing;
+mod version;
+mod fingerprint;
+mod sync;
+mod probe;
+mod warm_start;
+pub mod util;
+#[cfg(feature = "capi")]
+pub mod capi;
+#[cfg(feature = "ffmpeg-raw")]
+pub use ffmpeg_next;
 pub use types::*;
 pub use decoder::*;
 pub use frame::*;
+pub use encoder::*;
+pub use transcoder::*;
+pub use conversion::*;
+pub use capabilities::*;
+pub use timing::*;
+pub use version::*;
+pub use fingerprint::*;
+pub use sync::*;
+pub use probe::*;
+pub use warm_start::*;
+pub use support::ffmpeg_hw::{ hw_device_references, DEVICE_REMOVED, UNAVAILABLE_HINT_EXPIRY_SECS };
+pub use backend::{ BackendAvailability, available_backends, refresh_available_backends, init_with, InitConfig, ExternalHwDevice };
@@ -0,0 +1,119 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+use crate::*;
+use std::panic::{ catch_unwind, AssertUnwindSafe };
+use std::time::Duration;
+
+/// Stream and container information read while opening, without decoding anything
+#[derive(Debug, Clone)]
+pub struct ProbeInfo {
+    pub streams: Vec<StreamDescriptor>,
+    /// `None` when there's no video stream
+    pub video: Option<VideoInfo>,
+    pub audio: Option<AudioInfo>,
+    pub chapters: Vec<Chapter>,
+}
+
+/// Opens `io` and reads its stream info, giving up with `TimedOut` after `timeout`
+pub fn probe(io: IoType, timeout: Option<Duration>) -> Result<ProbeInfo, VideoProcessingError> {
+    let mut decoder = Decoder::new_from_io(io, DecoderOptions { open_timeout: timeout, ..Default::default() })?;
+    let streams = decoder.stream_infos();
+    let has_video = streams.iter().any(|x| matches!(x.stream_type, StreamType::Video));
+    let has_audio = streams.iter().any(|x| matches!(x.stream_type, StreamType::Audio));
+    Ok(ProbeInfo {
+        video: if has_video { decoder.get_video_info().ok() } else { None },
+        audio: if has_audio { decoder.get_audio_info().ok() } else { None },
+        chapters: decoder.chapters(),
+        streams,
+    })
+}
+
+pub struct ProbeBatchOptions {
+    /// Number of files probed at once, the number of CPUs when 0. Probing is mostly waiting for IO, so more is usually fine
+    pub workers: usize,
+    pub per_file_timeout: Option<Duration>,
+    /// Called on the worker threads as soon as each file is done, with its index in `inputs`
+    pub on_result: Box<dyn Fn(usize, Result<ProbeInfo, VideoProcessingError>) + Send + Sync>,
+}
+
+/// Probes all `inputs` on a pool of worker threads and returns when every result was delivered to `on_result`, exactly once per input.
+/// A panic while probing a file is reported as `Panicked` for that file and doesn't affect the others
+pub fn probe_batch(inputs: Vec<IoType>, options: ProbeBatchOptions) -> Result<(), VideoProcessingError> {
+    // Initialized once up front, the workers only open files
+    crate::backend::ensure_available("ffmpeg")?;
+    crate::backend::init_ffmpeg()?;
+
+    let workers = match options.workers {
+        0 => std::thread::available_parallelism().map_or(4, |x| x.get()),
+        n => n
+    }.min(inputs.len().max(1));
+    let queue = parking_lot::Mutex::new(inputs.into_iter().enumerate());
+    std::thread::scope(|scope| {
+        for i in 0..workers {
+            let worker = || loop {
+                let Some((index, io)) = queue.lock().next() else { break; };
+                let name = format!("{io:?}");
+                let result = catch_unwind(AssertUnwindSafe(|| probe(io, options.per_file_timeout))).unwrap_or_else(|e| {
+                    let message = e.downcast_ref::<&str>().map(|x| x.to_string()).or_else(|| e.downcast_ref::<String>().cloned()).unwrap_or_default();
+                    log::error!("Probing {name} panicked: {message}");
+                    Err(VideoProcessingError::Panicked(message))
+                });
+                (options.on_result)(index, result);
+            };
+            std::thread::Builder::new().name(format!("gpu-video probe {i}")).spawn_scoped(scope, worker)?;
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{ Arc, Mutex };
+
+    fn collect(inputs: Vec<IoType>, workers: usize) -> Vec<(usize, Result<ProbeInfo, VideoProcessingError>)> {
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let sink = results.clone();
+        probe_batch(inputs, ProbeBatchOptions {
+            workers,
+            per_file_timeout: Some(Duration::from_secs(10)),
+            on_result: Box::new(move |index, result| sink.lock().unwrap().push((index, result))),
+        }).unwrap();
+        Arc::try_unwrap(results).ok().unwrap().into_inner().unwrap()
+    }
+
+    #[test]
+    fn batch_of_mixed_files() {
+        let valid = crate::util::test::temp_path("batch-valid.mp4");
+        let av = crate::util::test::temp_path("batch-av.nut");
+        let corrupt = crate::util::test::temp_path("batch-corrupt.mp4");
+        let unsupported = crate::util::test::temp_path("batch-unsupported.dat");
+        crate::util::test::write_test_clip(&valid, crate::EncoderCodec::H264, (64, 48), 4, 25.0).unwrap();
+        crate::util::test::write_av_test_clip(&av, 4).unwrap();
+        // Cut off before the moov box is complete
+        std::fs::write(&corrupt, &std::fs::read(&valid).unwrap()[..64]).unwrap();
+        std::fs::write(&unsupported, "This is not a video file.\n").unwrap();
+
+        let files = [&valid, &corrupt, &av, &unsupported, &crate::util::test::temp_path("batch-missing.mp4")];
+        let inputs: Vec<IoType> = (0..4).flat_map(|_| files.iter().map(|x| IoType::from(x.as_str()))).collect();
+        let count = inputs.len();
+        let results = collect(inputs, 3);
+
+        let mut seen = vec![0; count];
+        for (index, result) in &results {
+            seen[*index] += 1;
+            match index % files.len() {
+                0 => assert_eq!(result.as_ref().unwrap().video.as_ref().unwrap().width, 64),
+                2 => assert!(result.as_ref().is_ok_and(|x| x.video.is_some() && x.audio.is_some())),
+                _ => assert!(result.is_err(), "{index}")
+            }
+        }
+        assert!(seen.iter().all(|x| *x == 1), "{seen:?}");
+
+        assert!(collect(Vec::new(), 0).is_empty());
+        for path in [valid, av, corrupt, unsupported] {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
@@ -0,0 +1,34 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+// Spans for the hot paths (packet read, send/receive, hw transfer, conversion).
+// Without the `tracing` feature `profile_span!` expands to nothing.
+
+#[cfg(feature = "tracing")]
+macro_rules! profile_span {
+    ($name:literal $(, $($fields:tt)*)?) => {
+        let _span = tracing::info_span!($name $(, $($fields)*)?).entered();
+    };
+}
+#[cfg(not(feature = "tracing"))]
+macro_rules! profile_span {
+    ($($t:tt)*) => { };
+}
+
+/// Records all spans to a Chrome trace file, which can be opened in Perfetto or `chrome://tracing`.
+/// The file is written when the returned guard is dropped.
+///
+/// ```no_run
+/// let _guard = gpu_video::profiling::chrome_trace_guard("trace.json");
+/// let mut decoder = gpu_video::Decoder::new("input.mp4", Default::default()).unwrap();
+/// while let Some(_frame) = decoder.next_frame() { }
+/// ```
+#[cfg(feature = "tracing")]
+pub fn chrome_trace_guard(path: impl AsRef<std::path::Path>) -> tracing_chrome::FlushGuard {
+    use tracing_subscriber::prelude::*;
+    let (layer, guard) = tracing_chrome::ChromeLayerBuilder::new().file(path.as_ref()).include_args(true).build();
+    if let Err(e) = tracing_subscriber::registry().with(layer).try_init() {
+        log::warn!("Failed to set the tracing subscriber: {e:?}");
+    }
+    guard
+}
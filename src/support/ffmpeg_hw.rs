@@ -3,7 +3,7 @@
 
 use ffmpeg_next::{ ffi, format, codec, encoder };
 
-use std::collections::HashMap;
+use std::collections::{ HashMap, HashSet };
 use std::collections::hash_map::Entry;
 use std::ffi::{ CStr, CString };
 use std::ptr;
@@ -47,6 +47,23 @@ impl HWDevice {
         }
     }
 
+    pub fn query_constraints(&mut self) {
+        unsafe {
+            let mut constraints = ffi::av_hwdevice_get_hwframe_constraints(self.as_mut_ptr(), ptr::null());
+            if !constraints.is_null() {
+                self.hw_formats = pix_formats_to_vec((*constraints).valid_hw_formats);
+                self.sw_formats = pix_formats_to_vec((*constraints).valid_sw_formats);
+                self.min_size = ((*constraints).min_width, (*constraints).min_height);
+                self.max_size = ((*constraints).max_width, (*constraints).max_height);
+
+                log::debug!("HW formats: {:?}", &self.hw_formats);
+                log::debug!("SW formats: {:?}", &self.sw_formats);
+
+                ffi::av_hwframe_constraints_free(&mut constraints);
+            }
+        }
+    }
+
     pub fn add_ref(&self) -> *mut ffi::AVBufferRef {
         unsafe { ffi::av_buffer_ref(self.device_ref) }
     }
@@ -68,13 +85,250 @@ impl Drop for HWDevice {
 unsafe impl Sync for HWDevice { }
 unsafe impl Send for HWDevice { }
 
+/// Devices are shared between decoders and encoders using the same device type and name,
+/// so e.g. two decoders on different GPUs never end up with each other's device
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct DeviceKey {
+    type_: DeviceType,
+    device_name: Option<String>,
+}
+impl DeviceKey {
+    fn new(type_: DeviceType, device_name: Option<&str>) -> Self {
+        Self { type_, device_name: device_name.filter(|x| !x.is_empty()).map(str::to_owned) }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref DEVICES: Mutex<HashMap<DeviceKey, HWDevice>> = Mutex::new(HashMap::new());
+}
+
+/// Probing results carried over between launches through `WarmStart`. Devices are keyed by `hint_key`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HwHints {
+    /// Decoder name and the device type used for it, that type is tried first
+    pub decoders: HashMap<String, String>,
+    pub working: HashSet<String>,
+    /// Devices which couldn't be created, with the time of the failure in seconds since the Unix epoch.
+    /// The next launches skip them until `UNAVAILABLE_HINT_EXPIRY_SECS` passes
+    pub unavailable: HashMap<String, u64>,
+}
+
+/// How long a device which couldn't be created is skipped, a driver update or a reconnected GPU can make it work again
+pub const UNAVAILABLE_HINT_EXPIRY_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Default)]
+struct HintState {
+    hints: HwHints,
+    // Failed devices are only recorded for applications using `WarmStart`
+    warm_start: bool,
+    // Unexpired failures of earlier launches. Failures of this process are only recorded for the next launch,
+    // so a transient one (e.g. right after a GPU reset) doesn't disable the device until the application restarts
+    skipped: HashSet<String>,
+}
+
+impl HintState {
+    fn merge(&mut self, hints: HwHints, now: u64) {
+        self.warm_start = true;
+        self.hints.decoders.extend(hints.decoders);
+        self.hints.working.extend(hints.working);
+        for (key, failed_at) in hints.unavailable {
+            if now.saturating_sub(failed_at) < UNAVAILABLE_HINT_EXPIRY_SECS {
+                self.skipped.insert(key.clone());
+                self.hints.unavailable.insert(key, failed_at);
+            }
+        }
+    }
+
+    fn record(&mut self, key: &str, created: bool, now: u64) {
+        if created {
+            self.hints.working.insert(key.to_owned());
+            self.hints.unavailable.remove(key);
+            return;
+        }
+        if !self.warm_start { return; }
+        if self.hints.working.contains(key) {
+            // Worked before, so the GPU or driver changed. Nothing from then can be trusted
+            log::info!("HW device {key} is no longer available, discarding the probing hints");
+            self.hints = HwHints::default();
+        }
+        self.hints.unavailable.insert(key.to_owned(), now);
+    }
+}
+
 lazy_static::lazy_static! {
-    static ref DEVICES: Mutex<HashMap<u64, HWDevice>> = Mutex::new(HashMap::new());
+    static ref HINTS: Mutex<HintState> = Mutex::new(HintState::default());
+}
+
+pub fn hw_hints() -> HwHints {
+    HINTS.lock().hints.clone()
+}
+/// Adds the hints of an earlier launch and starts recording the devices which can't be created. Expired failures are dropped
+pub fn merge_hw_hints(hints: HwHints) {
+    HINTS.lock().merge(hints, unix_time());
+}
+
+fn unix_time() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |x| x.as_secs())
+}
+
+pub fn type_name(type_: DeviceType) -> String {
+    unsafe {
+        let name = ffi::av_hwdevice_get_type_name(type_);
+        if name.is_null() { return String::new(); }
+        CStr::from_ptr(name).to_string_lossy().into()
+    }
+}
+fn hint_key(type_: DeviceType, device_name: Option<&str>) -> String {
+    format!("{}:{}", type_name(type_), device_name.unwrap_or_default())
+}
+
+/// Creates the device unless an earlier launch couldn't, and records the outcome in the hints
+fn create_hinted_device(type_: DeviceType, device_name: Option<&str>) -> Option<HWDevice> {
+    let key = hint_key(type_, device_name);
+    if HINTS.lock().skipped.contains(&key) {
+        log::debug!("Skipping HW device {key}, it couldn't be created before");
+        return None;
+    }
+    let device = HWDevice::from_type(type_, device_name).ok();
+    HINTS.lock().record(&key, device.is_some(), unix_time());
+    device
+}
+
+/// References to the cached devices held outside of the cache, i.e. by open decoders, encoders and their frames.
+/// Should drop back to zero once everything is closed
+pub fn hw_device_references() -> usize {
+    DEVICES.lock().values().map(|dev| unsafe { ffi::av_buffer_get_ref_count(dev.device_ref) as usize }.saturating_sub(1)).sum()
+}
+
+/// `DXGI_ERROR_DEVICE_REMOVED`, the GPU was reset (e.g. a Windows driver timeout) and the device is unusable
+pub const DEVICE_REMOVED: i32 = 0x887A0005u32 as i32;
+
+#[cfg(test)]
+thread_local! {
+    static FAILURE_HOOK: std::cell::Cell<Option<fn() -> Option<i32>>> = const { std::cell::Cell::new(None) };
+}
+
+/// Test hook called before every video decode call on this thread, an error code it returns is used instead of decoding.
+/// Returning `DEVICE_REMOVED` simulates a lost device, with software decoding too
+#[cfg(test)]
+pub fn set_hw_failure_hook(hook: Option<fn() -> Option<i32>>) {
+    FAILURE_HOOK.with(|x| x.set(hook));
+}
+#[cfg(test)]
+pub fn injected_failure() -> Option<i32> {
+    FAILURE_HOOK.with(|x| x.get()).and_then(|hook| hook())
+}
+#[cfg(not(test))]
+#[inline]
+pub fn injected_failure() -> Option<i32> {
+    None
+}
+
+// `AVHWDeviceContext` pointers of the devices found lost outside of a decode call, in the order they were lost
+static LOST_DEVICES: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+static LOST_DEVICE_COUNT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+/// Drops a device lost outside of a decode call (e.g. in `av_hwframe_transfer_data`) from the cache.
+/// The decoders using it find out with `device_lost_since` before their next packet
+pub unsafe fn mark_device_lost(device_ref: *mut ffi::AVBufferRef) {
+    if device_ref.is_null() { return; }
+    invalidate_device(device_ref);
+    let mut lost = LOST_DEVICES.lock();
+    lost.push((*device_ref).data as usize);
+    LOST_DEVICE_COUNT.store(lost.len(), std::sync::atomic::Ordering::Release);
+}
+
+/// Number of devices marked lost so far, the starting point for `device_lost_since`
+pub fn lost_device_count() -> usize {
+    LOST_DEVICE_COUNT.load(std::sync::atomic::Ordering::Acquire)
+}
+
+/// Whether `device_ref` was marked lost since `seen` devices were, `seen` is moved past them.
+/// Only locks when a device was lost in between, it's checked for every packet
+pub unsafe fn device_lost_since(seen: &mut usize, device_ref: *mut ffi::AVBufferRef) -> bool {
+    let count = lost_device_count();
+    if count == *seen { return false; }
+    let from = std::mem::replace(seen, count);
+    !device_ref.is_null() && LOST_DEVICES.lock()[from..count].contains(&((*device_ref).data as usize))
+}
+
+/// Whether `err` from a hardware decode or transfer means the device itself is gone, not just a failed call
+pub unsafe fn is_device_lost(device_ref: *mut ffi::AVBufferRef, err: i32) -> bool {
+    if err == DEVICE_REMOVED { return true; }
+    if device_ref.is_null() { return false; }
+    #[cfg(target_os = "windows")]
+    {
+        use windows::core::Interface;
+        let ctx = (*device_ref).data as *mut ffi::AVHWDeviceContext;
+        if (*ctx).type_ == ffi::AVHWDeviceType::AV_HWDEVICE_TYPE_D3D11VA && !(*ctx).hwctx.is_null() {
+            // `AVD3D11VADeviceContext` starts with the ID3D11Device pointer
+            let device = *((*ctx).hwctx as *mut *mut std::ffi::c_void);
+            if let Some(device) = windows::Win32::Graphics::Direct3D11::ID3D11Device::from_raw_borrowed(&device) {
+                return device.GetDeviceRemovedReason().is_err();
+            }
+        }
+    }
+    false
+}
+
+/// Best effort check that `device_ref` is an `AVBufferRef` of an `AVHWDeviceContext`
+///
+/// # Safety
+/// `device_ref` must be null or point to a valid `AVBufferRef`
+pub unsafe fn validate_external_device(device_ref: *mut ffi::AVBufferRef) -> Result<DeviceType, crate::VideoProcessingError> {
+    let invalid = |reason: &str| crate::VideoProcessingError::InvalidExternalHandle { what: "hw device", reason: reason.into() };
+    if device_ref.is_null() || (*device_ref).data.is_null() {
+        return Err(invalid("null AVBufferRef"));
+    }
+    if (*device_ref).size < std::mem::size_of::<ffi::AVHWDeviceContext>() as _ {
+        return Err(invalid("the buffer is too small for an AVHWDeviceContext"));
+    }
+    let type_ = (*((*device_ref).data as *const ffi::AVHWDeviceContext)).type_;
+    if type_ == DeviceType::AV_HWDEVICE_TYPE_NONE || ffi::av_hwdevice_get_type_name(type_).is_null() {
+        return Err(invalid("unknown device type, the buffer isn't an AVHWDeviceContext"));
+    }
+    Ok(type_)
+}
+
+/// Uses a device created by the host application instead of creating one, for decoders and encoders asking for its type and name
+///
+/// # Safety
+/// `device_ref` must be null or point to a valid `AVBufferRef`. If it's an `AVHWDeviceContext`, it must be initialized
+pub unsafe fn register_external_device(device_ref: *mut ffi::AVBufferRef, device_name: Option<&str>) -> Result<(), crate::VideoProcessingError> {
+    let type_ = validate_external_device(device_ref)?;
+    let device_ref = ffi::av_buffer_ref(device_ref);
+    if device_ref.is_null() {
+        return Err(crate::VideoProcessingError::InvalidExternalHandle { what: "hw device", reason: "av_buffer_ref failed".into() });
+    }
+    log::debug!("Using external HW device {type_:?}, device: {device_name:?}");
+    let mut dev = HWDevice {
+        type_,
+        device_name: device_name.map(|x| x.to_string()),
+        device_ref,
+        hw_formats: Vec::new(),
+        sw_formats: Vec::new(),
+        min_size: (0, 0),
+        max_size: (0, 0),
+    };
+    dev.query_constraints();
+    DEVICES.lock().insert(DeviceKey::new(type_, device_name), dev);
+    Ok(())
+}
+
+/// Drops the cached device of `device_ref`, so the next decoder or encoder creates a fresh one.
+/// Decoders and frames still using the old device keep it alive until they are dropped
+pub unsafe fn invalidate_device(device_ref: *mut ffi::AVBufferRef) {
+    if device_ref.is_null() { return; }
+    DEVICES.lock().retain(|key, dev| {
+        let same = (*dev.device_ref).data == (*device_ref).data;
+        if same { log::warn!("Dropping lost HW device {:?} ({:?})", key.type_, key.device_name); }
+        !same
+    });
 }
 
 pub fn initialize_ctx(type_: ffi::AVHWDeviceType) {
     let mut devices = DEVICES.lock();
-    if let Entry::Vacant(e) = devices.entry(type_ as u64) {
+    if let Entry::Vacant(e) = devices.entry(DeviceKey::new(type_, None)) {
         ::log::debug!("create {:?}", type_);
         if let Ok(dev) = HWDevice::from_type(type_, None) {
             ::log::debug!("created ok {:?}", type_);
@@ -112,8 +366,41 @@ pub unsafe fn pix_formats_to_vec(formats: *const ffi::AVPixelFormat) -> Vec<form
     ret
 }
 
-pub fn init_device_for_decoding(index: usize, codec: *const ffi::AVCodec, decoder_ctx: &mut codec::context::Context, device: Option<&str>) -> Result<(usize, ffi::AVHWDeviceType, String, Option<ffi::AVPixelFormat>), crate::VideoProcessingError> {
-    for i in index..20 {
+/// Device name which selects the GPU with the given index for this device type. Index 0 uses the default device
+fn device_name_for_index(type_: DeviceType, gpu_index: usize) -> Option<String> {
+    use ffi::AVHWDeviceType::*;
+    if gpu_index == 0 { return None; }
+    match type_ {
+        AV_HWDEVICE_TYPE_CUDA | AV_HWDEVICE_TYPE_D3D11VA | AV_HWDEVICE_TYPE_DXVA2 | AV_HWDEVICE_TYPE_VULKAN | AV_HWDEVICE_TYPE_OPENCL => Some(gpu_index.to_string()),
+        AV_HWDEVICE_TYPE_VAAPI | AV_HWDEVICE_TYPE_VDPAU if cfg!(target_os = "linux") => Some(format!("/dev/dri/renderD{}", 128 + gpu_index)),
+        _ => None
+    }
+}
+
+#[derive(Debug)]
+pub struct DecodingDevice {
+    pub config_index: usize,
+    pub device_type: DeviceType,
+    pub backend: String,
+    pub pix_fmt: Option<ffi::AVPixelFormat>,
+    pub device_name: Option<String>,
+    /// Frame size constraints of the device, (0, 0) when unknown
+    pub min_size: (i32, i32),
+    pub max_size: (i32, i32),
+}
+
+/// `gpu_index` selects the GPU, unless `device` explicitly names the device to use
+pub fn init_device_for_decoding(gpu_index: usize, codec: *const ffi::AVCodec, decoder_ctx: &mut codec::context::Context, device: Option<&str>) -> Result<DecodingDevice, crate::VideoProcessingError> {
+    let codec_name = unsafe { CStr::from_ptr((*codec).name).to_string_lossy().into_owned() };
+    let mut configs: Vec<usize> = (0..20).collect();
+    if let Some(hinted) = HINTS.lock().hints.decoders.get(&codec_name) {
+        // The type which worked the last time goes first, the order of the rest is kept
+        configs.sort_by_key(|&i| unsafe {
+            let config = ffi::avcodec_get_hw_config(codec, i as i32);
+            config.is_null() || type_name((*config).device_type) != *hinted
+        });
+    }
+    for i in configs {
         unsafe {
             let config = ffi::avcodec_get_hw_config(codec, i as i32);
             if config.is_null() {
@@ -129,36 +416,68 @@ pub fn init_device_for_decoding(index: usize, codec: *const ffi::AVCodec, decode
                 continue;
             }
             ::log::debug!("[dec] codec type {:?} {}", type_, i);
+            let device = device.map(str::to_owned).or_else(|| device_name_for_index(type_, gpu_index));
             let mut devices = DEVICES.lock();
-            let mut device_hash = 0;
-            if let Some(dev_name) = device {
-                let mut hasher = crc32fast::Hasher::new();
-                hasher.update(dev_name.as_bytes());
-                device_hash = hasher.finalize() as u64;
-            }
-            if let Entry::Vacant(e) = devices.entry(type_ as u64 + device_hash) {
-                if let Ok(dev) = HWDevice::from_type(type_, device) {
+            let key = DeviceKey::new(type_, device.as_deref());
+            if let Entry::Vacant(e) = devices.entry(key.clone()) {
+                if let Some(mut dev) = create_hinted_device(type_, device.as_deref()) {
+                    dev.query_constraints();
                     e.insert(dev);
                 }
             }
-            if let Some(dev) = devices.get(&(type_ as u64 + device_hash)) {
+            if let Some(dev) = devices.get(&key) {
+                HINTS.lock().hints.decoders.insert(codec_name.clone(), type_name(type_));
                 (*decoder_ctx.as_mut_ptr()).hw_device_ctx = dev.add_ref();
-                return Ok((i, type_, dev.name(), Some((*config).pix_fmt)));
+                return Ok(DecodingDevice {
+                    config_index: i,
+                    device_type: type_,
+                    backend: dev.name(),
+                    pix_fmt: Some((*config).pix_fmt),
+                    device_name: key.device_name,
+                    min_size: dev.min_size,
+                    max_size: dev.max_size,
+                });
             }
         }
     }
-    Ok((0, ffi::AVHWDeviceType::AV_HWDEVICE_TYPE_NONE, String::new(), None))
+    Ok(DecodingDevice {
+        config_index: 0,
+        device_type: ffi::AVHWDeviceType::AV_HWDEVICE_TYPE_NONE,
+        backend: String::new(),
+        pix_fmt: None,
+        device_name: None,
+        min_size: (0, 0),
+        max_size: (0, 0),
+    })
 }
 
-pub fn find_working_encoder(encoders: &[(&'static str, bool)], device: Option<&str>) -> (&'static str, bool, Option<DeviceType>) {
-    if encoders.is_empty() { return ("", false, None); } // TODO: should be Result<>
+// The preferred HW format is stored in `AVCodecContext.opaque`, see `set_preferred_hw_format`
+unsafe extern "C" fn get_hw_format(ctx: *mut ffi::AVCodecContext, formats: *const ffi::AVPixelFormat) -> ffi::AVPixelFormat {
+    let preferred = (*ctx).opaque as isize as i32;
+    for i in 0..100 {
+        let p = *formats.offset(i);
+        if p == ffi::AVPixelFormat::AV_PIX_FMT_NONE {
+            break;
+        }
+        if p as i32 == preferred {
+            return p;
+        }
+    }
+    log::warn!("HW pixel format {} not offered by the decoder, falling back to software decoding", preferred);
+    ffi::avcodec_default_get_format(ctx, formats)
+}
 
-    let mut device_hash = 0;
-    if let Some(dev_name) = device {
-        let mut hasher = crc32fast::Hasher::new();
-        hasher.update(dev_name.as_bytes());
-        device_hash = hasher.finalize() as u64;
+/// Sets the `get_format` callback, so the decoder actually selects the HW format instead of silently falling back to software
+pub fn set_preferred_hw_format(decoder_ctx: &mut codec::context::Context, pix_fmt: ffi::AVPixelFormat) {
+    unsafe {
+        let ctx = decoder_ctx.as_mut_ptr();
+        (*ctx).opaque = pix_fmt as i32 as isize as *mut std::ffi::c_void;
+        (*ctx).get_format = Some(get_hw_format);
     }
+}
+
+pub fn find_working_encoder(encoders: &[(&'static str, bool)], device: Option<&str>) -> (&'static str, bool, Option<DeviceType>) {
+    if encoders.is_empty() { return ("", false, None); } // TODO: should be Result<>
 
     for x in encoders {
         if let Some(mut enc) = encoder::find_by_name(x.0) {
@@ -175,9 +494,9 @@ pub fn find_working_encoder(encoders: &[(&'static str, bool)], device: Option<&s
                         let type_ = (*config).device_type;
                         ::log::debug!("[enc] codec type {:?} {}, for: {}", type_, i, x.0);
                         let mut devices = DEVICES.lock();
-                        if let Entry::Vacant(e) = devices.entry(type_ as u64 + device_hash) {
+                        if let Entry::Vacant(e) = devices.entry(DeviceKey::new(type_, device)) {
                             ::log::debug!("create {:?}", type_);
-                            if let Ok(dev) = HWDevice::from_type(type_, device) {
+                            if let Some(dev) = create_hinted_device(type_, device) {
                                 ::log::debug!("created ok {:?}", type_);
                                 e.insert(dev);
                             }
@@ -187,19 +506,8 @@ pub fn find_working_encoder(encoders: &[(&'static str, bool)], device: Option<&s
                         ffi::AVHWDeviceType::AV_HWDEVICE_TYPE_VIDEOTOOLBOX
                     };
                     let mut devices = DEVICES.lock();
-                    if let Some(dev) = devices.get_mut(&(type_ as u64 + device_hash)) {
-                        let mut constraints = ffi::av_hwdevice_get_hwframe_constraints(dev.as_mut_ptr(), ptr::null());
-                        if !constraints.is_null() {
-                            dev.hw_formats = pix_formats_to_vec((*constraints).valid_hw_formats);
-                            dev.sw_formats = pix_formats_to_vec((*constraints).valid_sw_formats);
-                            dev.min_size = ((*constraints).min_width, (*constraints).min_height);
-                            dev.max_size = ((*constraints).max_width, (*constraints).max_height);
-
-                            log::debug!("HW formats: {:?}", &dev.hw_formats);
-                            log::debug!("SW formats: {:?}", &dev.sw_formats);
-
-                            ffi::av_hwframe_constraints_free(&mut constraints);
-                        }
+                    if let Some(dev) = devices.get_mut(&DeviceKey::new(type_, device)) {
+                        dev.query_constraints();
                         return (x.0, x.1, Some(dev.device_type()));
                     }
                 }
@@ -212,6 +520,72 @@ pub fn find_working_encoder(encoders: &[(&'static str, bool)], device: Option<&s
     (x.0, x.1, None)
 }
 
+/// Like `find_working_encoder`, without creating any device: a hardware encoder is only picked when its device was already created
+/// in this process or worked in an earlier launch (see `WarmStart`), the first software encoder otherwise. `None` when the build has none
+pub fn find_known_encoder(encoders: &[(&'static str, bool)], device: Option<&str>) -> Option<(&'static str, Option<DeviceType>)> {
+    // DEVICES and HINTS are locked one after the other, creating a device locks them in that order
+    let known = |type_: DeviceType| DEVICES.lock().contains_key(&DeviceKey::new(type_, device)) || {
+        let hints = HINTS.lock();
+        let key = hint_key(type_, device);
+        hints.hints.working.contains(&key) && !hints.skipped.contains(&key)
+    };
+    for &(name, is_hw) in encoders {
+        let Some(mut enc) = encoder::find_by_name(name) else { continue; };
+        if !is_hw { return Some((name, None)); }
+        let types: Vec<DeviceType> = if name.contains("videotoolbox") {
+            vec![ffi::AVHWDeviceType::AV_HWDEVICE_TYPE_VIDEOTOOLBOX]
+        } else {
+            (0..20).map_while(|i| unsafe {
+                let config = ffi::avcodec_get_hw_config(enc.as_mut_ptr(), i);
+                (!config.is_null()).then(|| (*config).device_type)
+            }).collect()
+        };
+        if let Some(type_) = types.into_iter().find(|x| *x != ffi::AVHWDeviceType::AV_HWDEVICE_TYPE_NONE && known(*x)) {
+            return Some((name, Some(type_)));
+        }
+    }
+    None
+}
+
+/// Queries every HW config of the codec and returns the constraints of the devices which could be created
+pub fn codec_hw_capabilities(codec: *const ffi::AVCodec, device: Option<&str>) -> Vec<crate::DeviceCapabilities> {
+    let mut ret = Vec::new();
+    if codec.is_null() { return ret; }
+
+    for i in 0..20 {
+        unsafe {
+            let config = ffi::avcodec_get_hw_config(codec, i);
+            if config.is_null() {
+                break;
+            }
+            let type_ = (*config).device_type;
+            if type_ == ffi::AVHWDeviceType::AV_HWDEVICE_TYPE_NONE {
+                continue;
+            }
+            let mut devices = DEVICES.lock();
+            let key = DeviceKey::new(type_, device);
+            if let Entry::Vacant(e) = devices.entry(key.clone()) {
+                if let Ok(dev) = HWDevice::from_type(type_, device) {
+                    e.insert(dev);
+                }
+            }
+            if let Some(dev) = devices.get_mut(&key) {
+                if dev.hw_formats.is_empty() && dev.sw_formats.is_empty() {
+                    dev.query_constraints();
+                }
+                ret.push(crate::DeviceCapabilities {
+                    backend: dev.name(),
+                    min_size: dev.min_size,
+                    max_size: dev.max_size,
+                    hw_formats: dev.hw_formats.iter().map(|x| format!("{x:?}")).collect(),
+                    sw_formats: dev.sw_formats.iter().map(|x| format!("{x:?}")).collect(),
+                });
+            }
+        }
+    }
+    ret
+}
+
 pub unsafe fn get_transfer_formats_from_gpu(frame: *mut ffi::AVFrame) -> Vec<format::Pixel> {
     let mut formats = ptr::null_mut();
     if !frame.is_null() && !(*frame).hw_frames_ctx.is_null() {
@@ -251,13 +625,7 @@ pub fn is_hardware_format(format: ffi::AVPixelFormat) -> bool {
 
 pub fn initialize_hwframes_context(encoder_ctx: *mut ffi::AVCodecContext, _frame_ctx: *mut ffi::AVFrame, type_: DeviceType, pixel_format: ffi::AVPixelFormat, size: (u32, u32), init_hwframes: bool, device_name: Option<&str>) -> Result<(), ()> {
     let mut devices = DEVICES.lock();
-    let mut device_hash = 0;
-    if let Some(dev_name) = device_name {
-        let mut hasher = crc32fast::Hasher::new();
-        hasher.update(dev_name.as_bytes());
-        device_hash = hasher.finalize() as u64;
-    }
-    if let Some(dev) = devices.get_mut(&(type_ as u64 + device_hash)) {
+    if let Some(dev) = devices.get_mut(&DeviceKey::new(type_, device_name)) {
         unsafe {
             if (*encoder_ctx).hw_device_ctx.is_null() {
                 (*encoder_ctx).hw_device_ctx = dev.add_ref();
@@ -340,6 +708,28 @@ pub fn initialize_hwframes_context(encoder_ctx: *mut ffi::AVCodecContext, _frame
     Ok(())
 }
 
+/// Software formats the frames context of an already created device accepts, from its hwframe constraints
+pub fn device_sw_formats(type_: DeviceType, device_name: Option<&str>) -> Vec<format::Pixel> {
+    DEVICES.lock().get(&DeviceKey::new(type_, device_name)).map(|x| x.sw_formats.clone()).unwrap_or_default()
+}
+
+/// Device types whose hardware frames the codec takes directly, e.g. `cuda` and `d3d11va` for `hevc_nvenc`
+pub fn codec_hw_frame_types(codec: *const ffi::AVCodec) -> Vec<String> {
+    let mut ret = Vec::new();
+    if codec.is_null() { return ret; }
+    for i in 0..20 {
+        unsafe {
+            let config = ffi::avcodec_get_hw_config(codec, i);
+            if config.is_null() { break; }
+            if (*config).device_type != ffi::AVHWDeviceType::AV_HWDEVICE_TYPE_NONE && is_hardware_format((*config).pix_fmt) {
+                let name = type_name((*config).device_type);
+                if !ret.contains(&name) { ret.push(name); }
+            }
+        }
+    }
+    ret
+}
+
 pub fn find_best_matching_codec(codec: format::Pixel, supported: &[format::Pixel]) -> format::Pixel {
     if supported.is_empty() { return format::Pixel::None; }
 
@@ -370,3 +760,151 @@ pub fn find_best_matching_codec(codec: format::Pixel, supported: &[format::Pixel
 //         Vec::new()
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ffi::AVHWDeviceType::*;
+
+    #[test]
+    fn device_names_per_index() {
+        assert_eq!(device_name_for_index(AV_HWDEVICE_TYPE_CUDA, 0), None);
+        assert_eq!(device_name_for_index(AV_HWDEVICE_TYPE_CUDA, 1).as_deref(), Some("1"));
+        assert_eq!(device_name_for_index(AV_HWDEVICE_TYPE_D3D11VA, 2).as_deref(), Some("2"));
+        assert_eq!(device_name_for_index(AV_HWDEVICE_TYPE_VIDEOTOOLBOX, 1), None);
+        if cfg!(target_os = "linux") {
+            assert_eq!(device_name_for_index(AV_HWDEVICE_TYPE_VAAPI, 1).as_deref(), Some("/dev/dri/renderD129"));
+        }
+        assert_ne!(DeviceKey::new(AV_HWDEVICE_TYPE_CUDA, None), DeviceKey::new(AV_HWDEVICE_TYPE_CUDA, Some("1")));
+        assert_eq!(DeviceKey::new(AV_HWDEVICE_TYPE_CUDA, None), DeviceKey::new(AV_HWDEVICE_TYPE_CUDA, Some("")));
+    }
+
+    #[test]
+    fn lost_devices_are_reported_once() {
+        unsafe {
+            // Only the buffer identity matters, it doesn't have to be a real device
+            let (mut a, mut b) = (ffi::av_buffer_alloc(16), ffi::av_buffer_alloc(16));
+            let (mut seen_a, mut seen_b) = (lost_device_count(), lost_device_count());
+            assert!(!device_lost_since(&mut seen_a, a));
+            mark_device_lost(a);
+            assert!(device_lost_since(&mut seen_a, a));
+            assert!(!device_lost_since(&mut seen_a, a));
+            assert!(!device_lost_since(&mut seen_b, b));
+            ffi::av_buffer_unref(&mut a);
+            ffi::av_buffer_unref(&mut b);
+        }
+    }
+
+    #[test]
+    fn two_gpus_on_two_threads() {
+        let path = crate::util::test::temp_path("two-gpus.mp4");
+        crate::util::test::write_test_clip(&path, crate::EncoderCodec::H264, (640, 360), 60, 30.0).unwrap();
+
+        let threads: Vec<_> = (0..2usize).map(|gpu_index| {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                let mut decoder = crate::Decoder::new(&path, crate::DecoderOptions { gpu_index: Some(gpu_index), ..Default::default() }).unwrap();
+                let mut frames = 0;
+                while let Some(frame) = decoder.next_frame() {
+                    if let crate::Frame::Video(_) = frame { frames += 1; }
+                }
+                (gpu_index, frames, decoder.hw_device())
+            })
+        }).collect();
+
+        for thread in threads {
+            let (gpu_index, frames, device) = thread.join().unwrap();
+            assert_eq!(frames, 60, "GPU {gpu_index}");
+            // Machines without a second GPU decode in software, a device is never borrowed from another index
+            if let Some(device) = device {
+                let type_ = unsafe { ffi::av_hwdevice_find_type_by_name(CString::new(device.backend.clone()).unwrap().as_ptr()) };
+                assert_eq!(device.device_name, device_name_for_index(type_, gpu_index), "GPU {gpu_index} used {device:?}");
+            }
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+    #[test]
+    fn failed_devices_are_skipped_by_the_next_launch() {
+        let now = 1_700_000_000;
+        // Nothing is recorded without warm start
+        let mut state = HintState::default();
+        state.record("cuda:", false, now);
+        assert!(state.hints.unavailable.is_empty());
+
+        // The first launch with warm start records the failure, but keeps trying the device
+        let mut first = HintState::default();
+        first.merge(HwHints::default(), now);
+        first.record("cuda:", false, now);
+        first.record("vaapi:", true, now);
+        assert_eq!(first.hints.unavailable.get("cuda:"), Some(&now));
+        assert!(first.skipped.is_empty());
+
+        // The next one skips it until the failure expires
+        let mut second = HintState::default();
+        second.merge(first.hints.clone(), now + 60);
+        assert!(second.skipped.contains("cuda:"));
+        assert!(!second.skipped.contains("vaapi:"));
+        let mut later = HintState::default();
+        later.merge(first.hints.clone(), now + UNAVAILABLE_HINT_EXPIRY_SECS);
+        assert!(later.skipped.is_empty());
+        assert!(later.hints.unavailable.is_empty());
+
+        // A device working again clears its failure, one which stops working discards the hints
+        first.record("cuda:", true, now);
+        assert!(first.hints.unavailable.is_empty());
+        first.hints.decoders.insert("h264".into(), "cuda".into());
+        first.record("vaapi:", false, now);
+        assert!(first.hints.decoders.is_empty());
+        assert_eq!(first.hints.unavailable.keys().collect::<Vec<_>>(), ["vaapi:"]);
+    }
+    #[test]
+    fn known_encoders_without_devices() {
+        let device = Some("gpu-video-known-encoder-test");
+        let encoders = crate::EncoderCodec::H264.encoders(true);
+        assert_eq!(find_known_encoder(&encoders, device), Some(("libx264", None)));
+        assert_eq!(find_known_encoder(&[("gpu-video-missing", false)], device), None);
+        assert!(!DEVICES.lock().keys().any(|x| x.device_name.as_deref() == device));
+    }
+    #[test]
+    fn external_device_handles() {
+        let reason = |x: Result<DeviceType, crate::VideoProcessingError>| match x {
+            Err(crate::VideoProcessingError::InvalidExternalHandle { what: "hw device", reason }) => reason,
+            x => panic!("{x:?}"),
+        };
+        unsafe {
+            assert_eq!(reason(validate_external_device(ptr::null_mut())), "null AVBufferRef");
+            let mut small = ffi::av_buffer_allocz(16);
+            assert!(reason(validate_external_device(small)).contains("too small"));
+            let mut zeroed = ffi::av_buffer_allocz(std::mem::size_of::<ffi::AVHWDeviceContext>());
+            assert!(reason(validate_external_device(zeroed)).contains("unknown device type"));
+            assert!(register_external_device(zeroed, Some("gpu-video-wrong-type-test")).is_err());
+            ffi::av_buffer_unref(&mut small);
+            ffi::av_buffer_unref(&mut zeroed);
+
+            // Allocated contexts of every type compiled in are accepted, initializing them needs the hardware
+            let mut type_ = ffi::av_hwdevice_iterate_types(AV_HWDEVICE_TYPE_NONE);
+            let mut created = ptr::null_mut();
+            while type_ != AV_HWDEVICE_TYPE_NONE {
+                let mut device_ref = ffi::av_hwdevice_ctx_alloc(type_);
+                assert_eq!(validate_external_device(device_ref).unwrap(), type_);
+                ffi::av_buffer_unref(&mut device_ref);
+                if created.is_null() {
+                    // Leaves `created` null when there's no such device
+                    ffi::av_hwdevice_ctx_create(&mut created, type_, ptr::null(), ptr::null_mut(), 0);
+                }
+                type_ = ffi::av_hwdevice_iterate_types(type_);
+            }
+
+            // A working device is shared with the crate, the host keeps its own reference
+            if !created.is_null() {
+                let device = Some("gpu-video-external-device-test");
+                register_external_device(created, device).unwrap();
+                assert_eq!(ffi::av_buffer_get_ref_count(created), 2);
+                let key = DeviceKey::new((*((*created).data as *const ffi::AVHWDeviceContext)).type_, device);
+                assert!(DEVICES.lock().remove(&key).is_some());
+                assert_eq!(ffi::av_buffer_get_ref_count(created), 1);
+                ffi::av_buffer_unref(&mut created);
+            }
+        }
+    }
+}
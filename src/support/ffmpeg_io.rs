@@ -0,0 +1,160 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+use ffmpeg_next::{ ffi, format, Dictionary, error::{ EIO, EINVAL, ENOMEM, ENOSYS } };
+use crate::types::*;
+
+use std::io::SeekFrom;
+use std::os::raw::{ c_int, c_void };
+use std::ptr;
+
+const IO_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Custom AVIOContext reading from a Rust reader. Must outlive the format context using it.
+pub struct CustomIo {
+    avio: *mut ffi::AVIOContext,
+    reader: *mut Box<dyn ReadSeek>,
+}
+unsafe impl Send for CustomIo { }
+
+impl Drop for CustomIo {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.avio.is_null() {
+                ffi::av_freep(&mut (*self.avio).buffer as *mut *mut u8 as *mut c_void);
+                ffi::avio_context_free(&mut self.avio);
+            }
+            drop(Box::from_raw(self.reader));
+        }
+    }
+}
+
+/// Opens the images as one video stream at `rate`, through an ffconcat list written to a temporary file in `scratch_dir` (the system temp directory when `None`)
+pub fn open_file_list(files: &[String], rate: (i32, i32), scratch_dir: Option<&std::path::Path>, mut options: Dictionary, interrupt: ffi::AVIOInterruptCB) -> Result<format::context::Input, VideoProcessingError> {
+    use std::fmt::Write;
+    if files.is_empty() { return Err(VideoProcessingError::VideoStreamNotFound); }
+    if rate.0 <= 0 || rate.1 <= 0 { return Err(VideoProcessingError::InvalidFrameRate(rate)); }
+    let mut list = String::from("ffconcat version 1.0\n");
+    for file in files {
+        // Quotes in the path are closed, escaped and reopened
+        let _ = writeln!(list, "file '{}'\noption framerate {}/{}\nduration {:.6}", file.replace('\'', "'\\''"), rate.0, rate.1, rate.1 as f64 / rate.0 as f64);
+    }
+    static COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+    let path = scratch_dir.map_or_else(std::env::temp_dir, ToOwned::to_owned).join(format!("gpu-video-{}-{}.ffconcat", std::process::id(), COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed)));
+    std::fs::write(&path, list)?;
+    // Absolute paths and protocols are "unsafe" for the concat demuxer, unless the caller asked for the check
+    if options.get("safe").is_none() {
+        options.set("safe", "0");
+    }
+    // The list is fully read while opening
+    let ctx = open_url(&path.to_string_lossy(), options, interrupt);
+    let _ = std::fs::remove_file(&path);
+    ctx
+}
+
+/// Like `format::input_with_dictionary`, with `interrupt` installed before anything is read, so opening can be aborted too
+pub fn open_url(url: &str, options: Dictionary, interrupt: ffi::AVIOInterruptCB) -> Result<format::context::Input, VideoProcessingError> {
+    let url = std::ffi::CString::new(url).map_err(|_| ffmpeg_next::Error::Other { errno: EINVAL })?;
+    unsafe {
+        let ctx = ffi::avformat_alloc_context();
+        if ctx.is_null() {
+            return Err(ffmpeg_next::Error::Other { errno: ENOMEM }.into());
+        }
+        (*ctx).interrupt_callback = interrupt;
+        open_context(ctx, url.as_ptr(), options)
+    }
+}
+
+/// Opens the allocated `ctx` and reads the stream info. `ctx` is freed on failure
+unsafe fn open_context(mut ctx: *mut ffi::AVFormatContext, url: *const std::os::raw::c_char, options: Dictionary) -> Result<format::context::Input, VideoProcessingError> {
+    let mut opts = options.disown();
+    let err = ffi::avformat_open_input(&mut ctx, url, ptr::null(), &mut opts);
+    Dictionary::own(opts);
+    if err < 0 {
+        return Err(ffmpeg_next::Error::from(err).into());
+    }
+    let err = ffi::avformat_find_stream_info(ctx, ptr::null_mut());
+    if err < 0 {
+        ffi::avformat_close_input(&mut ctx);
+        return Err(ffmpeg_next::Error::from(err).into());
+    }
+    Ok(format::context::Input::wrap(ctx))
+}
+
+unsafe extern "C" fn read_packet(opaque: *mut c_void, buf: *mut u8, size: c_int) -> c_int {
+    let reader = &mut *(opaque as *mut Box<dyn ReadSeek>);
+    match reader.read(std::slice::from_raw_parts_mut(buf, size as usize)) {
+        Ok(0) => ffi::AVERROR_EOF,
+        Ok(n) => n as c_int,
+        Err(e) => { log::error!("Read error: {e:?}"); ffi::AVERROR(EIO) }
+    }
+}
+
+unsafe extern "C" fn seek(opaque: *mut c_void, offset: i64, whence: c_int) -> i64 {
+    let reader = &mut *(opaque as *mut Box<dyn ReadSeek>);
+    let result = if whence & ffi::AVSEEK_SIZE as c_int != 0 {
+        // Return the stream size and restore the position
+        reader.stream_position().and_then(|pos| {
+            let size = reader.seek(SeekFrom::End(0))?;
+            reader.seek(SeekFrom::Start(pos))?;
+            Ok(size)
+        })
+    } else {
+        match whence & !(ffi::AVSEEK_FORCE as c_int) {
+            0 => reader.seek(SeekFrom::Start(offset as u64)), // SEEK_SET
+            1 => reader.seek(SeekFrom::Current(offset)),      // SEEK_CUR
+            2 => reader.seek(SeekFrom::End(offset)),          // SEEK_END
+            _ => return ffi::AVERROR(EINVAL) as i64
+        }
+    };
+    match result {
+        Ok(x) => x as i64,
+        // Non-seekable input, e.g. the C API without a seek callback
+        Err(e) if e.kind() == std::io::ErrorKind::Unsupported => ffi::AVERROR(ENOSYS) as i64,
+        Err(e) => { log::error!("Seek error: {e:?}"); ffi::AVERROR(EIO) as i64 }
+    }
+}
+
+/// Opens an input context reading through `reader`
+pub fn open_input(reader: Box<dyn ReadSeek>, options: Dictionary, interrupt: ffi::AVIOInterruptCB) -> Result<(format::context::Input, CustomIo), VideoProcessingError> {
+    unsafe {
+        let reader = Box::into_raw(Box::new(reader));
+        let buffer = ffi::av_malloc(IO_BUFFER_SIZE) as *mut u8;
+        let avio = ffi::avio_alloc_context(buffer, IO_BUFFER_SIZE as c_int, 0, reader as *mut c_void, Some(read_packet), None, Some(seek));
+        let io = CustomIo { avio, reader };
+        if avio.is_null() {
+            return Err(ffmpeg_next::Error::Other { errno: ENOMEM }.into());
+        }
+
+        let ctx = ffi::avformat_alloc_context();
+        if ctx.is_null() {
+            return Err(ffmpeg_next::Error::Other { errno: ENOMEM }.into());
+        }
+        (*ctx).pb = avio;
+        (*ctx).flags |= ffi::AVFMT_FLAG_CUSTOM_IO as c_int;
+        (*ctx).interrupt_callback = interrupt;
+
+        Ok((open_context(ctx, ptr::null(), options)?, io))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NO_INTERRUPT: ffi::AVIOInterruptCB = ffi::AVIOInterruptCB { callback: None, opaque: ptr::null_mut() };
+
+    #[test]
+    fn file_list_options() {
+        let path = crate::util::test::temp_path("list-item.mp4");
+        crate::util::test::write_test_clip(&path, crate::EncoderCodec::H264, (64, 48), 2, 25.0).unwrap();
+        let files = [path.clone()];
+        assert!(open_file_list(&files, (25, 1), None, Dictionary::new(), NO_INTERRUPT).is_ok());
+        // The caller's `safe` isn't overridden, and the concat demuxer rejects the absolute path
+        let mut options = Dictionary::new();
+        options.set("safe", "1");
+        assert!(open_file_list(&files, (25, 1), None, options, NO_INTERRUPT).is_err());
+        assert!(matches!(open_file_list(&files, (0, 1), None, Dictionary::new(), NO_INTERRUPT), Err(VideoProcessingError::InvalidFrameRate((0, 1)))));
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,409 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+// Audio/video sync measurement, from the stream timelines and optionally a linear timecode (LTC) audio track.
+
+use crate::*;
+
+#[derive(Debug, Clone, Copy)]
+pub struct AvOffsetOptions {
+    /// Decode LTC from the audio and compare it with the video timecode of the container, when it has one
+    pub ltc: bool,
+    /// Audio stream carrying the LTC, every channel of every audio stream is tried when `None`
+    pub ltc_stream: Option<usize>,
+}
+
+impl Default for AvOffsetOptions {
+    fn default() -> Self {
+        Self { ltc: true, ltc_stream: None }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AvOffset {
+    pub video_stream: usize,
+    pub audio_stream: usize,
+    /// Timeline coverage, from the first timestamp to the end of the last frame
+    pub video_start_us: i64,
+    pub video_end_us: i64,
+    pub audio_start_us: i64,
+    pub audio_end_us: i64,
+    /// Sum of the frame durations and of the decoded samples, less than the coverage when the stream has gaps
+    pub video_duration_us: i64,
+    pub audio_duration_us: i64,
+    /// Audio minus video, positive when the audio is late
+    pub start_offset_us: i64,
+    pub end_offset_us: i64,
+    /// Change of the offset over the file, in milliseconds per minute of video
+    pub drift_ms_per_minute: f64,
+    pub ltc: Option<LtcOffset>,
+}
+
+/// LTC compared with the video timecode. The offsets are where the LTC frames are relative to where the video timecode puts them
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LtcOffset {
+    pub stream_index: usize,
+    pub channel: u32,
+    /// Number of decoded LTC frames
+    pub frames: usize,
+    /// Timecode of the first video frame
+    pub video_timecode: String,
+    pub first_timecode: String,
+    pub last_timecode: String,
+    pub start_offset_us: i64,
+    pub end_offset_us: i64,
+    pub drift_ms_per_minute: f64,
+}
+
+/// Measures how the audio lines up with the video at the start and the end of `input`.
+/// Reads the video packets for their timestamps and decodes the audio, so it takes roughly as long as extracting the audio
+pub fn measure_av_offset(input: &str, options: AvOffsetOptions) -> Result<AvOffset, VideoProcessingError> {
+    let mut decoder = Decoder::new(input, DecoderOptions::default())?;
+    let infos = decoder.stream_infos();
+    let video_stream = infos.iter().find(|x| matches!(x.stream_type, StreamType::Video)).ok_or(VideoProcessingError::VideoStreamNotFound)?.index;
+    let audio_streams: Vec<usize> = infos.iter().filter(|x| matches!(x.stream_type, StreamType::Audio)).map(|x| x.index).collect();
+    let audio_stream = *audio_streams.first().ok_or(VideoProcessingError::AudioStreamNotFound)?;
+    let info = decoder.get_video_info()?;
+    for stream in decoder.streams() {
+        stream.decode = stream.index == video_stream;
+    }
+
+    // Packet timestamps are the presentation timeline, decoding the frames wouldn't change them
+    let frame_duration_us = if info.fps > 0.0 { (1000000.0 / info.fps).round() as i64 } else { 0 };
+    let mut video = Coverage::default();
+    while let Some(packet) = decoder.next_packet() {
+        if packet.stream_index != video_stream { continue; }
+        if let Some(pts) = packet.pts_us {
+            video.add(pts, packet.duration_us.filter(|x| *x > 0).unwrap_or(frame_duration_us));
+        }
+    }
+    drop(decoder);
+
+    let mut audio = Coverage::default();
+    let mut ltc_tracks = Vec::new();
+    for &index in &audio_streams {
+        let decode_ltc = options.ltc && options.ltc_stream.is_none_or(|x| x == index);
+        if index != audio_stream && !decode_ltc { continue; }
+        let mut extractor = AudioExtractor::new(input, Some(index), PcmFormat::F32)?;
+        let (rate, channels) = (extractor.sample_rate, extractor.channels.max(1) as usize);
+        if rate == 0 { continue; }
+        let mut decoders: Vec<LtcDecoder> = if decode_ltc { (0..channels).map(|_| LtcDecoder::new(rate)).collect() } else { Vec::new() };
+        let mut next_us = 0;
+        while let Some(chunk) = extractor.next_samples() {
+            let (ts, pcm) = chunk?;
+            let samples: Vec<f32> = pcm.chunks_exact(4).map(|x| f32::from_le_bytes([x[0], x[1], x[2], x[3]])).collect();
+            let count = samples.len() / channels;
+            let ts = ts.unwrap_or(next_us);
+            let duration_us = count as i64 * 1000000 / rate as i64;
+            next_us = ts + duration_us;
+            if index == audio_stream {
+                audio.add(ts, duration_us);
+            }
+            for (channel, ltc) in decoders.iter_mut().enumerate() {
+                ltc.feed(ts, samples.iter().skip(channel).step_by(channels).copied());
+            }
+        }
+        for (channel, ltc) in decoders.into_iter().enumerate() {
+            if !ltc.frames.is_empty() {
+                ltc_tracks.push((index, channel as u32, ltc.frames));
+            }
+        }
+    }
+
+    let start_offset_us = audio.start_us - video.start_us;
+    let end_offset_us = audio.end_us - video.end_us;
+    let ltc = ltc_tracks.into_iter().max_by_key(|x| x.2.len()).and_then(|(stream_index, channel, frames)| {
+        let Some(video_timecode) = info.metadata.get("timecode") else {
+            log::debug!("Found LTC on stream {stream_index}, but the video has no timecode to compare it with");
+            return None;
+        };
+        ltc_offset(stream_index, channel, &frames, video_timecode, video.start_us, info.fps)
+    });
+
+    Ok(AvOffset {
+        video_stream,
+        audio_stream,
+        video_start_us: video.start_us,
+        video_end_us: video.end_us,
+        audio_start_us: audio.start_us,
+        audio_end_us: audio.end_us,
+        video_duration_us: video.duration_us,
+        audio_duration_us: audio.duration_us,
+        start_offset_us,
+        end_offset_us,
+        drift_ms_per_minute: drift(end_offset_us - start_offset_us, video.end_us - video.start_us),
+        ltc,
+    })
+}
+
+/// Where the decoded LTC `frames` are relative to the video starting at `video_start_us` with `video_timecode`
+fn ltc_offset(stream_index: usize, channel: u32, frames: &[LtcFrame], video_timecode: &str, video_start_us: i64, fps: f64) -> Option<LtcOffset> {
+    let nominal = fps.round() as i64;
+    let video_frames = parse_timecode(video_timecode).map(|x| x.frames(nominal))?;
+    let offset = |x: &LtcFrame| x.timestamp_us - (video_start_us + ((x.timecode.frames(nominal) - video_frames) as f64 * 1000000.0 / fps).round() as i64);
+    let (first, last) = (frames.first()?, frames.last()?);
+    Some(LtcOffset {
+        stream_index,
+        channel,
+        frames: frames.len(),
+        video_timecode: video_timecode.to_string(),
+        first_timecode: first.timecode.to_string(),
+        last_timecode: last.timecode.to_string(),
+        start_offset_us: offset(first),
+        end_offset_us: offset(last),
+        drift_ms_per_minute: drift(offset(last) - offset(first), last.timestamp_us - first.timestamp_us),
+    })
+}
+
+fn drift(change_us: i64, span_us: i64) -> f64 {
+    if span_us <= 0 { return 0.0; }
+    change_us as f64 / 1000.0 / (span_us as f64 / 60000000.0)
+}
+
+#[derive(Default)]
+struct Coverage {
+    start_us: i64,
+    end_us: i64,
+    duration_us: i64,
+    any: bool,
+}
+impl Coverage {
+    fn add(&mut self, timestamp_us: i64, duration_us: i64) {
+        if !self.any {
+            (self.start_us, self.end_us, self.any) = (timestamp_us, timestamp_us, true);
+        }
+        self.start_us = self.start_us.min(timestamp_us);
+        self.end_us = self.end_us.max(timestamp_us + duration_us);
+        self.duration_us += duration_us;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Timecode { hours: u32, minutes: u32, seconds: u32, frames: u32, drop_frame: bool }
+
+impl Timecode {
+    /// Frames since midnight at `nominal` fps (30 for 29.97), skipping the dropped frame numbers
+    fn frames(&self, nominal: i64) -> i64 {
+        let minutes = self.hours as i64 * 60 + self.minutes as i64;
+        let dropped = if self.drop_frame { nominal / 15 * (minutes - minutes / 10) } else { 0 };
+        (minutes * 60 + self.seconds as i64) * nominal + self.frames as i64 - dropped
+    }
+}
+
+impl std::fmt::Display for Timecode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let separator = if self.drop_frame { ';' } else { ':' };
+        write!(f, "{:02}:{:02}:{:02}{separator}{:02}", self.hours, self.minutes, self.seconds, self.frames)
+    }
+}
+
+/// `HH:MM:SS:FF`, or `HH:MM:SS;FF` for drop frame
+fn parse_timecode(s: &str) -> Option<Timecode> {
+    let drop_frame = s.contains(';') || s.contains(',');
+    let parts: Vec<u32> = s.split([':', ';', ',', '.']).map(|x| x.trim().parse().ok()).collect::<Option<_>>()?;
+    match parts[..] {
+        [hours, minutes, seconds, frames] => Some(Timecode { hours, minutes, seconds, frames, drop_frame }),
+        _ => None
+    }
+}
+
+struct LtcFrame {
+    timecode: Timecode,
+    /// Presentation time of the start of the LTC frame
+    timestamp_us: i64,
+}
+
+/// SMPTE 12M linear timecode reader. The signal is biphase mark coded: every bit starts with a transition
+/// and ones have another one in the middle, so the bits follow from the intervals between the zero crossings
+struct LtcDecoder {
+    sample_rate: f64,
+    // Samples fed so far
+    position: u64,
+    // Timestamp and position of the current chunk, for converting positions to timestamps
+    chunk: (i64, u64),
+    high: bool,
+    peak: f32,
+    last_edge: Option<u64>,
+    // Estimated samples per bit
+    bit_period: f64,
+    // Start of a one whose first half was seen
+    half_bit: Option<u64>,
+    // Last 80 bits with the newest one at the top, so a complete frame is in transmission order
+    bits: u128,
+    bit_starts: [u64; 80],
+    bit_count: usize,
+    frames: Vec<LtcFrame>,
+}
+
+/// Bits 64-79 of every LTC frame
+const LTC_SYNC_WORD: u128 = 0xBFFC;
+
+impl LtcDecoder {
+    fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate: sample_rate as f64,
+            position: 0,
+            chunk: (0, 0),
+            high: false,
+            peak: 0.0,
+            last_edge: None,
+            // 80 bits per frame at 24-30 fps
+            bit_period: sample_rate as f64 / 2160.0,
+            half_bit: None,
+            bits: 0,
+            bit_starts: [0; 80],
+            bit_count: 0,
+            frames: Vec::new(),
+        }
+    }
+
+    fn feed(&mut self, timestamp_us: i64, samples: impl Iterator<Item = f32>) {
+        self.chunk = (timestamp_us, self.position);
+        for sample in samples {
+            self.peak = (self.peak * 0.9999).max(sample.abs());
+            // Hysteresis, so noise around zero isn't taken for transitions
+            let threshold = (self.peak * 0.1).max(0.001);
+            let edge = (!self.high && sample > threshold) || (self.high && sample < -threshold);
+            if edge {
+                self.high = !self.high;
+                self.edge(self.position);
+            }
+            self.position += 1;
+        }
+    }
+
+    fn edge(&mut self, position: u64) {
+        let Some(last) = self.last_edge.replace(position) else { return; };
+        let interval = (position - last) as f64;
+        if interval > self.bit_period * 1.5 || interval < self.bit_period * 0.25 {
+            // Silence, a dropout or noise
+            self.half_bit = None;
+            self.bit_count = 0;
+            return;
+        }
+        if interval < self.bit_period * 0.75 {
+            self.bit_period = self.bit_period * 0.9 + interval * 2.0 * 0.1;
+            match self.half_bit.take() {
+                Some(start) => self.push_bit(true, start),
+                None => self.half_bit = Some(last),
+            }
+        } else {
+            self.bit_period = self.bit_period * 0.9 + interval * 0.1;
+            if self.half_bit.take().is_some() {
+                // A lone half bit, the bit boundaries were misjudged
+                self.bit_count = 0;
+            }
+            self.push_bit(false, last);
+        }
+    }
+
+    fn push_bit(&mut self, bit: bool, start: u64) {
+        self.bits = (self.bits >> 1) | ((bit as u128) << 79);
+        self.bit_starts[self.bit_count % 80] = start;
+        self.bit_count += 1;
+        if self.bit_count < 80 || (self.bits >> 64) & 0xFFFF != LTC_SYNC_WORD { return; }
+
+        let field = |first: u32, len: u32| ((self.bits >> first) & ((1 << len) - 1)) as u32;
+        let timecode = Timecode {
+            frames:  field(0, 4)  + field(8, 2)  * 10,
+            seconds: field(16, 4) + field(24, 3) * 10,
+            minutes: field(32, 4) + field(40, 3) * 10,
+            hours:   field(48, 4) + field(56, 2) * 10,
+            drop_frame: field(10, 1) != 0,
+        };
+        if timecode.frames >= 60 || timecode.seconds >= 60 || timecode.minutes >= 60 || timecode.hours >= 24 { return; }
+
+        let start = self.bit_starts[self.bit_count % 80];
+        let timestamp_us = self.chunk.0 + ((start as f64 - self.chunk.1 as f64) * 1000000.0 / self.sample_rate).round() as i64;
+        self.frames.push(LtcFrame { timecode, timestamp_us });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+
+    /// Biphase mark coded LTC of `count` frames from `start` at 25 fps, each frame `stretch` times longer than nominal
+    fn ltc_signal(start: Timecode, count: i64, stretch: f64) -> Vec<f32> {
+        let bits_per_sample = 25.0 * 80.0 / RATE as f64 / stretch;
+        let digit = |value: u32, first: u32| ((value % 10) as u128) << first | ((value / 10) as u128) << (first + 8);
+        let mut samples = Vec::new();
+        let (mut level, mut last) = (-0.5f32, None);
+        for frame in 0..count {
+            let mut total = start.frames(25) + frame;
+            let frames = (total % 25) as u32; total /= 25;
+            let seconds = (total % 60) as u32; total /= 60;
+            let word = digit(frames, 0) | digit(seconds, 16) | digit((total % 60) as u32, 32) | digit((total / 60) as u32, 48) | LTC_SYNC_WORD << 64;
+            loop {
+                let position = samples.len() as f64 * bits_per_sample - frame as f64 * 80.0;
+                if position >= 80.0 { break; }
+                // Every bit starts with a transition, ones have another one in the middle
+                let (bit, second_half) = (position as u32, position.fract() >= 0.5);
+                let one = (word >> bit) & 1 == 1;
+                let toggle = match last {
+                    Some((f, b, h)) => (f, b) != (frame, bit) || (one && second_half && !h),
+                    None => true
+                };
+                if toggle { level = -level; }
+                last = Some((frame, bit, second_half));
+                samples.push(level);
+            }
+        }
+        samples
+    }
+
+    fn decode(samples: &[f32], start_us: i64) -> Vec<LtcFrame> {
+        let mut decoder = LtcDecoder::new(RATE);
+        for (i, chunk) in samples.chunks(1024).enumerate() {
+            decoder.feed(start_us + (i * 1024) as i64 * 1000000 / RATE as i64, chunk.iter().copied());
+        }
+        decoder.frames
+    }
+
+    #[test]
+    fn timecodes() {
+        assert_eq!(parse_timecode("01:02:03:04"), Some(Timecode { hours: 1, minutes: 2, seconds: 3, frames: 4, drop_frame: false }));
+        assert_eq!(parse_timecode("01:02:03;04").unwrap().to_string(), "01:02:03;04");
+        assert_eq!(parse_timecode("01:02:03"), None);
+        // 29.97 drop frame skips frames 0 and 1 of every minute except each tenth
+        assert_eq!(parse_timecode("00:01:00;02").unwrap().frames(30), 1800);
+        assert_eq!(parse_timecode("00:10:00;00").unwrap().frames(30), 17982);
+    }
+
+    #[test]
+    fn decodes_ltc() {
+        let start = parse_timecode("10:59:59:20").unwrap();
+        let frames = decode(&ltc_signal(start, 50, 1.0), 0);
+        // The last frame ends without a transition, so it can't be completed
+        assert!(frames.len() >= 49, "{}", frames.len());
+        for (i, frame) in frames.iter().enumerate() {
+            assert_eq!(frame.timecode.frames(25), start.frames(25) + i as i64);
+            // Within a sample
+            assert!((frame.timestamp_us - i as i64 * 40000).abs() <= 21, "{i} {}", frame.timestamp_us);
+        }
+        assert_eq!(frames[5].timecode.to_string(), "11:00:00:00");
+    }
+
+    #[test]
+    fn measures_ltc_drift() {
+        // LTC 20 ms late and running 0.1% slow, which is 60 ms per minute
+        let start = parse_timecode("01:00:00:00").unwrap();
+        let frames = decode(&ltc_signal(start, 250, 1.001), 20000);
+        assert!(frames.len() >= 249);
+        let offset = ltc_offset(1, 0, &frames, "01:00:00:00", 0, 25.0).unwrap();
+        assert_eq!(offset.first_timecode, "01:00:00:00");
+        assert_eq!(offset.frames, frames.len());
+        assert!((offset.start_offset_us - 20000).abs() <= 21, "{offset:?}");
+        let expected_end = 20000 + (frames.len() as i64 - 1) * 40;
+        assert!((offset.end_offset_us - expected_end).abs() <= 42, "{offset:?}");
+        assert!((offset.drift_ms_per_minute - 60.0).abs() < 1.0, "{offset:?}");
+
+        // The video starting a second later, at the timecode of the 26th frame
+        let offset = ltc_offset(1, 0, &frames, "01:00:01:00", 1000000, 25.0).unwrap();
+        assert!((offset.start_offset_us - 20000).abs() <= 21, "{offset:?}");
+    }
+}
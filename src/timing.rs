@@ -0,0 +1,120 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+use std::ops::Range;
+
+/// Output slots for one input frame. Slot `n` has the timestamp `n / rate`, see `FrameRetimer::timestamp_us`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetimedSlots {
+    /// Slots to fill with the previous frame, non-empty when the input has a gap
+    pub previous: Range<i64>,
+    /// Slot for the current frame, `None` when the frame should be dropped because the input is faster than the output rate
+    pub current: Option<i64>,
+}
+
+/// Resamples variable frame rate input to a constant output rate, duplicating and dropping frames as needed.
+/// It only deals with timestamps, so the caller can duplicate by re-referencing the same frame without copying it.
+#[derive(Debug, Clone)]
+pub struct FrameRetimer {
+    rate: (i32, i32),
+    next_slot: Option<i64>,
+}
+
+impl FrameRetimer {
+    /// `rate` is the output frame rate as numerator/denominator, e.g. (30000, 1001)
+    pub fn new(rate: (i32, i32)) -> Result<Self, crate::VideoProcessingError> {
+        if rate.0 <= 0 || rate.1 <= 0 {
+            return Err(crate::VideoProcessingError::InvalidFrameRate(rate));
+        }
+        Ok(Self { rate, next_slot: None })
+    }
+
+    /// Nearest output slot of a timestamp
+    pub fn slot_for(&self, timestamp_us: i64) -> i64 {
+        crate::util::timestamp_to_frame(timestamp_us, self.rate)
+    }
+
+    /// Exact timestamp of the output slot, rounded to microseconds
+    pub fn timestamp_us(&self, slot: i64) -> i64 {
+        crate::util::frame_to_timestamp(slot, self.rate)
+    }
+
+    /// Call for every input frame in presentation order. The first frame starts at its nearest slot, so the offset of the input is kept.
+    pub fn push(&mut self, timestamp_us: i64) -> RetimedSlots {
+        let slot = self.slot_for(timestamp_us);
+        match self.next_slot {
+            None => {
+                self.next_slot = Some(slot + 1);
+                RetimedSlots { previous: slot..slot, current: Some(slot) }
+            },
+            Some(next) if slot < next => RetimedSlots { previous: next..next, current: None },
+            Some(next) => {
+                self.next_slot = Some(slot + 1);
+                RetimedSlots { previous: next..slot, current: Some(slot) }
+            }
+        }
+    }
+
+    /// Slots to fill with the last frame so the output reaches `end_us`, e.g. the stream duration
+    pub fn finish(&mut self, end_us: i64) -> Range<i64> {
+        let next = self.next_slot.unwrap_or_default();
+        let end = self.slot_for(end_us).max(next);
+        self.next_slot = Some(end);
+        next..end
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slots(previous: Range<i64>, current: Option<i64>) -> RetimedSlots {
+        RetimedSlots { previous, current }
+    }
+
+    #[test]
+    fn invalid_rate() {
+        assert!(matches!(FrameRetimer::new((0, 1)), Err(crate::VideoProcessingError::InvalidFrameRate((0, 1)))));
+        assert!(FrameRetimer::new((30, -1)).is_err());
+        assert!(FrameRetimer::new((30000, 1001)).is_ok());
+    }
+
+    #[test]
+    fn first_frame_offset() {
+        let mut retimer = FrameRetimer::new((30, 1)).unwrap();
+        assert_eq!(retimer.push(1_000_000), slots(30..30, Some(30)));
+        assert_eq!(retimer.push(1_033_333), slots(31..31, Some(31)));
+        assert_eq!(retimer.timestamp_us(31), 1_033_333);
+    }
+
+    #[test]
+    fn bursty_input() {
+        let mut retimer = FrameRetimer::new((30, 1)).unwrap();
+        assert_eq!(retimer.push(0), slots(0..0, Some(0)));
+        // Faster than the output, these land on slots which are already taken
+        assert_eq!(retimer.push(10_000), slots(1..1, None));
+        assert_eq!(retimer.push(20_000), slots(1..1, Some(1)));
+        assert_eq!(retimer.push(33_333), slots(2..2, None));
+        assert_eq!(retimer.push(66_667), slots(2..2, Some(2)));
+        assert_eq!(retimer.timestamp_us(2), 66_667);
+    }
+
+    #[test]
+    fn long_gap() {
+        let mut retimer = FrameRetimer::new((30, 1)).unwrap();
+        assert_eq!(retimer.push(0), slots(0..0, Some(0)));
+        assert_eq!(retimer.push(500_000), slots(1..15, Some(15)));
+        // The last frame is held until the end of the stream
+        assert_eq!(retimer.finish(1_000_000), 16..30);
+        assert_eq!(retimer.finish(1_000_000), 30..30);
+    }
+
+    #[test]
+    fn ntsc_timestamps_are_exact() {
+        let retimer = FrameRetimer::new((30000, 1001)).unwrap();
+        for slot in [0, 1, 29, 30, 1799, 1800, 107_892] {
+            assert_eq!(retimer.slot_for(retimer.timestamp_us(slot)), slot);
+        }
+        assert_eq!(retimer.timestamp_us(30000), 1001 * 1_000_000);
+    }
+}
@@ -0,0 +1,911 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+use super::*;
+use crate::types::VideoProcessingError;
+
+use ffmpeg_next::{ codec, format, media, rescale::Rescale, Rational };
+
+const SMART_CUT_CODECS: &[codec::Id] = &[codec::Id::H264, codec::Id::HEVC];
+const SMART_CUT_CONTAINERS: &[&str] = &["mov", "mp4"];
+
+/// Bitstream filter needed to stream copy `codec` between the containers. MP4-style containers store length-prefixed NAL units
+/// with the parameter sets in the extradata, TS and raw streams need Annex B with in-band parameter sets, so they survive mid-stream changes.
+/// Annex B input to MP4 needs no filter, the muxer converts it and keeps the in-band parameter sets
+pub fn stream_copy_bsf(codec: codec::Id, input_format: &str, output_format: &str) -> Option<&'static str> {
+    let is_mp4 = |name: &str| name.split(',').any(|x| matches!(x, "mov" | "mp4" | "matroska" | "webm" | "ismv"));
+    if !is_mp4(input_format) || is_mp4(output_format) { return None; }
+    match codec {
+        codec::Id::H264 => Some("h264_mp4toannexb"),
+        codec::Id::HEVC => Some("hevc_mp4toannexb"),
+        _ => None
+    }
+}
+
+/// Copies `AV_PKT_DATA_NEW_EXTRADATA` side data to the output stream parameters. Returns whether the extradata was replaced.
+/// Containers writing the codec parameters at the end (non-fragmented MP4) pick up the last ones
+pub fn apply_new_extradata(stream: &mut format::stream::StreamMut, packet: &ffmpeg_next::Packet) -> Result<bool, VideoProcessingError> {
+    use ffmpeg_next::ffi::*;
+    let Some(sd) = packet.side_data().find(|sd| sd.kind() == ffmpeg_next::packet::side_data::Type::NewExtraData) else { return Ok(false); };
+    let data = sd.data();
+    unsafe {
+        let par = (*stream.as_mut_ptr()).codecpar;
+        let extradata = av_mallocz(data.len() + AV_INPUT_BUFFER_PADDING_SIZE as usize) as *mut u8;
+        if extradata.is_null() { return Err(ffmpeg_next::Error::Other { errno: ffmpeg_next::error::ENOMEM }.into()); }
+        std::ptr::copy_nonoverlapping(data.as_ptr(), extradata, data.len());
+        av_freep(&mut (*par).extradata as *mut *mut u8 as *mut std::ffi::c_void);
+        (*par).extradata = extradata;
+        (*par).extradata_size = data.len() as i32;
+    }
+    log::info!("Stream {} extradata updated mid-stream ({} bytes)", stream.index(), data.len());
+    Ok(true)
+}
+
+/// `AVBSFContext` for one stream, ffmpeg-next has no wrapper for bitstream filters
+pub struct BitstreamFilter {
+    ctx: *mut ffmpeg_next::ffi::AVBSFContext,
+}
+
+impl BitstreamFilter {
+    pub fn new(name: &str, parameters: &codec::Parameters, time_base: Rational) -> Result<Self, VideoProcessingError> {
+        use ffmpeg_next::ffi::*;
+        let name = std::ffi::CString::new(name).map_err(|_| ffmpeg_next::Error::BsfNotFound)?;
+        unsafe {
+            let filter = av_bsf_get_by_name(name.as_ptr());
+            if filter.is_null() { return Err(ffmpeg_next::Error::BsfNotFound.into()); }
+            let mut ctx = std::ptr::null_mut();
+            let err = av_bsf_alloc(filter, &mut ctx);
+            if err < 0 { return Err(ffmpeg_next::Error::from(err).into()); }
+            // Freed on drop from here on
+            let ret = Self { ctx };
+            let err = avcodec_parameters_copy((*ctx).par_in, parameters.as_ptr());
+            if err < 0 { return Err(ffmpeg_next::Error::from(err).into()); }
+            (*ctx).time_base_in = time_base.into();
+            let err = av_bsf_init(ctx);
+            if err < 0 { return Err(ffmpeg_next::Error::from(err).into()); }
+            Ok(ret)
+        }
+    }
+
+    /// Sets the codec parameters of `stream` to the ones of the filtered packets, e.g. the Annex B extradata after `h264_mp4toannexb`
+    pub fn set_output_parameters(&self, stream: &mut format::stream::StreamMut) -> Result<(), VideoProcessingError> {
+        let err = unsafe { ffmpeg_next::ffi::avcodec_parameters_copy((*stream.as_mut_ptr()).codecpar, (*self.ctx).par_out) };
+        if err < 0 { return Err(ffmpeg_next::Error::from(err).into()); }
+        Ok(())
+    }
+
+    /// Sends `packet`, or the end of the stream when `None`, and returns the filtered packets
+    pub fn filter(&mut self, packet: Option<&mut ffmpeg_next::Packet>) -> Result<Vec<ffmpeg_next::Packet>, VideoProcessingError> {
+        use ffmpeg_next::packet::Mut;
+        let err = unsafe { ffmpeg_next::ffi::av_bsf_send_packet(self.ctx, packet.map_or(std::ptr::null_mut(), |x| x.as_mut_ptr())) };
+        if err < 0 { return Err(ffmpeg_next::Error::from(err).into()); }
+        let mut ret = Vec::new();
+        loop {
+            let mut packet = ffmpeg_next::Packet::empty();
+            match unsafe { ffmpeg_next::ffi::av_bsf_receive_packet(self.ctx, packet.as_mut_ptr()) } {
+                0 => ret.push(packet),
+                err => match ffmpeg_next::Error::from(err) {
+                    ffmpeg_next::Error::Other { errno: ffmpeg_next::error::EAGAIN } | ffmpeg_next::Error::Eof => return Ok(ret),
+                    e => return Err(e.into())
+                }
+            }
+        }
+    }
+}
+
+impl Drop for BitstreamFilter {
+    fn drop(&mut self) {
+        unsafe { ffmpeg_next::ffi::av_bsf_free(&mut self.ctx); }
+    }
+}
+
+/// Matroska stores the parameter sets as codec private data in the header. Streams copied from Annex B or OBU sources
+/// (TS, raw elementary streams) only carry them in-band, so they're extracted from the first keyframe before writing the header
+pub fn needs_codec_private(codec: codec::Id, extradata_size: usize, output_format: &str) -> bool {
+    extradata_size == 0 &&
+        output_format.split(',').any(|x| matches!(x, "matroska" | "webm")) &&
+        matches!(codec, codec::Id::H264 | codec::Id::HEVC | codec::Id::AV1)
+}
+
+/// Scans the keyframes of the best video stream and computes which parts of the range have to be re-encoded.
+/// Returns `SmartCutUnsupported` for codec and container combinations which can't be spliced, so the caller can fall back to full re-encode.
+pub fn plan_smart_cut(path: &str, start_us: i64, end_us: i64) -> Result<SmartCutPlan, VideoProcessingError> {
+    crate::backend::init_ffmpeg()?;
+
+    let mut context = format::input(&path)?;
+
+    let container = context.format().name().to_string();
+    let (stream_index, time_base, codec_id) = {
+        let stream = context.streams().best(media::Type::Video).ok_or(VideoProcessingError::VideoStreamNotFound)?;
+        (stream.index(), stream.time_base(), stream.parameters().id())
+    };
+
+    if !SMART_CUT_CODECS.contains(&codec_id) || !container.split(',').any(|x| SMART_CUT_CONTAINERS.contains(&x)) {
+        return Err(VideoProcessingError::SmartCutUnsupported { codec: codec_id.name().to_string(), container });
+    }
+
+    let mut keyframes = Vec::new();
+    for (stream, packet) in context.packets() {
+        if stream.index() != stream_index || !packet.is_key() { continue; }
+        if let Some(pts) = packet.pts() {
+            keyframes.push(pts.rescale(time_base, (1, 1000000)));
+        }
+    }
+    keyframes.sort_unstable();
+
+    Ok(SmartCutPlan::from_keyframes(&keyframes, start_us, end_us))
+}
+
+/// Cuts `start_us..end_us` of `input` to `output`, re-encoding only the partial GOPs at the ends of the range and stream copying the rest, see `plan_smart_cut`.
+/// Audio packets in the range are copied, other streams are dropped. Both files have to be MP4 or MOV.
+/// The copied GOPs must be closed, leading frames of an open GOP referencing the previous one are dropped.
+/// Re-encoded frames carry their own in-band parameter sets, the source ones are repeated on the first copied keyframe so the decoder switches back.
+pub fn smart_cut(input: &str, output: &str, start_us: i64, end_us: i64) -> Result<SmartCutPlan, VideoProcessingError> {
+    let plan = plan_smart_cut(input, start_us, end_us)?;
+
+    let mut ictx = format::input(&input)?;
+    let mut octx = format::output(&output)?;
+
+    let (video_index, time_base, parameters) = {
+        let stream = ictx.streams().best(media::Type::Video).ok_or(VideoProcessingError::VideoStreamNotFound)?;
+        (stream.index(), stream.time_base(), stream.parameters())
+    };
+    let unsupported = |container: &str| VideoProcessingError::SmartCutUnsupported { codec: parameters.id().name().to_string(), container: container.to_string() };
+    let output_container = octx.format().name().to_string();
+    if !output_container.split(',').any(|x| SMART_CUT_CONTAINERS.contains(&x)) {
+        return Err(unsupported(&output_container));
+    }
+    let extradata = unsafe {
+        let par = parameters.as_ptr();
+        if (*par).extradata.is_null() { Vec::new() } else { std::slice::from_raw_parts((*par).extradata, (*par).extradata_size as usize).to_vec() }
+    };
+    // Annex B extradata can't be mixed with the length-prefixed packets
+    let (length_size, parameter_sets) = mp4_parameter_sets(parameters.id(), &extradata).ok_or_else(|| unsupported(ictx.format().name()))?;
+
+    let mut writer = CutWriter { stream_map: vec![None; ictx.nb_streams() as usize], start_us, octx };
+    for ist in ictx.streams() {
+        if ist.index() != video_index && ist.parameters().medium() != media::Type::Audio { continue; }
+        let mut ost = writer.octx.add_stream(ffmpeg_next::encoder::find(codec::Id::None))?;
+        ost.set_parameters(ist.parameters());
+        ost.set_time_base(ist.time_base());
+        unsafe { (*(*ost.as_mut_ptr()).codecpar).codec_tag = 0; }
+        writer.stream_map[ist.index()] = Some((ost.index(), ist.time_base()));
+    }
+    writer.octx.write_header()?;
+
+    let mut video = CutVideo {
+        index: video_index,
+        time_base,
+        parameters,
+        length_size,
+        parameter_sets: parameter_sets.iter().flat_map(|nal| length_prefixed(nal, length_size)).collect(),
+        delay: 0,
+        repeat_parameter_sets: plan.head_reencode.is_some(),
+    };
+    if let Some((first, _)) = plan.copy {
+        video.delay = first_packet_delay(&mut ictx, video_index, first)?;
+    }
+
+    if let Some(range) = plan.head_reencode { cut_pass(&mut ictx, &mut writer, &mut video, range, true)?; }
+    if let Some(range) = plan.copy          { cut_pass(&mut ictx, &mut writer, &mut video, range, false)?; }
+    if let Some(range) = plan.tail_reencode { cut_pass(&mut ictx, &mut writer, &mut video, range, true)?; }
+
+    writer.octx.write_trailer()?;
+    Ok(plan)
+}
+
+struct CutWriter {
+    octx: format::context::Output,
+    /// Output index and time base of the input streams
+    stream_map: Vec<Option<(usize, Rational)>>,
+    start_us: i64,
+}
+
+impl CutWriter {
+    /// Writes a packet with timestamps in the input stream time base, shifted so the cut starts at 0
+    fn write(&mut self, input_index: usize, mut packet: ffmpeg_next::Packet) -> Result<(), VideoProcessingError> {
+        let Some((index, time_base)) = self.stream_map[input_index] else { return Ok(()); };
+        let shift = self.start_us.rescale((1, 1_000_000), time_base);
+        packet.set_pts(packet.pts().map(|x| x - shift));
+        packet.set_dts(packet.dts().map(|x| x - shift));
+        let out_time_base = self.octx.stream(index).ok_or(VideoProcessingError::NoOutputContext)?.time_base();
+        packet.rescale_ts(time_base, out_time_base);
+        packet.set_position(-1);
+        packet.set_stream(index);
+        packet.write_interleaved(&mut self.octx)?;
+        Ok(())
+    }
+}
+
+struct CutVideo {
+    index: usize,
+    time_base: Rational,
+    parameters: codec::Parameters,
+    length_size: usize,
+    /// Length-prefixed parameter sets of the source
+    parameter_sets: Vec<u8>,
+    /// Difference between pts and dts of the copied packets, caused by B-frames
+    delay: i64,
+    repeat_parameter_sets: bool,
+}
+
+impl CutVideo {
+    fn write_copied(&mut self, writer: &mut CutWriter, packet: ffmpeg_next::Packet) -> Result<(), VideoProcessingError> {
+        if !self.repeat_parameter_sets || !packet.is_key() {
+            return writer.write(self.index, packet);
+        }
+        self.repeat_parameter_sets = false;
+        let mut data = self.parameter_sets.clone();
+        data.extend_from_slice(packet.data().unwrap_or_default());
+        let mut out = ffmpeg_next::Packet::copy(&data);
+        out.set_pts(packet.pts());
+        out.set_dts(packet.dts());
+        out.set_duration(packet.duration());
+        out.set_flags(packet.flags());
+        writer.write(self.index, out)
+    }
+
+    fn write_encoded(&mut self, writer: &mut CutWriter, encoder: &mut ffmpeg_next::encoder::video::Encoder) -> Result<(), VideoProcessingError> {
+        let mut packet = ffmpeg_next::Packet::empty();
+        while encoder.receive_packet(&mut packet).is_ok() {
+            let Some(data) = packet.data() else { continue; };
+            let mut out = ffmpeg_next::Packet::copy(&annexb_to_length_prefixed(data, self.length_size));
+            out.set_pts(packet.pts());
+            // There are no B-frames in the re-encoded parts, delaying them keeps the dts increasing into the copied GOPs
+            out.set_dts(packet.pts().map(|x| x - self.delay));
+            out.set_duration(packet.duration());
+            out.set_flags(packet.flags());
+            writer.write(self.index, out)?;
+        }
+        Ok(())
+    }
+
+    /// Same codec and format as the source, the encoder time base is the stream time base
+    fn open_encoder(&self, frame: &ffmpeg_next::frame::Video) -> Result<ffmpeg_next::encoder::video::Encoder, VideoProcessingError> {
+        let codec = ffmpeg_next::encoder::find(self.parameters.id()).ok_or(VideoProcessingError::EncoderNotFound)?;
+        let mut ctx = codec::context::Context::new_with_codec(codec).encoder().video()?;
+        ctx.set_width(frame.width());
+        ctx.set_height(frame.height());
+        ctx.set_format(frame.format());
+        ctx.set_time_base(self.time_base);
+        ctx.set_max_b_frames(0);
+        ctx.set_colorspace(frame.color_space());
+        ctx.set_color_range(frame.color_range());
+        unsafe {
+            let (ptr, par) = (ctx.as_mut_ptr(), self.parameters.as_ptr());
+            (*ptr).color_primaries = (*frame.as_ptr()).color_primaries;
+            (*ptr).color_trc = (*frame.as_ptr()).color_trc;
+            (*ptr).sample_aspect_ratio = (*par).sample_aspect_ratio;
+            // Close to the quality of the copied GOPs, 0 leaves the encoder default
+            (*ptr).bit_rate = (*par).bit_rate;
+        }
+        Ok(ctx.open_as(codec)?)
+    }
+}
+
+/// pts - dts of the first video packet at `timestamp_us`
+fn first_packet_delay(ictx: &mut format::context::Input, video_index: usize, timestamp_us: i64) -> Result<i64, VideoProcessingError> {
+    ictx.seek(timestamp_us, ..timestamp_us)?;
+    for (stream, packet) in ictx.packets() {
+        if stream.index() != video_index { continue; }
+        return Ok(match (packet.pts(), packet.dts()) {
+            (Some(pts), Some(dts)) => (pts - dts).max(0),
+            _ => 0
+        });
+    }
+    Ok(0)
+}
+
+/// Writes the video of `lo..hi` (microseconds), decoding and re-encoding it or copying the packets, and copies the audio packets in the range
+fn cut_pass(ictx: &mut format::context::Input, writer: &mut CutWriter, video: &mut CutVideo, (lo, hi): (i64, i64), reencode: bool) -> Result<(), VideoProcessingError> {
+    ictx.seek(lo, ..lo)?;
+    let (lo_tb, hi_tb) = (lo.rescale((1, 1_000_000), video.time_base), hi.rescale((1, 1_000_000), video.time_base));
+
+    let mut segment = if reencode {
+        let mut decoder = codec::context::Context::from_parameters(video.parameters.clone())?.decoder().video()?;
+        unsafe { (*decoder.as_mut_ptr()).pkt_timebase = video.time_base.into(); }
+        Some(CutSegment { decoder, encoder: None, range: (lo_tb, hi_tb), done: false })
+    } else {
+        None
+    };
+
+    let mut audio_pending: Vec<usize> = writer.stream_map.iter().enumerate().filter(|(i, x)| x.is_some() && *i != video.index).map(|(i, _)| i).collect();
+    let mut video_done = false;
+    for (stream, packet) in ictx.packets() {
+        let index = stream.index();
+        let Some(pts) = packet.pts() else { continue; };
+        if index == video.index {
+            if video_done { continue; }
+            match segment.as_mut() {
+                Some(segment) => {
+                    segment.decoder.send_packet(&packet)?;
+                    video_done = segment.receive(video, writer)?;
+                },
+                None if packet.is_key() && pts >= hi_tb => video_done = true,
+                None if pts < lo_tb => { },
+                None => video.write_copied(writer, packet)?,
+            }
+        } else if audio_pending.contains(&index) {
+            let pts_us = pts.rescale(stream.time_base(), (1, 1_000_000));
+            if pts_us >= hi {
+                audio_pending.retain(|&x| x != index);
+            } else if pts_us >= lo {
+                writer.write(index, packet)?;
+            }
+        }
+        if video_done && audio_pending.is_empty() { break; }
+    }
+
+    if let Some(mut segment) = segment {
+        if !segment.done {
+            segment.decoder.send_eof()?;
+            segment.receive(video, writer)?;
+        }
+        if let Some(mut encoder) = segment.encoder.take() {
+            encoder.send_eof()?;
+            video.write_encoded(writer, &mut encoder)?;
+        }
+    }
+    Ok(())
+}
+
+struct CutSegment {
+    decoder: ffmpeg_next::decoder::Video,
+    encoder: Option<ffmpeg_next::encoder::video::Encoder>,
+    /// In the stream time base
+    range: (i64, i64),
+    done: bool,
+}
+
+impl CutSegment {
+    /// Encodes the decoded frames in the range, returns whether the end of the range was reached
+    fn receive(&mut self, video: &mut CutVideo, writer: &mut CutWriter) -> Result<bool, VideoProcessingError> {
+        let mut frame = ffmpeg_next::frame::Video::empty();
+        while !self.done && self.decoder.receive_frame(&mut frame).is_ok() {
+            let Some(pts) = frame.timestamp() else { continue; };
+            if pts < self.range.0 { continue; }
+            if pts >= self.range.1 { self.done = true; break; }
+            if self.encoder.is_none() {
+                self.encoder = Some(video.open_encoder(&frame)?);
+            }
+            let encoder = self.encoder.as_mut().ok_or(VideoProcessingError::EncoderNotFound)?;
+            frame.set_pts(Some(pts));
+            frame.set_kind(ffmpeg_next::picture::Type::None);
+            encoder.send_frame(&frame)?;
+            video.write_encoded(writer, encoder)?;
+        }
+        Ok(self.done)
+    }
+}
+
+/// NAL unit length size and the parameter set NAL units of avcC (H.264) or hvcC (HEVC) extradata. `None` for Annex B or malformed extradata
+pub fn mp4_parameter_sets(codec: codec::Id, extradata: &[u8]) -> Option<(usize, Vec<&[u8]>)> {
+    fn read_nal<'a>(data: &'a [u8], pos: &mut usize) -> Option<&'a [u8]> {
+        let len = u16::from_be_bytes([*data.get(*pos)?, *data.get(*pos + 1)?]) as usize;
+        let nal = data.get(*pos + 2..*pos + 2 + len)?;
+        *pos += 2 + len;
+        Some(nal)
+    }
+    if extradata.first() != Some(&1) { return None; }
+    let mut nals = Vec::new();
+    match codec {
+        codec::Id::H264 => {
+            let length_size = (*extradata.get(4)? & 3) as usize + 1;
+            let mut pos = 6;
+            for _ in 0..(extradata.get(5)? & 0x1f) { nals.push(read_nal(extradata, &mut pos)?); }
+            let pps_count = *extradata.get(pos)?;
+            pos += 1;
+            for _ in 0..pps_count { nals.push(read_nal(extradata, &mut pos)?); }
+            Some((length_size, nals))
+        },
+        codec::Id::HEVC => {
+            let length_size = (*extradata.get(21)? & 3) as usize + 1;
+            let mut pos = 23;
+            for _ in 0..*extradata.get(22)? {
+                let count = u16::from_be_bytes([*extradata.get(pos + 1)?, *extradata.get(pos + 2)?]);
+                pos += 3;
+                for _ in 0..count { nals.push(read_nal(extradata, &mut pos)?); }
+            }
+            Some((length_size, nals))
+        },
+        _ => None
+    }
+}
+
+fn length_prefixed(nal: &[u8], length_size: usize) -> Vec<u8> {
+    let mut ret = (nal.len() as u32).to_be_bytes()[4 - length_size..].to_vec();
+    ret.extend_from_slice(nal);
+    ret
+}
+
+/// Rewrites Annex B NAL units with the `length_size` bytes big endian length prefixes stored in MP4
+pub fn annexb_to_length_prefixed(data: &[u8], length_size: usize) -> Vec<u8> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 { starts.push(i + 3); i += 3; } else { i += 1; }
+    }
+    let mut ret = Vec::with_capacity(data.len() + starts.len() * length_size);
+    for (n, &start) in starts.iter().enumerate() {
+        let end = starts.get(n + 1).map_or(data.len(), |&next| next - 3);
+        // The zero byte of a 4-byte start code and trailing zeros aren't part of the NAL unit
+        let mut nal = &data[start..end.max(start)];
+        while let [rest @ .., 0] = nal { nal = rest; }
+        if !nal.is_empty() {
+            ret.extend(length_prefixed(nal, length_size));
+        }
+    }
+    ret
+}
+
+/// Rewrites the container and stream tags and the display matrix, stream copying all packets unchanged.
+/// Every stream is kept, including data (GoPro GPMF, timecode) and attachments, except the ones the output container can't store.
+/// When `output` is `None`, MP4/MOV inputs where every tag in `set` already exists with a value of the same length are edited in place,
+/// without copying the media. `creation_time` always fits. Otherwise the result is remuxed next to `input` and then replaces it.
+/// Tags in `set` are written to the container, MP4 only stores the keys known to the muxer (e.g. `creation_time`, `title`)
+pub fn edit_metadata(input: &str, output: Option<&str>, edits: &MetadataEdits) -> Result<(), VideoProcessingError> {
+    crate::backend::init_ffmpeg()?;
+
+    let Some(output) = output else {
+        let removes_tags = {
+            let ictx = format::input(&input)?;
+            ictx.metadata().iter().any(|(k, _)| edits.removes(k)) || ictx.streams().any(|x| x.metadata().iter().any(|(k, _)| edits.removes(k)))
+        };
+        if edits.rotation.is_none() && !removes_tags && super::mp4::edit_tags_in_place(input, edits)? {
+            return Ok(());
+        }
+        // Keep the extension, the muxer is picked from it
+        let path = std::path::Path::new(input);
+        let ext = path.extension().map(|x| format!(".{}", x.to_string_lossy())).unwrap_or_default();
+        let temp = path.with_file_name(format!("{}.tag-tmp{ext}", path.file_stem().unwrap_or_default().to_string_lossy())).to_string_lossy().to_string();
+        return match remux_with_metadata(input, &temp, edits) {
+            Ok(()) => Ok(std::fs::rename(&temp, input)?),
+            Err(e) => { let _ = std::fs::remove_file(&temp); Err(e) }
+        };
+    };
+    remux_with_metadata(input, output, edits)
+}
+
+fn edited_tags(tags: ffmpeg_next::DictionaryRef, edits: &MetadataEdits) -> ffmpeg_next::Dictionary<'static> {
+    let mut dict = ffmpeg_next::Dictionary::new();
+    for (k, v) in tags.iter() {
+        if !edits.removes(k) { dict.set(k, v); }
+    }
+    dict
+}
+
+fn remux_with_metadata(input: &str, output: &str, edits: &MetadataEdits) -> Result<(), VideoProcessingError> {
+    use ffmpeg_next::ffi::*;
+
+    let mut ictx = format::input(&input)?;
+    let mut octx = format::output(&output)?;
+
+    let mut stream_map = vec![None; ictx.nb_streams() as usize];
+    let mut extractors = std::collections::HashMap::new();
+    // Bitstream format conversions between the containers, see `stream_copy_bsf`
+    let mut filters = std::collections::HashMap::new();
+    let (input_format, output_format) = (ictx.format().name().to_string(), octx.format().name().to_string());
+    let output_tags = unsafe { (*octx.format().as_ptr()).codec_tag };
+    let matroska = crate::is_matroska(&octx);
+    let mut output_index = 0;
+    for ist in ictx.streams() {
+        let medium = ist.parameters().medium();
+        // Attachments only exist in Matroska, which in turn has no data streams
+        let supported = match medium {
+            media::Type::Attachment => matroska,
+            media::Type::Data => !matroska,
+            media::Type::Unknown => false,
+            _ => true
+        };
+        if !supported {
+            log::warn!("Dropping {medium:?} stream {} (tag {:08x}), {output_format} can't store it", ist.index(), unsafe { (*ist.parameters().as_ptr()).codec_tag });
+            continue;
+        }
+        stream_map[ist.index()] = Some(output_index);
+        let extradata_size = unsafe { (*ist.parameters().as_ptr()).extradata_size as usize };
+        if needs_codec_private(ist.parameters().id(), extradata_size, &output_format) {
+            extractors.insert(ist.index(), BitstreamFilter::new("extract_extradata", &ist.parameters(), ist.time_base())?);
+        }
+        output_index += 1;
+
+        let mut ost = octx.add_stream(ffmpeg_next::encoder::find(codec::Id::None))?;
+        ost.set_parameters(ist.parameters());
+        if let Some(name) = stream_copy_bsf(ist.parameters().id(), &input_format, &output_format) {
+            let bsf = BitstreamFilter::new(name, &ist.parameters(), ist.time_base())?;
+            bsf.set_output_parameters(&mut ost)?;
+            filters.insert(ist.index(), bsf);
+        }
+        ost.set_time_base(ist.time_base());
+        ost.set_metadata(edited_tags(ist.metadata(), edits));
+        let rotate = edits.rotation.filter(|_| medium == media::Type::Video);
+        unsafe {
+            // Keep the tag when the output container accepts it (e.g. `tmcd`, `gpmd`), otherwise let the muxer pick one
+            let par = (*ost.as_mut_ptr()).codecpar;
+            let mut tag = 0;
+            let keep = output_tags.is_null() || av_codec_get_id(output_tags, (*par).codec_tag) == (*par).codec_id || av_codec_get_tag2(output_tags, (*par).codec_id, &mut tag) == 0;
+            if !keep { (*par).codec_tag = 0; }
+
+            let ist_ptr = ist.as_ptr();
+            for i in 0..(*ist_ptr).nb_side_data as usize {
+                let sd = &*(*ist_ptr).side_data.add(i);
+                if rotate.is_some() && sd.type_ == AVPacketSideDataType::AV_PKT_DATA_DISPLAYMATRIX { continue; }
+                let data = av_stream_new_side_data(ost.as_mut_ptr(), sd.type_, sd.size as _);
+                if data.is_null() { return Err(ffmpeg_next::Error::Other { errno: ffmpeg_next::error::ENOMEM }.into()); }
+                std::ptr::copy_nonoverlapping(sd.data, data, sd.size as usize);
+            }
+            if let Some(rotation) = rotate {
+                let data = av_stream_new_side_data(ost.as_mut_ptr(), AVPacketSideDataType::AV_PKT_DATA_DISPLAYMATRIX, 9 * 4);
+                if data.is_null() { return Err(ffmpeg_next::Error::Other { errno: ffmpeg_next::error::ENOMEM }.into()); }
+                // Display matrix rotation is counter-clockwise
+                av_display_rotation_set(data as *mut i32, -rotation as f64);
+            }
+        }
+    }
+
+    let mut tags = edited_tags(ictx.metadata(), edits);
+    for (k, v) in &edits.set {
+        tags.set(k, v);
+    }
+    octx.set_metadata(tags);
+
+    // Packets read while looking for the codec private data are written after the header
+    let mut queued = Vec::new();
+    while !extractors.is_empty() && queued.len() < MAX_CODEC_PRIVATE_PACKETS {
+        let Some((ist, packet)) = ictx.packets().next() else { break; };
+        let (input_index, time_base) = (ist.index(), ist.time_base());
+        if let (Some(bsf), Some(index)) = (extractors.get_mut(&input_index), stream_map[input_index]) {
+            let mut ost = octx.stream_mut(index).ok_or(VideoProcessingError::NoOutputContext)?;
+            let mut found = false;
+            for filtered in bsf.filter(Some(&mut packet.clone()))? {
+                found |= apply_new_extradata(&mut ost, &filtered)?;
+            }
+            if found { extractors.remove(&input_index); }
+        }
+        queued.push((input_index, time_base, packet));
+    }
+    if !extractors.is_empty() {
+        log::warn!("No parameter sets found in the first {} packets, the output may not be playable", queued.len());
+    }
+    octx.write_header()?;
+
+    let packets = ictx.packets().map(|(ist, packet)| (ist.index(), ist.time_base(), packet));
+    let mut time_bases = std::collections::HashMap::new();
+    for (input_index, input_time_base, mut packet) in queued.into_iter().chain(packets) {
+        let Some(index) = stream_map[input_index] else { continue; };
+        let packets = match filters.get_mut(&input_index) {
+            // The filters handle new extradata themselves
+            Some(bsf) => { time_bases.insert(input_index, input_time_base); bsf.filter(Some(&mut packet))? },
+            None => {
+                // Parameter sets switched mid-stream, e.g. a TS source spliced from two encodes
+                apply_new_extradata(&mut octx.stream_mut(index).ok_or(VideoProcessingError::NoOutputContext)?, &packet)?;
+                vec![packet]
+            }
+        };
+        for packet in packets {
+            write_copied_packet(&mut octx, packet, input_time_base, index)?;
+        }
+    }
+    for (input_index, mut bsf) in filters {
+        let (Some(index), Some(&time_base)) = (stream_map[input_index], time_bases.get(&input_index)) else { continue; };
+        for packet in bsf.filter(None)? {
+            write_copied_packet(&mut octx, packet, time_base, index)?;
+        }
+    }
+    octx.write_trailer()?;
+    Ok(())
+}
+
+fn write_copied_packet(octx: &mut format::context::Output, mut packet: ffmpeg_next::Packet, input_time_base: Rational, index: usize) -> Result<(), VideoProcessingError> {
+    let time_base = octx.stream(index).ok_or(VideoProcessingError::NoOutputContext)?.time_base();
+    packet.rescale_ts(input_time_base, time_base);
+    packet.set_position(-1);
+    packet.set_stream(index);
+    packet.write_interleaved(octx)?;
+    Ok(())
+}
+
+/// Upper bound of the packets buffered before the header while extracting the codec private data
+const MAX_CODEC_PRIVATE_PACKETS: usize = 1000;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codec_private_for_matroska() {
+        assert!(needs_codec_private(codec::Id::HEVC, 0, "matroska"));
+        assert!(needs_codec_private(codec::Id::AV1, 0, "matroska,webm"));
+        assert!(needs_codec_private(codec::Id::H264, 0, "webm"));
+        assert!(!needs_codec_private(codec::Id::HEVC, 23, "matroska"));
+        assert!(!needs_codec_private(codec::Id::HEVC, 0, "mp4"));
+        assert!(!needs_codec_private(codec::Id::AAC, 0, "matroska"));
+    }
+
+    #[test]
+    fn hevc_from_ts_to_matroska() {
+        let ts = crate::util::test::temp_path("copy.ts");
+        let mkv = crate::util::test::temp_path("copy.mkv");
+        crate::util::test::write_test_clip(&ts, crate::EncoderCodec::H265, (320, 240), 30, 30.0).unwrap();
+        edit_metadata(&ts, Some(&mkv), &MetadataEdits::default()).unwrap();
+
+        let ictx = format::input(&mkv).unwrap();
+        let stream = ictx.streams().best(media::Type::Video).unwrap();
+        let extradata_size = unsafe { (*stream.parameters().as_ptr()).extradata_size };
+        // hvcC, with the parameter sets taken from the first keyframe
+        assert!(extradata_size > 23, "{extradata_size}");
+        drop(ictx);
+
+        let mut decoder = crate::Decoder::new(&mkv, crate::DecoderOptions::default()).unwrap();
+        let mut frames = 0;
+        while let Some(frame) = decoder.next_frame() {
+            if let crate::Frame::Video(_) = frame { frames += 1; }
+        }
+        let _ = std::fs::remove_file(&ts);
+        let _ = std::fs::remove_file(&mkv);
+        assert_eq!(frames, 30);
+    }
+
+    /// Joins TS files one after another with continuous timestamps, the parameter sets switch at every join
+    fn splice_ts(inputs: &[&str], output: &str) {
+        let mut octx = format::output(&output).unwrap();
+        let mut offset = 0;
+        for (i, input) in inputs.iter().enumerate() {
+            let mut ictx = format::input(input).unwrap();
+            let ist = ictx.streams().best(media::Type::Video).unwrap();
+            let (index, time_base) = (ist.index(), ist.time_base());
+            if i == 0 {
+                let mut ost = octx.add_stream(ffmpeg_next::encoder::find(codec::Id::None)).unwrap();
+                ost.set_parameters(ist.parameters());
+                ost.set_time_base(time_base);
+                octx.write_header().unwrap();
+            }
+            let out_time_base = octx.stream(0).unwrap().time_base();
+            let mut end = offset;
+            for (stream, mut packet) in ictx.packets() {
+                if stream.index() != index { continue; }
+                packet.rescale_ts(time_base, out_time_base);
+                packet.set_pts(packet.pts().map(|x| x + offset));
+                packet.set_dts(packet.dts().map(|x| x + offset));
+                end = end.max(packet.pts().unwrap_or(0) + packet.duration().max(1));
+                packet.set_stream(0);
+                packet.set_position(-1);
+                packet.write_interleaved(&mut octx).unwrap();
+            }
+            offset = end;
+        }
+        octx.write_trailer().unwrap();
+    }
+
+    #[test]
+    fn parameter_sets_switch_mid_stream() {
+        let parts = [crate::util::test::temp_path("part1.ts"), crate::util::test::temp_path("part2.ts")];
+        let ts = crate::util::test::temp_path("spliced.ts");
+        let mp4 = crate::util::test::temp_path("spliced.mp4");
+        crate::util::test::write_test_clip(&parts[0], crate::EncoderCodec::H264, (320, 240), 24, 24.0).unwrap();
+        crate::util::test::write_test_clip(&parts[1], crate::EncoderCodec::H264, (640, 360), 24, 24.0).unwrap();
+        splice_ts(&[&parts[0], &parts[1]], &ts);
+        edit_metadata(&ts, Some(&mp4), &MetadataEdits::default()).unwrap();
+
+        // Plays through both segments
+        let mut decoder = crate::Decoder::new(&mp4, crate::DecoderOptions::default()).unwrap();
+        let mut sizes = Vec::new();
+        while let Some(frame) = decoder.next_frame() {
+            if let crate::Frame::Video(frame) = frame {
+                use crate::VideoFrameInterface;
+                sizes.push((frame.width(), frame.height()));
+            }
+        }
+        drop(decoder);
+        for path in parts.iter().chain([&ts, &mp4]) {
+            let _ = std::fs::remove_file(path);
+        }
+        assert_eq!(sizes.len(), 48);
+        assert!(sizes[..24].iter().all(|&x| x == (320, 240)));
+        assert!(sizes[24..].iter().all(|&x| x == (640, 360)));
+    }
+
+    fn write_tagged_clip(path: &str, tags: &[(&str, &str)]) {
+        use crate::{ Encoder, EncoderParams, EncoderCodec, PixelFormat, VideoFrame };
+        let params = EncoderParams { metadata: tags.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(), ..EncoderParams::new(EncoderCodec::H264, 320, 240, PixelFormat::YUV420P, 24.0) };
+        let mut encoder = Encoder::new(crate::IoType::from(path), params).unwrap();
+        for i in 0..12 {
+            encoder.encode_frame(&mut VideoFrame::solid_color(PixelFormat::RGBA, 320, 240, [i * 20, 40, 60, 255]).unwrap()).unwrap();
+        }
+        encoder.finish().unwrap();
+    }
+
+    #[test]
+    fn data_streams_are_copied() {
+        let mov = crate::util::test::temp_path("timecode.mov");
+        let copy = crate::util::test::temp_path("timecode-copy.mov");
+        let mkv = crate::util::test::temp_path("timecode-copy.mkv");
+        // The muxer adds a `tmcd` track for the timecode
+        write_tagged_clip(&mov, &[("timecode", "01:00:00:00")]);
+        edit_metadata(&mov, Some(&copy), &MetadataEdits::default()).unwrap();
+        edit_metadata(&mov, Some(&mkv), &MetadataEdits::default()).unwrap();
+
+        let streams = |path: &str| format::input(&path).unwrap().streams().map(|x| (x.parameters().medium(), unsafe { (*x.parameters().as_ptr()).codec_tag })).collect::<Vec<_>>();
+        let tmcd = u32::from_le_bytes(*b"tmcd");
+        let (source, copied, matroska) = (streams(&mov), streams(&copy), streams(&mkv));
+        let timecode = format::input(&copy).unwrap().streams().find_map(|x| x.metadata().get("timecode").map(str::to_string));
+        for path in [&mov, &copy, &mkv] {
+            let _ = std::fs::remove_file(path);
+        }
+        assert_eq!(source.iter().filter(|x| x.0 == media::Type::Data).count(), 1);
+        assert_eq!(copied, source);
+        assert!(copied.contains(&(media::Type::Data, tmcd)));
+        assert_eq!(timecode.as_deref(), Some("01:00:00:00"));
+        // Matroska can't store data streams
+        assert_eq!(matroska.iter().map(|x| x.0).collect::<Vec<_>>(), [media::Type::Video]);
+    }
+
+    #[test]
+    fn tags_edited_in_place() {
+        let path = crate::util::test::temp_path("tags.mp4");
+        write_tagged_clip(&path, &[("title", "First take")]);
+        let before = std::fs::read(&path).unwrap();
+        let set = |tags: &[(&str, &str)]| MetadataEdits { set: tags.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(), ..Default::default() };
+        let tags = |path: &str| {
+            let ictx = format::input(&path).unwrap();
+            let tags = ictx.metadata();
+            (tags.get("title").map(str::to_string), tags.get("creation_time").map(str::to_string))
+        };
+
+        edit_metadata(&path, None, &set(&[("title", "Final take"), ("creation_time", "2023-01-01T12:00:00Z")])).unwrap();
+        let after = std::fs::read(&path).unwrap();
+        let in_place = tags(&path);
+
+        // Needs a remux, the title gets shorter
+        edit_metadata(&path, None, &set(&[("title", "Take 2")])).unwrap();
+        let remuxed = tags(&path);
+        let mut decoder = crate::Decoder::new(&path, crate::DecoderOptions::default()).unwrap();
+        let frames = std::iter::from_fn(|| decoder.next_frame()).filter(|x| matches!(x, crate::Frame::Video(_))).count();
+        drop(decoder);
+        let _ = std::fs::remove_file(&path);
+
+        // Only the tag bytes changed, the media is untouched
+        assert_eq!(after.len(), before.len());
+        assert!(before.iter().zip(&after).filter(|(a, b)| a != b).count() <= 14);
+        assert_eq!(in_place.0.as_deref(), Some("Final take"));
+        assert!(in_place.1.is_some_and(|x| x.starts_with("2023-01-01T12:00:00")));
+        assert_eq!(remuxed.0.as_deref(), Some("Take 2"));
+        assert_eq!(frames, 12);
+    }
+
+    #[test]
+    fn bsf_for_the_target_container() {
+        assert_eq!(stream_copy_bsf(codec::Id::H264, "mov,mp4,m4a,3gp,3g2,mj2", "mpegts"), Some("h264_mp4toannexb"));
+        assert_eq!(stream_copy_bsf(codec::Id::HEVC, "matroska,webm", "mpegts"), Some("hevc_mp4toannexb"));
+        assert_eq!(stream_copy_bsf(codec::Id::H264, "mov,mp4,m4a,3gp,3g2,mj2", "matroska"), None);
+        assert_eq!(stream_copy_bsf(codec::Id::H264, "mpegts", "mp4"), None);
+        assert_eq!(stream_copy_bsf(codec::Id::AAC, "mov,mp4,m4a,3gp,3g2,mj2", "mpegts"), None);
+    }
+
+    #[test]
+    fn mp4_to_ts() {
+        let mp4 = crate::util::test::temp_path("copy-src.mp4");
+        let ts = crate::util::test::temp_path("copy-dst.ts");
+        crate::util::test::write_test_clip(&mp4, crate::EncoderCodec::H264, (320, 240), 24, 24.0).unwrap();
+        edit_metadata(&mp4, Some(&ts), &MetadataEdits::default()).unwrap();
+        let mut decoder = crate::Decoder::new(&ts, crate::DecoderOptions::default()).unwrap();
+        let mut frames = 0;
+        while let Some(frame) = decoder.next_frame() {
+            if let crate::Frame::Video(_) = frame { frames += 1; }
+        }
+        drop(decoder);
+        let _ = std::fs::remove_file(&mp4);
+        let _ = std::fs::remove_file(&ts);
+        // Length-prefixed NAL units in TS wouldn't decode at all
+        assert_eq!(frames, 24);
+    }
+
+    #[test]
+    fn constant_rate_transcode() {
+        let input = crate::util::test::temp_path("24fps.mp4");
+        let output = crate::util::test::temp_path("30fps.mp4");
+        crate::util::test::write_test_clip(&input, crate::EncoderCodec::H264, (320, 240), 24, 24.0).unwrap();
+        let params = || crate::EncoderParams::new(crate::EncoderCodec::H264, 320, 240, crate::PixelFormat::YUV420P, 24.0);
+
+        assert!(matches!(Transcoder::new(&input, &output).output_frame_rate((30, 0)).transcode(params()), Err(VideoProcessingError::InvalidFrameRate((30, 0)))));
+        let written = Transcoder::new(&input, &output).output_frame_rate((30, 1)).transcode(params()).unwrap();
+        assert_eq!(written, 30);
+
+        let mut decoder = crate::Decoder::new(&output, crate::DecoderOptions::default()).unwrap();
+        let mut timestamps = Vec::new();
+        while let Some(frame) = decoder.next_frame() {
+            if let crate::Frame::Video(frame) = frame {
+                use crate::VideoFrameInterface;
+                timestamps.push(frame.timestamp_us().unwrap());
+            }
+        }
+        let _ = std::fs::remove_file(&input);
+        let _ = std::fs::remove_file(&output);
+        let expected: Vec<_> = (0..30).map(|n| crate::util::frame_to_timestamp(n, (30, 1))).collect();
+        assert_eq!(timestamps, expected);
+    }
+
+    #[test]
+    fn avcc_parameter_sets() {
+        let avcc = [1, 0x64, 0, 0x28, 0xff, 0xe1, 0, 3, 0x67, 1, 2, 1, 0, 2, 0x68, 3];
+        let (length_size, nals) = mp4_parameter_sets(codec::Id::H264, &avcc).unwrap();
+        assert_eq!(length_size, 4);
+        assert_eq!(nals, [&[0x67, 1, 2][..], &[0x68, 3][..]]);
+
+        assert!(mp4_parameter_sets(codec::Id::H264, &[0, 0, 0, 1, 0x67, 1]).is_none());
+        assert!(mp4_parameter_sets(codec::Id::H264, &avcc[..12]).is_none());
+    }
+
+    #[test]
+    fn hvcc_parameter_sets() {
+        let mut hvcc = vec![0u8; 23];
+        hvcc[0] = 1;
+        hvcc[21] = 0xfc | 1;
+        hvcc[22] = 2;
+        hvcc.extend([0xa0, 0, 1, 0, 2, 0x40, 1]);
+        hvcc.extend([0xa2, 0, 2, 0, 1, 0x44, 0, 1, 0x45]);
+        let (length_size, nals) = mp4_parameter_sets(codec::Id::HEVC, &hvcc).unwrap();
+        assert_eq!(length_size, 2);
+        assert_eq!(nals, [&[0x40, 1][..], &[0x44][..], &[0x45][..]]);
+    }
+
+    #[test]
+    fn annexb_to_mp4() {
+        let annexb = [0, 0, 0, 1, 0x67, 1, 2, 0, 0, 1, 0x68, 3, 0, 0, 0, 0, 1, 0x65, 9, 9, 9];
+        assert_eq!(annexb_to_length_prefixed(&annexb, 4), [0, 0, 0, 3, 0x67, 1, 2, 0, 0, 0, 2, 0x68, 3, 0, 0, 0, 4, 0x65, 9, 9, 9]);
+        assert_eq!(annexb_to_length_prefixed(&annexb[..12], 2), [0, 3, 0x67, 1, 2, 0, 2, 0x68, 3]);
+        assert!(annexb_to_length_prefixed(&[0x65, 1], 4).is_empty());
+    }
+
+    #[test]
+    fn smart_cut_plan() {
+        let keyframes = [0, 1_000_000, 2_000_000, 3_000_000];
+        assert_eq!(SmartCutPlan::from_keyframes(&keyframes, 500_000, 2_500_000), SmartCutPlan {
+            head_reencode: Some((500_000, 1_000_000)),
+            copy: Some((1_000_000, 2_000_000)),
+            tail_reencode: Some((2_000_000, 2_500_000)),
+        });
+        assert_eq!(SmartCutPlan::from_keyframes(&keyframes, 1_000_000, 3_000_000).head_reencode, None);
+        assert_eq!(SmartCutPlan::from_keyframes(&keyframes, 1_200_000, 1_800_000).copy, None);
+    }
+
+    #[test]
+    fn smart_cut_with_b_frames() {
+        use crate::{ EncoderParams, EncoderCodec, PixelFormat, VideoFrame, VideoFrameInterface };
+        // 30 frames at 25 fps with a keyframe every 10 frames (0.4 s) and B-frames
+        let input = crate::util::test::temp_path("smart-cut-input.mp4");
+        let mut params = EncoderParams::new(EncoderCodec::H264, 320, 240, PixelFormat::YUV420P, 25.0);
+        params.gop_size = Some(10);
+        params.custom_options.insert("bf".into(), "2".into());
+        params.custom_options.insert("sc_threshold".into(), "0".into());
+        let mut encoder = crate::Encoder::new(crate::IoType::from(input.as_str()), params).unwrap();
+        for i in 0..30 {
+            let shade = (i * 8) as u8;
+            encoder.encode_frame(&mut VideoFrame::solid_color(PixelFormat::RGBA, 320, 240, [shade, 255 - shade, 128, 255]).unwrap()).unwrap();
+        }
+        encoder.finish().unwrap();
+
+        // Frames 3 to 24, both ends are inside a GOP
+        let output = crate::util::test::temp_path("smart-cut-output.mp4");
+        let plan = crate::Transcoder::new(&input, &output).smart_cut(120_000, 1_000_000).unwrap();
+        assert_eq!(plan, SmartCutPlan {
+            head_reencode: Some((120_000, 400_000)),
+            copy: Some((400_000, 800_000)),
+            tail_reencode: Some((800_000, 1_000_000)),
+        });
+
+        let mut decoder = crate::Decoder::new(&output, Default::default()).unwrap();
+        let timestamps: Vec<i64> = std::iter::from_fn(|| decoder.next_frame())
+            .filter_map(|x| match x { crate::Frame::Video(v) => v.timestamp_us(), _ => None })
+            .collect();
+        drop(decoder);
+        assert_eq!(timestamps.len(), 22, "{timestamps:?}");
+        assert_eq!(timestamps[0], 0, "{timestamps:?}");
+        assert!(timestamps.windows(2).all(|x| x[0] < x[1]), "{timestamps:?}");
+
+        let mkv = crate::util::test::temp_path("smart-cut-output.mkv");
+        assert!(matches!(crate::Transcoder::new(&input, &mkv).smart_cut(120_000, 1_000_000), Err(VideoProcessingError::SmartCutUnsupported { .. })));
+        for path in [&input, &output, &mkv] {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
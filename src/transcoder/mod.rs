@@ -0,0 +1,141 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+mod ffmpeg; pub use ffmpeg::*;
+mod mp4;
+
+use std::collections::HashMap;
+
+/// Changes applied by `edit_metadata`
+#[derive(Debug, Clone, Default)]
+pub struct MetadataEdits {
+    /// Container tags to add or overwrite, e.g. `creation_time`
+    pub set: HashMap<String, String>,
+    /// Tags to remove from the container and all streams
+    pub remove: Vec<String>,
+    /// New clockwise rotation of the video streams in degrees, written to the display matrix
+    pub rotation: Option<i32>,
+    /// Removes location tags (`location`, `com.apple.quicktime.location.ISO6709`, `gps*`)
+    pub strip_gps: bool,
+}
+
+impl MetadataEdits {
+    pub fn is_gps_key(key: &str) -> bool {
+        let key = key.to_ascii_lowercase();
+        key.contains("location") || key.starts_with("gps")
+    }
+
+    /// Whether the tag `key` should be dropped from the output
+    pub fn removes(&self, key: &str) -> bool {
+        self.remove.iter().any(|x| x.eq_ignore_ascii_case(key)) || (self.strip_gps && Self::is_gps_key(key))
+    }
+}
+
+/// Writes a new file from `input` to `output`
+#[derive(Debug, Clone)]
+pub struct Transcoder {
+    input: String,
+    output: String,
+    output_frame_rate: Option<(i32, i32)>,
+}
+
+impl Transcoder {
+    pub fn new(input: &str, output: &str) -> Self {
+        Self { input: input.to_string(), output: output.to_string(), output_frame_rate: None }
+    }
+
+    /// Resamples the video to a constant rate (numerator, denominator) in `transcode`, duplicating and dropping frames, see `FrameRetimer`
+    pub fn output_frame_rate(mut self, rate: (i32, i32)) -> Self {
+        self.output_frame_rate = Some(rate);
+        self
+    }
+
+    /// Decodes the video of the input and encodes it with `params`. Returns the number of frames written.
+    /// With `output_frame_rate`, the params frame rate and time base are replaced by it, so every frame is one tick of the time base
+    pub fn transcode(&self, mut params: crate::EncoderParams) -> Result<usize, crate::VideoProcessingError> {
+        use crate::VideoFrameInterface;
+        let mut retimer = self.output_frame_rate.map(crate::FrameRetimer::new).transpose()?;
+        if let Some(rate) = self.output_frame_rate {
+            params.frame_rate = rate.0 as f32 / rate.1 as f32;
+            params.time_base = Some((rate.1 as u32, rate.0 as u32));
+        }
+        let mut decoder = crate::Decoder::new(&self.input, crate::DecoderOptions::default())?;
+        let duration_us = (decoder.get_video_info()?.duration_ms * 1000.0).round() as i64;
+        let mut encoder = crate::Encoder::new(crate::IoType::from(self.output.as_str()), params)?;
+
+        let mut written = 0;
+        // Latest input frame, duplicated into the gaps by re-referencing it
+        let mut previous: Option<crate::VideoFrame> = None;
+        while let Some(frame) = decoder.next_frame() {
+            let crate::Frame::Video(mut frame) = frame else { continue; };
+            let Some(retimer) = retimer.as_mut() else {
+                encoder.encode_frame(&mut frame)?;
+                written += 1;
+                continue;
+            };
+            // Can't be placed on the output grid
+            let Some(timestamp_us) = frame.timestamp_us() else { continue; };
+            let slots = retimer.push(timestamp_us);
+            if let Some(previous) = previous.as_mut() {
+                for slot in slots.previous {
+                    previous.set_timestamp_us(Some(retimer.timestamp_us(slot)));
+                    encoder.encode_frame(previous)?;
+                    written += 1;
+                }
+            }
+            if let Some(slot) = slots.current {
+                frame.set_timestamp_us(Some(retimer.timestamp_us(slot)));
+                encoder.encode_frame(&mut frame)?;
+                written += 1;
+            }
+            previous = Some(frame);
+        }
+        if let (Some(retimer), Some(previous)) = (retimer.as_mut(), previous.as_mut()) {
+            for slot in retimer.finish(duration_us) {
+                previous.set_timestamp_us(Some(retimer.timestamp_us(slot)));
+                encoder.encode_frame(previous)?;
+                written += 1;
+            }
+        }
+        encoder.finish()?;
+        Ok(written)
+    }
+
+    /// Cuts `start_us..end_us` without re-encoding the whole range, see `smart_cut`. Returns the executed plan
+    pub fn smart_cut(&self, start_us: i64, end_us: i64) -> Result<SmartCutPlan, crate::VideoProcessingError> {
+        smart_cut(&self.input, &self.output, start_us, end_us)
+    }
+}
+
+/// Time ranges in microseconds
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SmartCutPlan {
+    /// Partial GOP at the in point, which has to be re-encoded
+    pub head_reencode: Option<(i64, i64)>,
+    /// Whole GOPs which can be stream copied
+    pub copy: Option<(i64, i64)>,
+    /// Partial GOP at the out point, which has to be re-encoded
+    pub tail_reencode: Option<(i64, i64)>,
+}
+
+impl SmartCutPlan {
+    /// Splits the `start_us..end_us` range on the keyframes. `keyframes_us` must be sorted.
+    pub fn from_keyframes(keyframes_us: &[i64], start_us: i64, end_us: i64) -> Self {
+        let first_kf = keyframes_us.iter().copied().find(|&x| x >= start_us && x < end_us);
+        let last_kf = keyframes_us.iter().copied().rev().find(|&x| x > start_us && x <= end_us);
+
+        match (first_kf, last_kf) {
+            (Some(first), Some(last)) if first < last => Self {
+                head_reencode: if first > start_us { Some((start_us, first)) } else { None },
+                copy: Some((first, last)),
+                tail_reencode: if last < end_us { Some((last, end_us)) } else { None },
+            },
+            // No complete GOP in the range, everything has to be re-encoded
+            _ => Self {
+                head_reencode: Some((start_us, end_us)),
+                copy: None,
+                tail_reencode: None,
+            }
+        }
+    }
+}
@@ -0,0 +1,244 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+use super::MetadataEdits;
+use crate::types::VideoProcessingError;
+use std::io::{ Read, Seek, SeekFrom, Write };
+use std::ops::Range;
+
+/// Seconds between the MP4 epoch (1904-01-01) and the Unix epoch
+const MP4_EPOCH_OFFSET: u64 = 2082844800;
+
+/// Tag atoms of `udta` and `ilst` with the ffmpeg metadata key they are read as
+const ATOM_KEYS: &[(&[u8; 4], &str)] = &[
+    (b"\xa9nam", "title"),
+    (b"\xa9ART", "artist"),
+    (b"aART",    "album_artist"),
+    (b"\xa9alb", "album"),
+    (b"\xa9cmt", "comment"),
+    (b"\xa9day", "date"),
+    (b"\xa9des", "description"),
+    (b"desc",    "description"),
+    (b"\xa9gen", "genre"),
+    (b"\xa9too", "encoder"),
+    (b"\xa9swr", "encoder"),
+    (b"\xa9wrt", "composer"),
+    (b"cprt",    "copyright"),
+    (b"\xa9cpy", "copyright"),
+    (b"\xa9grp", "grouping"),
+    (b"\xa9xyz", "location"),
+];
+
+#[derive(Debug, Clone, Copy)]
+struct Mp4Box {
+    kind: [u8; 4],
+    /// Start of the payload, after the size and type
+    body: usize,
+    end: usize,
+}
+
+/// Value of a text tag inside `moov`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TagValue {
+    key: String,
+    range: Range<usize>,
+    /// QuickTime text with a Macintosh language code, stored in Mac Roman
+    mac_roman: bool,
+}
+
+fn be16(data: &[u8], pos: usize) -> usize {
+    u16::from_be_bytes([data[pos], data[pos + 1]]) as usize
+}
+fn be32(data: &[u8], pos: usize) -> u64 {
+    u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as u64
+}
+
+/// Boxes directly inside `data[start..end]`, stops at the first malformed one
+fn children(data: &[u8], start: usize, end: usize) -> Vec<Mp4Box> {
+    let mut ret = Vec::new();
+    let mut pos = start;
+    while pos + 8 <= end {
+        let (size, header) = match be32(data, pos) {
+            0 => ((end - pos) as u64, 8),
+            1 if pos + 16 <= end => ((be32(data, pos + 8) << 32) | be32(data, pos + 12), 16),
+            1 => break,
+            x => (x, 8)
+        };
+        if size < header as u64 || size > (end - pos) as u64 { break; }
+        let kind = [data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]];
+        ret.push(Mp4Box { kind, body: pos + header, end: pos + size as usize });
+        pos += size as usize;
+    }
+    ret
+}
+
+fn child(data: &[u8], parent: Mp4Box, kind: &[u8; 4]) -> Option<Mp4Box> {
+    children(data, parent.body, parent.end).into_iter().find(|x| &x.kind == kind)
+}
+
+/// `meta` is a full box in `udta` (iTunes style) but not directly in `moov` (QuickTime `mdta` keys)
+fn meta_children(data: &[u8], meta: Mp4Box) -> Vec<Mp4Box> {
+    let skip = if data.get(meta.body + 4..meta.body + 8) == Some(&b"hdlr"[..]) { 0 } else { 4 };
+    children(data, (meta.body + skip).min(meta.end), meta.end)
+}
+
+/// UTF-8 value of the `data` box of an `ilst` item
+fn ilst_value(data: &[u8], item: Mp4Box) -> Option<Range<usize>> {
+    let value = child(data, item, b"data")?;
+    // Type 1 is UTF-8, followed by the locale
+    (value.body + 8 <= value.end && be32(data, value.body) == 1).then_some(value.body + 8..value.end)
+}
+
+/// Container tags of `moov`: QuickTime `udta` text atoms, iTunes `udta/meta/ilst` items and `mdta` keys
+fn tag_values(data: &[u8], moov: Mp4Box) -> Vec<TagValue> {
+    let key = |kind: &[u8; 4]| ATOM_KEYS.iter().find(|(atom, _)| *atom == kind).map(|(_, key)| key.to_string());
+    let mut ret = Vec::new();
+    if let Some(udta) = child(data, moov, b"udta") {
+        for atom in children(data, udta.body, udta.end) {
+            if &atom.kind == b"meta" {
+                let Some(ilst) = meta_children(data, atom).into_iter().find(|x| &x.kind == b"ilst") else { continue; };
+                for item in children(data, ilst.body, ilst.end) {
+                    if let (Some(key), Some(range)) = (key(&item.kind), ilst_value(data, item)) {
+                        ret.push(TagValue { key, range, mac_roman: false });
+                    }
+                }
+            } else if atom.kind[0] == 0xa9 && atom.body + 4 <= atom.end {
+                let (Some(key), len, lang) = (key(&atom.kind), be16(data, atom.body), be16(data, atom.body + 2)) else { continue; };
+                if atom.body + 4 + len <= atom.end {
+                    ret.push(TagValue { key, range: atom.body + 4..atom.body + 4 + len, mac_roman: lang < 0x400 });
+                }
+            }
+        }
+    }
+    if let Some(meta) = child(data, moov, b"meta") {
+        let boxes = meta_children(data, meta);
+        let (Some(keys), Some(ilst)) = (boxes.iter().find(|x| &x.kind == b"keys"), boxes.iter().find(|x| &x.kind == b"ilst")) else { return ret; };
+        // Full box with the entry count, then the keys
+        let names: Vec<String> = children(data, (keys.body + 8).min(keys.end), keys.end).iter()
+            .map(|x| String::from_utf8_lossy(&data[x.body..x.end]).into_owned())
+            .collect();
+        for item in children(data, ilst.body, ilst.end) {
+            let index = u32::from_be_bytes(item.kind) as usize;
+            if let (Some(key), Some(range)) = (index.checked_sub(1).and_then(|x| names.get(x)), ilst_value(data, item)) {
+                ret.push(TagValue { key: key.clone(), range, mac_roman: false });
+            }
+        }
+    }
+    ret
+}
+
+/// Writes `creation_time` to `mvhd`, `false` when it can't be parsed or doesn't fit a version 0 box
+fn set_creation_time(data: &mut [u8], moov: Mp4Box, value: &str) -> bool {
+    let (Some(mvhd), Some(time)) = (child(data, moov, b"mvhd"), crate::util::parse_creation_time(value)) else { return false; };
+    let time = time + MP4_EPOCH_OFFSET;
+    match data.get(mvhd.body) {
+        Some(0) if mvhd.body + 8 <= mvhd.end && time <= u32::MAX as u64 => data[mvhd.body + 4..mvhd.body + 8].copy_from_slice(&(time as u32).to_be_bytes()),
+        Some(1) if mvhd.body + 12 <= mvhd.end => data[mvhd.body + 4..mvhd.body + 12].copy_from_slice(&time.to_be_bytes()),
+        _ => return false
+    }
+    true
+}
+
+/// Offset and contents of the top level `moov` box, reading only the box headers before it
+fn read_moov(file: &mut std::fs::File) -> std::io::Result<Option<(u64, Vec<u8>)>> {
+    let len = file.metadata()?.len();
+    let mut pos = 0;
+    while pos + 8 <= len {
+        let mut header = [0u8; 16];
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut header[..(len - pos).min(16) as usize])?;
+        let (size, header_size) = match be32(&header, 0) {
+            0 => (len - pos, 8),
+            1 => ((be32(&header, 8) << 32) | be32(&header, 12), 16),
+            x => (x, 8)
+        };
+        if size < header_size || size > len - pos { return Ok(None); }
+        if &header[4..8] == b"moov" {
+            let mut data = vec![0u8; size as usize];
+            file.seek(SeekFrom::Start(pos))?;
+            file.read_exact(&mut data)?;
+            return Ok(Some((pos, data)));
+        }
+        pos += size;
+    }
+    Ok(None)
+}
+
+/// Applies `edits.set` by overwriting the tag values in the `moov` box of an MP4/MOV file, without copying the media.
+/// Only possible when no box changes size: every tag already exists with a value of the same length, except `creation_time`
+/// which is a fixed size field of `mvhd`. Returns `false` without touching the file otherwise, the caller has to remux.
+/// Removals and rotation aren't handled here
+pub(crate) fn edit_tags_in_place(path: &str, edits: &MetadataEdits) -> Result<bool, VideoProcessingError> {
+    let mut file = std::fs::OpenOptions::new().read(true).write(true).open(path)?;
+    let Some((offset, mut data)) = read_moov(&mut file)? else { return Ok(false); };
+    let Some(moov) = children(&data, 0, data.len()).first().copied() else { return Ok(false); };
+    if !apply_tags(&mut data, moov, edits) { return Ok(false); }
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(&data)?;
+    Ok(true)
+}
+
+fn apply_tags(data: &mut [u8], moov: Mp4Box, edits: &MetadataEdits) -> bool {
+    let tags = tag_values(data, moov);
+    for (key, value) in &edits.set {
+        if key.eq_ignore_ascii_case("creation_time") {
+            if !set_creation_time(data, moov, value) { return false; }
+            continue;
+        }
+        let found: Vec<&TagValue> = tags.iter().filter(|x| x.key.eq_ignore_ascii_case(key)).collect();
+        if found.is_empty() || found.iter().any(|x| x.range.len() != value.len() || (x.mac_roman && !value.is_ascii())) {
+            return false;
+        }
+        for tag in found {
+            data[tag.range.clone()].copy_from_slice(value.as_bytes());
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mp4_box(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        [&(payload.len() as u32 + 8).to_be_bytes()[..], kind, payload].concat()
+    }
+    fn data_box(text: &str) -> Vec<u8> {
+        mp4_box(b"data", &[&1u32.to_be_bytes()[..], &[0; 4], text.as_bytes()].concat())
+    }
+
+    #[test]
+    fn tags_in_every_location() {
+        let mut mvhd = vec![0u8; 100];
+        mvhd[4..8].copy_from_slice(&(MP4_EPOCH_OFFSET as u32 + 1000).to_be_bytes());
+        let quicktime = mp4_box(b"\xa9cmt", &[&4u16.to_be_bytes()[..], &0u16.to_be_bytes(), b"take"].concat());
+        let itunes = mp4_box(b"meta", &[&[0u8; 4][..], &mp4_box(b"hdlr", &[0; 25]), &mp4_box(b"ilst", &mp4_box(b"\xa9nam", &data_box("First take")))].concat());
+        let keys = mp4_box(b"keys", &[&[0u8; 4][..], &1u32.to_be_bytes(), &mp4_box(b"mdta", b"com.apple.quicktime.make")].concat());
+        let mdta = mp4_box(b"meta", &[mp4_box(b"hdlr", &[0; 25]), keys, mp4_box(b"ilst", &mp4_box(&1u32.to_be_bytes(), &data_box("Apple")))].concat());
+        let mut data = mp4_box(b"moov", &[mp4_box(b"mvhd", &mvhd), mp4_box(b"udta", &[quicktime, itunes].concat()), mdta].concat());
+        let moov = children(&data, 0, data.len())[0];
+
+        let tags = tag_values(&data, moov);
+        let value = |tags: &[TagValue], data: &[u8], key: &str| tags.iter().find(|x| x.key == key).map(|x| String::from_utf8_lossy(&data[x.range.clone()]).into_owned());
+        assert_eq!(tags.len(), 3);
+        assert_eq!(value(&tags, &data, "comment").as_deref(), Some("take"));
+        assert_eq!(value(&tags, &data, "title").as_deref(), Some("First take"));
+        assert_eq!(value(&tags, &data, "com.apple.quicktime.make").as_deref(), Some("Apple"));
+        assert!(tags.iter().find(|x| x.key == "comment").unwrap().mac_roman);
+
+        let edits = |set: &[(&str, &str)]| MetadataEdits { set: set.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(), ..Default::default() };
+        let len = data.len();
+        assert!(apply_tags(&mut data, moov, &edits(&[("title", "Final take"), ("creation_time", "2023-01-01T12:00:00Z"), ("com.apple.quicktime.make", "Canon")])));
+        assert_eq!(data.len(), len);
+        let tags = tag_values(&data, moov);
+        assert_eq!(value(&tags, &data, "title").as_deref(), Some("Final take"));
+        assert_eq!(value(&tags, &data, "com.apple.quicktime.make").as_deref(), Some("Canon"));
+        assert_eq!(be32(&data, 8 + 8 + 4), 1672574400 + MP4_EPOCH_OFFSET);
+
+        // Different lengths, missing tags and non-ASCII Mac Roman text need a remux
+        assert!(!apply_tags(&mut data, moov, &edits(&[("title", "Take")])));
+        assert!(!apply_tags(&mut data, moov, &edits(&[("artist", "Someone")])));
+        assert!(!apply_tags(&mut data, moov, &edits(&[("comment", "tåk")])));
+        assert!(!apply_tags(&mut data, moov, &edits(&[("creation_time", "yesterday")])));
+    }
+}
@@ -3,27 +3,263 @@
 
 use thiserror::Error;
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum PixelFormat {
     Unknown,
-    AYUV64LE,
+    AYUV64LE, // packed 4:4:4:4, 16-bit little endian A, Y, U, V
+    VUYA, // packed 4:4:4:4, 8-bit V, U, Y, A
 
     NV12, NV21,
     NV16,
     NV24, NV42,
-    P010LE, P016LE,
-    P210LE, P216LE,
-    P410LE, P416LE,
+    P010LE, P012LE, P016LE,
+    P210LE, P212LE, P216LE,
+    P410LE, P412LE, P416LE,
+    Y210LE, Y212LE, // packed 4:2:2, data in the MSBs
+    XV30LE, // packed 4:4:4, 10 bits per component in a 32-bit word
+    XV36LE, // packed 4:4:4, data in the MSBs
+    GRAY8,
+    GRAY16LE,
+    GRAY16BE, // e.g. 16-bit grayscale PNG and TIFF
+    RGB24,
     RGB32,
     RGB48BE,
     RGBA,
     BGRA,
     RGBA64BE,
+    RGBF16, // half float, used for scene-linear output
+    RGB48LE, RGBA64LE,
+    GBRP10LE, GBRP12LE, GBRP16LE, // planar G, B, R, as decoded from DPX
+    GBRP10BE, GBRP12BE, // planar G, B, R from big-endian DPX, the common case
+    GBRAP10LE, GBRAP12LE,
+    GBRPF32LE, GBRAPF32LE, // planar 32-bit float G, B, R (A), as decoded from EXR, values are not clamped to 0..1
+    GRAYF32LE,
     YUV420P, YUV420P10LE, YUV420P12LE, YUV420P14LE, YUV420P16LE,
     YUV422P, YUV422P10LE, YUV422P12LE, YUV422P14LE, YUV422P16LE,
     YUV444P, YUV444P10LE, YUV444P12LE, YUV444P14LE, YUV444P16LE,
+    YUVA420P, YUVA444P, // alpha plane is always full resolution
+
+    UYVY422, YUYV422,
+
+    PAL8, // 8-bit indices into a 256 entry palette, see `VideoFrameInterface::palette`
+}
+
+impl PixelFormat {
+    /// Every variant except `Unknown`
+    pub const ALL: &'static [PixelFormat] = {
+        use PixelFormat::*;
+        &[
+            AYUV64LE, VUYA, NV12, NV21, NV16, NV24, NV42,
+            P010LE, P012LE, P016LE, P210LE, P212LE, P216LE, P410LE, P412LE, P416LE,
+            Y210LE, Y212LE, XV30LE, XV36LE, GRAY8, GRAY16LE, GRAY16BE,
+            RGB24, RGB32, RGB48BE, RGBA, BGRA, RGBA64BE, RGBF16, RGB48LE, RGBA64LE,
+            GBRP10LE, GBRP12LE, GBRP16LE, GBRP10BE, GBRP12BE, GBRAP10LE, GBRAP12LE, GBRPF32LE, GBRAPF32LE, GRAYF32LE,
+            YUV420P, YUV420P10LE, YUV420P12LE, YUV420P14LE, YUV420P16LE,
+            YUV422P, YUV422P10LE, YUV422P12LE, YUV422P14LE, YUV422P16LE,
+            YUV444P, YUV444P10LE, YUV444P12LE, YUV444P14LE, YUV444P16LE,
+            YUVA420P, YUVA444P, UYVY422, YUYV422, PAL8,
+        ]
+    };
+
+    /// Number of significant bits per component
+    pub fn bit_depth(&self) -> u32 {
+        use PixelFormat::*;
+        match self {
+            P010LE | P210LE | P410LE | Y210LE | XV30LE |
+            YUV420P10LE | YUV422P10LE | YUV444P10LE | GBRP10LE | GBRP10BE | GBRAP10LE => 10,
+            P012LE | P212LE | P412LE | Y212LE | XV36LE |
+            YUV420P12LE | YUV422P12LE | YUV444P12LE | GBRP12LE | GBRP12BE | GBRAP12LE => 12,
+            YUV420P14LE | YUV422P14LE | YUV444P14LE => 14,
+            P016LE | P216LE | P416LE | AYUV64LE | RGB48BE | RGBA64BE | RGBF16 | GRAY16LE | GRAY16BE | RGB48LE | RGBA64LE | GBRP16LE |
+            YUV420P16LE | YUV422P16LE | YUV444P16LE => 16,
+            GBRPF32LE | GBRAPF32LE | GRAYF32LE => 32,
+            _ => 8
+        }
+    }
+
+    /// Whether the samples are floating point, usually scene-linear. Such frames are never reduced by `preferred_bit_depth`
+    pub fn is_float(&self) -> bool {
+        matches!(self, PixelFormat::RGBF16 | PixelFormat::GBRPF32LE | PixelFormat::GBRAPF32LE | PixelFormat::GRAYF32LE)
+    }
+
+    /// Whether the samples are stored in the most significant bits of a 16-bit container (P010 style),
+    /// as opposed to the least significant bits (YUV420P10LE style)
+    pub fn is_msb_aligned(&self) -> bool {
+        use PixelFormat::*;
+        matches!(self, P010LE | P012LE | P210LE | P212LE | P410LE | P412LE | Y210LE | Y212LE | XV36LE)
+    }
+
+    /// Normalizes a raw 16-bit sample of this format to 0..1
+    pub fn normalize_sample(&self, raw: u16) -> f32 {
+        let depth = self.bit_depth().min(16);
+        let value = if self.is_msb_aligned() { raw >> (16 - depth) } else { raw & ((1u32 << depth) - 1) as u16 };
+        value as f32 / ((1u32 << depth) - 1) as f32
+    }
+
+    /// Memory layout of every plane, in the order returned by `get_cpu_buffers`
+    pub fn plane_layouts(&self) -> Vec<PlaneLayout> {
+        use PixelFormat::*;
+        let plane = |components, block_bytes, block_width, subsampling| PlaneLayout { components, block_bytes, block_width, subsampling };
+        let wide = if self.bit_depth() > 8 { 2 } else { 1 };
+        match self {
+            Unknown => Vec::new(),
+            AYUV64LE => vec![plane("AYUV", 8, 1, (1, 1))],
+            VUYA     => vec![plane("VUYA", 4, 1, (1, 1))],
+            XV30LE   => vec![plane("UYVX", 4, 1, (1, 1))], // 10-bit fields from the LSB of a 32-bit word
+            XV36LE   => vec![plane("UYVX", 8, 1, (1, 1))],
+            NV12 => vec![plane("Y", 1, 1, (1, 1)), plane("UV", 2, 1, (2, 2))],
+            NV21 => vec![plane("Y", 1, 1, (1, 1)), plane("VU", 2, 1, (2, 2))],
+            NV16 => vec![plane("Y", 1, 1, (1, 1)), plane("UV", 2, 1, (2, 1))],
+            NV24 => vec![plane("Y", 1, 1, (1, 1)), plane("UV", 2, 1, (1, 1))],
+            NV42 => vec![plane("Y", 1, 1, (1, 1)), plane("VU", 2, 1, (1, 1))],
+            P010LE | P012LE | P016LE => vec![plane("Y", 2, 1, (1, 1)), plane("UV", 4, 1, (2, 2))],
+            P210LE | P212LE | P216LE => vec![plane("Y", 2, 1, (1, 1)), plane("UV", 4, 1, (2, 1))],
+            P410LE | P412LE | P416LE => vec![plane("Y", 2, 1, (1, 1)), plane("UV", 4, 1, (1, 1))],
+            Y210LE | Y212LE => vec![plane("YUYV", 8, 2, (1, 1))],
+            UYVY422 => vec![plane("UYVY", 4, 2, (1, 1))],
+            YUYV422 => vec![plane("YUYV", 4, 2, (1, 1))],
+            GRAY8 => vec![plane("Y", 1, 1, (1, 1))],
+            GRAY16LE | GRAY16BE => vec![plane("Y", 2, 1, (1, 1))],
+            PAL8  => vec![plane("P", 1, 1, (1, 1))],
+            RGB24 => vec![plane("RGB", 3, 1, (1, 1))],
+            RGB32 if cfg!(target_endian = "little") => vec![plane("BGRA", 4, 1, (1, 1))],
+            RGB32 => vec![plane("ARGB", 4, 1, (1, 1))],
+            RGBA  => vec![plane("RGBA", 4, 1, (1, 1))],
+            BGRA  => vec![plane("BGRA", 4, 1, (1, 1))],
+            RGB48BE | RGB48LE | RGBF16 => vec![plane("RGB", 6, 1, (1, 1))],
+            RGBA64BE | RGBA64LE => vec![plane("RGBA", 8, 1, (1, 1))],
+            GBRP10LE | GBRP12LE | GBRP16LE | GBRP10BE | GBRP12BE => vec![plane("G", 2, 1, (1, 1)), plane("B", 2, 1, (1, 1)), plane("R", 2, 1, (1, 1))],
+            GBRAP10LE | GBRAP12LE => vec![plane("G", 2, 1, (1, 1)), plane("B", 2, 1, (1, 1)), plane("R", 2, 1, (1, 1)), plane("A", 2, 1, (1, 1))],
+            GBRPF32LE  => vec![plane("G", 4, 1, (1, 1)), plane("B", 4, 1, (1, 1)), plane("R", 4, 1, (1, 1))],
+            GBRAPF32LE => vec![plane("G", 4, 1, (1, 1)), plane("B", 4, 1, (1, 1)), plane("R", 4, 1, (1, 1)), plane("A", 4, 1, (1, 1))],
+            GRAYF32LE  => vec![plane("Y", 4, 1, (1, 1))],
+            YUV420P | YUV420P10LE | YUV420P12LE | YUV420P14LE | YUV420P16LE => vec![plane("Y", wide, 1, (1, 1)), plane("U", wide, 1, (2, 2)), plane("V", wide, 1, (2, 2))],
+            YUV422P | YUV422P10LE | YUV422P12LE | YUV422P14LE | YUV422P16LE => vec![plane("Y", wide, 1, (1, 1)), plane("U", wide, 1, (2, 1)), plane("V", wide, 1, (2, 1))],
+            YUV444P | YUV444P10LE | YUV444P12LE | YUV444P14LE | YUV444P16LE => vec![plane("Y", wide, 1, (1, 1)), plane("U", wide, 1, (1, 1)), plane("V", wide, 1, (1, 1))],
+            YUVA420P => vec![plane("Y", 1, 1, (1, 1)), plane("U", 1, 1, (2, 2)), plane("V", 1, 1, (2, 2)), plane("A", 1, 1, (1, 1))],
+            YUVA444P => vec![plane("Y", 1, 1, (1, 1)), plane("U", 1, 1, (1, 1)), plane("V", 1, 1, (1, 1)), plane("A", 1, 1, (1, 1))],
+        }
+    }
+}
+
+/// Layout of a single plane. Rows are padded to the frame stride
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PlaneLayout {
+    /// Components in memory order, e.g. "AYUV" or "UV" for interleaved chroma
+    pub components: &'static str,
+    /// Bytes of one block of `block_width` pixels, e.g. 4 for the 2 pixels of YUYV422
+    pub block_bytes: u32,
+    pub block_width: u32,
+    /// Horizontal and vertical divisor of the plane size relative to the frame
+    pub subsampling: (u32, u32),
+}
+
+impl PlaneLayout {
+    /// Number of meaningful bytes in a row of a frame `width` pixels wide, excluding the padding
+    pub fn row_bytes(&self, width: u32) -> usize {
+        (width.div_ceil(self.subsampling.0).div_ceil(self.block_width) * self.block_bytes) as usize
+    }
+    pub fn rows(&self, height: u32) -> u32 {
+        height.div_ceil(self.subsampling.1)
+    }
+    /// Byte offset of `component` of pixel `x` from the start of a row, `x` in plane coordinates (divided by the subsampling).
+    /// In packed 4:2:2 each pixel has its own luma and shares the chroma with its neighbour, e.g. `U Y0 V Y1` in UYVY422.
+    /// Not meaningful for XV30LE, whose components are bit fields of a 32-bit word
+    ///
+    /// ```
+    /// use gpu_video::PixelFormat;
+    /// let uyvy = PixelFormat::UYVY422.plane_layouts()[0];
+    /// assert_eq!(uyvy.row_bytes(1920), 3840);
+    /// assert_eq!(uyvy.component_offset(5, 'U'), Some(8));
+    /// assert_eq!(uyvy.component_offset(5, 'Y'), Some(11));
+    /// assert_eq!(uyvy.component_offset(5, 'V'), Some(10));
+    /// assert_eq!(uyvy.component_offset(5, 'A'), None);
+    /// ```
+    pub fn component_offset(&self, x: u32, component: char) -> Option<usize> {
+        let occurrences = self.components.matches(component).count();
+        if occurrences == 0 { return None; }
+        let sample_bytes = self.block_bytes as usize / self.components.len();
+        let (position, _) = self.components.match_indices(component).nth((x % self.block_width) as usize % occurrences)?;
+        Some((x / self.block_width) as usize * self.block_bytes as usize + position * sample_bytes)
+    }
+    pub fn role(&self) -> PlaneRole {
+        match self.components {
+            "Y" => PlaneRole::Luma,
+            "U" => PlaneRole::ChromaU,
+            "V" => PlaneRole::ChromaV,
+            "UV" | "VU" => PlaneRole::ChromaUV,
+            "A" => PlaneRole::Alpha,
+            "P" => PlaneRole::PaletteIndex,
+            "R" => PlaneRole::Red,
+            "G" => PlaneRole::Green,
+            "B" => PlaneRole::Blue,
+            c if c.contains('R') => PlaneRole::Rgb,
+            _ => PlaneRole::PackedYuv,
+        }
+    }
+}
 
-    UYVY422
+/// What a plane returned by `get_cpu_buffers` holds
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PlaneRole {
+    Luma,
+    ChromaU,
+    ChromaV,
+    /// Interleaved chroma (NV12, P010), see `PlaneLayout::components` for the order
+    ChromaUV,
+    /// Packed RGB, with alpha when the components include it
+    Rgb,
+    /// Packed luma, chroma and possibly alpha (YUYV422, AYUV64LE)
+    PackedYuv,
+    /// Single component of planar RGB (GBRP10LE, GBRPF32LE)
+    Red,
+    Green,
+    Blue,
+    Alpha,
+    PaletteIndex,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ColorSpace {
+    BT709,
+    BT470BG,
+    SMPTE170M,
+    BT2020NCL,
+    BT2020CL,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ColorTrc {
+    BT709,
+    SMPTE170M,
+    Linear,
+    SRGB,
+    Gamma22,
+    SMPTE2084,   // PQ
+    AribStdB67,  // HLG
+}
+impl ColorTrc {
+    pub fn is_hdr(&self) -> bool {
+        matches!(self, ColorTrc::SMPTE2084 | ColorTrc::AribStdB67)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ColorPrimaries {
+    BT709,
+    BT470BG,
+    SMPTE170M,
+    BT2020,
+    DCIP3,     // SMPTE431
+    DisplayP3, // SMPTE432
+    ACESAP0,   // ACES2065-1
+    ACESAP1,   // ACEScg, ACEScct
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ColorRange {
+    Limited,
+    Full,
 }
 
 #[derive(Debug)]
@@ -37,30 +273,183 @@ pub enum HWTexture {
     VideoToolbox { resource: *mut std::ffi::c_void }, // MTLTexture*
 }
 
+pub trait ReadSeek: std::io::Read + std::io::Seek + Send { }
+impl<T: std::io::Read + std::io::Seek + Send> ReadSeek for T { }
+
+pub enum IoType {
+    FileOrUrl(String),
+    FileDescriptor(i32),
+    Reader(Box<dyn ReadSeek>),
+    /// Images decoded as the frames of one video stream, see `DecoderOptions::sequence_frame_rate`
+    FileList(Vec<String>),
+}
+impl From<&str> for IoType {
+    fn from(path: &str) -> Self {
+        match path.strip_prefix("fd:").and_then(|x| x.parse().ok()) {
+            Some(fd) => IoType::FileDescriptor(fd),
+            None => IoType::FileOrUrl(path.to_string())
+        }
+    }
+}
+impl std::fmt::Debug for IoType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IoType::FileOrUrl(x) => write!(f, "FileOrUrl({x:?})"),
+            IoType::FileDescriptor(x) => write!(f, "FileDescriptor({x})"),
+            IoType::Reader(_) => f.write_str("Reader"),
+            IoType::FileList(x) => write!(f, "FileList({} files, first {:?})", x.len(), x.first()),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct VideoInfo {
     pub duration_ms: f64,
     pub frame_count: usize,
     pub fps: f64,
-    pub width: u32,
+    pub width: u32, // coded size
     pub height: u32,
+    pub rotation: i32, // clockwise, in degrees
+    pub mirrored: bool, // flipped horizontally before the rotation, a vertical flip is a mirror with a 180° rotation
+    pub display_width: u32, // size after applying the rotation
+    pub display_height: u32,
+    pub bitrate: f64, // in Mbps
+    pub created_at: Option<u64>, // seconds since the Unix epoch, from `creation_time` or `com.apple.quicktime.creationdate`
+    pub metadata: std::collections::HashMap<String, String>, // container and video stream tags, including the raw `creation_time`
+    pub color_space: Option<ColorSpace>, // from the codec parameters, frames can still differ
+    pub color_trc: Option<ColorTrc>,
+    pub color_primaries: Option<ColorPrimaries>,
+    pub color_range: Option<ColorRange>,
+    pub dolby_vision: Option<DolbyVisionConfig>,
+}
+
+/// Dolby Vision configuration record of the stream (`dvcC`/`dvvC` box in MP4/MOV)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DolbyVisionConfig {
+    pub profile: u8,
+    pub level: u8,
+    pub rpu_present: bool,
+    pub el_present: bool,
+    pub bl_present: bool,
+    /// What the base layer is compatible with without Dolby Vision, e.g. 4 for HLG in the 8.4 profile recorded by iPhones
+    pub bl_compatibility_id: u8,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AudioInfo {
+    pub duration_ms: f64,
+    pub codec: String,
+    pub sample_rate: u32,
+    pub channels: u32,
     pub bitrate: f64, // in Mbps
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HWDeviceInfo {
+    pub backend: String,
+    /// `None` for the default device of the backend
+    pub device_name: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Chapter {
+    pub start_us: i64,
+    pub end_us: i64,
+    pub title: Option<String>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct VideoParams {
+    pub width: u32,
+    pub height: u32,
+    pub format: PixelFormat,
+}
+
+#[derive(Debug, Clone)]
+pub enum Diagnostic {
+    BlackFrame { timestamp_us: i64, confidence: f32 },
+    FrozenFrame { timestamp_us: i64, confidence: f32 },
+    StreamReconfigured { stream_index: usize, timestamp_us: Option<i64>, old: VideoParams, new: VideoParams },
+    /// New SPS/PPS mid-stream, e.g. in MPEG-TS. Stream copies need the new parameters in the output from this point on
+    ParameterSetsChanged { stream_index: usize, timestamp_us: Option<i64> },
+    /// Hardware decoding kept failing, the rest of the stream is decoded in software
+    HardwareFallback { reason: String },
+    /// The GPU device was lost (e.g. a Windows driver timeout). The decoder is reopened on a fresh device and resumes from the last keyframe,
+    /// decoding fails with `GPUDecodingFailed` when `attempt` exceeds `max_attempts`
+    DeviceLost { attempt: u32, max_attempts: u32, reason: String },
+    /// A `custom_options` key wasn't recognized by the backend
+    UnknownOption { key: String, suggestion: Option<String> },
+    /// The consumer holds almost `DecoderOptions::max_outstanding_frames` frames, `next_frame` returns `None` at the limit
+    FrameBudgetLow { outstanding: usize, limit: usize },
+    /// Effective decode pipeline, emitted once on the first video frame
+    Pipeline(Box<crate::PipelineReport>),
+}
+
+#[derive(Clone)]
+pub struct DiagnosticsCallback(pub std::sync::Arc<dyn Fn(&Diagnostic) + Send + Sync>);
+impl std::fmt::Debug for DiagnosticsCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { f.write_str("DiagnosticsCallback") }
+}
+
+/// AES-128 key of CENC encrypted MP4/MOV streams. `Debug` doesn't show it, so it doesn't end up in logs
+#[derive(Clone, PartialEq, Eq)]
+pub struct DecryptionKey(pub Vec<u8>);
+impl DecryptionKey {
+    /// Parses the usual hex notation, e.g. `"00112233445566778899aabbccddeeff"`
+    ///
+    /// ```
+    /// use gpu_video::DecryptionKey;
+    /// assert_eq!(DecryptionKey::from_hex("00ff10").unwrap().0, [0x00, 0xff, 0x10]);
+    /// assert!(DecryptionKey::from_hex("0g").is_none());
+    /// assert!(DecryptionKey::from_hex("abc").is_none());
+    /// ```
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        let hex = hex.trim();
+        if !hex.len().is_multiple_of(2) || !hex.is_ascii() { return None; }
+        (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok()).collect::<Option<Vec<_>>>().map(Self)
+    }
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|x| format!("{x:02x}")).collect()
+    }
+}
+impl std::fmt::Debug for DecryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { write!(f, "DecryptionKey({} bytes)", self.0.len()) }
+}
+
 #[derive(Error, Debug)]
 pub enum VideoProcessingError {
     #[error("Encoder not found")]
     EncoderNotFound,
     #[error("Decoder not found")]
     DecoderNotFound,
+    #[error("No backend was compiled in, enable at least one of the backend features (e.g. `ffmpeg`)")]
+    NoBackendCompiled,
     #[error("No supported formats")]
     NoSupportedFormats,
     #[error("No output context")]
     NoOutputContext,
     #[error("Encoder converter is null")]
     EncoderConverterEmpty,
+    #[error("Encoder is already finished")]
+    EncoderFinished,
+    #[error("{outstanding} frames are alive, which reaches the limit of {limit}. Drop some frames before decoding more")]
+    WouldBlock { outstanding: usize, limit: usize },
+    #[error("Timed out after {0:?}")]
+    TimedOut(std::time::Duration),
+    #[error("Panicked: {0}")]
+    Panicked(String),
+    #[error("Frame size {needed:?} exceeds the device limit {limit:?}, {suggestion}")]
+    ExceedsDeviceLimits { needed: (u32, u32), limit: (u32, u32), suggestion: String },
+    #[error("Frame size {needed:?} is below the device minimum {minimum:?}, decode in software")]
+    BelowDeviceMinimum { needed: (u32, u32), minimum: (u32, u32) },
+    #[error("Unknown custom options: {}", .0.iter().map(|(k, s)| match s { Some(s) => format!("{k} (did you mean {s}?)"), None => k.clone() }).collect::<Vec<_>>().join(", "))]
+    UnknownOptions(Vec<(String, Option<String>)>),
     #[error("Video stream was not found")]
     VideoStreamNotFound,
+    #[error("The input has no video stream")]
+    NoVideoStream,
+    #[error("Audio stream was not found")]
+    AudioStreamNotFound,
     #[error("Converter is null")]
     ConverterEmpty,
     #[error("Frame is null")]
@@ -81,10 +470,68 @@ pub enum VideoProcessingError {
     GPUDecodingFailed,
     #[error("Error getting HW transfer buffer to the GPU: {0:?}")] // , ffmpeg_next::Error::Other { errno: .0 }
     ToHWBufferError(i32),
-    #[error("Pixel format {format:?} is not supported. Supported ones: {supported:?}")]
-    PixelFormatNotSupported { format: PixelFormat, supported: Vec<PixelFormat> },
+    #[error("Pixel format {format:?} is not supported. Supported ones: {supported:?}{}", .frame.as_ref().map(|x| format!("\n{x}")).unwrap_or_default())]
+    PixelFormatNotSupported { format: PixelFormat, supported: Vec<PixelFormat>, frame: Option<Box<crate::frame::FrameDescription>> },
     #[error("Unknown pixel format: {0:?}")]
     UnknownPixelFormat(PixelFormat),
+    #[error("Region {rect:?} is out of bounds for plane {plane} or the destination buffer is too small")]
+    InvalidRegion { plane: usize, rect: (u32, u32, u32, u32) },
+    #[error("Invalid encoder {field}: {reason}. Allowed: {allowed}")]
+    InvalidEncoderConfig { field: &'static str, reason: String, allowed: String },
+    #[error("Invalid frame rate {}/{}", .0.0, .0.1)]
+    InvalidFrameRate((i32, i32)),
+    #[error("Smart cut is not supported for {codec} in {container}")]
+    SmartCutUnsupported { codec: String, container: String },
+    #[error("Backend {backend} is not available: {reason}. Searched paths: {searched_paths:?}\n{versions}")]
+    BackendUnavailable { backend: String, reason: String, searched_paths: Vec<String>, versions: Box<crate::RuntimeVersions> },
+    #[error("Invalid external {what}: {reason}")]
+    InvalidExternalHandle { what: &'static str, reason: String },
+    #[error("gpu-video is already initialized, init_with has to be called before anything else")]
+    AlreadyInitialized,
+    #[error("Invalid decryption key: {0}")]
+    InvalidDecryptionKey(String),
+    #[error("I/O error: {0}")]
+    IoError(#[from] std::io::Error),
     #[error("ffmpeg error: {0:?}")]
     InternalError(#[from] ffmpeg_next::Error),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stores a `depth`-bit sample the way `format` does
+    fn raw_sample(format: PixelFormat, value: u16) -> u16 {
+        if format.is_msb_aligned() { value << (16 - format.bit_depth()) } else { value }
+    }
+
+    #[test]
+    fn gradient_normalizes_equally() {
+        use PixelFormat::*;
+        let formats_10 = [P010LE, P210LE, P410LE, Y210LE, YUV420P10LE, YUV422P10LE, YUV444P10LE];
+        let formats_12 = [P012LE, P212LE, P412LE, Y212LE, XV36LE, YUV420P12LE, YUV422P12LE, YUV444P12LE];
+        for (depth, formats) in [(10, &formats_10[..]), (12, &formats_12[..])] {
+            let max = (1u16 << depth) - 1;
+            for value in (0..=max).step_by(7).chain([max]) {
+                let expected = value as f32 / max as f32;
+                for &format in formats {
+                    assert_eq!(format.bit_depth(), depth);
+                    assert_eq!(format.normalize_sample(raw_sample(format, value)), expected, "{format:?} {value}");
+                }
+            }
+        }
+        // Full range 16-bit containers
+        for format in [P016LE, P216LE, P416LE] {
+            assert_eq!(format.normalize_sample(0xffff), 1.0);
+            assert_eq!(format.normalize_sample(0x8000), 0x8000 as f32 / 65535.0);
+        }
+    }
+
+    #[test]
+    fn msb_alignment() {
+        // The low bits of MSB-aligned formats are padding and the high bits of LSB-aligned ones are ignored
+        assert_eq!(PixelFormat::P012LE.normalize_sample(0xfff0 | 0xf), 1.0);
+        assert_eq!(PixelFormat::YUV420P12LE.normalize_sample(0xf000 | 0xfff), 1.0);
+        assert_eq!(PixelFormat::P016LE.bit_depth(), 16);
+    }
+}
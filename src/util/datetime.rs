@@ -0,0 +1,77 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+// Containers and cameras write `creation_time` in many formats, RFC 3339 is only one of them.
+// Timestamps without a time zone are assumed to be UTC, which is what the MP4 and MKV muxers write.
+
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (m + if m > 2 { -3 } else { 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn number(s: &str, len: usize) -> Option<(i64, &str)> {
+    if s.len() < len || !s.as_bytes()[..len].iter().all(u8::is_ascii_digit) { return None; }
+    Some((s[..len].parse().ok()?, &s[len..]))
+}
+
+fn separator<'a>(s: &'a str, allowed: &[char]) -> Option<&'a str> {
+    let c = s.chars().next()?;
+    allowed.contains(&c).then(|| &s[c.len_utf8()..])
+}
+
+/// Offset from UTC in seconds, `None` when the rest of the string isn't a time zone
+fn time_zone(s: &str) -> Option<i64> {
+    let s = s.trim();
+    match s {
+        "" | "Z" | "z" | "UTC" | "GMT" => return Some(0),
+        _ => { }
+    }
+    let s = s.strip_prefix("UTC").or_else(|| s.strip_prefix("GMT")).unwrap_or(s);
+    let sign = match s.chars().next()? { '+' => 1, '-' => -1, _ => return None };
+    let (h, rest) = number(&s[1..], 2)?;
+    let rest = rest.strip_prefix(':').unwrap_or(rest);
+    let (m, rest) = if rest.is_empty() { (0, rest) } else { number(rest, 2)? };
+    rest.is_empty().then_some(sign * (h * 3600 + m * 60))
+}
+
+/// Parses a `creation_time` tag to seconds since the Unix epoch. Accepts RFC 3339 and the common variants, e.g.
+/// `2023-01-01T12:00:00.000000Z`, `2023-01-01 12:00:00`, `2023:01:01 12:00:00+02:00` (EXIF) or `2023/01/01`.
+/// Dates before 1970, like the zeroed MP4 epoch `1904-01-01`, return `None`
+///
+/// ```
+/// use gpu_video::util::parse_creation_time;
+/// assert_eq!(parse_creation_time("2023-01-01T12:00:00Z"), Some(1672574400));
+/// assert_eq!(parse_creation_time("2023-01-01 12:00:00"), Some(1672574400));
+/// assert_eq!(parse_creation_time("2023:01:01 14:00:00+02:00"), Some(1672574400));
+/// assert_eq!(parse_creation_time("2023-01-01T12:00:00.123456 UTC"), Some(1672574400));
+/// assert_eq!(parse_creation_time("1904-01-01T00:00:00Z"), None);
+/// assert_eq!(parse_creation_time("yesterday"), None);
+/// ```
+pub fn parse_creation_time(value: &str) -> Option<u64> {
+    let s = value.trim();
+    let (year, s) = number(s, 4)?;
+    let (month, s) = number(separator(s, &['-', ':', '/', '.'])?, 2)?;
+    let (day, s) = number(separator(s, &['-', ':', '/', '.'])?, 2)?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) { return None; }
+
+    let (mut seconds, mut s) = (0, s);
+    if let Some(time) = separator(s, &['T', 't', ' ']).filter(|x| x.as_bytes().first().is_some_and(u8::is_ascii_digit)) {
+        let (h, rest) = number(time, 2)?;
+        let (m, rest) = number(separator(rest, &[':'])?, 2)?;
+        let (sec, rest) = match separator(rest, &[':']) {
+            Some(rest) => number(rest, 2)?,
+            None => (0, rest)
+        };
+        if h > 23 || m > 59 || sec > 60 { return None; }
+        seconds = h * 3600 + m * 60 + sec;
+        // Fractional seconds are dropped
+        s = rest.strip_prefix(['.', ',']).map(|x| x.trim_start_matches(|c: char| c.is_ascii_digit())).unwrap_or(rest);
+    }
+
+    let timestamp = days_from_civil(year, month, day) * 86400 + seconds - time_zone(s)?;
+    u64::try_from(timestamp).ok().filter(|&x| x > 0)
+}
@@ -0,0 +1,7 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+pub mod test;
+mod timestamp; pub use timestamp::*;
+mod suggest; pub use suggest::*;
+mod datetime; pub use datetime::*;
@@ -0,0 +1,57 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            cur[j + 1] = (prev[j] + usize::from(ca != *cb)).min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+/// Closest candidate to a mistyped `key`, if it's close enough to be a plausible typo
+///
+/// ```
+/// use gpu_video::util::did_you_mean;
+/// let known = ["decode_resolution", "hwaccel_device"];
+/// assert_eq!(did_you_mean("decode_resolutoin", &known), Some("decode_resolution"));
+/// assert_eq!(did_you_mean("bitrate", &known), None);
+/// ```
+pub fn did_you_mean<'a>(key: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    let max_distance = (key.chars().count() / 3).max(1);
+    candidates.iter()
+        .map(|c| (edit_distance(key, c), *c))
+        .filter(|(d, _)| *d <= max_distance)
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, c)| c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distance() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("abc", ""), 3);
+        assert_eq!(edit_distance("lowres", "lowres"), 0);
+        assert_eq!(edit_distance("lowers", "lowres"), 2);
+        assert_eq!(edit_distance("thread", "threads"), 1);
+    }
+
+    #[test]
+    fn suggestions() {
+        let known = ["hwaccel_device", "lowres", "threads", "skip_loop_filter"];
+        assert_eq!(did_you_mean("hwacel_device", &known), Some("hwaccel_device"));
+        assert_eq!(did_you_mean("thread", &known), Some("threads"));
+        assert_eq!(did_you_mean("skip_loopfilter", &known), Some("skip_loop_filter"));
+        // Too far from anything known
+        assert_eq!(did_you_mean("bitrate", &known), None);
+        assert_eq!(did_you_mean("x", &known), None);
+    }
+}
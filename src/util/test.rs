@@ -0,0 +1,201 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+//! Synthetic frames for exercising the pixel paths without real media files
+
+use crate::*;
+
+/// Value of the byte at column `x` (in bytes) and row `y` of `plane` in frames of `format` created by `synthetic_frame`.
+/// Samples of 10 to 14-bit formats in 16-bit containers only use the significant bits, so the frames are valid for the converters.
+pub fn pattern_value(format: PixelFormat, plane: usize, x: usize, y: usize) -> u8 {
+    let v = (x.wrapping_add(y * 3).wrapping_add(plane * 85) & 0xff) as u8;
+    let depth = format.bit_depth();
+    if depth == 8 || depth >= 16 || format == PixelFormat::XV30LE { return v; }
+    // The odd byte is the high one in little endian 16-bit containers, the even one in big endian
+    let high = x % 2 == if matches!(format, PixelFormat::GBRP10BE | PixelFormat::GBRP12BE) { 0 } else { 1 };
+    match (format.is_msb_aligned(), high) {
+        (false, true) => v & ((1u8 << (depth - 8)) - 1),
+        (true, false) => v & !((1u8 << (16 - depth)) - 1),
+        _ => v
+    }
+}
+
+/// Creates a CPU frame filled with a known, deterministic pattern (see `pattern_value`).
+/// Padding bytes at the end of each row are zeroed.
+pub fn synthetic_frame(format: PixelFormat, width: u32, height: u32) -> Result<VideoFrame, VideoProcessingError> {
+    let mut frame: VideoFrame = FfmpegVideoFrame::new_cpu(format, width, height)?.into();
+    for (plane, buffer) in frame.get_cpu_buffers()?.into_iter().enumerate() {
+        let rows = plane_rows(format, plane, height as usize);
+        let stride = buffer.len() / rows.max(1);
+        let row_bytes = plane_row_bytes(format, plane, width as usize).min(stride);
+        for (y, row) in buffer.chunks_exact_mut(stride.max(1)).enumerate() {
+            for (x, v) in row.iter_mut().enumerate() {
+                *v = if x < row_bytes { pattern_value(format, plane, x, y) } else { 0 };
+            }
+        }
+    }
+    Ok(frame)
+}
+
+/// Stable hash (FNV-1a) of the visible pixels of every plane, padding is skipped. Same across runs and platforms
+pub fn frame_hash(frame: &mut VideoFrame) -> Result<u64, VideoProcessingError> {
+    let (format, width, height) = (frame.format(), frame.width() as usize, frame.height() as usize);
+    let mut hash = 0xcbf29ce484222325u64;
+    for (plane, buffer) in frame.get_cpu_buffers()?.into_iter().enumerate() {
+        let rows = plane_rows(format, plane, height);
+        let stride = buffer.len() / rows.max(1);
+        let row_bytes = plane_row_bytes(format, plane, width).min(stride);
+        for row in buffer.chunks_exact(stride.max(1)).take(rows) {
+            for &b in &row[..row_bytes] {
+                hash = (hash ^ b as u64).wrapping_mul(0x100000001b3);
+            }
+        }
+    }
+    Ok(hash)
+}
+
+/// Path in the temp directory unique to this process, for files written by tests
+pub fn temp_path(name: &str) -> String {
+    std::env::temp_dir().join(format!("gpu-video-{}-{name}", std::process::id())).to_string_lossy().to_string()
+}
+
+/// Encodes `frames` frames of a solid color changing with every frame, so each decoded frame can be told apart
+pub fn write_test_clip(path: &str, codec: EncoderCodec, size: (u32, u32), frames: usize, fps: f32) -> Result<(), VideoProcessingError> {
+    let format = if codec == EncoderCodec::ProRes { PixelFormat::YUV422P10LE } else { PixelFormat::YUV420P };
+    let mut encoder = Encoder::new(IoType::from(path), EncoderParams::new(codec, size.0, size.1, format, fps))?;
+    for i in 0..frames {
+        let shade = (i * 255 / frames.max(1)) as u8;
+        let mut frame = VideoFrame::solid_color(PixelFormat::RGBA, size.0, size.1, [shade, 255 - shade, (i * 37 % 256) as u8, 255])?;
+        encoder.encode_frame(&mut frame)?;
+    }
+    encoder.finish()
+}
+
+/// Muxes `frames` frames of 64x48 raw video at 25 fps with mono 48 kHz 16-bit PCM into NUT, 1920 samples per video frame.
+/// Video frame `i` is filled with `i` and audio sample `n` is `n % 30000`, so positions can be checked after seeking
+pub fn write_av_test_clip(path: &str, frames: usize) -> Result<(), VideoProcessingError> {
+    use ffmpeg_next::{ codec, ffi, format, Packet, Rational };
+    const SAMPLES: usize = 1920;
+    let mut octx = format::output_as(&path, "nut")?;
+    unsafe {
+        let video = octx.add_stream(codec::Id::RAWVIDEO)?.as_mut_ptr();
+        let par = (*video).codecpar;
+        (*par).codec_type = ffi::AVMediaType::AVMEDIA_TYPE_VIDEO;
+        (*par).codec_id = ffi::AVCodecID::AV_CODEC_ID_RAWVIDEO;
+        (*par).format = ffi::AVPixelFormat::AV_PIX_FMT_YUV420P as i32;
+        (*par).width = 64;
+        (*par).height = 48;
+        (*video).time_base = ffi::AVRational { num: 1, den: 25 };
+
+        let audio = octx.add_stream(codec::Id::PCM_S16LE)?.as_mut_ptr();
+        let par = (*audio).codecpar;
+        (*par).codec_type = ffi::AVMediaType::AVMEDIA_TYPE_AUDIO;
+        (*par).codec_id = ffi::AVCodecID::AV_CODEC_ID_PCM_S16LE;
+        (*par).format = ffi::AVSampleFormat::AV_SAMPLE_FMT_S16 as i32;
+        (*par).sample_rate = 48000;
+        (*par).bits_per_coded_sample = 16;
+        (*par).block_align = 2;
+        ffi::av_channel_layout_default(&mut (*par).ch_layout, 1);
+        (*audio).time_base = ffi::AVRational { num: 1, den: 48000 };
+    }
+    octx.write_header()?;
+    let time_bases = [octx.stream(0).unwrap().time_base(), octx.stream(1).unwrap().time_base()];
+    for i in 0..frames {
+        let samples: Vec<u8> = (i * SAMPLES..(i + 1) * SAMPLES).flat_map(|n| ((n % 30000) as i16).to_le_bytes()).collect();
+        let packets = [(vec![i as u8; 64 * 48 * 3 / 2], i as i64, 1, Rational(1, 25)), (samples, (i * SAMPLES) as i64, SAMPLES as i64, Rational(1, 48000))];
+        for (stream, (data, pts, duration, time_base)) in packets.into_iter().enumerate() {
+            let mut packet = Packet::copy(&data);
+            packet.set_stream(stream);
+            packet.set_pts(Some(pts));
+            packet.set_dts(Some(pts));
+            packet.set_duration(duration);
+            packet.set_flags(ffmpeg_next::packet::Flags::KEY);
+            packet.rescale_ts(time_base, time_bases[stream]);
+            packet.write_interleaved(&mut octx)?;
+        }
+    }
+    octx.write_trailer()?;
+    Ok(())
+}
+
+fn plane_rows(format: PixelFormat, plane: usize, height: usize) -> usize {
+    format.plane_layouts().get(plane).map_or(height, |p| p.rows(height as u32) as usize)
+}
+
+/// Number of meaningful bytes in a row, excluding the padding
+fn plane_row_bytes(format: PixelFormat, plane: usize, width: usize) -> usize {
+    format.plane_layouts().get(plane).map_or(width, |p| p.row_bytes(width as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples_16(frame: &mut VideoFrame, plane: usize) -> Vec<u16> {
+        let (format, width, height) = (frame.format(), frame.width(), frame.height());
+        let layout = format.plane_layouts()[plane];
+        let buffers = frame.get_cpu_buffers().unwrap();
+        let stride = buffers[plane].len() / layout.rows(height) as usize;
+        buffers[plane].chunks_exact(stride).take(layout.rows(height) as usize)
+            .flat_map(|row| row[..layout.row_bytes(width)].chunks_exact(2).map(|x| match format {
+                PixelFormat::GBRP10BE | PixelFormat::GBRP12BE => u16::from_be_bytes([x[0], x[1]]),
+                _ => u16::from_le_bytes([x[0], x[1]])
+            }).collect::<Vec<_>>())
+            .collect()
+    }
+
+    #[test]
+    fn high_bit_depth_samples_are_in_range() {
+        for format in [PixelFormat::YUV420P10LE, PixelFormat::YUV422P12LE, PixelFormat::YUV444P14LE, PixelFormat::GBRP10BE, PixelFormat::GBRP12BE] {
+            let mut frame = synthetic_frame(format, 64, 16).unwrap();
+            let max = (1u16 << format.bit_depth()) - 1;
+            for plane in 0..3 {
+                let samples = samples_16(&mut frame, plane);
+                assert!(samples.iter().all(|&x| x <= max), "{format:?}");
+                // Still a pattern and not a constant
+                assert!(samples.iter().any(|&x| x > max / 2));
+            }
+        }
+        for format in [PixelFormat::P010LE, PixelFormat::P012LE] {
+            let mut frame = synthetic_frame(format, 64, 16).unwrap();
+            let padding = (1u16 << (16 - format.bit_depth())) - 1;
+            for plane in 0..2 {
+                assert!(samples_16(&mut frame, plane).iter().all(|&x| x & padding == 0), "{format:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn deterministic_hash() {
+        for format in [PixelFormat::NV12, PixelFormat::YUV420P10LE, PixelFormat::RGBA, PixelFormat::UYVY422] {
+            let a = frame_hash(&mut synthetic_frame(format, 33, 17).unwrap()).unwrap();
+            let b = frame_hash(&mut synthetic_frame(format, 33, 17).unwrap()).unwrap();
+            assert_eq!(a, b, "{format:?}");
+        }
+        let nv12 = frame_hash(&mut synthetic_frame(PixelFormat::NV12, 32, 16).unwrap()).unwrap();
+        let nv12_larger = frame_hash(&mut synthetic_frame(PixelFormat::NV12, 32, 18).unwrap()).unwrap();
+        assert_ne!(nv12, nv12_larger);
+    }
+
+    #[test]
+    fn conversion_of_synthetic_frames() {
+        let mut frame = synthetic_frame(PixelFormat::UYVY422, 16, 4).unwrap();
+        let [y, u, v] = Converter::default().packed_to_planar(&mut frame).unwrap();
+        for row in 0..4 {
+            for x in 0..8 {
+                assert_eq!(u[row * 8 + x], pattern_value(PixelFormat::UYVY422, 0, x * 4, row));
+                assert_eq!(y[row * 16 + x * 2], pattern_value(PixelFormat::UYVY422, 0, x * 4 + 1, row));
+                assert_eq!(v[row * 8 + x], pattern_value(PixelFormat::UYVY422, 0, x * 4 + 2, row));
+            }
+        }
+
+        let mut frame = synthetic_frame(PixelFormat::GRAY8, 8, 2).unwrap();
+        let rgba = Converter::default().gray_to_rgba(&mut frame).unwrap();
+        assert_eq!(&rgba[4..8], &[1, 1, 1, 255]);
+        // Pixel 1 is bytes 2 and 3, the pattern is the byte offset
+        let mut frame = synthetic_frame(PixelFormat::GRAY16LE, 8, 2).unwrap();
+        assert_eq!(&Converter::default().gray_to_rgba(&mut frame).unwrap()[4..8], &[3, 3, 3, 255]);
+        let mut frame = synthetic_frame(PixelFormat::GRAY16BE, 8, 2).unwrap();
+        assert_eq!(&Converter::default().gray_to_rgba(&mut frame).unwrap()[4..8], &[2, 2, 2, 255]);
+    }
+}
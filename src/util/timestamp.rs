@@ -0,0 +1,34 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+// Frame index <-> timestamp conversions shared by all backends. Everything is done in integers with round-half-to-even,
+// so `timestamp_to_frame(frame_to_timestamp(n, rate), rate) == n` for any rate below 1 MHz.
+
+fn div_round_half_even(n: i128, d: i128) -> i128 {
+    let (q, r) = (n.div_euclid(d), n.rem_euclid(d));
+    match (r * 2).cmp(&d) {
+        std::cmp::Ordering::Less => q,
+        std::cmp::Ordering::Greater => q + 1,
+        std::cmp::Ordering::Equal => q + (q & 1),
+    }
+}
+
+/// Presentation timestamp in microseconds of frame `frame` at `rate` (numerator/denominator, e.g. (30000, 1001))
+///
+/// ```
+/// use gpu_video::util::{ frame_to_timestamp, timestamp_to_frame };
+/// assert_eq!(frame_to_timestamp(1, (30000, 1001)), 33367);
+/// for n in 0..100000 {
+///     assert_eq!(timestamp_to_frame(frame_to_timestamp(n, (30000, 1001)), (30000, 1001)), n);
+/// }
+/// ```
+pub fn frame_to_timestamp(frame: i64, rate: (i32, i32)) -> i64 {
+    assert!(rate.0 > 0 && rate.1 > 0, "Invalid frame rate {rate:?}");
+    div_round_half_even(frame as i128 * rate.1 as i128 * 1_000_000, rate.0 as i128) as i64
+}
+
+/// Index of the frame nearest to `timestamp_us` at `rate`
+pub fn timestamp_to_frame(timestamp_us: i64, rate: (i32, i32)) -> i64 {
+    assert!(rate.0 > 0 && rate.1 > 0, "Invalid frame rate {rate:?}");
+    div_round_half_even(timestamp_us as i128 * rate.0 as i128, rate.1 as i128 * 1_000_000) as i64
+}
@@ -0,0 +1,69 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+use std::fmt;
+
+/// Library versions actually loaded at runtime, for bug reports
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct RuntimeVersions {
+    pub crate_version: String,
+    pub features: Vec<String>,
+
+    pub avutil: String,
+    pub avcodec: String,
+    pub avformat: String,
+    pub swscale: String,
+    pub ffmpeg_configuration: String,
+
+    // There are no BRAW and R3D backends in this build yet, these stay `None` until they are added
+    pub braw_sdk: Option<String>,
+    pub r3d_sdk: Option<String>,
+    pub r3d_sdk_path: Option<String>,
+}
+
+pub(crate) fn lib_version(v: u32) -> String {
+    format!("{}.{}.{}", v >> 16, (v >> 8) & 0xff, v & 0xff)
+}
+
+pub fn runtime_versions() -> RuntimeVersions {
+    use ffmpeg_next::ffi::*;
+    let configuration = unsafe {
+        let ptr = avcodec_configuration();
+        if ptr.is_null() { String::new() } else { std::ffi::CStr::from_ptr(ptr).to_string_lossy().into_owned() }
+    };
+    let features = [
+        ("serde", cfg!(feature = "serde")),
+        ("capi", cfg!(feature = "capi")),
+        ("ndarray", cfg!(feature = "ndarray")),
+        ("image", cfg!(feature = "image")),
+        ("tracing", cfg!(feature = "tracing")),
+    ];
+    RuntimeVersions {
+        crate_version: env!("CARGO_PKG_VERSION").into(),
+        features: features.iter().filter(|x| x.1).map(|x| x.0.to_string()).collect(),
+        avutil: lib_version(unsafe { avutil_version() }),
+        avcodec: lib_version(unsafe { avcodec_version() }),
+        avformat: lib_version(unsafe { avformat_version() }),
+        swscale: lib_version(unsafe { swscale_version() }),
+        ffmpeg_configuration: configuration,
+        braw_sdk: None,
+        r3d_sdk: None,
+        r3d_sdk_path: None,
+    }
+}
+
+impl fmt::Display for RuntimeVersions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let none = || "not loaded".to_string();
+        writeln!(f, "gpu-video {} (features: {})", self.crate_version, if self.features.is_empty() { "none".into() } else { self.features.join(", ") })?;
+        writeln!(f, "avutil {}, avcodec {}, avformat {}, swscale {}", self.avutil, self.avcodec, self.avformat, self.swscale)?;
+        writeln!(f, "ffmpeg configuration: {}", self.ffmpeg_configuration)?;
+        writeln!(f, "BRAW SDK: {}", self.braw_sdk.clone().unwrap_or_else(none))?;
+        write!(f, "R3D SDK: {}", self.r3d_sdk.clone().unwrap_or_else(none))?;
+        if let Some(path) = &self.r3d_sdk_path {
+            write!(f, " ({path})")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,110 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+use crate::support::ffmpeg_hw::{ self, HwHints };
+
+const HEADER: &str = "gpu-video warm start 2";
+
+/// Hardware probing results of this process, so the next launch can skip the devices which can't be created
+/// and try the device type which worked for each decoder first. Store `to_bytes` and pass it to `apply_warm_start`.
+/// Failed devices are only recorded after `apply_warm_start`, call it on every launch, with empty data the first time
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WarmStart {
+    environment: String,
+    hints: HwHints,
+}
+
+impl WarmStart {
+    /// Everything probed so far, call it after the first decode or encode
+    pub fn capture() -> Self {
+        Self { environment: environment(), hints: ffmpeg_hw::hw_hints() }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut lines = vec![HEADER.to_string(), format!("environment\t{}", self.environment)];
+        let mut decoders: Vec<_> = self.hints.decoders.iter().collect();
+        decoders.sort();
+        lines.extend(decoders.into_iter().map(|(codec, type_)| format!("decoder\t{codec}\t{type_}")));
+        let mut working: Vec<_> = self.hints.working.iter().collect();
+        working.sort();
+        lines.extend(working.into_iter().map(|x| format!("working\t{x}")));
+        let mut unavailable: Vec<_> = self.hints.unavailable.iter().collect();
+        unavailable.sort();
+        lines.extend(unavailable.into_iter().map(|(x, failed_at)| format!("unavailable\t{x}\t{failed_at}")));
+        (lines.join("\n") + "\n").into_bytes()
+    }
+
+    /// `None` for data of another format version
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let text = std::str::from_utf8(bytes).ok()?;
+        let mut lines = text.lines();
+        if lines.next()? != HEADER { return None; }
+        let mut ret = Self::default();
+        for line in lines {
+            match line.split('\t').collect::<Vec<_>>()[..] {
+                ["environment", x] => ret.environment = x.to_string(),
+                ["decoder", codec, type_] => { ret.hints.decoders.insert(codec.to_string(), type_.to_string()); },
+                ["working", x] => { ret.hints.working.insert(x.to_string()); },
+                ["unavailable", x, failed_at] => { ret.hints.unavailable.insert(x.to_string(), failed_at.parse().ok()?); },
+                _ => { }
+            }
+        }
+        Some(ret)
+    }
+}
+
+/// Applies a `WarmStart` captured by an earlier launch. Returns `false` and keeps the full probing when the data is invalid
+/// or the environment changed (library versions, GPU device nodes). A hinted device which fails later drops all hints as well.
+/// Devices which couldn't be created are skipped for `UNAVAILABLE_HINT_EXPIRY_SECS`, the failures of this launch only by the next one
+pub fn apply_warm_start(bytes: &[u8]) -> bool {
+    let Some(warm_start) = WarmStart::from_bytes(bytes) else {
+        log::debug!("Ignoring invalid warm start data");
+        ffmpeg_hw::merge_hw_hints(HwHints::default());
+        return false;
+    };
+    if warm_start.environment != environment() {
+        log::debug!("Ignoring the warm start, the environment changed: {} != {}", warm_start.environment, environment());
+        ffmpeg_hw::merge_hw_hints(HwHints::default());
+        return false;
+    }
+    ffmpeg_hw::merge_hw_hints(warm_start.hints);
+    true
+}
+
+/// Everything the probing results depend on that's cheap to check
+fn environment() -> String {
+    let versions = unsafe { (ffmpeg_next::ffi::avcodec_version(), ffmpeg_next::ffi::avutil_version()) };
+    let mut parts = vec![
+        env!("CARGO_PKG_VERSION").to_string(),
+        format!("avcodec {:x} avutil {:x}", versions.0, versions.1),
+        format!("{} {}", std::env::consts::OS, std::env::consts::ARCH),
+        ffmpeg_hw::supported_gpu_backends().join(","),
+    ];
+    if cfg!(target_os = "linux") {
+        // GPUs added or removed change the render nodes
+        let mut nodes: Vec<String> = std::fs::read_dir("/dev/dri").into_iter().flatten().flatten()
+            .map(|x| x.file_name().to_string_lossy().into_owned())
+            .filter(|x| x.starts_with("renderD"))
+            .collect();
+        nodes.sort();
+        parts.push(nodes.join(","));
+    }
+    parts.join(" | ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialization() {
+        let mut warm_start = WarmStart::capture();
+        warm_start.hints.decoders.insert("hevc".into(), "cuda".into());
+        warm_start.hints.working.insert("cuda:".into());
+        warm_start.hints.unavailable.insert("vaapi:/dev/dri/renderD129".into(), 1_700_000_000);
+        assert_eq!(WarmStart::from_bytes(&warm_start.to_bytes()), Some(warm_start));
+        // Failures without a time are from the first version of the format
+        assert_eq!(WarmStart::from_bytes(b"gpu-video warm start 1\nunavailable\tcuda:\n"), None);
+        assert_eq!(WarmStart::from_bytes(b"gpu-video warm start 2\nunavailable\tcuda:\tyesterday\n"), None);
+    }
+}